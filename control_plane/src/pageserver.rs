@@ -17,7 +17,9 @@ use std::time::Duration;
 use anyhow::{bail, Context};
 use camino::Utf8PathBuf;
 use futures::SinkExt;
-use pageserver_api::models::{self, LocationConfig, TenantInfo, TimelineInfo};
+use pageserver_api::models::{
+    self, CompressionAlgorithm, LocationConfig, TenantInfo, TimelineInfo,
+};
 use pageserver_api::shard::TenantShardId;
 use pageserver_client::mgmt_api;
 use postgres_backend::AuthType;
@@ -370,6 +372,11 @@ impl PageServerNode {
                 .transpose()
                 .context("Failed to parse 'gc_feedback' as bool")?,
             heatmap_period: settings.remove("heatmap_period").map(|x| x.to_string()),
+            blob_compression: settings
+                .remove("blob_compression")
+                .map(|x| x.parse::<CompressionAlgorithm>())
+                .transpose()
+                .context("Failed to parse 'blob_compression'")?,
         };
 
         let request = models::TenantCreateRequest {
@@ -455,6 +462,11 @@ impl PageServerNode {
                     .transpose()
                     .context("Failed to parse 'gc_feedback' as bool")?,
                 heatmap_period: settings.remove("heatmap_period").map(|x| x.to_string()),
+                blob_compression: settings
+                    .remove("blob_compression")
+                    .map(|x| x.parse::<CompressionAlgorithm>())
+                    .transpose()
+                    .context("Failed to parse 'blob_compression'")?,
             }
         };
 
@@ -74,6 +74,16 @@ impl KeySpace {
             Err(index) => self.ranges[index - 1].end > range.start,
         }
     }
+
+    ///
+    /// Number of keys in the key space
+    ///
+    pub fn total_size(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| key_range_size(range) as usize)
+            .sum()
+    }
 }
 
 ///
@@ -394,4 +404,13 @@ mod tests {
         //        xxxxxxxxxxx
         assert!(ks.overlaps(&kr(0..30))); // XXXXX This fails currently!
     }
+
+    #[test]
+    fn keyspace_total_size() {
+        let ks = KeySpace {
+            ranges: vec![kr(0..10), kr(20..21), kr(100..200)],
+        };
+        assert_eq!(ks.total_size(), 111);
+        assert_eq!(KeySpace::default().total_size(), 0);
+    }
 }
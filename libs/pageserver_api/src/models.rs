@@ -176,6 +176,19 @@ pub enum TimelineState {
     Broken { reason: String, backtrace: String },
 }
 
+/// Whether a timeline is archived: archived timelines are offloaded to remote storage, and
+/// keep no state on the pageserver until they are unarchived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TimelineArchivalState {
+    Archived,
+    Unarchived,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimelineArchivalConfigRequest {
+    pub state: TimelineArchivalState,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TimelineCreateRequest {
     pub new_timeline_id: TimelineId,
@@ -241,6 +254,28 @@ pub struct TenantConfig {
     pub evictions_low_residence_duration_metric_threshold: Option<String>,
     pub gc_feedback: Option<bool>,
     pub heatmap_period: Option<String>,
+    pub blob_compression: Option<CompressionAlgorithm>,
+}
+
+/// Compression applied to individual blobs (page images and WAL records) when
+/// writing image and delta layer files.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    strum_macros::EnumString,
+    strum_macros::Display,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum CompressionAlgorithm {
+    Disabled,
+    Zstd,
+    Lz4,
 }
 
 /// A flattened analog of a `pagesever::tenant::LocationMode`, which
@@ -557,6 +592,22 @@ pub enum DownloadRemoteLayersTaskState {
     ShutDown,
 }
 
+/// Progress of a secondary location towards holding all the layers listed in the heatmap
+/// of its tenant, as of the last download pass.
+#[serde_as]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SecondaryProgress {
+    /// When the last download pass completed, successfully or not.
+    #[serde_as(as = "Option<serde_with::TimestampMilliSeconds>")]
+    pub last_download: Option<SystemTime>,
+
+    pub layers_downloaded: usize,
+    pub layers_total: usize,
+
+    pub bytes_downloaded: u64,
+    pub bytes_total: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TimelineGcRequest {
     pub gc_horizon: Option<u64>,
@@ -569,6 +620,7 @@ pub enum PagestreamFeMessage {
     Nblocks(PagestreamNblocksRequest),
     GetPage(PagestreamGetPageRequest),
     DbSize(PagestreamDbSizeRequest),
+    GetPageBatch(PagestreamGetPageBatchRequest),
 }
 
 // Wrapped in libpq CopyData
@@ -579,6 +631,7 @@ pub enum PagestreamBeMessage {
     GetPage(PagestreamGetPageResponse),
     Error(PagestreamErrorResponse),
     DbSize(PagestreamDbSizeResponse),
+    GetPageBatch(PagestreamGetPageBatchResponse),
 }
 
 // Keep in sync with `pagestore_client.h`
@@ -589,6 +642,7 @@ enum PagestreamBeMessageTag {
     GetPage = 102,
     Error = 103,
     DbSize = 104,
+    GetPageBatch = 105,
 }
 impl TryFrom<u8> for PagestreamBeMessageTag {
     type Error = u8;
@@ -599,6 +653,7 @@ impl TryFrom<u8> for PagestreamBeMessageTag {
             102 => Ok(PagestreamBeMessageTag::GetPage),
             103 => Ok(PagestreamBeMessageTag::Error),
             104 => Ok(PagestreamBeMessageTag::DbSize),
+            105 => Ok(PagestreamBeMessageTag::GetPageBatch),
             _ => Err(value),
         }
     }
@@ -633,6 +688,23 @@ pub struct PagestreamDbSizeRequest {
     pub dbnode: u32,
 }
 
+/// Request for the blocks `blkno..blkno + nblocks` of a relation, all at the same LSN.
+///
+/// `nblocks` is at most [`PagestreamGetPageBatchRequest::MAX_BLOCKS`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct PagestreamGetPageBatchRequest {
+    pub latest: bool,
+    pub lsn: Lsn,
+    pub rel: RelTag,
+    pub blkno: u32,
+    pub nblocks: u32,
+}
+
+impl PagestreamGetPageBatchRequest {
+    /// Largest batch a client may ask for: this bounds the size of a response message.
+    pub const MAX_BLOCKS: u32 = 128;
+}
+
 #[derive(Debug)]
 pub struct PagestreamExistsResponse {
     pub exists: bool,
@@ -658,6 +730,12 @@ pub struct PagestreamDbSizeResponse {
     pub db_size: i64,
 }
 
+/// The pages of a [`PagestreamGetPageBatchRequest`], in block number order.
+#[derive(Debug)]
+pub struct PagestreamGetPageBatchResponse {
+    pub pages: Vec<Bytes>,
+}
+
 impl PagestreamFeMessage {
     pub fn serialize(&self) -> Bytes {
         let mut bytes = BytesMut::new();
@@ -700,6 +778,18 @@ impl PagestreamFeMessage {
                 bytes.put_u64(req.lsn.0);
                 bytes.put_u32(req.dbnode);
             }
+
+            Self::GetPageBatch(req) => {
+                bytes.put_u8(4);
+                bytes.put_u8(u8::from(req.latest));
+                bytes.put_u64(req.lsn.0);
+                bytes.put_u32(req.rel.spcnode);
+                bytes.put_u32(req.rel.dbnode);
+                bytes.put_u32(req.rel.relnode);
+                bytes.put_u8(req.rel.forknum);
+                bytes.put_u32(req.blkno);
+                bytes.put_u32(req.nblocks);
+            }
         }
 
         bytes.into()
@@ -750,6 +840,20 @@ impl PagestreamFeMessage {
                 lsn: Lsn::from(body.read_u64::<BigEndian>()?),
                 dbnode: body.read_u32::<BigEndian>()?,
             })),
+            4 => Ok(PagestreamFeMessage::GetPageBatch(
+                PagestreamGetPageBatchRequest {
+                    latest: body.read_u8()? != 0,
+                    lsn: Lsn::from(body.read_u64::<BigEndian>()?),
+                    rel: RelTag {
+                        spcnode: body.read_u32::<BigEndian>()?,
+                        dbnode: body.read_u32::<BigEndian>()?,
+                        relnode: body.read_u32::<BigEndian>()?,
+                        forknum: body.read_u8()?,
+                    },
+                    blkno: body.read_u32::<BigEndian>()?,
+                    nblocks: body.read_u32::<BigEndian>()?,
+                },
+            )),
             _ => bail!("unknown smgr message tag: {:?}", msg_tag),
         }
     }
@@ -785,6 +889,14 @@ impl PagestreamBeMessage {
                 bytes.put_u8(Tag::DbSize as u8);
                 bytes.put_i64(resp.db_size);
             }
+
+            Self::GetPageBatch(resp) => {
+                bytes.put_u8(Tag::GetPageBatch as u8);
+                bytes.put_u32(resp.pages.len() as u32);
+                for page in &resp.pages {
+                    bytes.put(&page[..]);
+                }
+            }
         }
 
         bytes.into()
@@ -824,6 +936,16 @@ impl PagestreamBeMessage {
                     let db_size = buf.read_i64::<BigEndian>()?;
                     Self::DbSize(PagestreamDbSizeResponse { db_size })
                 }
+                Tag::GetPageBatch => {
+                    let n_pages = buf.read_u32::<BigEndian>()?;
+                    let mut pages = Vec::with_capacity(n_pages as usize);
+                    for _ in 0..n_pages {
+                        let mut page = vec![0; 8192];
+                        buf.read_exact(&mut page)?;
+                        pages.push(page.into());
+                    }
+                    Self::GetPageBatch(PagestreamGetPageBatchResponse { pages })
+                }
             };
         let remaining = buf.into_inner();
         if !remaining.is_empty() {
@@ -842,6 +964,7 @@ impl PagestreamBeMessage {
             Self::GetPage(_) => "GetPage",
             Self::Error(_) => "Error",
             Self::DbSize(_) => "DbSize",
+            Self::GetPageBatch(_) => "GetPageBatch",
         }
     }
 }
@@ -893,6 +1016,18 @@ mod tests {
                 lsn: Lsn(4),
                 dbnode: 7,
             }),
+            PagestreamFeMessage::GetPageBatch(PagestreamGetPageBatchRequest {
+                latest: false,
+                lsn: Lsn(4),
+                rel: RelTag {
+                    forknum: 1,
+                    spcnode: 2,
+                    dbnode: 3,
+                    relnode: 4,
+                },
+                blkno: 7,
+                nblocks: 16,
+            }),
         ];
         for msg in messages {
             let bytes = msg.serialize();
@@ -901,6 +1036,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pagestream_get_page_batch_response() {
+        let pages: Vec<Bytes> = (0..3u8).map(|i| Bytes::from(vec![i; 8192])).collect();
+        let msg = PagestreamBeMessage::GetPageBatch(PagestreamGetPageBatchResponse {
+            pages: pages.clone(),
+        });
+        let bytes = msg.serialize();
+        assert_eq!(bytes.len(), 1 + 4 + 3 * 8192);
+        match PagestreamBeMessage::deserialize(bytes).unwrap() {
+            PagestreamBeMessage::GetPageBatch(resp) => assert_eq!(resp.pages, pages),
+            other => panic!("unexpected response {}", other.kind()),
+        }
+    }
+
     #[test]
     fn test_tenantinfo_serde() {
         // Test serialization/deserialization of TenantInfo
@@ -1,32 +1,42 @@
 //! Azure Blob Storage wrapper
 
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::num::NonZeroU32;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use super::REMOTE_STORAGE_PREFIX_SEPARATOR;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use azure_core::request_options::{MaxResults, Metadata, Range};
 use azure_core::RetryOptions;
 use azure_identity::DefaultAzureCredential;
 use azure_storage::StorageCredentials;
+use azure_storage_blobs::blob::CopyStatus;
 use azure_storage_blobs::prelude::ClientBuilder;
 use azure_storage_blobs::{blob::operations::GetBlobBuilder, prelude::ContainerClient};
 use bytes::Bytes;
 use futures::stream::Stream;
 use futures_util::StreamExt;
 use http_types::StatusCode;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing::debug;
 
 use crate::s3_bucket::RequestKind;
+use crate::time_travel::{self, ObjectVersion, RecoveryAction, VersionKind};
 use crate::{
     AzureConfig, ConcurrencyLimiter, Download, DownloadError, Listing, ListingMode, RemotePath,
-    RemoteStorage, StorageMetadata,
+    RemoteStorage, StorageMetadata, TimeTravelError,
 };
 
+/// How long to wait for an asynchronous blob copy to complete.
+const COPY_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often to check whether an asynchronous blob copy has completed.
+const COPY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct AzureBlobStorage {
     client: ContainerClient,
     prefix_in_container: Option<String>,
@@ -151,6 +161,146 @@ impl AzureBlobStorage {
         })
     }
 
+    async fn copy_from_url(
+        &self,
+        source_url: azure_core::Url,
+        to: &RemotePath,
+    ) -> anyhow::Result<()> {
+        let _permit = self.permit(RequestKind::Copy).await;
+        let blob_client = self.client.blob_client(self.relative_path_to_name(to));
+
+        // Copies within a storage account usually complete right away, but
+        // the service is free to finish them asynchronously.
+        let response = blob_client.copy(source_url).into_future().await?;
+        let started_at = Instant::now();
+        let mut copy_status = response.copy_status;
+        loop {
+            match copy_status {
+                CopyStatus::Success => return Ok(()),
+                CopyStatus::Pending => {}
+                CopyStatus::Aborted => anyhow::bail!("copy from {source_url} to {to} was aborted"),
+                CopyStatus::Failed => anyhow::bail!("copy from {source_url} to {to} failed"),
+            }
+            if started_at.elapsed() > COPY_TIMEOUT {
+                anyhow::bail!(
+                    "copy from {source_url} to {to} did not complete within {}s",
+                    COPY_TIMEOUT.as_secs()
+                );
+            }
+
+            tokio::time::sleep(COPY_POLL_INTERVAL).await;
+            let properties = blob_client.get_properties().into_future().await?;
+            copy_status = properties
+                .blob
+                .properties
+                .copy_status
+                .ok_or_else(|| anyhow::anyhow!("copy status of {to} is missing"))?;
+        }
+    }
+
+    /// Makes a version of the blob its current version again, by copying the
+    /// version over the blob.
+    async fn restore_version(&self, key: &RemotePath, version_id: &str) -> anyhow::Result<()> {
+        let mut source_url = self
+            .client
+            .blob_client(self.relative_path_to_name(key))
+            .url()?;
+        source_url
+            .query_pairs_mut()
+            .append_pair("versionid", version_id);
+        self.copy_from_url(source_url, key).await
+    }
+
+    /// Lists the versions of the blobs under the prefix, newest first, along
+    /// with the keys of the blobs which have a current version, i.e. haven't
+    /// been deleted, and the deletion times of the soft-deleted blobs. Blob
+    /// versioning must be enabled for the container.
+    async fn list_versions(
+        &self,
+        prefix: Option<&RemotePath>,
+        cancel: &CancellationToken,
+    ) -> Result<BlobVersions, TimeTravelError> {
+        // Only list the blobs "inside" the prefix, not the ones next to it
+        // which share the beginning of their names.
+        let list_prefix = prefix
+            .map(|p| self.relative_path_to_name(p))
+            .or_else(|| self.prefix_in_container.clone())
+            .map(|mut p| {
+                if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+                    p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+                }
+                p
+            });
+
+        let mut builder = self
+            .client
+            .list_blobs()
+            .include_versions(true)
+            .include_deleted(true);
+        if let Some(prefix) = list_prefix {
+            builder = builder.prefix(Cow::from(prefix));
+        }
+        if let Some(limit) = self.max_keys_per_list_response {
+            builder = builder.max_results(MaxResults::new(limit));
+        }
+
+        let mut versions = Vec::new();
+        let mut current = HashSet::new();
+        let mut deleted_at = HashMap::new();
+        let mut response = builder.into_stream();
+        while let Some(page) = response.next().await {
+            if cancel.is_cancelled() {
+                return Err(TimeTravelError::Cancelled);
+            }
+            let page = page
+                .context("Failed to list Azure blob versions")
+                .map_err(TimeTravelError::Other)?;
+            for blob in page.blobs.blobs() {
+                if blob.deleted == Some(true) {
+                    // Only its deletion time is of use, it can't be copied from.
+                    if let Some(time) = blob.properties.deleted_time {
+                        let time = SystemTime::from(time);
+                        let at = deleted_at
+                            .entry(self.name_to_relative_path(&blob.name))
+                            .or_insert(time);
+                        *at = (*at).max(time);
+                    }
+                    continue;
+                }
+                let Some(version_id) = &blob.version_id else {
+                    return Err(TimeTravelError::Other(anyhow::anyhow!(
+                        "blob {} has no version id, is blob versioning enabled?",
+                        blob.name
+                    )));
+                };
+                let key = self.name_to_relative_path(&blob.name);
+                let is_latest = blob.is_current_version == Some(true);
+                if is_latest {
+                    current.insert(key.clone());
+                }
+                versions.push(ObjectVersion {
+                    key,
+                    version_id: version_id.clone(),
+                    last_modified: blob.properties.last_modified.into(),
+                    kind: VersionKind::Version,
+                    is_latest,
+                });
+            }
+        }
+        // Version ids are the creation times of the versions, with a precision
+        // of 100ns, unlike the modification times.
+        versions.sort_by(|a, b| {
+            a.key
+                .cmp(&b.key)
+                .then_with(|| b.version_id.cmp(&a.version_id))
+        });
+        Ok(BlobVersions {
+            versions,
+            current,
+            deleted_at,
+        })
+    }
+
     async fn permit(&self, kind: RequestKind) -> tokio::sync::SemaphorePermit<'_> {
         self.concurrency_limiter
             .acquire(kind)
@@ -159,6 +309,12 @@ impl AzureBlobStorage {
     }
 }
 
+struct BlobVersions {
+    versions: Vec<ObjectVersion>,
+    current: HashSet<RemotePath>,
+    deleted_at: HashMap<RemotePath, SystemTime>,
+}
+
 fn to_azure_metadata(metadata: StorageMetadata) -> Metadata {
     let mut res = Metadata::new();
     for (k, v) in metadata.0.into_iter() {
@@ -322,6 +478,53 @@ impl RemoteStorage for AzureBlobStorage {
         }
         Ok(())
     }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()> {
+        let source_url = self
+            .client
+            .blob_client(self.relative_path_to_name(from))
+            .url()?;
+        self.copy_from_url(source_url, to).await
+    }
+
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        let BlobVersions {
+            mut versions,
+            current,
+            deleted_at,
+        } = self.list_versions(prefix, cancel).await?;
+        tracing::info!(
+            "time travel recovery: found {} blob versions",
+            versions.len()
+        );
+
+        // Unlike S3, Azure has no delete markers: a deleted blob is left with
+        // only non-current versions, and the time of its deletion is only kept
+        // with soft delete.
+        time_travel::mark_deletions(&mut versions, &current, &deleted_at, timestamp)?;
+
+        let actions = time_travel::plan_recovery(versions, timestamp, done_if_after);
+        for (key, action) in actions {
+            if cancel.is_cancelled() {
+                return Err(TimeTravelError::Cancelled);
+            }
+            tracing::info!("time travel recovery: {action:?} for {key}");
+            match action {
+                RecoveryAction::Restore(version_id) => {
+                    self.restore_version(&key, &version_id).await
+                }
+                RecoveryAction::Delete => self.delete(&key).await,
+            }
+            .map_err(TimeTravelError::Other)?;
+        }
+        Ok(())
+    }
 }
 
 pin_project_lite::pin_project! {
@@ -0,0 +1,786 @@
+//! A read-through cache of the downloads from a remote storage on the local disk.
+//!
+//! Downloaded objects and byte ranges of objects are kept in a local directory,
+//! and downloading them again is served from there as long as the remote object
+//! still has the same etag. The etag is checked by downloading the first bytes
+//! of the object: a cache hit still costs a request, but not the transfer of the
+//! object. A byte range of an object that is cached as a whole is served from
+//! the whole object. Objects without an etag are not cached.
+//!
+//! The directory is kept below its configured size by evicting the least
+//! recently used entries. It can be reused by the next process with the same
+//! configuration, which finds the entries again in the order they were cached.
+//!
+//! A download is only returned once it has been written to the cache completely.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use futures::stream::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use tracing::{debug, warn};
+
+use crate::{
+    CacheConfig, Download, DownloadError, DownloadStream, GenericRemoteStorage, Listing,
+    ListingMode, RemotePath, RemoteStorage, StorageMetadata, TimeTravelError,
+};
+
+const META_EXTENSION: &str = "meta";
+const TEMP_EXTENSION: &str = "temp";
+
+/// Length of the download that checks the etag of a cached object. [`crate::LocalFs`]
+/// refuses single byte ranges.
+const PROBE_LEN: u64 = 2;
+
+type ByteRange = (u64, Option<u64>);
+
+/// What is known about a cached download, stored next to its contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedDownload {
+    key: RemotePath,
+    /// `None` if the whole object is cached.
+    range: Option<ByteRange>,
+    etag: String,
+    last_modified: Option<SystemTime>,
+    metadata: Option<HashMap<String, String>>,
+    size: u64,
+    /// Orders the entries found on startup.
+    cached_at: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    /// Name of the file with the contents, unique for every download: an
+    /// evicted entry can be cached again before its file is removed.
+    file_name: String,
+    download: CachedDownload,
+    last_access: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Entries by the hash of their key and range.
+    entries: HashMap<String, CacheEntry>,
+    /// Names of the entries by their last access, the least recent first.
+    lru: BTreeMap<u64, String>,
+    size: u64,
+    access_counter: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, name: &str) {
+        self.access_counter += 1;
+        if let Some(entry) = self.entries.get_mut(name) {
+            self.lru.remove(&entry.last_access);
+            entry.last_access = self.access_counter;
+            self.lru.insert(entry.last_access, name.to_string());
+        }
+    }
+
+    /// Returns the file of the entry it replaces, if any.
+    fn insert(
+        &mut self,
+        name: String,
+        file_name: String,
+        download: CachedDownload,
+    ) -> Option<String> {
+        let replaced = self.remove(&name).map(|entry| entry.file_name);
+        self.access_counter += 1;
+        self.size += download.size;
+        self.lru.insert(self.access_counter, name.clone());
+        self.entries.insert(
+            name,
+            CacheEntry {
+                file_name,
+                download,
+                last_access: self.access_counter,
+            },
+        );
+        replaced
+    }
+
+    fn remove(&mut self, name: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(name)?;
+        self.lru.remove(&entry.last_access);
+        self.size -= entry.download.size;
+        Some(entry)
+    }
+
+    /// Removes the entry if it still has the given file.
+    fn remove_file(&mut self, name: &str, file_name: &str) -> bool {
+        if self
+            .entries
+            .get(name)
+            .is_some_and(|entry| entry.file_name == file_name)
+        {
+            self.remove(name);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Evicts the least recently used entries until the cache fits into
+    /// `max_size`, returning their files.
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let Some((_, name)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&name) {
+                self.size -= entry.download.size;
+                evicted.push(entry.file_name);
+            }
+        }
+        evicted
+    }
+}
+
+/// A [`RemoteStorage`] that caches the downloads of another one on the local disk.
+pub struct CachingStorage {
+    inner: GenericRemoteStorage,
+    root: Utf8PathBuf,
+    max_size_bytes: u64,
+    state: Mutex<CacheState>,
+    next_file_id: AtomicU64,
+}
+
+impl CachingStorage {
+    /// Creates the cache directory if needed, picking up the entries cached in it before.
+    pub fn new(inner: GenericRemoteStorage, config: &CacheConfig) -> anyhow::Result<Self> {
+        let root = config.path.clone();
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create the cache directory {root}"))?;
+
+        let mut meta_files = Vec::new();
+        let mut data_files = HashSet::new();
+        let mut max_file_id = 0;
+        for dir_entry in root
+            .read_dir_utf8()
+            .with_context(|| format!("Failed to read the cache directory {root}"))?
+        {
+            let path = dir_entry?.into_path();
+            let Some(file_id) = path
+                .file_stem()
+                .and_then(|stem| stem.rsplit_once('-'))
+                .and_then(|(_, file_id)| file_id.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            max_file_id = max_file_id.max(file_id);
+            match path.extension() {
+                Some(META_EXTENSION) => meta_files.push(path),
+                Some(TEMP_EXTENSION) => remove_file_if_exists(&path)?,
+                None => {
+                    data_files.insert(path);
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut found = Vec::new();
+        for meta_path in meta_files {
+            let data_path = meta_path.with_extension("");
+            match read_entry(&meta_path, &data_path) {
+                Ok(download) => {
+                    data_files.remove(&data_path);
+                    let file_name = data_path.file_name().expect("read from dir").to_string();
+                    found.push((file_name, download));
+                }
+                Err(e) => {
+                    warn!("Removing unreadable cache entry {data_path}: {e:#}");
+                    remove_file_if_exists(&meta_path)?;
+                }
+            }
+        }
+        // Contents without a description, left by a crash.
+        for data_path in data_files {
+            remove_file_if_exists(&data_path)?;
+        }
+
+        let mut state = CacheState::default();
+        found.sort_by_key(|(_, download)| download.cached_at);
+        for (file_name, download) in found {
+            let name = entry_name(&download.key, download.range);
+            if let Some(replaced) = state.insert(name, file_name, download) {
+                remove_entry_files(&root, &replaced)?;
+            }
+        }
+        for evicted in state.evict(config.max_size_bytes) {
+            remove_entry_files(&root, &evicted)?;
+        }
+
+        Ok(Self {
+            inner,
+            root,
+            max_size_bytes: config.max_size_bytes,
+            state: Mutex::new(state),
+            next_file_id: AtomicU64::new(max_file_id + 1),
+        })
+    }
+
+    async fn cached_download(
+        &self,
+        from: &RemotePath,
+        range: Option<ByteRange>,
+    ) -> Result<Download, DownloadError> {
+        if let Some((start_inclusive, Some(end_exclusive))) = range {
+            if end_exclusive <= start_inclusive {
+                return Err(DownloadError::BadInput(anyhow!(
+                    "Invalid range, start ({start_inclusive}) is not less than end_exclusive ({end_exclusive})"
+                )));
+            }
+        }
+
+        let name = entry_name(from, range);
+        let whole_object_name = entry_name(from, None);
+        let cached = {
+            let state = self.state.lock().unwrap();
+            state
+                .entries
+                .get(&name)
+                .map(|entry| (&name, entry.clone()))
+                .or_else(|| {
+                    state
+                        .entries
+                        .get(&whole_object_name)
+                        .map(|entry| (&whole_object_name, entry.clone()))
+                })
+        };
+
+        if let Some((cached_name, entry)) = cached {
+            match self.current_etag(from).await {
+                Ok(Some(etag)) if etag == entry.download.etag => {
+                    match self.open_cached(&entry, range).await {
+                        Ok(download) => {
+                            debug!("Serving {from} from the cache");
+                            self.state.lock().unwrap().touch(cached_name);
+                            return Ok(download);
+                        }
+                        Err(e) => {
+                            warn!("Failed to open the cached {from}: {e:#}");
+                            self.remove_entry(cached_name, &entry.file_name).await;
+                        }
+                    }
+                }
+                Ok(_) => self.remove_entry(cached_name, &entry.file_name).await,
+                Err(DownloadError::NotFound) => {
+                    self.remove_entry(cached_name, &entry.file_name).await;
+                    return Err(DownloadError::NotFound);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let download = match range {
+            None => self.inner.download(from).await?,
+            Some((start_inclusive, end_exclusive)) => {
+                self.inner
+                    .download_byte_range(from, start_inclusive, end_exclusive)
+                    .await?
+            }
+        };
+        let Some(etag) = download.etag.clone() else {
+            return Ok(download);
+        };
+
+        let file_name = format!(
+            "{name}-{}",
+            self.next_file_id.fetch_add(1, Ordering::Relaxed)
+        );
+        let data_path = self.root.join(&file_name);
+        let temp_path = data_path.with_extension(TEMP_EXTENSION);
+        let size = match write_stream(download.download_stream, &temp_path).await {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(DownloadError::Other(
+                    e.context(format!("Failed to download {from} into the cache")),
+                ));
+            }
+        };
+        let cached = CachedDownload {
+            key: from.clone(),
+            range,
+            etag,
+            last_modified: download.last_modified,
+            metadata: download.metadata.map(|metadata| metadata.0),
+            size,
+            cached_at: SystemTime::now(),
+        };
+
+        // The file is opened before it is cached, so that it can be served even
+        // if it's evicted right away, or doesn't fit into the cache at all.
+        let file = fs::File::open(&temp_path)
+            .await
+            .with_context(|| format!("Failed to open the downloaded {temp_path}"))
+            .map_err(DownloadError::Other)?;
+        // Empty objects cannot be probed for their etag, and are cheap to download anyway.
+        if size == 0 || size > self.max_size_bytes {
+            let _ = fs::remove_file(&temp_path).await;
+        } else if let Err(e) = self.commit(&name, &file_name, cached.clone()).await {
+            warn!("Failed to cache {from}: {e:#}");
+            let _ = fs::remove_file(&temp_path).await;
+            self.remove_files(&file_name).await;
+        }
+
+        Ok(serve(file, &cached, None))
+    }
+
+    async fn commit(
+        &self,
+        name: &str,
+        file_name: &str,
+        download: CachedDownload,
+    ) -> anyhow::Result<()> {
+        let data_path = self.root.join(file_name);
+        fs::rename(data_path.with_extension(TEMP_EXTENSION), &data_path).await?;
+        let meta = serde_json::to_vec(&download)?;
+        fs::write(data_path.with_extension(META_EXTENSION), meta).await?;
+
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let replaced = state.insert(name.to_string(), file_name.to_string(), download);
+            let mut removed = state.evict(self.max_size_bytes);
+            removed.extend(replaced);
+            removed
+        };
+        for file_name in removed {
+            self.remove_files(&file_name).await;
+        }
+        Ok(())
+    }
+
+    /// The etag of the remote object, without downloading all of it.
+    async fn current_etag(&self, from: &RemotePath) -> Result<Option<String>, DownloadError> {
+        let probe = self
+            .inner
+            .download_byte_range(from, 0, Some(PROBE_LEN))
+            .await?;
+        Ok(probe.etag)
+    }
+
+    async fn open_cached(
+        &self,
+        entry: &CacheEntry,
+        range: Option<ByteRange>,
+    ) -> anyhow::Result<Download> {
+        let mut file = fs::File::open(self.root.join(&entry.file_name)).await?;
+        // Only a whole object entry is used for a different range.
+        let range = if entry.download.range == range {
+            None
+        } else {
+            range
+        };
+        if let Some((start_inclusive, _)) = range {
+            file.seek(std::io::SeekFrom::Start(start_inclusive)).await?;
+        }
+        Ok(serve(file, &entry.download, range))
+    }
+
+    async fn remove_entry(&self, name: &str, file_name: &str) {
+        let removed = self.state.lock().unwrap().remove_file(name, file_name);
+        if removed {
+            self.remove_files(file_name).await;
+        }
+    }
+
+    /// Drops the cached downloads of the object after it has been changed.
+    async fn invalidate(&self, path: &RemotePath) {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let names = state
+                .entries
+                .iter()
+                .filter(|(_, entry)| &entry.download.key == path)
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            names
+                .iter()
+                .filter_map(|name| state.remove(name))
+                .map(|entry| entry.file_name)
+                .collect::<Vec<_>>()
+        };
+        for file_name in removed {
+            self.remove_files(&file_name).await;
+        }
+    }
+
+    async fn remove_files(&self, file_name: &str) {
+        let data_path = self.root.join(file_name);
+        for path in [data_path.with_extension(META_EXTENSION), data_path] {
+            if let Err(e) = fs::remove_file(&path).await {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Failed to remove the cache file {path}: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for CachingStorage {
+    async fn list(
+        &self,
+        prefix: Option<&RemotePath>,
+        mode: ListingMode,
+    ) -> Result<Listing, DownloadError> {
+        self.inner.list(prefix, mode).await
+    }
+
+    async fn upload(
+        &self,
+        data: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        let res = self.inner.upload(data, data_size_bytes, to, metadata).await;
+        self.invalidate(to).await;
+        res
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
+        self.cached_download(from, None).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, DownloadError> {
+        self.cached_download(from, Some((start_inclusive, end_exclusive)))
+            .await
+    }
+
+    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+        let res = self.inner.delete(path).await;
+        self.invalidate(path).await;
+        res
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()> {
+        let res = self.inner.delete_objects(paths).await;
+        for path in paths {
+            self.invalidate(path).await;
+        }
+        res
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()> {
+        let res = self.inner.copy(from, to).await;
+        self.invalidate(to).await;
+        res
+    }
+
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        // The restored objects get new etags, or the ones of their old contents.
+        self.inner
+            .time_travel_recover(prefix, timestamp, done_if_after, cancel)
+            .await
+    }
+}
+
+/// Name of the cache entry of an object or a byte range of it.
+fn entry_name(key: &RemotePath, range: Option<ByteRange>) -> String {
+    let id = match range {
+        None => key.to_string(),
+        Some((start_inclusive, end_exclusive)) => {
+            format!("{key}\0{start_inclusive}-{end_exclusive:?}")
+        }
+    };
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, id.as_bytes()))
+}
+
+/// Serves a cached download, or the `range` of a cached whole object that
+/// `file` is positioned at the start of.
+fn serve(file: fs::File, download: &CachedDownload, range: Option<ByteRange>) -> Download {
+    let download_stream: DownloadStream = match range {
+        Some((start_inclusive, Some(end_exclusive))) => Box::pin(ReaderStream::new(
+            file.take(end_exclusive - start_inclusive),
+        )),
+        _ => Box::pin(ReaderStream::new(file)),
+    };
+    Download {
+        download_stream,
+        last_modified: download.last_modified,
+        etag: Some(download.etag.clone()),
+        metadata: download.metadata.clone().map(StorageMetadata),
+    }
+}
+
+async fn write_stream(mut stream: DownloadStream, path: &Utf8Path) -> anyhow::Result<u64> {
+    let mut file = fs::File::create(path).await?;
+    let mut size = 0;
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes?;
+        file.write_all(&bytes).await?;
+        size += bytes.len() as u64;
+    }
+    file.sync_all().await?;
+    Ok(size)
+}
+
+/// Reads the description of a cache entry, checking it against the contents.
+fn read_entry(meta_path: &Utf8Path, data_path: &Utf8Path) -> anyhow::Result<CachedDownload> {
+    let meta = std::fs::read(meta_path)?;
+    let download: CachedDownload = serde_json::from_slice(&meta)?;
+    let size = std::fs::metadata(data_path)?.len();
+    anyhow::ensure!(
+        size == download.size,
+        "the cached file has {size} bytes instead of {}",
+        download.size
+    );
+    Ok(download)
+}
+
+fn remove_entry_files(root: &Utf8Path, file_name: &str) -> anyhow::Result<()> {
+    let data_path = root.join(file_name);
+    remove_file_if_exists(&data_path.with_extension(META_EXTENSION))?;
+    remove_file_if_exists(&data_path)
+}
+
+fn remove_file_if_exists(path: &Utf8Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove the cache file {path}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use camino_tempfile::Utf8TempDir;
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::LocalFs;
+
+    struct TestStorage {
+        dir: Utf8TempDir,
+        inner: GenericRemoteStorage,
+    }
+
+    impl TestStorage {
+        fn new() -> anyhow::Result<Self> {
+            let dir = camino_tempfile::tempdir()?;
+            let inner = GenericRemoteStorage::LocalFs(LocalFs::new(dir.path().join("storage"))?);
+            Ok(TestStorage { dir, inner })
+        }
+
+        fn cache(&self, max_size_bytes: u64) -> anyhow::Result<CachingStorage> {
+            let config = CacheConfig {
+                path: self.dir.path().join("cache"),
+                max_size_bytes,
+            };
+            CachingStorage::new(self.inner.clone(), &config)
+        }
+
+        async fn upload(&self, to: &RemotePath, data: &[u8]) -> anyhow::Result<()> {
+            let data = Bytes::copy_from_slice(data);
+            let size = data.len();
+            let stream = futures::stream::once(futures::future::ready(Ok::<_, io::Error>(data)));
+            self.inner.upload(stream, size, to, None).await
+        }
+    }
+
+    fn path(p: &str) -> RemotePath {
+        RemotePath::new(Utf8Path::new(p)).unwrap()
+    }
+
+    async fn read_all(download: Download) -> io::Result<Vec<u8>> {
+        let pieces: Vec<Bytes> = download.download_stream.try_collect().await?;
+        Ok(pieces.concat())
+    }
+
+    /// Number of downloads that were written into the cache.
+    fn cached_downloads(cache: &CachingStorage) -> u64 {
+        cache.next_file_id.load(Ordering::Relaxed)
+    }
+
+    fn cached_keys(cache: &CachingStorage) -> Vec<(String, Option<ByteRange>)> {
+        let state = cache.state.lock().unwrap();
+        let mut keys = state
+            .entries
+            .values()
+            .map(|entry| (entry.download.key.to_string(), entry.download.range))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn downloads_are_cached_until_changed() -> anyhow::Result<()> {
+        let storage = TestStorage::new()?;
+        let cache = storage.cache(1024)?;
+        let object = path("object");
+        storage.upload(&object, b"first version").await?;
+
+        let downloads = cached_downloads(&cache);
+        assert_eq!(
+            read_all(cache.download(&object).await?).await?,
+            b"first version"
+        );
+        assert_eq!(cached_downloads(&cache), downloads + 1);
+        assert_eq!(
+            read_all(cache.download(&object).await?).await?,
+            b"first version"
+        );
+        assert_eq!(
+            cached_downloads(&cache),
+            downloads + 1,
+            "served from the cache"
+        );
+
+        // Changed behind the back of the cache: the etag differs.
+        storage.upload(&object, b"second, longer version").await?;
+        assert_eq!(
+            read_all(cache.download(&object).await?).await?,
+            b"second, longer version"
+        );
+        assert_eq!(cached_downloads(&cache), downloads + 2);
+
+        storage.inner.delete(&object).await?;
+        assert!(matches!(
+            cache.download(&object).await,
+            Err(DownloadError::NotFound)
+        ));
+        assert_eq!(cached_keys(&cache), vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn byte_ranges() -> anyhow::Result<()> {
+        let storage = TestStorage::new()?;
+        let cache = storage.cache(1024)?;
+        let object = path("object");
+        storage.upload(&object, b"0123456789").await?;
+
+        let range = cache.download_byte_range(&object, 2, Some(5)).await?;
+        assert_eq!(read_all(range).await?, b"234");
+        assert_eq!(
+            cached_keys(&cache),
+            vec![("object".to_string(), Some((2, Some(5))))]
+        );
+
+        assert_eq!(
+            read_all(cache.download(&object).await?).await?,
+            b"0123456789"
+        );
+        let downloads = cached_downloads(&cache);
+        for (start, end, expected) in [
+            (2, Some(5), &b"234"[..]),
+            (0, Some(10), b"0123456789"),
+            (7, None, b"789"),
+        ] {
+            let range = cache.download_byte_range(&object, start, end).await?;
+            assert_eq!(read_all(range).await?, expected, "range {start}..{end:?}");
+        }
+        assert_eq!(cached_downloads(&cache), downloads, "served from the cache");
+
+        cache
+            .download_byte_range(&object, 5, Some(5))
+            .await
+            .expect_err("empty range");
+
+        // Writes through the cache drop the cached downloads right away.
+        cache.delete(&object).await?;
+        assert_eq!(cached_keys(&cache), vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_are_evicted() -> anyhow::Result<()> {
+        let storage = TestStorage::new()?;
+        let cache = storage.cache(250)?;
+        for name in ["a", "b", "c", "large"] {
+            let size = if name == "large" { 300 } else { 100 };
+            storage.upload(&path(name), &vec![0; size]).await?;
+        }
+
+        read_all(cache.download(&path("a")).await?).await?;
+        read_all(cache.download(&path("b")).await?).await?;
+        read_all(cache.download(&path("a")).await?).await?;
+        read_all(cache.download(&path("c")).await?).await?;
+        assert_eq!(
+            cached_keys(&cache),
+            vec![("a".to_string(), None), ("c".to_string(), None)]
+        );
+
+        // Too large to be cached at all.
+        assert_eq!(
+            read_all(cache.download(&path("large")).await?).await?.len(),
+            300
+        );
+        assert_eq!(
+            cached_keys(&cache),
+            vec![("a".to_string(), None), ("c".to_string(), None)]
+        );
+
+        // Only the cached files are left in the directory.
+        let files = std::fs::read_dir(&cache.root)?.count();
+        assert_eq!(files, 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reused_after_restart() -> anyhow::Result<()> {
+        let storage = TestStorage::new()?;
+        let object = path("object");
+        storage.upload(&object, b"contents").await?;
+        let other = path("other");
+        storage.upload(&other, b"other contents").await?;
+
+        let cache = storage.cache(1024)?;
+        read_all(cache.download(&object).await?).await?;
+        read_all(cache.download(&other).await?).await?;
+        drop(cache);
+        // Left by a crash while caching.
+        std::fs::write(storage.dir.path().join("cache/0123-100.temp"), b"partial")?;
+
+        let cache = storage.cache(1024)?;
+        assert_eq!(
+            cached_keys(&cache),
+            vec![("object".to_string(), None), ("other".to_string(), None)]
+        );
+        let downloads = cached_downloads(&cache);
+        assert_eq!(read_all(cache.download(&object).await?).await?, b"contents");
+        assert_eq!(cached_downloads(&cache), downloads, "served from the cache");
+        assert!(!storage.dir.path().join("cache/0123-100.temp").exists());
+        drop(cache);
+
+        // A smaller cache only keeps what fits, the most recently cached first.
+        let cache = storage.cache(14)?;
+        assert_eq!(cached_keys(&cache), vec![("other".to_string(), None)]);
+
+        Ok(())
+    }
+}
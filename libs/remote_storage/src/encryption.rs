@@ -0,0 +1,882 @@
+//! Client-side envelope encryption of the objects in a remote storage.
+//!
+//! Every object is encrypted with a key of its own, derived with HKDF-SHA256
+//! from a data key of its tenant and a random salt. The salt and the data key
+//! are stored in the object metadata, the latter wrapped by a key-encryption
+//! key of a [`KeyManagement`] service, together with the id of that
+//! key-encryption key: the key-encryption keys themselves never leave the KMS.
+//!
+//! Objects are split into chunks of 64 KiB that are encrypted
+//! separately with AES-256-GCM, with the chunk index as the nonce, so that a
+//! byte range of an object can be downloaded and decrypted without fetching
+//! the whole object. Objects without
+//! the encryption metadata, e.g. ones uploaded before the encryption was
+//! enabled, are downloaded as they are.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, ensure, Context as _};
+use bytes::{Bytes, BytesMut};
+use camino::Utf8Path;
+use futures::stream::Stream;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use utils::id::TenantId;
+
+use crate::{
+    Download, DownloadError, GenericRemoteStorage, Listing, ListingMode, RemotePath, RemoteStorage,
+    StorageMetadata, TimeTravelError,
+};
+
+/// Size of the plaintext chunks that are encrypted separately.
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_LEN: u64 = 16;
+/// Size of the random salt that the key of an object is derived with.
+const SALT_LEN: usize = 32;
+/// HKDF info of the object keys, to not share them with other uses of the data keys.
+const OBJECT_KEY_INFO: &[u8] = b"neon remote storage object key";
+
+pub const DATA_KEY_LEN: usize = 32;
+
+// Azure only allows C# identifiers as metadata keys, hence the underscores.
+const KEY_ID_METADATA_KEY: &str = "neon_encryption_key_id";
+const WRAPPED_KEY_METADATA_KEY: &str = "neon_encryption_wrapped_key";
+const SALT_METADATA_KEY: &str = "neon_encryption_salt";
+const PLAINTEXT_SIZE_METADATA_KEY: &str = "neon_encryption_plaintext_size";
+
+/// A data key generated by a [`KeyManagement`] service.
+pub struct DataKey {
+    /// Id of the key-encryption key that wrapped the data key.
+    pub key_id: String,
+    pub plaintext: [u8; DATA_KEY_LEN],
+    pub wrapped: Vec<u8>,
+}
+
+/// A key management service, holding the key-encryption keys.
+#[async_trait::async_trait]
+pub trait KeyManagement: Send + Sync + 'static {
+    /// Generates a data key for the objects of the tenant, wrapped by the
+    /// key-encryption key of that tenant. Objects outside of any tenant get
+    /// `None`.
+    async fn generate_data_key(&self, tenant_id: Option<TenantId>) -> anyhow::Result<DataKey>;
+
+    /// Unwraps a data key from [`KeyManagement::generate_data_key`].
+    async fn unwrap_data_key(
+        &self,
+        key_id: &str,
+        wrapped: &[u8],
+    ) -> anyhow::Result<[u8; DATA_KEY_LEN]>;
+}
+
+/// A [`KeyManagement`] with the key-encryption keys in a local JSON file,
+/// for testing:
+///
+/// ```json
+/// {
+///   "default_key_id": "main",
+///   "keys": { "main": "<32 bytes in hex>", "customer": "<32 bytes in hex>" },
+///   "tenant_key_ids": { "<tenant id>": "customer" }
+/// }
+/// ```
+///
+/// Tenants without a key of their own use the default one.
+pub struct LocalFileKms {
+    default_key_id: String,
+    keys: HashMap<String, LessSafeKey>,
+    tenant_key_ids: HashMap<TenantId, String>,
+    rng: SystemRandom,
+}
+
+#[derive(Deserialize)]
+struct LocalKmsFile {
+    default_key_id: String,
+    keys: HashMap<String, String>,
+    #[serde(default)]
+    tenant_key_ids: HashMap<String, String>,
+}
+
+impl LocalFileKms {
+    pub fn from_file(path: &Utf8Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the KMS keys from '{path}'"))?;
+        let file: LocalKmsFile = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse the KMS keys from '{path}'"))?;
+
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|(key_id, key)| {
+                let key = hex::decode(key)
+                    .ok()
+                    .and_then(|key| aead_key(&key).ok())
+                    .with_context(|| format!("key '{key_id}' is not 32 bytes in hex"))?;
+                Ok((key_id, key))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        let tenant_key_ids = file
+            .tenant_key_ids
+            .into_iter()
+            .map(|(tenant_id, key_id)| Ok((tenant_id.parse()?, key_id)))
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        for key_id in std::iter::once(&file.default_key_id).chain(tenant_key_ids.values()) {
+            ensure!(keys.contains_key(key_id), "unknown key '{key_id}'");
+        }
+
+        Ok(Self {
+            default_key_id: file.default_key_id,
+            keys,
+            tenant_key_ids,
+            rng: SystemRandom::new(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyManagement for LocalFileKms {
+    async fn generate_data_key(&self, tenant_id: Option<TenantId>) -> anyhow::Result<DataKey> {
+        let key_id = tenant_id
+            .and_then(|tenant_id| self.tenant_key_ids.get(&tenant_id))
+            .unwrap_or(&self.default_key_id);
+        let key = &self.keys[key_id];
+
+        let mut plaintext = [0; DATA_KEY_LEN];
+        fill_random(&self.rng, &mut plaintext)?;
+        let mut nonce = [0; aead::NONCE_LEN];
+        fill_random(&self.rng, &mut nonce)?;
+        let mut sealed = plaintext.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(key_id.as_bytes()),
+            &mut sealed,
+        )
+        .map_err(|_| anyhow!("failed to wrap a data key"))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(sealed);
+        Ok(DataKey {
+            key_id: key_id.clone(),
+            plaintext,
+            wrapped,
+        })
+    }
+
+    async fn unwrap_data_key(
+        &self,
+        key_id: &str,
+        wrapped: &[u8],
+    ) -> anyhow::Result<[u8; DATA_KEY_LEN]> {
+        let key = self
+            .keys
+            .get(key_id)
+            .with_context(|| format!("unknown key '{key_id}'"))?;
+        ensure!(
+            wrapped.len() > aead::NONCE_LEN,
+            "wrapped data key is too short"
+        );
+        let (nonce, sealed) = wrapped.split_at(aead::NONCE_LEN);
+        let nonce =
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("invalid nonce"))?;
+        let mut sealed = sealed.to_vec();
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(key_id.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow!("failed to unwrap a data key with key '{key_id}'"))?;
+        <[u8; DATA_KEY_LEN]>::try_from(&*plaintext).context("unwrapped data key has a wrong size")
+    }
+}
+
+/// Encrypts the objects uploaded to the inner storage, and decrypts the
+/// downloaded ones. See the module docs for the details.
+pub struct EncryptedStorage {
+    inner: GenericRemoteStorage,
+    kms: Arc<dyn KeyManagement>,
+    rng: SystemRandom,
+
+    /// The data keys that the keys of new objects are derived from, by
+    /// tenant. A data key lives as long as the process.
+    data_keys: Mutex<HashMap<Option<TenantId>, Arc<ActiveDataKey>>>,
+    /// Data keys unwrapped to decrypt objects, by key-encryption key id and
+    /// wrapped key. There is one per tenant per process that uploaded its
+    /// objects, so we don't bother evicting them.
+    unwrapped_keys: Mutex<HashMap<(String, Vec<u8>), Arc<[u8; DATA_KEY_LEN]>>>,
+}
+
+struct ActiveDataKey {
+    key_id: String,
+    wrapped: Vec<u8>,
+    plaintext: Arc<[u8; DATA_KEY_LEN]>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: GenericRemoteStorage, kms: Arc<dyn KeyManagement>) -> Self {
+        Self {
+            inner,
+            kms,
+            rng: SystemRandom::new(),
+            data_keys: Mutex::new(HashMap::new()),
+            unwrapped_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn data_key(&self, tenant_id: Option<TenantId>) -> anyhow::Result<Arc<ActiveDataKey>> {
+        if let Some(data_key) = self.data_keys.lock().unwrap().get(&tenant_id) {
+            return Ok(Arc::clone(data_key));
+        }
+
+        let data_key = self.kms.generate_data_key(tenant_id).await?;
+        let data_key = Arc::new(ActiveDataKey {
+            key_id: data_key.key_id,
+            wrapped: data_key.wrapped,
+            plaintext: Arc::new(data_key.plaintext),
+        });
+        // A concurrent upload could have generated a key meanwhile, stick to one.
+        let mut data_keys = self.data_keys.lock().unwrap();
+        Ok(Arc::clone(data_keys.entry(tenant_id).or_insert(data_key)))
+    }
+
+    async fn unwrap_key(
+        &self,
+        key_id: String,
+        wrapped: Vec<u8>,
+    ) -> anyhow::Result<Arc<[u8; DATA_KEY_LEN]>> {
+        let cache_key = (key_id, wrapped);
+        if let Some(key) = self.unwrapped_keys.lock().unwrap().get(&cache_key) {
+            return Ok(Arc::clone(key));
+        }
+
+        let (key_id, wrapped) = &cache_key;
+        let key = Arc::new(self.kms.unwrap_data_key(key_id, wrapped).await?);
+        self.unwrapped_keys
+            .lock()
+            .unwrap()
+            .insert(cache_key, Arc::clone(&key));
+        Ok(key)
+    }
+
+    /// Takes the encryption metadata out of the metadata of a downloaded
+    /// object. Returns `None` if the object isn't encrypted.
+    async fn object_cipher(
+        &self,
+        metadata: &mut Option<StorageMetadata>,
+    ) -> anyhow::Result<Option<ObjectCipher>> {
+        let Some(StorageMetadata(entries)) = metadata else {
+            return Ok(None);
+        };
+        let Some(key_id) = entries.remove(KEY_ID_METADATA_KEY) else {
+            return Ok(None);
+        };
+        let mut take = |key: &str| {
+            entries
+                .remove(key)
+                .with_context(|| format!("encrypted object has no '{key}' metadata"))
+        };
+        let wrapped =
+            hex::decode(take(WRAPPED_KEY_METADATA_KEY)?).context("invalid wrapped data key")?;
+        let salt: [u8; SALT_LEN] = hex::decode(take(SALT_METADATA_KEY)?)
+            .ok()
+            .and_then(|salt| salt.try_into().ok())
+            .context("invalid encryption salt")?;
+        let plaintext_size: u64 = take(PLAINTEXT_SIZE_METADATA_KEY)?
+            .parse()
+            .context("invalid plaintext size")?;
+        if entries.is_empty() {
+            *metadata = None;
+        }
+
+        let data_key = self.unwrap_key(key_id, wrapped).await?;
+        Ok(Some(ObjectCipher::new(&data_key, &salt, plaintext_size)?))
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for EncryptedStorage {
+    async fn list(
+        &self,
+        prefix: Option<&RemotePath>,
+        mode: ListingMode,
+    ) -> Result<Listing, DownloadError> {
+        self.inner.list(prefix, mode).await
+    }
+
+    async fn upload(
+        &self,
+        data: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        let data_key = self
+            .data_key(tenant_id(to))
+            .await
+            .context("Failed to get a data key")?;
+        let mut salt = [0; SALT_LEN];
+        fill_random(&self.rng, &mut salt)?;
+        let cipher = ObjectCipher::new(&data_key.plaintext, &salt, data_size_bytes as u64)?;
+
+        let mut metadata = metadata.unwrap_or_else(|| StorageMetadata(HashMap::new()));
+        metadata.0.extend([
+            (KEY_ID_METADATA_KEY.to_string(), data_key.key_id.clone()),
+            (
+                WRAPPED_KEY_METADATA_KEY.to_string(),
+                hex::encode(&data_key.wrapped),
+            ),
+            (SALT_METADATA_KEY.to_string(), hex::encode(salt)),
+            (
+                PLAINTEXT_SIZE_METADATA_KEY.to_string(),
+                data_size_bytes.to_string(),
+            ),
+        ]);
+
+        let ciphertext_size = usize::try_from(cipher.ciphertext_size())?;
+        let data = CipherStream::seal(data, cipher);
+        self.inner
+            .upload(data, ciphertext_size, to, Some(metadata))
+            .await
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
+        let mut download = self.inner.download(from).await?;
+        let cipher = self
+            .object_cipher(&mut download.metadata)
+            .await
+            .map_err(DownloadError::Other)?;
+        if let Some(cipher) = cipher {
+            download.download_stream = Box::pin(CipherStream::open(
+                download.download_stream,
+                cipher,
+                0,
+                None,
+            ));
+        }
+        Ok(download)
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, DownloadError> {
+        if end_exclusive.is_some_and(|end_exclusive| end_exclusive <= start_inclusive) {
+            return Err(DownloadError::BadInput(anyhow!(
+                "Invalid range, start ({start_inclusive}) is not less than end_exclusive ({end_exclusive:?})"
+            )));
+        }
+
+        // Download the chunks that contain the range.
+        let ciphertext_chunk_size = CHUNK_SIZE + TAG_LEN;
+        let ciphertext_start = start_inclusive / CHUNK_SIZE * ciphertext_chunk_size;
+        let ciphertext_end = end_exclusive
+            .map(|end_exclusive| end_exclusive.div_ceil(CHUNK_SIZE) * ciphertext_chunk_size);
+        let mut download = self
+            .inner
+            .download_byte_range(from, ciphertext_start, ciphertext_end)
+            .await?;
+        let cipher = self
+            .object_cipher(&mut download.metadata)
+            .await
+            .map_err(DownloadError::Other)?;
+        match cipher {
+            Some(cipher) => {
+                download.download_stream = Box::pin(CipherStream::open(
+                    download.download_stream,
+                    cipher,
+                    start_inclusive,
+                    end_exclusive,
+                ));
+                Ok(download)
+            }
+            // We only learn that the object isn't encrypted from the response,
+            // so the range we asked for is wrong.
+            None => {
+                drop(download);
+                self.inner
+                    .download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+        }
+    }
+
+    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()> {
+        self.inner.delete_objects(paths).await
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()> {
+        // The copy keeps the metadata, and with it the salt and key of the original.
+        self.inner.copy(from, to).await
+    }
+
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        self.inner
+            .time_travel_recover(prefix, timestamp, done_if_after, cancel)
+            .await
+    }
+}
+
+/// The tenant that an object belongs to, going by the pageserver
+/// (`tenants/<tenant shard id>/...`) and safekeeper (`<tenant id>/...`)
+/// layouts of the storage. The shards of a tenant share its data keys.
+fn tenant_id(path: &RemotePath) -> Option<TenantId> {
+    let mut components = path.get_path().components();
+    let mut first = components.next()?.as_str();
+    if first == "tenants" {
+        first = components.next()?.as_str();
+    }
+    let tenant_id = first
+        .split_once('-')
+        .map_or(first, |(tenant_id, _)| tenant_id);
+    tenant_id.parse().ok()
+}
+
+fn aead_key(key: &[u8]) -> anyhow::Result<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("invalid key size"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn fill_random(rng: &SystemRandom, dest: &mut [u8]) -> anyhow::Result<()> {
+    rng.fill(dest)
+        .map_err(|_| anyhow!("failed to generate random bytes"))
+}
+
+/// The key that the chunks of an object are encrypted with.
+struct ObjectCipher {
+    key: LessSafeKey,
+    plaintext_size: u64,
+}
+
+impl ObjectCipher {
+    /// Derives the key of an object from the data key and the salt of the object.
+    fn new(
+        data_key: &[u8; DATA_KEY_LEN],
+        salt: &[u8],
+        plaintext_size: u64,
+    ) -> anyhow::Result<Self> {
+        let key = hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+            .extract(data_key)
+            .expand(&[OBJECT_KEY_INFO], &AES_256_GCM)
+            .map_err(|_| anyhow!("failed to derive an object key"))?;
+        Ok(Self {
+            key: LessSafeKey::new(UnboundKey::from(key)),
+            plaintext_size,
+        })
+    }
+
+    /// Even an empty object has a chunk, so that its contents are authenticated.
+    fn chunk_count(&self) -> u64 {
+        self.plaintext_size.div_ceil(CHUNK_SIZE).max(1)
+    }
+
+    fn ciphertext_size(&self) -> u64 {
+        self.plaintext_size + self.chunk_count() * TAG_LEN
+    }
+
+    fn plaintext_chunk_size(&self, index: u64) -> u64 {
+        (self.plaintext_size - index * CHUNK_SIZE).min(CHUNK_SIZE)
+    }
+
+    /// The key is used for this object only, so the chunk index is a unique nonce.
+    fn nonce_and_aad(&self, index: u64) -> (Nonce, Aad<[u8; 1]>) {
+        let mut nonce = [0; aead::NONCE_LEN];
+        nonce[aead::NONCE_LEN - 8..].copy_from_slice(&index.to_be_bytes());
+        // Mark the last chunk, so that a truncated object doesn't decrypt.
+        let is_last = index + 1 == self.chunk_count();
+        (
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from([is_last as u8]),
+        )
+    }
+
+    fn seal(&self, index: u64, mut chunk: BytesMut) -> io::Result<Bytes> {
+        let (nonce, aad) = self.nonce_and_aad(index);
+        self.key
+            .seal_in_place_append_tag(nonce, aad, &mut chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to encrypt a chunk"))?;
+        Ok(chunk.freeze())
+    }
+
+    fn open(&self, index: u64, mut chunk: BytesMut) -> io::Result<Bytes> {
+        let (nonce, aad) = self.nonce_and_aad(index);
+        let plaintext_size = self
+            .key
+            .open_in_place(nonce, aad, &mut chunk)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "failed to decrypt a chunk, the object is corrupted or the key is wrong",
+                )
+            })?
+            .len();
+        chunk.truncate(plaintext_size);
+        Ok(chunk.freeze())
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Encrypts or decrypts the chunks `next_chunk..end_chunk` of an object,
+    /// streamed by `inner`.
+    struct CipherStream<S> {
+        #[pin]
+        inner: S,
+        cipher: ObjectCipher,
+        seal: bool,
+        buffer: BytesMut,
+        next_chunk: u64,
+        end_chunk: u64,
+        // Decrypted bytes to drop from the first chunk, when decrypting a range.
+        skip: u64,
+        // Decrypted bytes left to return, when decrypting a range.
+        remaining: u64,
+    }
+}
+
+impl<S> CipherStream<S> {
+    fn seal(inner: S, cipher: ObjectCipher) -> Self {
+        Self {
+            inner,
+            seal: true,
+            buffer: BytesMut::new(),
+            next_chunk: 0,
+            end_chunk: cipher.chunk_count(),
+            skip: 0,
+            remaining: u64::MAX,
+            cipher,
+        }
+    }
+
+    /// `inner` streams the object from the chunk that `start_inclusive` is in.
+    fn open(
+        inner: S,
+        cipher: ObjectCipher,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Self {
+        let end = end_exclusive.map_or(cipher.plaintext_size, |end_exclusive| {
+            end_exclusive.min(cipher.plaintext_size)
+        });
+        let start = start_inclusive.min(end);
+        let next_chunk = start / CHUNK_SIZE;
+        let end_chunk = match end_exclusive {
+            None => cipher.chunk_count(),
+            Some(_) => end.div_ceil(CHUNK_SIZE),
+        };
+        Self {
+            inner,
+            seal: false,
+            buffer: BytesMut::new(),
+            next_chunk,
+            end_chunk,
+            skip: start - next_chunk * CHUNK_SIZE,
+            remaining: end - start,
+            cipher,
+        }
+    }
+}
+
+impl<S: Stream<Item = std::io::Result<Bytes>>> Stream for CipherStream<S> {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if *this.next_chunk >= *this.end_chunk {
+                return Poll::Ready(None);
+            }
+
+            let index = *this.next_chunk;
+            let mut chunk_size = this.cipher.plaintext_chunk_size(index);
+            if !*this.seal {
+                chunk_size += TAG_LEN;
+            }
+            if this.buffer.len() as u64 >= chunk_size {
+                let chunk = this.buffer.split_to(chunk_size as usize);
+                *this.next_chunk += 1;
+                let res = if *this.seal {
+                    this.cipher.seal(index, chunk)
+                } else {
+                    this.cipher.open(index, chunk)
+                };
+                let mut output = match res {
+                    Ok(output) => output,
+                    Err(e) => {
+                        *this.next_chunk = *this.end_chunk;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                };
+
+                let skip = (*this.skip).min(output.len() as u64);
+                *this.skip -= skip;
+                output = output.slice(skip as usize..);
+                let take = (*this.remaining).min(output.len() as u64);
+                *this.remaining -= take;
+                output.truncate(take as usize);
+                if output.is_empty() {
+                    continue;
+                }
+                return Poll::Ready(Some(Ok(output)));
+            }
+
+            match ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(Ok(bytes)) => this.buffer.extend_from_slice(&bytes),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    *this.next_chunk = *this.end_chunk;
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended in the middle of an encrypted object",
+                    ))));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalFs;
+    use camino_tempfile::Utf8TempDir;
+    use futures::TryStreamExt;
+    use rand::RngCore;
+
+    const TENANT_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    struct TestStorage {
+        _dir: Utf8TempDir,
+        inner: GenericRemoteStorage,
+        encrypted: EncryptedStorage,
+    }
+
+    fn create_storage() -> anyhow::Result<TestStorage> {
+        let dir = camino_tempfile::tempdir()?;
+        let kms_file = dir.path().join("kms.json");
+        let key = |byte: u8| hex::encode([byte; DATA_KEY_LEN]);
+        std::fs::write(
+            &kms_file,
+            serde_json::json!({
+                "default_key_id": "main",
+                "keys": { "main": key(1), "customer": key(2) },
+                "tenant_key_ids": { TENANT_ID: "customer" },
+            })
+            .to_string(),
+        )?;
+        let kms = Arc::new(LocalFileKms::from_file(&kms_file)?);
+        let inner = GenericRemoteStorage::LocalFs(LocalFs::new(dir.path().join("storage"))?);
+        Ok(TestStorage {
+            _dir: dir,
+            encrypted: EncryptedStorage::new(inner.clone(), kms),
+            inner,
+        })
+    }
+
+    fn path(p: &str) -> RemotePath {
+        RemotePath::new(Utf8Path::new(p)).unwrap()
+    }
+
+    async fn upload(
+        storage: &EncryptedStorage,
+        to: &RemotePath,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        // Uneven pieces, to not line up with the chunks
+        let pieces = data
+            .chunks(1000)
+            .map(|piece| Ok::<_, io::Error>(Bytes::copy_from_slice(piece)))
+            .collect::<Vec<_>>();
+        storage
+            .upload(futures::stream::iter(pieces), data.len(), to, None)
+            .await
+    }
+
+    async fn read_all(download: Download) -> std::io::Result<Vec<u8>> {
+        let pieces: Vec<Bytes> = download.download_stream.try_collect().await?;
+        Ok(pieces.concat())
+    }
+
+    #[tokio::test]
+    async fn roundtrip() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let mut data = vec![0; 3 * CHUNK_SIZE as usize + 123];
+        rand::thread_rng().fill_bytes(&mut data);
+        let layer = path(&format!("tenants/{TENANT_ID}-0102/timelines/layer"));
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+        let pieces = futures::stream::iter([Ok::<_, io::Error>(Bytes::from(data.clone()))]);
+        storage
+            .encrypted
+            .upload(pieces, data.len(), &layer, Some(metadata.clone()))
+            .await?;
+
+        let raw = storage.inner.download(&layer).await?;
+        let raw_metadata = raw.metadata.clone().unwrap();
+        assert_eq!(raw_metadata.0[KEY_ID_METADATA_KEY], "customer");
+        let raw = read_all(raw).await?;
+        assert_eq!(raw.len(), data.len() + 4 * TAG_LEN as usize);
+        assert_ne!(&raw[..data.len()], &data[..]);
+
+        let download = storage.encrypted.download(&layer).await?;
+        assert_eq!(download.metadata.as_ref(), Some(&metadata));
+        assert_eq!(read_all(download).await?, data);
+
+        let chunk = CHUNK_SIZE;
+        for (start, end) in [
+            (0, Some(10)),
+            (5, Some(chunk)),
+            (chunk - 1, Some(chunk + 1)),
+            (chunk, Some(2 * chunk)),
+            (100, Some(3 * chunk + 100)),
+            (2 * chunk + 7, None),
+            (3 * chunk + 100, Some(10 * chunk)),
+        ] {
+            let download = storage
+                .encrypted
+                .download_byte_range(&layer, start, end)
+                .await?;
+            let expected_end = end.map_or(data.len(), |end| data.len().min(end as usize));
+            assert_eq!(
+                read_all(download).await?,
+                &data[start as usize..expected_end],
+                "range {start}..{end:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_and_unencrypted_objects() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+
+        let empty = path("empty");
+        upload(&storage.encrypted, &empty, &[]).await?;
+        let raw = storage.inner.download(&empty).await?;
+        assert_eq!(raw.metadata.unwrap().0[KEY_ID_METADATA_KEY], "main");
+        let download = storage.encrypted.download(&empty).await?;
+        assert_eq!(download.metadata, None);
+        assert!(read_all(download).await?.is_empty());
+
+        // Written before the encryption was enabled.
+        let plain = path(&format!("{TENANT_ID}/timeline/segment"));
+        let data = b"not encrypted".to_vec();
+        let pieces = futures::stream::iter([Ok::<_, io::Error>(Bytes::from(data.clone()))]);
+        storage
+            .inner
+            .upload(pieces, data.len(), &plain, None)
+            .await?;
+        let download = storage.encrypted.download(&plain).await?;
+        assert_eq!(read_all(download).await?, data);
+        let download = storage
+            .encrypted
+            .download_byte_range(&plain, 4, Some(13))
+            .await?;
+        assert_eq!(read_all(download).await?, b"encrypted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupted_object() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let mut data = vec![0; 2 * CHUNK_SIZE as usize];
+        rand::thread_rng().fill_bytes(&mut data);
+        let object = path("object");
+        upload(&storage.encrypted, &object, &data).await?;
+
+        // Flip a bit in the second chunk.
+        let raw = storage.inner.download(&object).await?;
+        let metadata = raw.metadata.clone();
+        let mut raw = read_all(raw).await?;
+        raw[CHUNK_SIZE as usize + 100] ^= 1;
+        let len = raw.len();
+        let pieces = futures::stream::iter([Ok::<_, io::Error>(Bytes::from(raw))]);
+        storage.inner.upload(pieces, len, &object, metadata).await?;
+
+        let download = storage.encrypted.download(&object).await?;
+        let err = read_all(download).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // The first chunk is still fine.
+        let download = storage
+            .encrypted
+            .download_byte_range(&object, 0, Some(CHUNK_SIZE))
+            .await?;
+        assert_eq!(read_all(download).await?, &data[..CHUNK_SIZE as usize]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn per_object_keys() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let data = vec![7; 1000];
+        let first = path(&format!("{TENANT_ID}/timeline/first"));
+        let second = path(&format!("{TENANT_ID}/timeline/second"));
+        upload(&storage.encrypted, &first, &data).await?;
+        upload(&storage.encrypted, &second, &data).await?;
+
+        let first_raw = storage.inner.download(&first).await?;
+        let first_metadata = first_raw.metadata.clone().unwrap();
+        let second_raw = storage.inner.download(&second).await?;
+        let second_metadata = second_raw.metadata.clone().unwrap();
+        // The same data key, but different salts and so different ciphertexts.
+        assert_eq!(
+            first_metadata.0[WRAPPED_KEY_METADATA_KEY],
+            second_metadata.0[WRAPPED_KEY_METADATA_KEY]
+        );
+        assert_ne!(
+            first_metadata.0[SALT_METADATA_KEY],
+            second_metadata.0[SALT_METADATA_KEY]
+        );
+        let second_raw = read_all(second_raw).await?;
+        assert_ne!(read_all(first_raw).await?, second_raw);
+
+        // The ciphertext of one object doesn't decrypt with the salt of another.
+        let len = second_raw.len();
+        let pieces = futures::stream::iter([Ok::<_, io::Error>(Bytes::from(second_raw))]);
+        storage
+            .inner
+            .upload(pieces, len, &first, Some(first_metadata))
+            .await?;
+        let download = storage.encrypted.download(&first).await?;
+        let err = read_all(download).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    fn object_tenants() {
+        let expected = TENANT_ID.parse().ok();
+        assert_eq!(
+            tenant_id(&path(&format!("tenants/{TENANT_ID}/x"))),
+            expected
+        );
+        assert_eq!(
+            tenant_id(&path(&format!("tenants/{TENANT_ID}-0104/x"))),
+            expected
+        );
+        assert_eq!(
+            tenant_id(&path(&format!("{TENANT_ID}/timeline/x"))),
+            expected
+        );
+        assert_eq!(tenant_id(&path("deletion/0000000000000001.list")), None);
+    }
+}
@@ -6,13 +6,19 @@
 //!   * [`s3_bucket`] uses AWS S3 bucket as an external storage
 //!   * [`azure_blob`] allows to use Azure Blob storage as an external storage
 //!
+//! Any of those can be wrapped into [`EncryptedStorage`] for client-side encryption of the stored objects,
+//! and into [`CachingStorage`] to keep the downloads in a local disk cache.
+//!
 #![deny(unsafe_code)]
 #![deny(clippy::undocumented_unsafe_blocks)]
 
 mod azure_blob;
+mod cache;
+mod encryption;
 mod local_fs;
 mod s3_bucket;
 mod simulate_failures;
+mod time_travel;
 
 use std::{
     collections::HashMap, fmt::Debug, num::NonZeroUsize, pin::Pin, sync::Arc, time::SystemTime,
@@ -25,11 +31,16 @@ use bytes::Bytes;
 use futures::stream::Stream;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 use toml_edit::Item;
 use tracing::info;
 
 pub use self::{
-    azure_blob::AzureBlobStorage, local_fs::LocalFs, s3_bucket::S3Bucket,
+    azure_blob::AzureBlobStorage,
+    cache::CachingStorage,
+    encryption::{DataKey, EncryptedStorage, KeyManagement, LocalFileKms, DATA_KEY_LEN},
+    local_fs::LocalFs,
+    s3_bucket::S3Bucket,
     simulate_failures::UnreliableWrapper,
 };
 use s3_bucket::RequestKind;
@@ -207,6 +218,26 @@ pub trait RemoteStorage: Send + Sync + 'static {
     async fn delete(&self, path: &RemotePath) -> anyhow::Result<()>;
 
     async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()>;
+
+    /// Copies the remote storage entry to another path inside the same storage,
+    /// without passing its contents through the caller. The copy gets the
+    /// metadata of the original, and replaces the target entry if there's one.
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()>;
+
+    /// Brings every entry under the prefix back to its state at `timestamp`,
+    /// using the versions kept by the storage: entries changed since then are
+    /// restored, and entries created since then are deleted.
+    ///
+    /// Entries changed after `done_if_after` are assumed to be restored by an
+    /// earlier, interrupted attempt of the same recovery, and are skipped.
+    /// Pass the time the first attempt started to make retries idempotent.
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError>;
 }
 
 pub type DownloadStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Unpin + Send + Sync>>;
@@ -237,6 +268,8 @@ pub enum DownloadError {
     /// A cancellation token aborted the download, typically during
     /// tenant detach or process shutdown.
     Cancelled,
+    /// The downloaded contents do not match the checksum recorded at upload time.
+    ChecksumMismatch { expected: u32, actual: u32 },
     /// The file was found in the remote storage, but the download failed.
     Other(anyhow::Error),
 }
@@ -249,6 +282,10 @@ impl std::fmt::Display for DownloadError {
             }
             DownloadError::Cancelled => write!(f, "Cancelled, shutting down"),
             DownloadError::NotFound => write!(f, "No file found for the remote object id given"),
+            DownloadError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Downloaded contents have checksum {actual:08x}, expected {expected:08x}"
+            ),
             DownloadError::Other(e) => write!(f, "Failed to download a remote file: {e:?}"),
         }
     }
@@ -256,6 +293,41 @@ impl std::fmt::Display for DownloadError {
 
 impl std::error::Error for DownloadError {}
 
+#[derive(Debug)]
+pub enum TimeTravelError {
+    /// Validation or other error happened due to user input.
+    BadInput(anyhow::Error),
+    /// The remote storage doesn't support time travel recovery.
+    Unimplemented,
+    /// A cancellation token aborted the recovery.
+    Cancelled,
+    /// Listing the versions or restoring them failed.
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for TimeTravelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeTravelError::BadInput(e) => {
+                write!(
+                    f,
+                    "Failed to time travel recover a prefix due to user input: {e}"
+                )
+            }
+            TimeTravelError::Unimplemented => {
+                write!(
+                    f,
+                    "time travel recovery is not implemented for this remote storage"
+                )
+            }
+            TimeTravelError::Cancelled => write!(f, "Cancelled, shutting down"),
+            TimeTravelError::Other(e) => write!(f, "Failed to time travel recover a prefix: {e:?}"),
+        }
+    }
+}
+
+impl std::error::Error for TimeTravelError {}
+
 /// Every storage, currently supported.
 /// Serves as a simple way to pass around the [`RemoteStorage`] without dealing with generics.
 #[derive(Clone)]
@@ -264,6 +336,8 @@ pub enum GenericRemoteStorage {
     AwsS3(Arc<S3Bucket>),
     AzureBlob(Arc<AzureBlobStorage>),
     Unreliable(Arc<UnreliableWrapper>),
+    Encrypted(Arc<EncryptedStorage>),
+    Cached(Arc<CachingStorage>),
 }
 
 impl GenericRemoteStorage {
@@ -277,6 +351,8 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.list(prefix, mode).await,
             Self::AzureBlob(s) => s.list(prefix, mode).await,
             Self::Unreliable(s) => s.list(prefix, mode).await,
+            Self::Encrypted(s) => s.list(prefix, mode).await,
+            Self::Cached(s) => s.list(prefix, mode).await,
         }
     }
 
@@ -289,6 +365,8 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.list_files(folder).await,
             Self::AzureBlob(s) => s.list_files(folder).await,
             Self::Unreliable(s) => s.list_files(folder).await,
+            Self::Encrypted(s) => s.list_files(folder).await,
+            Self::Cached(s) => s.list_files(folder).await,
         }
     }
 
@@ -304,6 +382,8 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.list_prefixes(prefix).await,
             Self::AzureBlob(s) => s.list_prefixes(prefix).await,
             Self::Unreliable(s) => s.list_prefixes(prefix).await,
+            Self::Encrypted(s) => s.list_prefixes(prefix).await,
+            Self::Cached(s) => s.list_prefixes(prefix).await,
         }
     }
 
@@ -319,6 +399,8 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.upload(from, data_size_bytes, to, metadata).await,
             Self::AzureBlob(s) => s.upload(from, data_size_bytes, to, metadata).await,
             Self::Unreliable(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Encrypted(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Cached(s) => s.upload(from, data_size_bytes, to, metadata).await,
         }
     }
 
@@ -328,6 +410,8 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.download(from).await,
             Self::AzureBlob(s) => s.download(from).await,
             Self::Unreliable(s) => s.download(from).await,
+            Self::Encrypted(s) => s.download(from).await,
+            Self::Cached(s) => s.download(from).await,
         }
     }
 
@@ -354,6 +438,14 @@ impl GenericRemoteStorage {
                 s.download_byte_range(from, start_inclusive, end_exclusive)
                     .await
             }
+            Self::Encrypted(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::Cached(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
         }
     }
 
@@ -363,6 +455,8 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.delete(path).await,
             Self::AzureBlob(s) => s.delete(path).await,
             Self::Unreliable(s) => s.delete(path).await,
+            Self::Encrypted(s) => s.delete(path).await,
+            Self::Cached(s) => s.delete(path).await,
         }
     }
 
@@ -372,13 +466,66 @@ impl GenericRemoteStorage {
             Self::AwsS3(s) => s.delete_objects(paths).await,
             Self::AzureBlob(s) => s.delete_objects(paths).await,
             Self::Unreliable(s) => s.delete_objects(paths).await,
+            Self::Encrypted(s) => s.delete_objects(paths).await,
+            Self::Cached(s) => s.delete_objects(paths).await,
+        }
+    }
+
+    pub async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()> {
+        match self {
+            Self::LocalFs(s) => s.copy(from, to).await,
+            Self::AwsS3(s) => s.copy(from, to).await,
+            Self::AzureBlob(s) => s.copy(from, to).await,
+            Self::Unreliable(s) => s.copy(from, to).await,
+            Self::Encrypted(s) => s.copy(from, to).await,
+            Self::Cached(s) => s.copy(from, to).await,
+        }
+    }
+
+    pub async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        if timestamp > done_if_after {
+            return Err(TimeTravelError::BadInput(anyhow::anyhow!(
+                "the time to recover to is after the start of the recovery"
+            )));
+        }
+        match self {
+            Self::LocalFs(s) => {
+                s.time_travel_recover(prefix, timestamp, done_if_after, cancel)
+                    .await
+            }
+            Self::AwsS3(s) => {
+                s.time_travel_recover(prefix, timestamp, done_if_after, cancel)
+                    .await
+            }
+            Self::AzureBlob(s) => {
+                s.time_travel_recover(prefix, timestamp, done_if_after, cancel)
+                    .await
+            }
+            Self::Unreliable(s) => {
+                s.time_travel_recover(prefix, timestamp, done_if_after, cancel)
+                    .await
+            }
+            Self::Encrypted(s) => {
+                s.time_travel_recover(prefix, timestamp, done_if_after, cancel)
+                    .await
+            }
+            Self::Cached(s) => {
+                s.time_travel_recover(prefix, timestamp, done_if_after, cancel)
+                    .await
+            }
         }
     }
 }
 
 impl GenericRemoteStorage {
     pub fn from_config(storage_config: &RemoteStorageConfig) -> anyhow::Result<Self> {
-        Ok(match &storage_config.storage {
+        let storage = match &storage_config.storage {
             RemoteStorageKind::LocalFs(root) => {
                 info!("Using fs root '{root}' as a remote storage");
                 Self::LocalFs(LocalFs::new(root.clone())?)
@@ -393,6 +540,26 @@ impl GenericRemoteStorage {
                       azure_config.container_name, azure_config.container_region, azure_config.prefix_in_container);
                 Self::AzureBlob(Arc::new(AzureBlobStorage::new(azure_config)?))
             }
+        };
+
+        // The cache is under the encryption, to not keep the decrypted objects on disk.
+        let storage = match &storage_config.cache {
+            None => storage,
+            Some(cache_config) => {
+                info!(
+                    "Caching the remote storage downloads in '{}', up to {} bytes",
+                    cache_config.path, cache_config.max_size_bytes
+                );
+                Self::cached(storage, cache_config)?
+            }
+        };
+
+        Ok(match &storage_config.encryption {
+            None => storage,
+            Some(EncryptionConfig::LocalFileKms(kms_file)) => {
+                info!("Encrypting the remote storage objects with the keys from '{kms_file}'");
+                Self::encrypted(storage, Arc::new(LocalFileKms::from_file(kms_file)?))
+            }
         })
     }
 
@@ -400,6 +567,14 @@ impl GenericRemoteStorage {
         Self::Unreliable(Arc::new(UnreliableWrapper::new(s, fail_first)))
     }
 
+    pub fn encrypted(s: Self, kms: Arc<dyn KeyManagement>) -> Self {
+        Self::Encrypted(Arc::new(EncryptedStorage::new(s, kms)))
+    }
+
+    pub fn cached(s: Self, config: &CacheConfig) -> anyhow::Result<Self> {
+        Ok(Self::Cached(Arc::new(CachingStorage::new(s, config)?)))
+    }
+
     /// Takes storage object contents and its size and uploads to remote storage,
     /// mapping `from_path` to the corresponding remote object id in the storage.
     ///
@@ -437,11 +612,31 @@ impl GenericRemoteStorage {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StorageMetadata(HashMap<String, String>);
 
+impl<const N: usize> From<[(&str, &str); N]> for StorageMetadata {
+    fn from(arr: [(&str, &str); N]) -> Self {
+        let map: HashMap<String, String> = arr
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Self(map)
+    }
+}
+
+impl StorageMetadata {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+}
+
 /// External backup storage configuration, enough for creating a client for that storage.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RemoteStorageConfig {
     /// The storage connection configuration.
     pub storage: RemoteStorageKind,
+    /// Client-side encryption of the stored objects, disabled if not set.
+    pub encryption: Option<EncryptionConfig>,
+    /// Local disk cache of the downloads, disabled if not set.
+    pub cache: Option<CacheConfig>,
 }
 
 /// A kind of a remote storage to connect to, with its connection configuration.
@@ -458,6 +653,21 @@ pub enum RemoteStorageKind {
     AzureContainer(AzureConfig),
 }
 
+/// Where [`EncryptedStorage`] gets the key-encryption keys from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptionConfig {
+    /// The keys are in a local file, see [`LocalFileKms`]. For testing.
+    LocalFileKms(Utf8PathBuf),
+}
+
+/// Where [`CachingStorage`] keeps the downloads, and how much of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub path: Utf8PathBuf,
+    /// The least recently used downloads are evicted to stay below this size.
+    pub max_size_bytes: u64,
+}
+
 /// AWS S3 bucket coordinates and access credentials to manage the bucket contents (read and write).
 #[derive(Clone, PartialEq, Eq)]
 pub struct S3Config {
@@ -550,6 +760,34 @@ impl RemoteStorageConfig {
                 .context("Failed to parse 'max_keys_per_list_response' as a positive integer")?
                 .or(DEFAULT_MAX_KEYS_PER_LIST_RESPONSE);
 
+        let encryption = toml
+            .get("encryption")
+            .map(|encryption| -> anyhow::Result<_> {
+                let local_kms_file = encryption
+                    .get("local_kms_file")
+                    .context("'encryption' needs a KMS to be configured, e.g. 'local_kms_file'")?;
+                Ok(EncryptionConfig::LocalFileKms(Utf8PathBuf::from(
+                    parse_toml_string("local_kms_file", local_kms_file)?,
+                )))
+            })
+            .transpose()?;
+
+        let cache = toml
+            .get("cache")
+            .map(|cache| -> anyhow::Result<_> {
+                let path = cache
+                    .get("path")
+                    .context("'cache' needs a 'path' to keep the downloads in")?;
+                let max_size_bytes = parse_optional_integer("max_size_bytes", cache)
+                    .context("Failed to parse 'max_size_bytes' as a positive integer")?
+                    .context("'cache' needs a 'max_size_bytes' limit")?;
+                Ok(CacheConfig {
+                    path: Utf8PathBuf::from(parse_toml_string("path", path)?),
+                    max_size_bytes,
+                })
+            })
+            .transpose()?;
+
         let endpoint = toml
             .get("endpoint")
             .map(|endpoint| parse_toml_string("endpoint", endpoint))
@@ -616,7 +854,11 @@ impl RemoteStorageConfig {
             }
         };
 
-        Ok(Some(RemoteStorageConfig { storage }))
+        Ok(Some(RemoteStorageConfig {
+            storage,
+            encryption,
+            cache,
+        }))
     }
 }
 
@@ -660,6 +902,7 @@ impl ConcurrencyLimiter {
             RequestKind::Put => &self.write,
             RequestKind::List => &self.read,
             RequestKind::Delete => &self.write,
+            RequestKind::Copy => &self.write,
         }
     }
 
@@ -710,4 +953,55 @@ mod tests {
         let err = RemotePath::new(Utf8Path::new("/")).expect_err("Should fail on absolute paths");
         assert_eq!(err.to_string(), "Path \"/\" is not relative");
     }
+
+    #[test]
+    fn parse_encryption_config() {
+        let parse = |toml: &str| {
+            let document = toml.parse::<toml_edit::Document>().unwrap();
+            RemoteStorageConfig::from_toml(document.as_item())
+        };
+
+        let config =
+            parse("local_path = '/remote'\nencryption = { local_kms_file = '/keys.json' }")
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            config.encryption,
+            Some(EncryptionConfig::LocalFileKms(Utf8PathBuf::from(
+                "/keys.json"
+            )))
+        );
+
+        let config = parse("local_path = '/remote'").unwrap().unwrap();
+        assert_eq!(config.encryption, None);
+
+        parse("local_path = '/remote'\nencryption = {}").expect_err("no KMS configured");
+    }
+
+    #[test]
+    fn parse_cache_config() {
+        let parse = |toml: &str| {
+            let document = toml.parse::<toml_edit::Document>().unwrap();
+            RemoteStorageConfig::from_toml(document.as_item())
+        };
+
+        let config =
+            parse("local_path = '/remote'\ncache = { path = '/cache', max_size_bytes = 1048576 }")
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            config.cache,
+            Some(CacheConfig {
+                path: Utf8PathBuf::from("/cache"),
+                max_size_bytes: 1048576,
+            })
+        );
+
+        let config = parse("local_path = '/remote'").unwrap().unwrap();
+        assert_eq!(config.cache, None);
+
+        parse("local_path = '/remote'\ncache = { path = '/cache' }").expect_err("no size limit");
+        parse("local_path = '/remote'\ncache = { path = '/cache', max_size_bytes = -1 }")
+            .expect_err("negative size limit");
+    }
 }
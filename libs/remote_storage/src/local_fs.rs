@@ -4,7 +4,13 @@
 //! This storage used in tests, but can also be used in cases when a certain persistent
 //! volume is mounted to the local FS.
 
-use std::{borrow::Cow, future::Future, io::ErrorKind, pin::Pin};
+use std::{
+    borrow::Cow,
+    future::Future,
+    io::ErrorKind,
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{bail, ensure, Context};
 use bytes::Bytes;
@@ -14,19 +20,32 @@ use tokio::{
     fs,
     io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
-use tokio_util::io::ReaderStream;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
 use tracing::*;
 use utils::{crashsafe::path_with_suffix_extension, fs_ext::is_directory_empty};
 
-use crate::{Download, DownloadError, DownloadStream, Listing, ListingMode, RemotePath};
+use crate::{
+    time_travel::{self, ObjectVersion, RecoveryAction, VersionKind},
+    Download, DownloadError, DownloadStream, Listing, ListingMode, RemotePath, TimeTravelError,
+};
 
 use super::{RemoteStorage, StorageMetadata};
 
 const LOCAL_FS_TEMP_FILE_SUFFIX: &str = "___temp";
 
+const VERSIONS_DIR_SUFFIX: &str = "versions";
+const DELETE_MARKER_SUFFIX: &str = "deleted";
+
 #[derive(Debug, Clone)]
 pub struct LocalFs {
     storage_root: Utf8PathBuf,
+    /// Where the history of the files is kept, if versioning is enabled.
+    ///
+    /// Every upload, copy or deletion of a file adds a version to
+    /// `<versions_root>/<file path>.versions/`: a copy of the new contents
+    /// named after the nanoseconds since the epoch (with the usual `.metadata`
+    /// file next to it), or an empty `<nanoseconds>.deleted` file.
+    versions_root: Option<Utf8PathBuf>,
 }
 
 impl LocalFs {
@@ -44,7 +63,35 @@ impl LocalFs {
             })?;
         }
 
-        Ok(Self { storage_root })
+        Ok(Self {
+            storage_root,
+            versions_root: None,
+        })
+    }
+
+    /// Like [`LocalFs::new`], but also keeps the history of the files under
+    /// `versions_root`, like a versioned S3 bucket does, so that the storage
+    /// supports time travel recovery. The versions root must be outside of
+    /// the storage root.
+    pub fn with_versioning(
+        storage_root: Utf8PathBuf,
+        mut versions_root: Utf8PathBuf,
+    ) -> anyhow::Result<Self> {
+        let mut storage = Self::new(storage_root)?;
+        std::fs::create_dir_all(&versions_root).with_context(|| {
+            format!("Failed to create all directories in the given versions path {versions_root:?}")
+        })?;
+        if !versions_root.is_absolute() {
+            versions_root = versions_root.canonicalize_utf8().with_context(|| {
+                format!("Failed to represent path {versions_root:?} as an absolute path")
+            })?;
+        }
+        ensure!(
+            !versions_root.starts_with(&storage.storage_root),
+            "versions root {versions_root:?} is inside the storage root"
+        );
+        storage.versions_root = Some(versions_root);
+        Ok(storage)
     }
 
     // mirrors S3Bucket::s3_object_to_relative_path
@@ -77,6 +124,112 @@ impl LocalFs {
         }
     }
 
+    /// Adds the current state of the file to its history, if versioning is
+    /// enabled: its contents, or a delete marker if it doesn't exist.
+    async fn record_version(&self, path: &RemotePath) -> anyhow::Result<()> {
+        let Some(versions_root) = &self.versions_root else {
+            return Ok(());
+        };
+        let versions_dir = versions_dir(versions_root, path);
+        fs::create_dir_all(&versions_dir)
+            .await
+            .with_context(|| format!("Failed to create the versions directory '{versions_dir}'"))?;
+
+        // Keep the version ids increasing, even if the clock isn't.
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .context("system time is before the epoch")?
+            .as_nanos();
+        let last = get_all_files(&versions_dir, false)
+            .await?
+            .iter()
+            .filter_map(|path| version_id_and_kind(path))
+            .filter_map(|(version_id, _)| version_id.parse::<u128>().ok())
+            .max();
+        let version_id = match last {
+            Some(last) if last >= now => last + 1,
+            _ => now,
+        }
+        .to_string();
+
+        let file_path = path.with_base(&self.storage_root);
+        let version_path = versions_dir.join(&version_id);
+        if file_exists(&file_path)? {
+            copy_with_metadata(&file_path, &version_path).await
+        } else {
+            let marker_path = path_with_suffix_extension(&version_path, DELETE_MARKER_SUFFIX);
+            fs::write(&marker_path, b"")
+                .await
+                .with_context(|| format!("Failed to write the delete marker '{marker_path}'"))
+        }
+    }
+
+    /// Lists the versions of the files under the prefix, taken as a directory
+    /// like in [`RemoteStorage::time_travel_recover`].
+    async fn list_versions(
+        &self,
+        versions_root: &Utf8Path,
+        prefix: Option<&RemotePath>,
+    ) -> anyhow::Result<Vec<ObjectVersion>> {
+        let prefix_dir = match prefix {
+            Some(prefix) => prefix.with_base(versions_root),
+            None => versions_root.to_owned(),
+        };
+
+        let mut versions = Vec::new();
+        for version_path in get_all_files(&prefix_dir, true).await? {
+            let Some((version_id, kind)) = version_id_and_kind(&version_path) else {
+                continue;
+            };
+            let key = version_path
+                .parent()
+                .and_then(|dir| dir.strip_prefix(versions_root).ok())
+                .and_then(|dir| {
+                    dir.as_str()
+                        .strip_suffix(VERSIONS_DIR_SUFFIX)?
+                        .strip_suffix('.')
+                })
+                .with_context(|| format!("Unexpected file in the versions root: {version_path}"))?;
+            let nanos = version_id
+                .parse::<u64>()
+                .with_context(|| format!("Invalid version id in {version_path}"))?;
+            versions.push(ObjectVersion {
+                key: RemotePath::from_string(key)?,
+                version_id: version_id.to_string(),
+                last_modified: SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos),
+                kind,
+                is_latest: false,
+            });
+        }
+
+        // Newest first, like the other storages list them, and the newest version is the
+        // current one.
+        versions.sort_by(|a, b| {
+            a.key
+                .cmp(&b.key)
+                .then_with(|| b.last_modified.cmp(&a.last_modified))
+        });
+        let mut previous_key = None;
+        for version in versions.iter_mut() {
+            version.is_latest = previous_key.as_ref() != Some(&version.key);
+            previous_key = Some(version.key.clone());
+        }
+        Ok(versions)
+    }
+
+    async fn restore_version(
+        &self,
+        versions_root: &Utf8Path,
+        path: &RemotePath,
+        version_id: &str,
+    ) -> anyhow::Result<()> {
+        let version_path = versions_dir(versions_root, path).join(version_id);
+        let target_path = path.with_base(&self.storage_root);
+        create_target_directory(&target_path).await?;
+        copy_with_metadata(&version_path, &target_path).await?;
+        self.record_version(path).await
+    }
+
     #[cfg(test)]
     async fn list_all(&self) -> anyhow::Result<Vec<RemotePath>> {
         Ok(get_all_files(&self.storage_root, true)
@@ -308,22 +461,25 @@ impl RemoteStorage for LocalFs {
             })?;
         }
 
-        Ok(())
+        self.record_version(to).await
     }
 
     async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
         let target_path = from.with_base(&self.storage_root);
         if file_exists(&target_path).map_err(DownloadError::BadInput)? {
-            let source = ReaderStream::new(
-                fs::OpenOptions::new()
-                    .read(true)
-                    .open(&target_path)
-                    .await
-                    .with_context(|| {
-                        format!("Failed to open source file {target_path:?} to use in the download")
-                    })
-                    .map_err(DownloadError::Other)?,
-            );
+            let source = fs::OpenOptions::new()
+                .read(true)
+                .open(&target_path)
+                .await
+                .with_context(|| {
+                    format!("Failed to open source file {target_path:?} to use in the download")
+                })
+                .map_err(DownloadError::Other)?;
+            let file_metadata = source
+                .metadata()
+                .await
+                .context("Failed to get the metadata of a local storage file")
+                .map_err(DownloadError::Other)?;
 
             let metadata = self
                 .read_storage_metadata(&target_path)
@@ -331,9 +487,9 @@ impl RemoteStorage for LocalFs {
                 .map_err(DownloadError::Other)?;
             Ok(Download {
                 metadata,
-                last_modified: None,
-                etag: None,
-                download_stream: Box::pin(source),
+                last_modified: file_metadata.modified().ok(),
+                etag: etag(&file_metadata),
+                download_stream: Box::pin(ReaderStream::new(source)),
             })
         } else {
             Err(DownloadError::NotFound)
@@ -364,6 +520,11 @@ impl RemoteStorage for LocalFs {
                     format!("Failed to open source file {target_path:?} to use in the download")
                 })
                 .map_err(DownloadError::Other)?;
+            let file_metadata = source
+                .metadata()
+                .await
+                .context("Failed to get the metadata of a local storage file")
+                .map_err(DownloadError::Other)?;
             source
                 .seek(io::SeekFrom::Start(start_inclusive))
                 .await
@@ -382,8 +543,8 @@ impl RemoteStorage for LocalFs {
             };
             Ok(Download {
                 metadata,
-                last_modified: None,
-                etag: None,
+                last_modified: file_metadata.modified().ok(),
+                etag: etag(&file_metadata),
                 download_stream,
             })
         } else {
@@ -394,7 +555,7 @@ impl RemoteStorage for LocalFs {
     async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
         let file_path = path.with_base(&self.storage_root);
         match fs::remove_file(&file_path).await {
-            Ok(()) => Ok(()),
+            Ok(()) => self.record_version(path).await,
             // The file doesn't exist. This shouldn't yield an error to mirror S3's behaviour.
             // See https://docs.aws.amazon.com/AmazonS3/latest/API/API_DeleteObject.html
             // > If there isn't a null version, Amazon S3 does not remove any objects but will still respond that the command was successful.
@@ -409,6 +570,113 @@ impl RemoteStorage for LocalFs {
         }
         Ok(())
     }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()> {
+        let source_path = from.with_base(&self.storage_root);
+        let target_path = to.with_base(&self.storage_root);
+        create_target_directory(&target_path).await?;
+        copy_with_metadata(&source_path, &target_path).await?;
+        self.record_version(to).await
+    }
+
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        let Some(versions_root) = &self.versions_root else {
+            return Err(TimeTravelError::Unimplemented);
+        };
+        let versions = self
+            .list_versions(versions_root, prefix)
+            .await
+            .map_err(TimeTravelError::Other)?;
+
+        for (path, action) in time_travel::plan_recovery(versions, timestamp, done_if_after) {
+            if cancel.is_cancelled() {
+                return Err(TimeTravelError::Cancelled);
+            }
+            debug!("time travel recovery of {path}: {action:?}");
+            match action {
+                RecoveryAction::Restore(version_id) => {
+                    self.restore_version(versions_root, &path, &version_id)
+                        .await
+                }
+                RecoveryAction::Delete => self.delete(&path).await,
+            }
+            .map_err(TimeTravelError::Other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the file and its metadata, replacing the target ones.
+async fn copy_with_metadata(source_path: &Utf8Path, target_path: &Utf8Path) -> anyhow::Result<()> {
+    // Same as for uploads, don't let a partial copy appear at the target path.
+    let temp_file_path = path_with_suffix_extension(target_path, LOCAL_FS_TEMP_FILE_SUFFIX);
+    fs::copy(source_path, &temp_file_path)
+        .await
+        .with_context(|| {
+            format!("Failed to copy '{source_path}' to the local storage at '{temp_file_path}'")
+        })?;
+    fs::rename(&temp_file_path, target_path)
+        .await
+        .with_context(|| {
+            format!("Failed to copy (rename) file to the local storage at '{target_path}'")
+        })?;
+
+    // Like S3, the copy keeps the metadata of the original.
+    let source_metadata_path = storage_metadata_path(source_path);
+    let target_metadata_path = storage_metadata_path(target_path);
+    if source_metadata_path.exists() {
+        fs::copy(&source_metadata_path, &target_metadata_path)
+            .await
+            .with_context(|| {
+                format!("Failed to copy metadata to the local storage at '{target_metadata_path}'")
+            })?;
+    } else {
+        match fs::remove_file(&target_metadata_path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(anyhow::anyhow!(e).context(format!(
+                "Failed to remove the metadata of the overwritten file at '{target_metadata_path}'"
+            )))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn versions_dir(versions_root: &Utf8Path, path: &RemotePath) -> Utf8PathBuf {
+    path_with_suffix_extension(path.with_base(versions_root), VERSIONS_DIR_SUFFIX)
+}
+
+/// Parses the name of a file in a versions directory, `None` for metadata files.
+fn version_id_and_kind(version_path: &Utf8Path) -> Option<(&str, VersionKind)> {
+    let file_name = version_path.file_name()?;
+    match file_name.split_once('.') {
+        None => Some((file_name, VersionKind::Version)),
+        Some((version_id, DELETE_MARKER_SUFFIX)) => Some((version_id, VersionKind::DeleteMarker)),
+        Some(_) => None,
+    }
+}
+
+/// Identifies the contents of a file the way web servers often do, by its modification time and
+/// size.
+fn etag(file_metadata: &std::fs::Metadata) -> Option<String> {
+    let modified = file_metadata
+        .modified()
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    Some(format!(
+        "\"{:x}-{:x}\"",
+        modified.as_nanos(),
+        file_metadata.len()
+    ))
 }
 
 fn storage_metadata_path(original_path: &Utf8Path) -> Utf8PathBuf {
@@ -739,6 +1007,114 @@ mod fs_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn copy_file() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+        let original = upload_dummy_file(&storage, "original", Some(metadata.clone())).await?;
+
+        let copy = RemotePath::new(Utf8Path::new("other_timeline/copy"))?;
+        storage.copy(&original, &copy).await?;
+        let contents =
+            read_and_assert_remote_file_contents(&storage, &copy, Some(&metadata)).await?;
+        assert_eq!(contents, dummy_contents("original"));
+
+        // Overwriting a file replaces its metadata, too.
+        let other = upload_dummy_file(&storage, "other", None).await?;
+        storage.copy(&other, &copy).await?;
+        let contents = read_and_assert_remote_file_contents(&storage, &copy, None).await?;
+        assert_eq!(contents, dummy_contents("other"));
+
+        let missing = RemotePath::new(Utf8Path::new("missing"))?;
+        assert!(storage.copy(&missing, &copy).await.is_err());
+
+        let listing = storage.list_all().await?;
+        assert!(
+            !listing.iter().any(|path| path
+                .get_path()
+                .as_str()
+                .ends_with(LOCAL_FS_TEMP_FILE_SUFFIX)),
+            "Should not leave temporary files behind: {listing:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn time_travel_recovery() -> anyhow::Result<()> {
+        let versions_root = tempdir()?.path().to_path_buf();
+        let storage = LocalFs::with_versioning(tempdir()?.path().to_path_buf(), versions_root)?;
+        let path = |p: &str| RemotePath::new(Utf8Path::new(p)).unwrap();
+        async fn upload(
+            storage: &LocalFs,
+            to: RemotePath,
+            contents: &'static str,
+        ) -> anyhow::Result<()> {
+            let data = futures::stream::once(futures::future::ready(Ok(Bytes::from(contents))));
+            storage.upload(data, contents.len(), &to, None).await
+        }
+        async fn read(storage: &LocalFs, from: RemotePath) -> anyhow::Result<Option<String>> {
+            match storage.download(&from).await {
+                Ok(download) => Ok(Some(String::from_utf8(
+                    aggregate(download.download_stream).await?,
+                )?)),
+                Err(DownloadError::NotFound) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        upload(&storage, path("tenant/overwritten"), "old").await?;
+        upload(&storage, path("tenant/deleted"), "deleted").await?;
+        upload(&storage, path("other/file"), "other old").await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let timestamp = SystemTime::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        upload(&storage, path("tenant/overwritten"), "new").await?;
+        storage.delete(&path("tenant/deleted")).await?;
+        upload(&storage, path("tenant/created"), "created").await?;
+        upload(&storage, path("other/file"), "other new").await?;
+        let done_if_after = SystemTime::now();
+
+        let cancel = CancellationToken::new();
+        storage
+            .time_travel_recover(Some(&path("tenant")), timestamp, done_if_after, &cancel)
+            .await?;
+        assert_eq!(
+            read(&storage, path("tenant/overwritten")).await?.as_deref(),
+            Some("old")
+        );
+        assert_eq!(
+            read(&storage, path("tenant/deleted")).await?.as_deref(),
+            Some("deleted")
+        );
+        assert_eq!(read(&storage, path("tenant/created")).await?, None);
+        // Outside of the prefix
+        assert_eq!(
+            read(&storage, path("other/file")).await?.as_deref(),
+            Some("other new")
+        );
+
+        // Retrying the same recovery leaves the files changed since alone.
+        upload(&storage, path("tenant/overwritten"), "newer").await?;
+        storage
+            .time_travel_recover(Some(&path("tenant")), timestamp, done_if_after, &cancel)
+            .await?;
+        assert_eq!(
+            read(&storage, path("tenant/overwritten")).await?.as_deref(),
+            Some("newer")
+        );
+
+        // Without versioning, there is nothing to recover from.
+        assert!(matches!(
+            create_storage()?
+                .time_travel_recover(None, timestamp, done_if_after, &cancel)
+                .await,
+            Err(TimeTravelError::Unimplemented)
+        ));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list() -> anyhow::Result<()> {
         // No delimiter: should recursively list everything
@@ -9,6 +9,7 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::SystemTime,
 };
 
 use anyhow::Context as _;
@@ -34,15 +35,18 @@ use aws_smithy_async::rt::sleep::TokioSleep;
 
 use aws_smithy_types::body::SdkBody;
 use aws_smithy_types::byte_stream::ByteStream;
+use aws_smithy_types::DateTime;
 use bytes::Bytes;
 use futures::stream::Stream;
 use hyper::Body;
 use scopeguard::ScopeGuard;
+use tokio_util::sync::CancellationToken;
 
 use super::StorageMetadata;
 use crate::{
+    time_travel::{self, ObjectVersion, RecoveryAction, VersionKind},
     ConcurrencyLimiter, Download, DownloadError, Listing, ListingMode, RemotePath, RemoteStorage,
-    S3Config, MAX_KEYS_PER_DELETE, REMOTE_STORAGE_PREFIX_SEPARATOR,
+    S3Config, TimeTravelError, MAX_KEYS_PER_DELETE, REMOTE_STORAGE_PREFIX_SEPARATOR,
 };
 
 pub(super) mod metrics;
@@ -211,6 +215,143 @@ impl S3Bucket {
         permit
     }
 
+    /// `copy_source` is the bucket name followed by the key, and optionally
+    /// the version id of the source object.
+    async fn copy_object(&self, copy_source: String, to: &RemotePath) -> anyhow::Result<()> {
+        let kind = RequestKind::Copy;
+        let _guard = self.permit(kind).await;
+
+        let started_at = start_measuring_requests(kind);
+
+        let res = self
+            .client
+            .copy_object()
+            .bucket(self.bucket_name.clone())
+            .key(self.relative_path_to_s3_object(to))
+            .copy_source(copy_source)
+            .send()
+            .await;
+
+        let started_at = ScopeGuard::into_inner(started_at);
+        metrics::BUCKET_METRICS
+            .req_seconds
+            .observe_elapsed(kind, &res, started_at);
+
+        res?;
+
+        Ok(())
+    }
+
+    /// Lists all versions and delete markers of the objects under the prefix.
+    async fn list_versions(
+        &self,
+        prefix: Option<&RemotePath>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<ObjectVersion>, TimeTravelError> {
+        let kind = RequestKind::List;
+
+        // Only list the objects "inside" the prefix, not the ones next to it
+        // which share the beginning of their names.
+        let list_prefix = prefix
+            .map(|p| self.relative_path_to_s3_object(p))
+            .or_else(|| self.prefix_in_bucket.clone())
+            .map(|mut p| {
+                if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+                    p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+                }
+                p
+            });
+
+        let mut versions = Vec::new();
+        let mut key_marker = None;
+        let mut version_id_marker = None;
+        loop {
+            if cancel.is_cancelled() {
+                return Err(TimeTravelError::Cancelled);
+            }
+
+            let _guard = self.permit(kind).await;
+            let started_at = start_measuring_requests(kind);
+
+            let response = self
+                .client
+                .list_object_versions()
+                .bucket(self.bucket_name.clone())
+                .set_prefix(list_prefix.clone())
+                .set_key_marker(key_marker.take())
+                .set_version_id_marker(version_id_marker.take())
+                .set_max_keys(self.max_keys_per_list_response)
+                .send()
+                .await;
+
+            let started_at = ScopeGuard::into_inner(started_at);
+            metrics::BUCKET_METRICS
+                .req_seconds
+                .observe_elapsed(kind, &response, started_at);
+
+            let response = response
+                .context("Failed to list S3 object versions")
+                .map_err(TimeTravelError::Other)?;
+
+            for v in response.versions.unwrap_or_default() {
+                versions.push(self.object_version(
+                    v.key,
+                    v.version_id,
+                    v.last_modified,
+                    VersionKind::Version,
+                    v.is_latest == Some(true),
+                )?);
+            }
+            for m in response.delete_markers.unwrap_or_default() {
+                versions.push(self.object_version(
+                    m.key,
+                    m.version_id,
+                    m.last_modified,
+                    VersionKind::DeleteMarker,
+                    m.is_latest == Some(true),
+                )?);
+            }
+
+            if response.is_truncated != Some(true) {
+                break;
+            }
+            key_marker = response.next_key_marker;
+            version_id_marker = response.next_version_id_marker;
+            if key_marker.is_none() && version_id_marker.is_none() {
+                return Err(TimeTravelError::Other(anyhow::anyhow!(
+                    "truncated S3 object version listing has no markers to continue from"
+                )));
+            }
+        }
+
+        Ok(versions)
+    }
+
+    fn object_version(
+        &self,
+        key: Option<String>,
+        version_id: Option<String>,
+        last_modified: Option<DateTime>,
+        kind: VersionKind,
+        is_latest: bool,
+    ) -> Result<ObjectVersion, TimeTravelError> {
+        let (Some(key), Some(version_id), Some(last_modified)) = (key, version_id, last_modified)
+        else {
+            return Err(TimeTravelError::Other(anyhow::anyhow!(
+                "S3 object version listing is missing a key, version id or modification time"
+            )));
+        };
+        let last_modified = SystemTime::try_from(last_modified)
+            .map_err(|e| TimeTravelError::Other(anyhow::Error::new(e)))?;
+        Ok(ObjectVersion {
+            key: self.s3_object_to_relative_path(&key),
+            version_id,
+            last_modified,
+            kind,
+            is_latest,
+        })
+    }
+
     async fn download_object(&self, request: GetObjectRequest) -> Result<Download, DownloadError> {
         let kind = RequestKind::Get;
         let permit = self.owned_permit(kind).await;
@@ -595,6 +736,55 @@ impl RemoteStorage for S3Bucket {
         let paths = std::array::from_ref(path);
         self.delete_objects(paths).await
     }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> anyhow::Result<()> {
+        // The source has to be prefixed with the bucket name, and should be
+        // URL-encoded, but our keys don't contain any characters to encode.
+        // CopyObject is limited to objects of 5 GiB, which is well above our
+        // layer sizes.
+        let copy_source = format!(
+            "{}/{}",
+            self.bucket_name,
+            self.relative_path_to_s3_object(from)
+        );
+        self.copy_object(copy_source, to).await
+    }
+
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<&RemotePath>,
+        timestamp: SystemTime,
+        done_if_after: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        let versions = self.list_versions(prefix, cancel).await?;
+        tracing::info!(
+            "time travel recovery: found {} object versions and delete markers",
+            versions.len()
+        );
+
+        let actions = time_travel::plan_recovery(versions, timestamp, done_if_after);
+        for (key, action) in actions {
+            if cancel.is_cancelled() {
+                return Err(TimeTravelError::Cancelled);
+            }
+            tracing::info!("time travel recovery: {action:?} for {key}");
+            match action {
+                RecoveryAction::Restore(version_id) => {
+                    // Copying a version over its object makes it the current version.
+                    let copy_source = format!(
+                        "{}/{}?versionId={version_id}",
+                        self.bucket_name,
+                        self.relative_path_to_s3_object(&key)
+                    );
+                    self.copy_object(copy_source, &key).await
+                }
+                RecoveryAction::Delete => self.delete(&key).await,
+            }
+            .map_err(TimeTravelError::Other)?;
+        }
+        Ok(())
+    }
 }
 
 /// On drop (cancellation) count towards [`metrics::BucketMetrics::cancelled_waits`].
@@ -0,0 +1,267 @@
+//! Storage-independent part of the time travel recovery: given all versions
+//! of the objects under a prefix, decide which objects to restore to an older
+//! version and which ones to delete.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
+
+use crate::{RemotePath, TimeTravelError};
+
+/// A version of a remote object, or the marker of its deletion.
+#[derive(Debug, Clone)]
+pub(crate) struct ObjectVersion {
+    pub(crate) key: RemotePath,
+    pub(crate) version_id: String,
+    pub(crate) last_modified: SystemTime,
+    pub(crate) kind: VersionKind,
+    /// Whether this is the current version of the object, as reported by the storage.
+    pub(crate) is_latest: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VersionKind {
+    Version,
+    DeleteMarker,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RecoveryAction {
+    /// Make the version with this id the current one again.
+    Restore(String),
+    Delete,
+}
+
+/// Adds delete markers for the objects without a current version, for the
+/// storages which don't have delete markers. `deleted_at` holds the times of
+/// the deletions that the storage keeps.
+///
+/// Without its time, a deletion is only known to come after `timestamp` if the
+/// last version of the object does. Otherwise, we can't tell whether the object
+/// existed at `timestamp`, and fail.
+pub(crate) fn mark_deletions(
+    versions: &mut Vec<ObjectVersion>,
+    current: &HashSet<RemotePath>,
+    deleted_at: &HashMap<RemotePath, SystemTime>,
+    timestamp: SystemTime,
+) -> Result<(), TimeTravelError> {
+    let mut last_modified = HashMap::<RemotePath, SystemTime>::new();
+    for version in versions.iter().filter(|v| !current.contains(&v.key)) {
+        let at = last_modified
+            .entry(version.key.clone())
+            .or_insert(version.last_modified);
+        *at = (*at).max(version.last_modified);
+    }
+
+    let mut markers = Vec::with_capacity(last_modified.len());
+    for (key, last_modified) in last_modified {
+        let at = match deleted_at.get(&key) {
+            Some(deleted_at) => (*deleted_at).max(last_modified),
+            None if last_modified > timestamp => last_modified,
+            None => {
+                tracing::warn!("time travel recovery: unknown time of the deletion of {key}");
+                return Err(TimeTravelError::Unimplemented);
+            }
+        };
+        markers.push(ObjectVersion {
+            key,
+            version_id: String::new(),
+            last_modified: at,
+            kind: VersionKind::DeleteMarker,
+            is_latest: true,
+        });
+    }
+    // Listed first, the markers are newer than the versions of the same time.
+    versions.splice(0..0, markers);
+    Ok(())
+}
+
+/// Returns what to do with each object to bring the prefix to its state at
+/// `timestamp`, in the order of the keys.
+///
+/// The versions of an object must be listed newest first: the modification
+/// times of versions can be the same, e.g. their resolution is a second on S3.
+///
+/// Objects changed after `done_if_after` are left alone: they have been
+/// restored by an earlier attempt of the same recovery.
+pub(crate) fn plan_recovery(
+    versions: Vec<ObjectVersion>,
+    timestamp: SystemTime,
+    done_if_after: SystemTime,
+) -> Vec<(RemotePath, RecoveryAction)> {
+    let mut by_key = HashMap::<RemotePath, Vec<ObjectVersion>>::new();
+    for version in versions {
+        by_key.entry(version.key.clone()).or_default().push(version);
+    }
+
+    let mut actions = Vec::new();
+    for (key, mut versions) in by_key {
+        // Newest first, the sort is stable.
+        versions.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+        let Some(newest) = versions.first() else {
+            continue;
+        };
+        if newest.last_modified > done_if_after {
+            continue;
+        }
+        let latest = versions.iter().find(|v| v.is_latest).unwrap_or(newest);
+
+        let target = versions.iter().find(|v| v.last_modified <= timestamp);
+        let action = match target {
+            Some(target) if target.kind == VersionKind::Version => {
+                if target.version_id == latest.version_id {
+                    continue;
+                }
+                RecoveryAction::Restore(target.version_id.clone())
+            }
+            // The object didn't exist at that time.
+            _ => {
+                if latest.kind == VersionKind::DeleteMarker {
+                    continue;
+                }
+                RecoveryAction::Delete
+            }
+        };
+        actions.push((key, action));
+    }
+    actions.sort_by(|(a, _), (b, _)| a.cmp(b));
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8Path;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn path(key: &str) -> RemotePath {
+        RemotePath::new(Utf8Path::new(key)).unwrap()
+    }
+
+    fn version(key: &str, id: &str, secs: u64, kind: VersionKind) -> ObjectVersion {
+        ObjectVersion {
+            key: path(key),
+            version_id: id.to_string(),
+            last_modified: at(secs),
+            kind,
+            is_latest: false,
+        }
+    }
+
+    fn latest(version: ObjectVersion) -> ObjectVersion {
+        ObjectVersion {
+            is_latest: true,
+            ..version
+        }
+    }
+
+    #[test]
+    fn plan() {
+        use VersionKind::*;
+        let versions = vec![
+            // overwritten after the timestamp
+            version("overwritten", "2", 20, Version),
+            version("overwritten", "1", 5, Version),
+            // deleted after the timestamp
+            version("deleted", "2", 20, DeleteMarker),
+            version("deleted", "1", 5, Version),
+            // created after the timestamp
+            version("created", "1", 20, Version),
+            // untouched since before the timestamp
+            version("untouched", "1", 5, Version),
+            // deleted before the timestamp
+            version("gone", "2", 8, DeleteMarker),
+            version("gone", "1", 5, Version),
+            // created and deleted after the timestamp
+            version("transient", "2", 30, DeleteMarker),
+            version("transient", "1", 20, Version),
+            // changed after the recovery has started
+            version("restored", "3", 100, Version),
+            version("restored", "2", 20, Version),
+            version("restored", "1", 5, Version),
+            // overwritten within the same second, before and after the timestamp
+            version("same-second", "4", 20, Version),
+            version("same-second", "3", 20, Version),
+            version("same-second", "2", 5, Version),
+            version("same-second", "1", 5, Version),
+            // the current version is the first of the same second
+            latest(version("same-second-latest", "2", 5, Version)),
+            version("same-second-latest", "1", 5, Version),
+        ];
+
+        let actions = plan_recovery(versions, at(10), at(50));
+        assert_eq!(
+            actions,
+            vec![
+                (path("created"), RecoveryAction::Delete),
+                (path("deleted"), RecoveryAction::Restore("1".to_string())),
+                (
+                    path("overwritten"),
+                    RecoveryAction::Restore("1".to_string())
+                ),
+                (
+                    path("same-second"),
+                    RecoveryAction::Restore("2".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_without_delete_markers() {
+        use VersionKind::*;
+        let mut versions = vec![
+            // deleted after its last change, which came after the timestamp
+            version("deleted-later", "2", 20, Version),
+            version("deleted-later", "1", 5, Version),
+            // deleted after its last change, which came before the timestamp,
+            // and the storage knows when
+            version("deleted", "1", 5, Version),
+            // same, but deleted before the timestamp
+            version("gone", "1", 5, Version),
+            // created and deleted after the timestamp
+            version("transient", "1", 20, Version),
+            // still there
+            version("overwritten", "2", 20, Version),
+            version("overwritten", "1", 5, Version),
+        ];
+        let current = HashSet::from([path("overwritten")]);
+        let deleted_at = HashMap::from([(path("deleted"), at(15)), (path("gone"), at(8))]);
+
+        mark_deletions(&mut versions, &current, &deleted_at, at(10)).unwrap();
+        let actions = plan_recovery(versions, at(10), at(50));
+        assert_eq!(
+            actions,
+            vec![
+                (path("deleted"), RecoveryAction::Restore("1".to_string())),
+                (
+                    path("deleted-later"),
+                    RecoveryAction::Restore("1".to_string())
+                ),
+                (
+                    path("overwritten"),
+                    RecoveryAction::Restore("1".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_deletion_time() {
+        use VersionKind::*;
+        let mut versions = vec![version("deleted", "1", 5, Version)];
+
+        // it may have been deleted before the timestamp, or after
+        let res = mark_deletions(&mut versions, &HashSet::new(), &HashMap::new(), at(10));
+        assert!(matches!(res, Err(TimeTravelError::Unimplemented)));
+
+        // but not before its last change: it didn't exist then either
+        mark_deletions(&mut versions, &HashSet::new(), &HashMap::new(), at(1)).unwrap();
+        assert_eq!(plan_recovery(versions, at(1), at(50)), vec![]);
+    }
+}
@@ -2,6 +2,7 @@
 #![deny(clippy::undocumented_unsafe_blocks)]
 use const_format::formatcp;
 
+pub mod membership;
 /// Public API types
 pub mod models;
 
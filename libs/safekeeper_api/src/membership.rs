@@ -0,0 +1,168 @@
+//! Types defining the set of safekeepers a timeline is replicated to.
+//!
+//! The set is changed with joint consensus: to move from members `A` to
+//! members `B`, safekeepers first switch to the joint configuration `(A, B)`,
+//! in which a quorum must be reached in both sets, and then to `B` alone. Each
+//! configuration has a generation number, a safekeeper only ever switches to a
+//! configuration with a higher generation than the one it has.
+
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use utils::id::NodeId;
+
+/// Number of a configuration, increases with every change.
+pub type Generation = u32;
+
+/// Generation of the configuration of timelines created before membership
+/// was tracked, which have no members recorded.
+pub const INVALID_GENERATION: Generation = 0;
+
+/// Identifies a safekeeper and how computes connect to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafekeeperId {
+    pub id: NodeId,
+    pub host: String,
+    pub pg_port: u16,
+}
+
+impl Display for SafekeeperId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sk{}@{}:{}", self.id, self.host, self.pg_port)
+    }
+}
+
+/// A set of safekeepers, without duplicates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberSet {
+    pub members: Vec<SafekeeperId>,
+}
+
+impl MemberSet {
+    pub fn new(members: Vec<SafekeeperId>) -> anyhow::Result<Self> {
+        let ids: HashSet<NodeId> = members.iter().map(|m| m.id).collect();
+        if ids.len() != members.len() {
+            bail!("duplicate safekeeper ids in member set");
+        }
+        Ok(MemberSet { members })
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.members.iter().any(|m| m.id == id)
+    }
+
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.members.iter().map(|m| m.id).collect()
+    }
+}
+
+impl Display for MemberSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, m) in self.members.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{m}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// Membership configuration of a timeline.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    pub generation: Generation,
+    pub members: MemberSet,
+    /// Set while the configuration is joint.
+    pub new_members: Option<MemberSet>,
+}
+
+impl Configuration {
+    /// Configuration of a timeline whose membership is not tracked: any
+    /// safekeeper serves it.
+    pub fn empty() -> Self {
+        Configuration {
+            generation: INVALID_GENERATION,
+            members: MemberSet::default(),
+            new_members: None,
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.new_members.is_some()
+    }
+
+    /// Whether the safekeeper `id` is a member of either of the sets.
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.members.contains(id)
+            || self
+                .new_members
+                .as_ref()
+                .is_some_and(|new_members| new_members.contains(id))
+    }
+
+    /// Whether the timeline may be served by safekeeper `id`.
+    pub fn allows(&self, id: NodeId) -> bool {
+        self.generation == INVALID_GENERATION || self.contains(id)
+    }
+}
+
+impl Display for Configuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gen={}, members={}", self.generation, self.members)?;
+        if let Some(new_members) = &self.new_members {
+            write!(f, ", new_members={new_members}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sk(id: u64) -> SafekeeperId {
+        SafekeeperId {
+            id: NodeId(id),
+            host: "localhost".to_owned(),
+            pg_port: 5454 + id as u16,
+        }
+    }
+
+    #[test]
+    fn member_set_rejects_duplicates() {
+        assert!(MemberSet::new(vec![sk(1), sk(2)]).is_ok());
+        assert!(MemberSet::new(vec![sk(1), sk(2), sk(1)]).is_err());
+    }
+
+    #[test]
+    fn joint_configuration_membership() {
+        assert!(Configuration::empty().allows(NodeId(1)));
+
+        let conf = Configuration {
+            generation: 2,
+            members: MemberSet::new(vec![sk(1), sk(2), sk(3)]).unwrap(),
+            new_members: Some(MemberSet::new(vec![sk(2), sk(3), sk(4)]).unwrap()),
+        };
+        assert!(conf.is_joint());
+        for id in 1..=4 {
+            assert!(conf.allows(NodeId(id)));
+        }
+        assert!(!conf.allows(NodeId(5)));
+
+        let conf = Configuration {
+            generation: 3,
+            members: conf.new_members.unwrap(),
+            new_members: None,
+        };
+        assert!(!conf.allows(NodeId(1)));
+        assert!(conf.allows(NodeId(4)));
+        assert_eq!(
+            conf.to_string(),
+            "gen=3, members=[sk2@localhost:5456, sk3@localhost:5457, sk4@localhost:5458]"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+use crate::membership::Configuration;
 use utils::{
     id::{NodeId, TenantId, TimelineId},
     lsn::Lsn,
@@ -51,3 +52,19 @@ pub struct SkTimelineInfo {
     #[serde(default)]
     pub http_connstr: Option<String>,
 }
+
+/// Request to switch a timeline to a new membership configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineMembershipSwitchRequest {
+    pub mconf: Configuration,
+    /// HTTP addresses of safekeepers to pull the timeline from, if this
+    /// safekeeper joins the timeline and doesn't have it yet.
+    #[serde(default)]
+    pub pull_from: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineMembershipSwitchResponse {
+    pub previous_conf: Configuration,
+    pub current_conf: Configuration,
+}
@@ -433,13 +433,10 @@ mod tests {
         }
     }
 
-    /// Test that walproposer can successfully connect to safekeeper and finish
-    /// sync_safekeepers. API is mocked in MockImpl.
-    ///
-    /// Run this test with valgrind to detect leaks:
-    /// `valgrind --leak-check=full target/debug/deps/walproposer-<build>`
-    #[test]
-    fn test_simple_sync_safekeepers() -> anyhow::Result<()> {
+    /// Runs sync_safekeepers against a single safekeeper with node id 1, which
+    /// sends `greeting` and votes for the walproposer. Returns the LSN sync
+    /// finished at, if it did. API is mocked in MockImpl.
+    fn sync_safekeepers_with_greeting(greeting: Vec<u8>) -> anyhow::Result<Option<u64>> {
         let ttid = TenantTimelineId::new(
             "9e4c8f36063c6c6e93bc20d65a820f3d".parse()?,
             "9e4c8f36063c6c6e93bc20d65a820f3d".parse()?,
@@ -453,9 +450,9 @@ mod tests {
                 event_mask: 0,
             }),
             expected_messages: vec![
-                // Greeting(ProposerGreeting { protocol_version: 2, pg_version: 160001, proposer_id: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], system_id: 0, timeline_id: 9e4c8f36063c6c6e93bc20d65a820f3d, tenant_id: 9e4c8f36063c6c6e93bc20d65a820f3d, tli: 1, wal_seg_size: 16777216 })
+                // Greeting(ProposerGreeting { protocol_version: 3, pg_version: 160001, proposer_id: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], system_id: 0, timeline_id: 9e4c8f36063c6c6e93bc20d65a820f3d, tenant_id: 9e4c8f36063c6c6e93bc20d65a820f3d, tli: 1, wal_seg_size: 16777216 })
                 vec![
-                    103, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 113, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                    103, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 113, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 158, 76, 143, 54, 6, 60, 108, 110,
                     147, 188, 32, 214, 90, 130, 15, 61, 158, 76, 143, 54, 6, 60, 108, 110, 147,
                     188, 32, 214, 90, 130, 15, 61, 1, 0, 0, 0, 0, 0, 0, 1,
@@ -468,10 +465,7 @@ mod tests {
             ],
             expected_ptr: AtomicUsize::new(0),
             safekeeper_replies: vec![
-                // Greeting(AcceptorGreeting { term: 2, node_id: NodeId(1) })
-                vec![
-                    103, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
-                ],
+                greeting,
                 // VoteResponse(VoteResponse { term: 3, vote_given: 1, flush_lsn: 0/539, truncate_lsn: 0/539, term_history: [(2, 0/539)], timeline_start_lsn: 0/539 })
                 vec![
                     118, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 57,
@@ -492,11 +486,43 @@ mod tests {
 
         let wp = Wrapper::new(my_impl, config);
 
-        // walproposer will panic when it finishes sync_safekeepers
+        // walproposer will panic when it finishes sync_safekeepers, or when
+        // it runs out of mocked messages
         std::panic::catch_unwind(|| wp.start()).unwrap_err();
+        Ok(receiver.try_recv().ok())
+        // drop() will free up resources here
+    }
+
+    /// Test that walproposer can successfully connect to safekeeper and finish
+    /// sync_safekeepers.
+    ///
+    /// Run this test with valgrind to detect leaks:
+    /// `valgrind --leak-check=full target/debug/deps/walproposer-<build>`
+    #[test]
+    fn test_simple_sync_safekeepers() -> anyhow::Result<()> {
+        // Greeting(AcceptorGreeting { term: 2, node_id: NodeId(1), mconf: Some(gen=0, members=[]) })
+        let greeting = vec![
+            103, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
         // validate the resulting LSN
-        assert_eq!(receiver.try_recv(), Ok(1337));
+        assert_eq!(sync_safekeepers_with_greeting(greeting)?, Some(1337));
+        Ok(())
+    }
+
+    /// Test that in a joint membership configuration the vote of a safekeeper
+    /// which is only in one of the member sets doesn't elect walproposer.
+    #[test]
+    fn test_joint_configuration_quorum() -> anyhow::Result<()> {
+        // Greeting(AcceptorGreeting { term: 2, node_id: NodeId(1), mconf: Some(gen=2, members=[sk1@h:5454], new_members=[sk2@h:5455]) })
+        let greeting = vec![
+            103, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, //
+            2, 0, 0, 0, //
+            1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 104, 0, 78, 21, //
+            1, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 104, 0, 79, 21,
+        ];
+        // the old set has acked, the new one hasn't
+        assert_eq!(sync_safekeepers_with_greeting(greeting)?, None);
         Ok(())
-        // drop() will free up resources here
     }
 }
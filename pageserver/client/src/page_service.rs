@@ -3,8 +3,8 @@ use std::pin::Pin;
 use futures::SinkExt;
 use pageserver_api::{
     models::{
-        PagestreamBeMessage, PagestreamFeMessage, PagestreamGetPageRequest,
-        PagestreamGetPageResponse,
+        PagestreamBeMessage, PagestreamFeMessage, PagestreamGetPageBatchRequest,
+        PagestreamGetPageBatchResponse, PagestreamGetPageRequest, PagestreamGetPageResponse,
     },
     reltag::RelTag,
 };
@@ -133,7 +133,8 @@ impl PagestreamClient {
             PagestreamBeMessage::Error(e) => anyhow::bail!("Error: {:?}", e),
             PagestreamBeMessage::Exists(_)
             | PagestreamBeMessage::Nblocks(_)
-            | PagestreamBeMessage::DbSize(_) => {
+            | PagestreamBeMessage::DbSize(_)
+            | PagestreamBeMessage::GetPageBatch(_) => {
                 anyhow::bail!(
                     "unexpected be message kind in response to getpage request: {}",
                     msg.kind()
@@ -141,4 +142,33 @@ impl PagestreamClient {
             }
         }
     }
+
+    pub async fn getpage_batch(
+        &mut self,
+        req: PagestreamGetPageBatchRequest,
+    ) -> anyhow::Result<PagestreamGetPageBatchResponse> {
+        let req = PagestreamFeMessage::GetPageBatch(req);
+        let req: bytes::Bytes = req.serialize();
+        let mut req = tokio_stream::once(Ok(req));
+
+        self.copy_both.send_all(&mut req).await?;
+
+        let next: Option<Result<bytes::Bytes, _>> = self.copy_both.next().await;
+        let next: bytes::Bytes = next.unwrap()?;
+
+        let msg = PagestreamBeMessage::deserialize(next)?;
+        match msg {
+            PagestreamBeMessage::GetPageBatch(p) => Ok(p),
+            PagestreamBeMessage::Error(e) => anyhow::bail!("Error: {:?}", e),
+            PagestreamBeMessage::Exists(_)
+            | PagestreamBeMessage::Nblocks(_)
+            | PagestreamBeMessage::GetPage(_)
+            | PagestreamBeMessage::DbSize(_) => {
+                anyhow::bail!(
+                    "unexpected be message kind in response to getpage batch request: {}",
+                    msg.kind()
+                )
+            }
+        }
+    }
 }
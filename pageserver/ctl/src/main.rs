@@ -9,6 +9,7 @@ mod index_part;
 mod layer_map_analyzer;
 mod layers;
 
+use anyhow::Context;
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, Subcommand};
 use index_part::IndexPartCmd;
@@ -17,10 +18,17 @@ use pageserver::{
     context::{DownloadBehavior, RequestContext},
     page_cache,
     task_mgr::TaskKind,
-    tenant::{dump_layerfile_from_path, metadata::TimelineMetadata},
+    tenant::{
+        dump_layerfile_from_path, metadata::TimelineMetadata,
+        remote_timeline_client::remote_tenant_path,
+    },
     virtual_file,
 };
+use pageserver_api::shard::TenantShardId;
 use postgres_ffi::ControlFileData;
+use remote_storage::{GenericRemoteStorage, RemoteStorageConfig};
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
 use utils::{lsn::Lsn, project_git_version};
 
 project_git_version!(GIT_VERSION);
@@ -47,6 +55,7 @@ enum Commands {
     AnalyzeLayerMap(AnalyzeLayerMapCmd),
     #[command(subcommand)]
     Layer(LayerCmd),
+    TimeTravelRemotePrefix(TimeTravelRemotePrefixCmd),
 }
 
 /// Read and update pageserver metadata file
@@ -76,6 +85,24 @@ struct AnalyzeLayerMapCmd {
     max_holes: Option<usize>,
 }
 
+/// Roll back the remote storage of a tenant shard to its state at a point in
+/// time. Needs a bucket with versioning enabled.
+#[derive(Parser)]
+struct TimeTravelRemotePrefixCmd {
+    /// The `remote_storage` section of a pageserver config, in TOML
+    #[arg(long)]
+    config_toml_str: String,
+    #[arg(long)]
+    tenant_shard_id: TenantShardId,
+    /// The time to roll back to, in RFC 3339 format
+    #[arg(long, value_parser = humantime::parse_rfc3339)]
+    travel_to: SystemTime,
+    /// The start of the recovery: objects changed after it are left alone, so
+    /// pass the same value when retrying. Defaults to the current time.
+    #[arg(long, value_parser = humantime::parse_rfc3339)]
+    done_if_after: Option<SystemTime>,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = CliOpts::parse();
@@ -96,6 +123,9 @@ async fn main() -> anyhow::Result<()> {
         Commands::AnalyzeLayerMap(cmd) => {
             layer_map_analyzer::main(&cmd).await?;
         }
+        Commands::TimeTravelRemotePrefix(cmd) => {
+            time_travel_remote_prefix(&cmd).await?;
+        }
         Commands::PrintLayerFile(cmd) => {
             if let Err(e) = read_pg_control_file(&cmd.path) {
                 println!(
@@ -129,6 +159,33 @@ async fn print_layerfile(path: &Utf8Path) -> anyhow::Result<()> {
     dump_layerfile_from_path(path, true, &ctx).await
 }
 
+async fn time_travel_remote_prefix(cmd: &TimeTravelRemotePrefixCmd) -> anyhow::Result<()> {
+    let toml_document = cmd.config_toml_str.parse::<toml_edit::Document>()?;
+    let toml_item = toml_document
+        .get("remote_storage")
+        .context("no remote_storage section in the config")?;
+    let config =
+        RemoteStorageConfig::from_toml(toml_item)?.context("empty remote storage config")?;
+    let storage = GenericRemoteStorage::from_config(&config)?;
+
+    let prefix = remote_tenant_path(&cmd.tenant_shard_id);
+    let done_if_after = cmd.done_if_after.unwrap_or_else(SystemTime::now);
+    println!(
+        "Recovering {prefix} to {}, leaving alone objects changed after {}",
+        humantime::format_rfc3339(cmd.travel_to),
+        humantime::format_rfc3339(done_if_after)
+    );
+    storage
+        .time_travel_recover(
+            Some(&prefix),
+            cmd.travel_to,
+            done_if_after,
+            &CancellationToken::new(),
+        )
+        .await?;
+    Ok(())
+}
+
 fn handle_metadata(
     MetadataCmd {
         metadata_path: path,
@@ -11,18 +11,19 @@
 //! from data stored in object storage.
 //!
 use anyhow::{anyhow, bail, ensure, Context};
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use fail::fail_point;
 use postgres_ffi::pg_constants;
 use std::fmt::Write as FmtWrite;
 use std::time::SystemTime;
 use tokio::io;
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tracing::*;
 
 use tokio_tar::{Builder, EntryType, Header};
 
 use crate::context::RequestContext;
+use crate::metrics::{BASEBACKUP_CACHE_HITS, BASEBACKUP_CACHE_MISSES};
 use crate::pgdatadir_mapping::Version;
 use crate::tenant::Timeline;
 use pageserver_api::reltag::{RelTag, SlruKind};
@@ -37,6 +38,9 @@ use postgres_ffi::PG_TLI;
 use postgres_ffi::{BLCKSZ, RELSEG_SIZE, WAL_SEGMENT_SIZE};
 use utils::lsn::Lsn;
 
+mod cache;
+pub(crate) use cache::BasebackupCache;
+
 /// Create basebackup with non-rel data in it.
 /// Only include relational data if 'full_backup' is true.
 ///
@@ -104,18 +108,73 @@ where
         backup_lsn, prev_lsn, full_backup
     );
 
-    let basebackup = Basebackup {
-        ar: Builder::new_non_terminated(write),
-        timeline,
-        lsn: backup_lsn,
-        prev_record_lsn: prev_lsn,
-        full_backup,
-        ctx,
-    };
-    basebackup
-        .send_tarball()
-        .instrument(info_span!("send_tarball", backup_lsn=%backup_lsn))
-        .await
+    let use_cache = timeline.conf.basebackup_cache && !full_backup;
+    async move {
+        if use_cache {
+            if let Some(entries) = timeline.basebackup_cache.get(backup_lsn) {
+                BASEBACKUP_CACHE_HITS.inc();
+                debug!("sending {} bytes of cached basebackup", entries.len());
+                write
+                    .write_all(&entries)
+                    .await
+                    .context("could not send cached basebackup entries")?;
+                let basebackup = Basebackup {
+                    ar: Builder::new_non_terminated(write),
+                    timeline,
+                    lsn: backup_lsn,
+                    prev_record_lsn: prev_lsn,
+                    full_backup,
+                    ctx,
+                };
+                return basebackup.finish_tarball().await;
+            }
+            BASEBACKUP_CACHE_MISSES.inc();
+        }
+
+        // New computes start at the end of the timeline, so that's the only LSN worth caching.
+        if use_cache && backup_lsn == timeline.get_last_record_lsn() {
+            let mut buf = Vec::new();
+            {
+                let mut basebackup = Basebackup {
+                    ar: Builder::new_non_terminated(&mut buf),
+                    timeline,
+                    lsn: backup_lsn,
+                    prev_record_lsn: prev_lsn,
+                    full_backup,
+                    ctx,
+                };
+                basebackup.add_cacheable_files().await?;
+            }
+            let entries = Bytes::from(buf);
+            write
+                .write_all(&entries)
+                .await
+                .context("could not send basebackup entries")?;
+            timeline.basebackup_cache.insert(backup_lsn, entries);
+
+            let basebackup = Basebackup {
+                ar: Builder::new_non_terminated(write),
+                timeline,
+                lsn: backup_lsn,
+                prev_record_lsn: prev_lsn,
+                full_backup,
+                ctx,
+            };
+            basebackup.finish_tarball().await
+        } else {
+            let basebackup = Basebackup {
+                ar: Builder::new_non_terminated(write),
+                timeline,
+                lsn: backup_lsn,
+                prev_record_lsn: prev_lsn,
+                full_backup,
+                ctx,
+            };
+            basebackup.send_tarball().await
+        }
+    }
+    .instrument(info_span!("send_tarball", backup_lsn=%backup_lsn))
+    .await
 }
 
 /// This is short-living object only for the time of tarball creation,
@@ -138,6 +197,13 @@ where
     W: AsyncWrite + Send + Sync + Unpin,
 {
     async fn send_tarball(mut self) -> anyhow::Result<()> {
+        self.add_cacheable_files().await?;
+        self.finish_tarball().await
+    }
+
+    /// Adds all the files except for the ones that depend on the LSN of the basebackup, see
+    /// [`BasebackupCache`].
+    async fn add_cacheable_files(&mut self) -> anyhow::Result<()> {
         // TODO include checksum
 
         // Create pgdata subdirs structure
@@ -256,7 +322,10 @@ where
         fail_point!("basebackup-before-control-file", |_| {
             bail!("failpoint basebackup-before-control-file")
         });
+        Ok(())
+    }
 
+    async fn finish_tarball(mut self) -> anyhow::Result<()> {
         // Generate pg_control and bootstrap WAL segment.
         self.add_pgcontrol_file().await?;
         self.ar.finish().await?;
@@ -286,12 +355,22 @@ where
             let endblk = std::cmp::min(startblk + RELSEG_SIZE, nblocks);
 
             let mut segment_data: Vec<u8> = vec![];
-            for blknum in startblk..endblk {
-                let img = self
+            for chunk_start in (startblk..endblk).step_by(Timeline::MAX_GET_VECTORED_KEYS) {
+                let chunk_end =
+                    std::cmp::min(chunk_start + Timeline::MAX_GET_VECTORED_KEYS as u32, endblk);
+                let imgs = self
                     .timeline
-                    .get_rel_page_at_lsn(src, blknum, Version::Lsn(self.lsn), false, self.ctx)
+                    .get_rel_page_batch_at_lsn(
+                        src,
+                        chunk_start..=chunk_end - 1,
+                        Version::Lsn(self.lsn),
+                        false,
+                        self.ctx,
+                    )
                     .await?;
-                segment_data.extend_from_slice(&img[..]);
+                for img in imgs {
+                    segment_data.extend_from_slice(&img[..]);
+                }
             }
 
             let file_name = dst.to_segfile_name(seg as u32);
@@ -315,19 +394,25 @@ where
             .await?;
 
         let mut slru_buf: Vec<u8> = Vec::with_capacity(nblocks as usize * BLCKSZ as usize);
-        for blknum in 0..nblocks {
-            let img = self
+        for chunk_start in (0..nblocks).step_by(Timeline::MAX_GET_VECTORED_KEYS) {
+            let chunk_end = std::cmp::min(
+                chunk_start + Timeline::MAX_GET_VECTORED_KEYS as u32,
+                nblocks,
+            );
+            let imgs = self
                 .timeline
-                .get_slru_page_at_lsn(slru, segno, blknum, self.lsn, self.ctx)
+                .get_slru_pages_at_lsn(slru, segno, chunk_start..chunk_end, self.lsn, self.ctx)
                 .await?;
 
-            if slru == SlruKind::Clog {
-                ensure!(img.len() == BLCKSZ as usize || img.len() == BLCKSZ as usize + 8);
-            } else {
-                ensure!(img.len() == BLCKSZ as usize);
-            }
+            for img in imgs {
+                if slru == SlruKind::Clog {
+                    ensure!(img.len() == BLCKSZ as usize || img.len() == BLCKSZ as usize + 8);
+                } else {
+                    ensure!(img.len() == BLCKSZ as usize);
+                }
 
-            slru_buf.extend_from_slice(&img[..BLCKSZ as usize]);
+                slru_buf.extend_from_slice(&img[..BLCKSZ as usize]);
+            }
         }
 
         let segname = format!("{}/{:>04X}", slru.to_str(), segno);
@@ -0,0 +1,118 @@
+//!
+//! A per-timeline cache of the most recent basebackup.
+//!
+//! Most of a basebackup tarball -- the SLRUs, relation maps, two-phase state files, init forks
+//! of unlogged relations and so on -- stays the same from one LSN to the next, until WAL arrives
+//! that modifies one of those non-relation keys. Only `zenith.signal`, `pg_control` and the WAL
+//! segment depend on the LSN of the basebackup itself.
+//!
+//! The cache keeps the uncompressed tar entries of the former, as they were generated by the
+//! most recent basebackup at the end of the timeline, and a later basebackup at the same or a
+//! higher LSN reuses them if no WAL that changes them has been ingested in between. The end of
+//! the timeline is always above the GC cutoff, so a cached entry can be served for any LSN at
+//! which a basebackup can be requested after it.
+//!
+//! WAL ingestion calls [`BasebackupCache::invalidate`] before the modified keys become visible
+//! to readers, see [`crate::pgdatadir_mapping::is_basebackup_cached_key`].
+//!
+
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use utils::lsn::Lsn;
+
+pub(crate) struct BasebackupCache {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The LSN at which the cached entries were generated, and the entries.
+    entry: Option<(Lsn, Bytes)>,
+    /// The highest LSN at which WAL modified a key of the cached entries.
+    invalidated_at: Lsn,
+}
+
+impl BasebackupCache {
+    pub(crate) fn new() -> Self {
+        BasebackupCache {
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Returns the cached tar entries, if they are valid for a basebackup at `lsn`.
+    pub(crate) fn get(&self, lsn: Lsn) -> Option<Bytes> {
+        let inner = self.inner.lock().unwrap();
+        match &inner.entry {
+            Some((cached_lsn, entries))
+                if *cached_lsn <= lsn && inner.invalidated_at <= *cached_lsn =>
+            {
+                Some(entries.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores the tar entries generated for a basebackup at `lsn`, unless they have already
+    /// been invalidated while they were generated, or the cache has more recent ones.
+    pub(crate) fn insert(&self, lsn: Lsn, entries: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.invalidated_at > lsn {
+            return;
+        }
+        if matches!(&inner.entry, Some((cached_lsn, _)) if *cached_lsn >= lsn) {
+            return;
+        }
+        inner.entry = Some((lsn, entries));
+    }
+
+    /// Records that WAL at `lsn` modifies a key of the cached entries.
+    pub(crate) fn invalidate(&self, lsn: Lsn) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.invalidated_at = std::cmp::max(inner.invalidated_at, lsn);
+        if matches!(&inner.entry, Some((cached_lsn, _)) if *cached_lsn < lsn) {
+            // free the memory right away, the entries can't be used anymore
+            inner.entry = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalidated_by_later_wal() {
+        let cache = BasebackupCache::new();
+        assert_eq!(cache.get(Lsn(0x10)), None);
+
+        cache.insert(Lsn(0x10), Bytes::from_static(b"at 0x10"));
+        // not valid before the LSN it was generated at
+        assert_eq!(cache.get(Lsn(0x8)), None);
+        assert_eq!(cache.get(Lsn(0x10)).unwrap(), &b"at 0x10"[..]);
+        assert_eq!(cache.get(Lsn(0x20)).unwrap(), &b"at 0x10"[..]);
+
+        // a change that was already included
+        cache.invalidate(Lsn(0x10));
+        assert_eq!(cache.get(Lsn(0x20)).unwrap(), &b"at 0x10"[..]);
+
+        cache.invalidate(Lsn(0x18));
+        assert_eq!(cache.get(Lsn(0x20)), None);
+
+        cache.insert(Lsn(0x20), Bytes::from_static(b"at 0x20"));
+        assert_eq!(cache.get(Lsn(0x20)).unwrap(), &b"at 0x20"[..]);
+    }
+
+    #[test]
+    fn insert_keeps_newest_valid_entry() {
+        let cache = BasebackupCache::new();
+        cache.insert(Lsn(0x20), Bytes::from_static(b"at 0x20"));
+        cache.insert(Lsn(0x10), Bytes::from_static(b"at 0x10"));
+        assert_eq!(cache.get(Lsn(0x20)).unwrap(), &b"at 0x20"[..]);
+
+        // generated concurrently with ingestion of WAL that changed its contents
+        cache.invalidate(Lsn(0x30));
+        cache.insert(Lsn(0x28), Bytes::from_static(b"at 0x28"));
+        assert_eq!(cache.get(Lsn(0x30)), None);
+    }
+}
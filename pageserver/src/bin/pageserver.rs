@@ -28,7 +28,7 @@ use pageserver::{
     task_mgr::TaskKind,
     task_mgr::{BACKGROUND_RUNTIME, COMPUTE_REQUEST_RUNTIME, MGMT_REQUEST_RUNTIME},
     tenant::mgr,
-    virtual_file,
+    virtual_file, walredo,
 };
 use postgres_backend::AuthType;
 use utils::failpoint_support;
@@ -132,6 +132,7 @@ fn main() -> anyhow::Result<()> {
     // Basic initialization of things that don't change after startup
     virtual_file::init(conf.max_file_descriptors);
     page_cache::init(conf.page_cache_size);
+    walredo::pool::init(conf);
 
     start_pageserver(launch_ts, conf).context("Failed to start pageserver")?;
 
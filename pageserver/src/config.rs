@@ -36,6 +36,7 @@ use crate::tenant::config::TenantConfOpt;
 use crate::tenant::{
     TENANTS_SEGMENT_NAME, TENANT_DELETED_MARKER_FILE_NAME, TIMELINES_SEGMENT_NAME,
 };
+use crate::walredo::{WalRedoNativeHeapMode, WalRedoProcessPoolConfig};
 use crate::{
     IGNORED_TENANT_FILE_NAME, METADATA_FILE_NAME, TENANT_CONFIG_NAME, TENANT_LOCATION_CONFIG_NAME,
     TIMELINE_DELETE_MARK_SUFFIX, TIMELINE_UNINIT_MARK_SUFFIX,
@@ -75,9 +76,12 @@ pub mod defaults {
     pub const DEFAULT_BACKGROUND_TASK_MAXIMUM_DELAY: &str = "10s";
 
     pub const DEFAULT_HEATMAP_UPLOAD_CONCURRENCY: usize = 8;
+    pub const DEFAULT_SECONDARY_DOWNLOAD_CONCURRENCY: usize = 1;
 
     pub const DEFAULT_INGEST_BATCH_SIZE: u64 = 100;
 
+    pub const DEFAULT_WAL_REDO_NATIVE_HEAP: &str = "disabled";
+
     ///
     /// Default built-in configuration file.
     ///
@@ -89,6 +93,8 @@ pub mod defaults {
 
 #wait_lsn_timeout = '{DEFAULT_WAIT_LSN_TIMEOUT}'
 #wal_redo_timeout = '{DEFAULT_WAL_REDO_TIMEOUT}'
+#wal_redo_native_heap = '{DEFAULT_WAL_REDO_NATIVE_HEAP}'
+#wal_redo_process_pool = {{ max_processes = .., acquire_timeout = "10s" }}
 
 #page_cache_size = {DEFAULT_PAGE_CACHE_SIZE}
 #max_file_descriptors = {DEFAULT_MAX_FILE_DESCRIPTORS}
@@ -113,6 +119,8 @@ pub mod defaults {
 
 #ingest_batch_size = {DEFAULT_INGEST_BATCH_SIZE}
 
+#basebackup_cache = false
+
 [tenant_config]
 #checkpoint_distance = {DEFAULT_CHECKPOINT_DISTANCE} # in bytes
 #checkpoint_timeout = {DEFAULT_CHECKPOINT_TIMEOUT}
@@ -128,8 +136,10 @@ pub mod defaults {
 #min_resident_size_override = .. # in bytes
 #evictions_low_residence_duration_metric_threshold = '{DEFAULT_EVICTIONS_LOW_RESIDENCE_DURATION_METRIC_THRESHOLD}'
 #gc_feedback = false
+#blob_compression = 'disabled'
 
 #heatmap_upload_concurrency = {DEFAULT_HEATMAP_UPLOAD_CONCURRENCY}
+#secondary_download_concurrency = {DEFAULT_SECONDARY_DOWNLOAD_CONCURRENCY}
 
 [remote_storage]
 
@@ -155,6 +165,12 @@ pub struct PageServerConf {
     pub wait_lsn_timeout: Duration,
     // How long to wait for WAL redo to complete.
     pub wal_redo_timeout: Duration,
+    /// Whether the common heap WAL records of PostgreSQL 16 are replayed in-process rather than
+    /// by the WAL redo process.
+    pub wal_redo_native_heap: WalRedoNativeHeapMode,
+    /// If set, all tenants share a capped pool of WAL redo processes, instead of each tenant
+    /// launching its own.
+    pub wal_redo_process_pool: Option<WalRedoProcessPoolConfig>,
 
     pub superuser: String,
 
@@ -239,8 +255,17 @@ pub struct PageServerConf {
     /// heatmap uploads vs. other remote storage operations.
     pub heatmap_upload_concurrency: usize,
 
+    /// How many secondary locations may download layers concurrently: lower values implicitly
+    /// deprioritize secondary downloads vs. other remote storage operations.
+    pub secondary_download_concurrency: usize,
+
     /// Maximum number of WAL records to be ingested and committed at the same time
     pub ingest_batch_size: u64,
+
+    /// If true, each timeline keeps the most recent basebackup at the end of the timeline in
+    /// memory, and serves the next basebackups from it until WAL arrives that changes their
+    /// contents. See [`crate::basebackup::BasebackupCache`].
+    pub basebackup_cache: bool,
 }
 
 /// We do not want to store this in a PageServerConf because the latter may be logged
@@ -277,6 +302,8 @@ struct PageServerConfigBuilder {
 
     wait_lsn_timeout: BuilderValue<Duration>,
     wal_redo_timeout: BuilderValue<Duration>,
+    wal_redo_native_heap: BuilderValue<WalRedoNativeHeapMode>,
+    wal_redo_process_pool: BuilderValue<Option<WalRedoProcessPoolConfig>>,
 
     superuser: BuilderValue<String>,
 
@@ -322,8 +349,11 @@ struct PageServerConfigBuilder {
     control_plane_emergency_mode: BuilderValue<bool>,
 
     heatmap_upload_concurrency: BuilderValue<usize>,
+    secondary_download_concurrency: BuilderValue<usize>,
 
     ingest_batch_size: BuilderValue<u64>,
+
+    basebackup_cache: BuilderValue<bool>,
 }
 
 impl Default for PageServerConfigBuilder {
@@ -338,6 +368,11 @@ impl Default for PageServerConfigBuilder {
                 .expect("cannot parse default wait lsn timeout")),
             wal_redo_timeout: Set(humantime::parse_duration(DEFAULT_WAL_REDO_TIMEOUT)
                 .expect("cannot parse default wal redo timeout")),
+            wal_redo_native_heap: Set(WalRedoNativeHeapMode::from_str(
+                DEFAULT_WAL_REDO_NATIVE_HEAP,
+            )
+            .expect("cannot parse default wal redo native heap mode")),
+            wal_redo_process_pool: Set(None),
             superuser: Set(DEFAULT_SUPERUSER.to_string()),
             page_cache_size: Set(DEFAULT_PAGE_CACHE_SIZE),
             max_file_descriptors: Set(DEFAULT_MAX_FILE_DESCRIPTORS),
@@ -396,8 +431,11 @@ impl Default for PageServerConfigBuilder {
             control_plane_emergency_mode: Set(false),
 
             heatmap_upload_concurrency: Set(DEFAULT_HEATMAP_UPLOAD_CONCURRENCY),
+            secondary_download_concurrency: Set(DEFAULT_SECONDARY_DOWNLOAD_CONCURRENCY),
 
             ingest_batch_size: Set(DEFAULT_INGEST_BATCH_SIZE),
+
+            basebackup_cache: Set(false),
         }
     }
 }
@@ -423,6 +461,14 @@ impl PageServerConfigBuilder {
         self.wal_redo_timeout = BuilderValue::Set(wal_redo_timeout)
     }
 
+    pub fn wal_redo_native_heap(&mut self, wal_redo_native_heap: WalRedoNativeHeapMode) {
+        self.wal_redo_native_heap = BuilderValue::Set(wal_redo_native_heap)
+    }
+
+    pub fn wal_redo_process_pool(&mut self, value: Option<WalRedoProcessPoolConfig>) {
+        self.wal_redo_process_pool = BuilderValue::Set(value)
+    }
+
     pub fn superuser(&mut self, superuser: String) {
         self.superuser = BuilderValue::Set(superuser)
     }
@@ -546,10 +592,18 @@ impl PageServerConfigBuilder {
         self.heatmap_upload_concurrency = BuilderValue::Set(value)
     }
 
+    pub fn secondary_download_concurrency(&mut self, value: usize) {
+        self.secondary_download_concurrency = BuilderValue::Set(value)
+    }
+
     pub fn ingest_batch_size(&mut self, ingest_batch_size: u64) {
         self.ingest_batch_size = BuilderValue::Set(ingest_batch_size)
     }
 
+    pub fn basebackup_cache(&mut self, basebackup_cache: bool) {
+        self.basebackup_cache = BuilderValue::Set(basebackup_cache)
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         let concurrent_tenant_warmup = self
             .concurrent_tenant_warmup
@@ -575,6 +629,12 @@ impl PageServerConfigBuilder {
             wal_redo_timeout: self
                 .wal_redo_timeout
                 .ok_or(anyhow!("missing wal_redo_timeout"))?,
+            wal_redo_native_heap: self
+                .wal_redo_native_heap
+                .ok_or(anyhow!("missing wal_redo_native_heap"))?,
+            wal_redo_process_pool: self
+                .wal_redo_process_pool
+                .ok_or(anyhow!("missing wal_redo_process_pool"))?,
             superuser: self.superuser.ok_or(anyhow!("missing superuser"))?,
             page_cache_size: self
                 .page_cache_size
@@ -651,9 +711,15 @@ impl PageServerConfigBuilder {
             heatmap_upload_concurrency: self
                 .heatmap_upload_concurrency
                 .ok_or(anyhow!("missing heatmap_upload_concurrency"))?,
+            secondary_download_concurrency: self
+                .secondary_download_concurrency
+                .ok_or(anyhow!("missing secondary_download_concurrency"))?,
             ingest_batch_size: self
                 .ingest_batch_size
                 .ok_or(anyhow!("missing ingest_batch_size"))?,
+            basebackup_cache: self
+                .basebackup_cache
+                .ok_or(anyhow!("missing basebackup_cache"))?,
         })
     }
 }
@@ -821,6 +887,11 @@ impl PageServerConf {
                 "availability_zone" => builder.availability_zone(Some(parse_toml_string(key, item)?)),
                 "wait_lsn_timeout" => builder.wait_lsn_timeout(parse_toml_duration(key, item)?),
                 "wal_redo_timeout" => builder.wal_redo_timeout(parse_toml_duration(key, item)?),
+                "wal_redo_native_heap" => builder.wal_redo_native_heap(parse_toml_from_str(key, item)?),
+                "wal_redo_process_pool" => builder.wal_redo_process_pool(Some(
+                    deserialize_from_item("wal_redo_process_pool", item)
+                        .context("parse wal_redo_process_pool")?
+                )),
                 "initial_superuser_name" => builder.superuser(parse_toml_string(key, item)?),
                 "page_cache_size" => builder.page_cache_size(parse_toml_u64(key, item)? as usize),
                 "max_file_descriptors" => {
@@ -896,7 +967,11 @@ impl PageServerConf {
                 "heatmap_upload_concurrency" => {
                     builder.heatmap_upload_concurrency(parse_toml_u64(key, item)? as usize)
                 },
+                "secondary_download_concurrency" => {
+                    builder.secondary_download_concurrency(parse_toml_u64(key, item)? as usize)
+                },
                 "ingest_batch_size" => builder.ingest_batch_size(parse_toml_u64(key, item)?),
+                "basebackup_cache" => builder.basebackup_cache(parse_toml_bool(key, item)?),
                 _ => bail!("unrecognized pageserver option '{key}'"),
             }
         }
@@ -933,6 +1008,8 @@ impl PageServerConf {
             id: NodeId(0),
             wait_lsn_timeout: Duration::from_secs(60),
             wal_redo_timeout: Duration::from_secs(60),
+            wal_redo_native_heap: WalRedoNativeHeapMode::Disabled,
+            wal_redo_process_pool: None,
             page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
             max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
             listen_pg_addr: defaults::DEFAULT_PG_LISTEN_ADDR.to_string(),
@@ -968,7 +1045,9 @@ impl PageServerConf {
             control_plane_api_token: None,
             control_plane_emergency_mode: false,
             heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY,
+            secondary_download_concurrency: defaults::DEFAULT_SECONDARY_DOWNLOAD_CONCURRENCY,
             ingest_batch_size: defaults::DEFAULT_INGEST_BATCH_SIZE,
+            basebackup_cache: false,
         }
     }
 }
@@ -1116,6 +1195,8 @@ listen_http_addr = '127.0.0.1:9898'
 
 wait_lsn_timeout = '111 s'
 wal_redo_timeout = '111 s'
+wal_redo_native_heap = 'differential'
+wal_redo_process_pool = { max_processes = 16, acquire_timeout = '5 s' }
 
 page_cache_size = 444
 max_file_descriptors = 333
@@ -1132,6 +1213,8 @@ synthetic_size_calculation_interval = '333 s'
 log_format = 'json'
 background_task_maximum_delay = '334 s'
 
+basebackup_cache = true
+
 "#;
 
     #[test]
@@ -1157,6 +1240,8 @@ background_task_maximum_delay = '334 s'
                 availability_zone: None,
                 wait_lsn_timeout: humantime::parse_duration(defaults::DEFAULT_WAIT_LSN_TIMEOUT)?,
                 wal_redo_timeout: humantime::parse_duration(defaults::DEFAULT_WAL_REDO_TIMEOUT)?,
+                wal_redo_native_heap: WalRedoNativeHeapMode::Disabled,
+                wal_redo_process_pool: None,
                 superuser: defaults::DEFAULT_SUPERUSER.to_string(),
                 page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
                 max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
@@ -1198,7 +1283,9 @@ background_task_maximum_delay = '334 s'
                 control_plane_api_token: None,
                 control_plane_emergency_mode: false,
                 heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY,
+                secondary_download_concurrency: defaults::DEFAULT_SECONDARY_DOWNLOAD_CONCURRENCY,
                 ingest_batch_size: defaults::DEFAULT_INGEST_BATCH_SIZE,
+                basebackup_cache: false,
             },
             "Correct defaults should be used when no config values are provided"
         );
@@ -1229,6 +1316,11 @@ background_task_maximum_delay = '334 s'
                 availability_zone: None,
                 wait_lsn_timeout: Duration::from_secs(111),
                 wal_redo_timeout: Duration::from_secs(111),
+                wal_redo_native_heap: WalRedoNativeHeapMode::Differential,
+                wal_redo_process_pool: Some(WalRedoProcessPoolConfig {
+                    max_processes: NonZeroUsize::new(16).unwrap(),
+                    acquire_timeout: Duration::from_secs(5),
+                }),
                 superuser: "zzzz".to_string(),
                 page_cache_size: 444,
                 max_file_descriptors: 333,
@@ -1260,7 +1352,9 @@ background_task_maximum_delay = '334 s'
                 control_plane_api_token: None,
                 control_plane_emergency_mode: false,
                 heatmap_upload_concurrency: defaults::DEFAULT_HEATMAP_UPLOAD_CONCURRENCY,
+                secondary_download_concurrency: defaults::DEFAULT_SECONDARY_DOWNLOAD_CONCURRENCY,
                 ingest_batch_size: 100,
+                basebackup_cache: true,
             },
             "Should be able to parse all basic config values correctly"
         );
@@ -1306,6 +1400,8 @@ broker_endpoint = '{broker_endpoint}'
                 parsed_remote_storage_config,
                 RemoteStorageConfig {
                     storage: RemoteStorageKind::LocalFs(local_storage_path.clone()),
+                    encryption: None,
+                    cache: None,
                 },
                 "Remote storage config should correctly parse the local FS config and fill other storage defaults"
             );
@@ -1373,6 +1469,8 @@ broker_endpoint = '{broker_endpoint}'
                         concurrency_limit: s3_concurrency_limit,
                         max_keys_per_list_response: None,
                     }),
+                    encryption: None,
+                    cache: None,
                 },
                 "Remote storage config should correctly parse the S3 config"
             );
@@ -867,6 +867,8 @@ mod test {
         let remote_fs_dir = harness.conf.workdir.join("remote_fs").canonicalize_utf8()?;
         let storage_config = RemoteStorageConfig {
             storage: RemoteStorageKind::LocalFs(remote_fs_dir.clone()),
+            encryption: None,
+            cache: None,
         };
         let storage = GenericRemoteStorage::from_config(&storage_config).unwrap();
 
@@ -963,7 +965,7 @@ mod test {
         let layer_generation = Generation::new(0xdeadbeef);
         let now_generation = Generation::new(0xfeedbeef);
         let layer_metadata =
-            LayerFileMetadata::new(0xf00, layer_generation, ShardIndex::unsharded());
+            LayerFileMetadata::new(0xf00, layer_generation, ShardIndex::unsharded(), None);
 
         let remote_layer_file_name_1 =
             format!("{}{}", layer_file_name_1, layer_generation.get_suffix());
@@ -1027,7 +1029,7 @@ mod test {
         // Generation that our example layer file was written with
         let layer_generation = stale_generation.previous();
         let layer_metadata =
-            LayerFileMetadata::new(0xf00, layer_generation, ShardIndex::unsharded());
+            LayerFileMetadata::new(0xf00, layer_generation, ShardIndex::unsharded(), None);
 
         ctx.set_latest_generation(latest_generation);
 
@@ -1088,7 +1090,7 @@ mod test {
         let layer_generation = Generation::new(0xdeadbeef);
         let now_generation = Generation::new(0xfeedbeef);
         let layer_metadata =
-            LayerFileMetadata::new(0xf00, layer_generation, ShardIndex::unsharded());
+            LayerFileMetadata::new(0xf00, layer_generation, ShardIndex::unsharded(), None);
 
         // Inject a deletion in the generation before generation_now: after restart,
         // this deletion should _not_ get executed (only the immediately previous
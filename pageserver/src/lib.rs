@@ -37,7 +37,13 @@ use tracing::info;
 /// format, bump this!
 /// Note that TimelineMetadata uses its own version number to track
 /// backwards-compatible changes to the metadata format.
-pub const STORAGE_FORMAT_VERSION: u16 = 3;
+///
+/// Version 4 added per-blob compression, see [`tenant::blob_io`].
+pub const STORAGE_FORMAT_VERSION: u16 = 4;
+
+/// The last storage format version without per-blob compression. Layers written
+/// with compression disabled still use it, so that older pageservers can read them.
+pub const STORAGE_FORMAT_VERSION_UNCOMPRESSED: u16 = 3;
 
 pub const DEFAULT_PG_VERSION: u32 = 15;
 
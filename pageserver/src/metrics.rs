@@ -444,6 +444,14 @@ pub(crate) static REMOTE_ONDEMAND_DOWNLOADED_BYTES: Lazy<IntCounter> = Lazy::new
     .unwrap()
 });
 
+pub(crate) static REMOTE_LAYER_CHECKSUM_MISMATCHES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_remote_layer_checksum_mismatches_total",
+        "Number of layer downloads discarded because their checksum did not match",
+    )
+    .unwrap()
+});
+
 static CURRENT_LOGICAL_SIZE: Lazy<UIntGaugeVec> = Lazy::new(|| {
     register_uint_gauge_vec!(
         "pageserver_current_logical_size",
@@ -1006,6 +1014,7 @@ pub enum SmgrQueryType {
     GetRelSize,
     GetPageAtLsn,
     GetDbSize,
+    GetPageBatchAtLsn,
 }
 
 #[derive(Debug)]
@@ -1120,11 +1129,12 @@ mod smgr_query_time_tests {
     #[test]
     fn op_label_name() {
         use super::SmgrQueryType::*;
-        let expect: [(super::SmgrQueryType, &'static str); 4] = [
+        let expect: [(super::SmgrQueryType, &'static str); 5] = [
             (GetRelExists, "get_rel_exists"),
             (GetRelSize, "get_rel_size"),
             (GetPageAtLsn, "get_page_at_lsn"),
             (GetDbSize, "get_db_size"),
+            (GetPageBatchAtLsn, "get_page_batch_at_lsn"),
         ];
         for (op, expect) in expect {
             let actual: &'static str = op.into();
@@ -1202,6 +1212,23 @@ impl DurationResultObserver for BasebackupQueryTime {
     }
 }
 
+pub(crate) static BASEBACKUP_CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_basebackup_cache_hits_total",
+        "Number of basebackups served from the basebackup cache",
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static BASEBACKUP_CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_basebackup_cache_misses_total",
+        "Number of basebackups that could have been served from the basebackup cache, \
+         but were generated because the cache was empty or stale",
+    )
+    .expect("failed to define a metric")
+});
+
 pub static LIVE_CONNECTIONS_COUNT: Lazy<IntGaugeVec> = Lazy::new(|| {
     register_int_gauge_vec!(
         "pageserver_live_connections",
@@ -1369,6 +1396,11 @@ pub(crate) struct SecondaryModeMetrics {
     pub(crate) upload_heatmap: IntCounter,
     pub(crate) upload_heatmap_errors: IntCounter,
     pub(crate) upload_heatmap_duration: Histogram,
+    pub(crate) download_heatmap: IntCounter,
+    pub(crate) download_layer: IntCounter,
+    pub(crate) download_layer_bytes: IntCounter,
+    pub(crate) download_errors: IntCounter,
+    pub(crate) evict_layer: IntCounter,
 }
 pub(crate) static SECONDARY_MODE: Lazy<SecondaryModeMetrics> = Lazy::new(|| SecondaryModeMetrics {
     upload_heatmap: register_int_counter!(
@@ -1386,6 +1418,31 @@ pub(crate) static SECONDARY_MODE: Lazy<SecondaryModeMetrics> = Lazy::new(|| Seco
         "Time to build and upload a heatmap, including any waiting inside the S3 client"
     )
     .expect("failed to define a metric"),
+    download_heatmap: register_int_counter!(
+        "pageserver_secondary_download_heatmap",
+        "Number of downloads of heatmaps by secondary mode locations"
+    )
+    .expect("failed to define a metric"),
+    download_layer: register_int_counter!(
+        "pageserver_secondary_download_layer",
+        "Number of downloads of layers by secondary mode locations"
+    )
+    .expect("failed to define a metric"),
+    download_layer_bytes: register_int_counter!(
+        "pageserver_secondary_download_layer_bytes",
+        "Bytes of layers downloaded by secondary mode locations"
+    )
+    .expect("failed to define a metric"),
+    download_errors: register_int_counter!(
+        "pageserver_secondary_download_errors",
+        "Failures downloading a heatmap or layer in secondary mode locations"
+    )
+    .expect("failed to define a metric"),
+    evict_layer: register_int_counter!(
+        "pageserver_secondary_evict_layer",
+        "Number of local layers deleted by secondary mode locations because they left the heatmap"
+    )
+    .expect("failed to define a metric"),
 });
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -1654,6 +1711,105 @@ impl Default for WalRedoProcessCounters {
 pub(crate) static WAL_REDO_PROCESS_COUNTERS: Lazy<WalRedoProcessCounters> =
     Lazy::new(WalRedoProcessCounters::default);
 
+pub(crate) struct WalRedoNativeHeapCounters {
+    pub(crate) applied: IntCounter,
+    pub(crate) fallbacks: IntCounter,
+    pub(crate) mismatches: IntCounter,
+}
+
+impl Default for WalRedoNativeHeapCounters {
+    fn default() -> Self {
+        let applied = register_int_counter!(
+            "pageserver_wal_redo_native_heap_records_total",
+            "Number of heap WAL records replayed in-process",
+        )
+        .unwrap();
+
+        let fallbacks = register_int_counter!(
+            "pageserver_wal_redo_native_heap_fallbacks_total",
+            "Number of in-process heap WAL redo failures that were retried in the WAL redo process",
+        )
+        .unwrap();
+
+        let mismatches = register_int_counter!(
+            "pageserver_wal_redo_native_heap_mismatches_total",
+            "Number of in-process heap WAL redo results that differ from the WAL redo process",
+        )
+        .unwrap();
+
+        Self {
+            applied,
+            fallbacks,
+            mismatches,
+        }
+    }
+}
+
+pub(crate) static WAL_REDO_NATIVE_HEAP_COUNTERS: Lazy<WalRedoNativeHeapCounters> =
+    Lazy::new(WalRedoNativeHeapCounters::default);
+
+pub(crate) struct WalRedoProcessPoolMetrics {
+    pub(crate) hits: IntCounter,
+    pub(crate) misses: IntCounter,
+    pub(crate) reassignments: IntCounter,
+    pub(crate) acquire_timeouts: IntCounter,
+    pub(crate) processes: UIntGauge,
+    pub(crate) acquire_duration: Histogram,
+}
+
+impl Default for WalRedoProcessPoolMetrics {
+    fn default() -> Self {
+        let hits = register_int_counter!(
+            "pageserver_wal_redo_pool_hits_total",
+            "Number of WAL redo requests served by an idle pooled process that last served the same tenant",
+        )
+        .unwrap();
+
+        let misses = register_int_counter!(
+            "pageserver_wal_redo_pool_misses_total",
+            "Number of WAL redo requests that needed a new or reassigned pooled process",
+        )
+        .unwrap();
+
+        let reassignments = register_int_counter!(
+            "pageserver_wal_redo_pool_reassignments_total",
+            "Number of pooled WAL redo processes that were reset and handed over to another tenant",
+        )
+        .unwrap();
+
+        let acquire_timeouts = register_int_counter!(
+            "pageserver_wal_redo_pool_acquire_timeouts_total",
+            "Number of WAL redo requests that gave up waiting for a pooled process",
+        )
+        .unwrap();
+
+        let processes = register_uint_gauge!(
+            "pageserver_wal_redo_pool_processes",
+            "Number of live pooled WAL redo processes, idle or in use",
+        )
+        .unwrap();
+
+        let acquire_duration = register_histogram!(
+            "pageserver_wal_redo_pool_acquire_seconds",
+            "Time to get a process from the WAL redo pool, including waiting and launching",
+            redo_histogram_time_buckets!(),
+        )
+        .unwrap();
+
+        Self {
+            hits,
+            misses,
+            reassignments,
+            acquire_timeouts,
+            processes,
+            acquire_duration,
+        }
+    }
+}
+
+pub(crate) static WAL_REDO_PROCESS_POOL_METRICS: Lazy<WalRedoProcessPoolMetrics> =
+    Lazy::new(WalRedoProcessPoolMetrics::default);
+
 /// Similar to `prometheus::HistogramTimer` but does not record on drop.
 pub struct StorageTimeMetricsTimer {
     metrics: StorageTimeMetrics,
@@ -2293,11 +2449,14 @@ pub fn preinitialize_metrics() {
     [
         &MATERIALIZED_PAGE_CACHE_HIT,
         &MATERIALIZED_PAGE_CACHE_HIT_DIRECT,
+        &BASEBACKUP_CACHE_HITS,
+        &BASEBACKUP_CACHE_MISSES,
         &UNEXPECTED_ONDEMAND_DOWNLOADS,
         &WALRECEIVER_STARTED_CONNECTIONS,
         &WALRECEIVER_BROKER_UPDATES,
         &WALRECEIVER_CANDIDATES_ADDED,
         &WALRECEIVER_CANDIDATES_REMOVED,
+        &REMOTE_LAYER_CHECKSUM_MISMATCHES,
     ]
     .into_iter()
     .for_each(|c| {
@@ -2341,4 +2500,6 @@ pub fn preinitialize_metrics() {
 
     // Custom
     Lazy::force(&RECONSTRUCT_TIME);
+    Lazy::force(&WAL_REDO_NATIVE_HEAP_COUNTERS);
+    Lazy::force(&WAL_REDO_PROCESS_POOL_METRICS);
 }
@@ -10,7 +10,7 @@
 //
 
 use anyhow::Context;
-use async_compression::tokio::write::GzipEncoder;
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
 use bytes::Buf;
 use bytes::Bytes;
 use futures::Stream;
@@ -18,8 +18,9 @@ use pageserver_api::models::TenantState;
 use pageserver_api::models::{
     PagestreamBeMessage, PagestreamDbSizeRequest, PagestreamDbSizeResponse,
     PagestreamErrorResponse, PagestreamExistsRequest, PagestreamExistsResponse,
-    PagestreamFeMessage, PagestreamGetPageRequest, PagestreamGetPageResponse,
-    PagestreamNblocksRequest, PagestreamNblocksResponse,
+    PagestreamFeMessage, PagestreamGetPageBatchRequest, PagestreamGetPageBatchResponse,
+    PagestreamGetPageRequest, PagestreamGetPageResponse, PagestreamNblocksRequest,
+    PagestreamNblocksResponse,
 };
 use postgres_backend::{self, is_expected_io_error, AuthType, PostgresBackend, QueryError};
 use pq_proto::framed::ConnectionError;
@@ -27,6 +28,7 @@ use pq_proto::FeStartupPacket;
 use pq_proto::{BeMessage, FeMessage, RowDescriptor};
 use std::io;
 use std::net::TcpListener;
+use std::ops::RangeInclusive;
 use std::pin::pin;
 use std::str;
 use std::str::FromStr;
@@ -53,7 +55,8 @@ use crate::context::{DownloadBehavior, RequestContext};
 use crate::import_datadir::import_wal_from_tar;
 use crate::metrics;
 use crate::metrics::LIVE_CONNECTIONS_COUNT;
-use crate::pgdatadir_mapping::{rel_block_to_key, Version};
+use crate::pgdatadir_mapping::{rel_block_to_key, BlockNumber, Version};
+use crate::repository::Key;
 use crate::task_mgr;
 use crate::task_mgr::TaskKind;
 use crate::tenant::debug_assert_current_span_has_tenant_and_timeline_id;
@@ -270,6 +273,13 @@ async fn page_service_conn_main(
     }
 }
 
+/// Compression of the tarball sent by the `basebackup` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BasebackupCompression {
+    Gzip,
+    Zstd,
+}
+
 struct PageServerHandler {
     _conf: &'static PageServerConf,
     broker_client: storage_broker::BrokerClientChannel,
@@ -518,6 +528,16 @@ impl PageServerHandler {
                         span,
                     )
                 }
+                PagestreamFeMessage::GetPageBatch(req) => {
+                    let _timer = metrics.start_timer(metrics::SmgrQueryType::GetPageBatchAtLsn);
+                    let span = tracing::info_span!("handle_get_page_batch_request", rel = %req.rel, blkno = %req.blkno, nblocks = %req.nblocks, req_lsn = %req.lsn);
+                    (
+                        self.handle_get_page_batch_request(&timeline, &req, &ctx)
+                            .instrument(span.clone())
+                            .await,
+                        span,
+                    )
+                }
             };
 
             if let Err(e) = &response {
@@ -821,6 +841,47 @@ impl PageServerHandler {
         }))
     }
 
+    /// Looks up the shard of the tenant that holds `key`, when it is not the shard that was
+    /// looked up at connection start.
+    async fn get_shard_timeline(
+        &self,
+        timeline: &Timeline,
+        key: Key,
+    ) -> anyhow::Result<Arc<Timeline>> {
+        // The Tenant shard we looked up at connection start does not hold this particular
+        // key: look for other shards in this tenant.  This scenario occurs if a pageserver
+        // has multiple shards for the same tenant.
+        //
+        // TODO: optimize this (https://github.com/neondatabase/neon/pull/6037)
+        let shard_timeline = match self
+            .get_active_tenant_timeline(
+                timeline.tenant_shard_id.tenant_id,
+                timeline.timeline_id,
+                ShardSelector::Page(key),
+            )
+            .await
+        {
+            Ok(t) => t,
+            Err(GetActiveTimelineError::Tenant(GetActiveTenantError::NotFound(_))) => {
+                // We already know this tenant exists in general, because we resolved it at
+                // start of connection.  Getting a NotFound here indicates that the shard containing
+                // the requested page is not present on this node.
+
+                // TODO: this should be some kind of structured error that the client will understand,
+                // so that it can block until its config is updated: this error is expected in the case
+                // that the Tenant's shards' placements are being updated and the client hasn't been
+                // informed yet.
+                //
+                // https://github.com/neondatabase/neon/issues/6038
+                tracing::warn!("Page request routed to wrong shard: my identity {:?}, should go to shard {}, key {}",
+                    timeline.get_shard_identity(), timeline.get_shard_identity().get_shard_number(&key).0, key);
+                return Err(anyhow::anyhow!("Request routed to wrong shard"));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(shard_timeline)
+    }
+
     async fn handle_get_page_at_lsn_request(
         &self,
         timeline: &Timeline,
@@ -832,37 +893,7 @@ impl PageServerHandler {
             self.do_handle_get_page_at_lsn_request(timeline, req, ctx)
                 .await
         } else {
-            // The Tenant shard we looked up at connection start does not hold this particular
-            // key: look for other shards in this tenant.  This scenario occurs if a pageserver
-            // has multiple shards for the same tenant.
-            //
-            // TODO: optimize this (https://github.com/neondatabase/neon/pull/6037)
-            let timeline = match self
-                .get_active_tenant_timeline(
-                    timeline.tenant_shard_id.tenant_id,
-                    timeline.timeline_id,
-                    ShardSelector::Page(key),
-                )
-                .await
-            {
-                Ok(t) => t,
-                Err(GetActiveTimelineError::Tenant(GetActiveTenantError::NotFound(_))) => {
-                    // We already know this tenant exists in general, because we resolved it at
-                    // start of connection.  Getting a NotFound here indicates that the shard containing
-                    // the requested page is not present on this node.
-
-                    // TODO: this should be some kind of structured error that the client will understand,
-                    // so that it can block until its config is updated: this error is expected in the case
-                    // that the Tenant's shards' placements are being updated and the client hasn't been
-                    // informed yet.
-                    //
-                    // https://github.com/neondatabase/neon/issues/6038
-                    tracing::warn!("Page request routed to wrong shard: my identity {:?}, should go to shard {}, key {}",
-                        timeline.get_shard_identity(), timeline.get_shard_identity().get_shard_number(&key).0, key);
-                    return Err(anyhow::anyhow!("Request routed to wrong shard"));
-                }
-                Err(e) => return Err(e.into()),
-            };
+            let timeline = self.get_shard_timeline(timeline, key).await?;
 
             // Take a GateGuard for the duration of this request.  If we were using our main Timeline object,
             // the GateGuard was already held over the whole connection.
@@ -873,8 +904,81 @@ impl PageServerHandler {
         }
     }
 
+    /// `blknums` are the blocks of the request, checked to not overflow.
+    async fn do_handle_get_page_batch_request(
+        &self,
+        timeline: &Timeline,
+        req: &PagestreamGetPageBatchRequest,
+        blknums: RangeInclusive<BlockNumber>,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<PagestreamBeMessage> {
+        let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
+        let lsn =
+            Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn, ctx)
+                .await?;
+        let pages = timeline
+            .get_rel_page_batch_at_lsn(req.rel, blknums, Version::Lsn(lsn), req.latest, ctx)
+            .await?;
+
+        Ok(PagestreamBeMessage::GetPageBatch(
+            PagestreamGetPageBatchResponse { pages },
+        ))
+    }
+
+    async fn handle_get_page_batch_request(
+        &self,
+        timeline: &Timeline,
+        req: &PagestreamGetPageBatchRequest,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<PagestreamBeMessage> {
+        if req.nblocks == 0 || req.nblocks > PagestreamGetPageBatchRequest::MAX_BLOCKS {
+            anyhow::bail!(
+                "invalid number of blocks in batch: {}, must be between 1 and {}",
+                req.nblocks,
+                PagestreamGetPageBatchRequest::MAX_BLOCKS
+            );
+        }
+        let Some(last_blkno) = req.blkno.checked_add(req.nblocks - 1) else {
+            anyhow::bail!(
+                "block range of batch overflows: {} + {}",
+                req.blkno,
+                req.nblocks
+            );
+        };
+
+        // All blocks of a batch must be on the same shard: clients split their batches at
+        // stripe boundaries.
+        let shard_identity = timeline.get_shard_identity();
+        let key = rel_block_to_key(req.rel, req.blkno);
+        let shard_number = shard_identity.get_shard_number(&key);
+        if !(req.blkno..=last_blkno).all(|blkno| {
+            shard_identity.get_shard_number(&rel_block_to_key(req.rel, blkno)) == shard_number
+        }) {
+            anyhow::bail!(
+                "batch of blocks {}..={} of {} spans multiple shards",
+                req.blkno,
+                last_blkno,
+                req.rel
+            );
+        }
+
+        let blknums = req.blkno..=last_blkno;
+        if shard_identity.is_key_local(&key) {
+            self.do_handle_get_page_batch_request(timeline, req, blknums, ctx)
+                .await
+        } else {
+            let timeline = self.get_shard_timeline(timeline, key).await?;
+
+            // Take a GateGuard for the duration of this request, as for single pages.
+            let _timeline_guard = timeline.gate.enter().map_err(|_| QueryError::Shutdown)?;
+
+            self.do_handle_get_page_batch_request(&timeline, req, blknums, ctx)
+                .await
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
-    #[instrument(skip_all, fields(?lsn, ?prev_lsn, %full_backup))]
+    #[instrument(skip_all, fields(?lsn, ?prev_lsn, %full_backup, ?compression))]
     async fn handle_basebackup_request<IO>(
         &mut self,
         pgb: &mut PostgresBackend<IO>,
@@ -883,7 +987,7 @@ impl PageServerHandler {
         lsn: Option<Lsn>,
         prev_lsn: Option<Lsn>,
         full_backup: bool,
-        gzip: bool,
+        compression: Option<BasebackupCompression>,
         ctx: RequestContext,
     ) -> anyhow::Result<()>
     where
@@ -928,37 +1032,52 @@ impl PageServerHandler {
             .await?;
         } else {
             let mut writer = pgb.copyout_writer();
-            if gzip {
-                let mut encoder = GzipEncoder::with_quality(
-                    writer,
-                    // NOTE using fast compression because it's on the critical path
-                    //      for compute startup. For an empty database, we get
-                    //      <100KB with this method. The Level::Best compression method
-                    //      gives us <20KB, but maybe we should add basebackup caching
-                    //      on compute shutdown first.
-                    async_compression::Level::Fastest,
-                );
-                basebackup::send_basebackup_tarball(
-                    &mut encoder,
-                    &timeline,
-                    lsn,
-                    prev_lsn,
-                    full_backup,
-                    &ctx,
-                )
-                .await?;
-                // shutdown the encoder to ensure the gzip footer is written
-                encoder.shutdown().await?;
-            } else {
-                basebackup::send_basebackup_tarball(
-                    &mut writer,
-                    &timeline,
-                    lsn,
-                    prev_lsn,
-                    full_backup,
-                    &ctx,
-                )
-                .await?;
+            // NOTE using fast compression because it's on the critical path
+            //      for compute startup. For an empty database, we get
+            //      <100KB with this method. The Level::Best compression method
+            //      gives us <20KB, but the time matters more than the size here.
+            match compression {
+                Some(BasebackupCompression::Gzip) => {
+                    let mut encoder =
+                        GzipEncoder::with_quality(writer, async_compression::Level::Fastest);
+                    basebackup::send_basebackup_tarball(
+                        &mut encoder,
+                        &timeline,
+                        lsn,
+                        prev_lsn,
+                        full_backup,
+                        &ctx,
+                    )
+                    .await?;
+                    // shutdown the encoder to ensure the gzip footer is written
+                    encoder.shutdown().await?;
+                }
+                Some(BasebackupCompression::Zstd) => {
+                    let mut encoder =
+                        ZstdEncoder::with_quality(writer, async_compression::Level::Fastest);
+                    basebackup::send_basebackup_tarball(
+                        &mut encoder,
+                        &timeline,
+                        lsn,
+                        prev_lsn,
+                        full_backup,
+                        &ctx,
+                    )
+                    .await?;
+                    // shutdown the encoder to ensure the end of the zstd frame is written
+                    encoder.shutdown().await?;
+                }
+                None => {
+                    basebackup::send_basebackup_tarball(
+                        &mut writer,
+                        &timeline,
+                        lsn,
+                        prev_lsn,
+                        full_backup,
+                        &ctx,
+                    )
+                    .await?;
+                }
             }
         }
 
@@ -1124,17 +1243,19 @@ where
                 None
             };
 
-            let gzip = if params.len() >= 4 {
-                if params[3] == "--gzip" {
-                    true
-                } else {
-                    return Err(QueryError::Other(anyhow::anyhow!(
-                        "Parameter in position 3 unknown {}",
-                        params[3],
-                    )));
+            let compression = if params.len() >= 4 {
+                match params[3] {
+                    "--gzip" => Some(BasebackupCompression::Gzip),
+                    "--zstd" => Some(BasebackupCompression::Zstd),
+                    _ => {
+                        return Err(QueryError::Other(anyhow::anyhow!(
+                            "Parameter in position 3 unknown {}",
+                            params[3],
+                        )));
+                    }
                 }
             } else {
-                false
+                None
             };
 
             ::metrics::metric_vec_duration::observe_async_block_duration_by_result(
@@ -1147,7 +1268,7 @@ where
                         lsn,
                         None,
                         false,
-                        gzip,
+                        compression,
                         ctx,
                     )
                     .await?;
@@ -1242,7 +1363,7 @@ where
                 lsn,
                 prev_lsn,
                 true,
-                false,
+                None,
                 ctx,
             )
             .await?;
@@ -8,20 +8,20 @@
 //!
 use super::tenant::{PageReconstructError, Timeline};
 use crate::context::RequestContext;
-use crate::keyspace::{KeySpace, KeySpaceAccum};
+use crate::keyspace::{key_range_size, KeySpace, KeySpaceAccum};
 use crate::repository::*;
 use crate::walrecord::NeonWalRecord;
 use anyhow::{ensure, Context};
 use bytes::{Buf, Bytes};
 use pageserver_api::key::is_rel_block_key;
 use pageserver_api::reltag::{RelTag, SlruKind};
-use postgres_ffi::relfile_utils::{FSM_FORKNUM, VISIBILITYMAP_FORKNUM};
+use postgres_ffi::relfile_utils::{FSM_FORKNUM, INIT_FORKNUM, VISIBILITYMAP_FORKNUM};
 use postgres_ffi::BLCKSZ;
 use postgres_ffi::{Oid, TimestampTz, TransactionId};
 use serde::{Deserialize, Serialize};
 use std::collections::{hash_map, HashMap, HashSet};
 use std::ops::ControlFlow;
-use std::ops::Range;
+use std::ops::{Range, RangeInclusive};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace, warn};
 use utils::bin_ser::DeserializeError;
@@ -190,6 +190,47 @@ impl Timeline {
         version.get(self, key, ctx).await
     }
 
+    /// Look up the blocks `blknums` of a relation, all at the same version.
+    pub async fn get_rel_page_batch_at_lsn(
+        &self,
+        tag: RelTag,
+        blknums: RangeInclusive<BlockNumber>,
+        version: Version<'_>,
+        latest: bool,
+        ctx: &RequestContext,
+    ) -> Result<Vec<Bytes>, PageReconstructError> {
+        if tag.relnode == 0 {
+            return Err(PageReconstructError::Other(
+                RelationError::InvalidRelnode.into(),
+            ));
+        }
+
+        let (start, last) = (*blknums.start(), *blknums.end());
+        let count = (last as usize + 1).saturating_sub(start as usize);
+
+        let nblocks = self.get_rel_size(tag, version, latest, ctx).await?;
+        if last >= nblocks {
+            debug!(
+                "read beyond EOF at {} blks {}..={} at {}, size is {}: returning all-zeros pages",
+                tag,
+                start,
+                last,
+                version.get_lsn(),
+                nblocks
+            );
+        }
+
+        let mut pages = Vec::with_capacity(count);
+        // The end of the existing blocks, which cannot overflow unlike `last + 1`.
+        let end = nblocks.min(last.saturating_add(1));
+        if start < end {
+            let keys = rel_block_to_key(tag, start)..rel_block_to_key(tag, end);
+            pages = version.get_range(self, keys, ctx).await?;
+        }
+        pages.resize(count, ZERO_PAGE.clone());
+        Ok(pages)
+    }
+
     // Get size of a database in blocks
     pub async fn get_db_size(
         &self,
@@ -331,6 +372,23 @@ impl Timeline {
         self.get(key, lsn, ctx).await
     }
 
+    /// Look up the SLRU pages `blknums`, all at the same LSN.
+    pub async fn get_slru_pages_at_lsn(
+        &self,
+        kind: SlruKind,
+        segno: u32,
+        blknums: Range<BlockNumber>,
+        lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<Vec<Bytes>, PageReconstructError> {
+        if blknums.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys = slru_block_to_key(kind, segno, blknums.start)
+            ..slru_block_to_key(kind, segno, blknums.end);
+        Version::Lsn(lsn).get_range(self, keys, ctx).await
+    }
+
     /// Get size of an SLRU segment
     pub async fn get_slru_segment_size(
         &self,
@@ -621,17 +679,25 @@ impl Timeline {
         lsn: Lsn,
         ctx: &RequestContext,
     ) -> Result<HashMap<String, Bytes>, PageReconstructError> {
-        match self.get(AUX_FILES_KEY, lsn, ctx).await {
-            Ok(buf) => match AuxFilesDirectory::des(&buf).context("deserialization failure") {
-                Ok(dir) => Ok(dir.files),
-                Err(e) => Err(PageReconstructError::from(e)),
-            },
-            Err(e) => {
-                // This is expected: historical databases do not have the key.
-                debug!("Failed to get info about AUX files: {}", e);
-                Ok(HashMap::new())
+        // Files written before the aux files got keys of their own are still in the legacy
+        // directory, until the next `put_file` moves them.
+        let mut files = match self.get_sparse(AUX_FILES_KEY, lsn, ctx).await? {
+            Some(buf) => {
+                AuxFilesDirectory::des(&buf)
+                    .context("deserialization failure")?
+                    .files
             }
+            None => HashMap::new(),
+        };
+        for buf in self
+            .scan_sparse(aux_files_key_range(), lsn, ctx)
+            .await?
+            .into_values()
+        {
+            let entries = AuxFileEntries::des(&buf).context("deserialization failure")?;
+            files.extend(entries.files);
         }
+        Ok(files)
     }
 
     /// Does the same as get_current_logical_size but counted on demand.
@@ -750,9 +816,21 @@ impl Timeline {
 
         result.add_key(CONTROLFILE_KEY);
         result.add_key(CHECKPOINT_KEY);
-        if self.get(AUX_FILES_KEY, lsn, ctx).await.is_ok() {
+        if self.get_sparse(AUX_FILES_KEY, lsn, ctx).await?.is_some() {
             result.add_key(AUX_FILES_KEY);
         }
+
+        // Keys whose files were all deleted are left out: image layers are authoritative for
+        // their key range, so the older versions of those keys are hidden by the next images.
+        for (key, buf) in self
+            .scan_sparse(aux_files_key_range(), lsn, ctx)
+            .await
+            .map_err(PageReconstructError::from)?
+        {
+            if !AuxFileEntries::des(&buf)?.files.is_empty() {
+                result.add_key(key);
+            }
+        }
         Ok(result.to_keyspace())
     }
 
@@ -849,9 +927,6 @@ impl<'a> DatadirModification<'a> {
         })?;
         self.put(DBDIR_KEY, Value::Image(buf.into()));
 
-        // Create AuxFilesDirectory
-        self.init_aux_dir()?;
-
         let buf = TwoPhaseDirectory::ser(&TwoPhaseDirectory {
             xids: HashSet::new(),
         })?;
@@ -956,9 +1031,6 @@ impl<'a> DatadirModification<'a> {
             // 'true', now write the updated 'dbdirs' map back.
             let buf = DbDirectory::ser(&dbdir)?;
             self.put(DBDIR_KEY, Value::Image(buf.into()));
-
-            // Create AuxFilesDirectory as well
-            self.init_aux_dir()?;
         }
         if r.is_none() {
             // Create RelDirectory
@@ -1284,40 +1356,75 @@ impl<'a> DatadirModification<'a> {
         Ok(())
     }
 
-    pub fn init_aux_dir(&mut self) -> anyhow::Result<()> {
-        let buf = AuxFilesDirectory::ser(&AuxFilesDirectory {
-            files: HashMap::new(),
-        })?;
-        self.put(AUX_FILES_KEY, Value::Image(Bytes::from(buf)));
-        Ok(())
-    }
-
+    /// Store an aux file, or delete it if `content` is empty.
+    ///
+    /// Each file is stored under its own key, see [`aux_file_key`], so that writing a file
+    /// doesn't rewrite all the others.
     pub async fn put_file(
         &mut self,
         path: &str,
         content: &[u8],
         ctx: &RequestContext,
     ) -> anyhow::Result<()> {
-        let mut dir = match self.get(AUX_FILES_KEY, ctx).await {
-            Ok(buf) => AuxFilesDirectory::des(&buf)?,
-            Err(e) => {
-                // This is expected: historical databases do not have the key.
-                debug!("Failed to get info about AUX files: {}", e);
-                AuxFilesDirectory {
-                    files: HashMap::new(),
-                }
-            }
-        };
-        let path = path.to_string();
-        if content.is_empty() {
-            dir.files.remove(&path);
+        self.migrate_aux_files_directory(ctx).await?;
+
+        let content = if content.is_empty() {
+            None
         } else {
-            dir.files.insert(path, Bytes::copy_from_slice(content));
+            Some(Bytes::copy_from_slice(content))
+        };
+        self.put_aux_file_entry(path, content, ctx).await
+    }
+
+    /// Move the files of the legacy [`AuxFilesDirectory`] to keys of their own, and leave
+    /// the directory empty.
+    async fn migrate_aux_files_directory(&mut self, ctx: &RequestContext) -> anyhow::Result<()> {
+        let dir = match self.get_sparse(AUX_FILES_KEY, ctx).await? {
+            Some(buf) => AuxFilesDirectory::des(&buf)?,
+            None => return Ok(()),
+        };
+        if dir.files.is_empty() {
+            return Ok(());
+        }
+        debug!("moving {} aux files out of the directory", dir.files.len());
+        for (path, content) in dir.files {
+            self.put_aux_file_entry(&path, Some(content), ctx).await?;
         }
         self.put(
             AUX_FILES_KEY,
             Value::Image(Bytes::from(
-                AuxFilesDirectory::ser(&dir).context("serialize")?,
+                AuxFilesDirectory::ser(&AuxFilesDirectory::default()).context("serialize")?,
+            )),
+        );
+        Ok(())
+    }
+
+    async fn put_aux_file_entry(
+        &mut self,
+        path: &str,
+        content: Option<Bytes>,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<()> {
+        let key = aux_file_key(path);
+        let mut entries = match self.get_sparse(key, ctx).await? {
+            Some(buf) => AuxFileEntries::des(&buf)?,
+            None => AuxFileEntries::default(),
+        };
+        let old_len = entries.files.len();
+        entries.files.retain(|(p, _)| p != path);
+        if content.is_none() && entries.files.len() == old_len {
+            // Deleting a file that doesn't exist
+            return Ok(());
+        }
+        if let Some(content) = content {
+            entries.files.push((path.to_string(), content));
+        }
+        // A key whose files are all deleted keeps the empty list: deleting the key would
+        // leave its older versions visible.
+        self.put(
+            key,
+            Value::Image(Bytes::from(
+                AuxFileEntries::ser(&entries).context("serialize")?,
             )),
         );
         Ok(())
@@ -1349,6 +1456,10 @@ impl<'a> DatadirModification<'a> {
             return Ok(());
         }
 
+        // SLRU blocks and init forks are part of the cached basebackup, and become readable
+        // as soon as they are put.
+        self.invalidate_basebackup_cache();
+
         let writer = self.tline.writer().await;
 
         // Flush relation and  SLRU data blocks, keep metadata.
@@ -1378,6 +1489,24 @@ impl<'a> DatadirModification<'a> {
         Ok(())
     }
 
+    /// Invalidate the cached basebackup if the pending changes modify any of its entries. Must be
+    /// called before the changes are written to the timeline.
+    fn invalidate_basebackup_cache(&self) {
+        if !self.tline.conf.basebackup_cache {
+            return;
+        }
+        let invalidated_at = self
+            .pending_updates
+            .iter()
+            .filter(|(key, _)| is_basebackup_cached_key(**key))
+            .flat_map(|(_, values)| values.iter().map(|(lsn, _)| *lsn))
+            .chain(self.pending_deletions.iter().map(|(_, lsn)| *lsn))
+            .max();
+        if let Some(lsn) = invalidated_at {
+            self.tline.basebackup_cache.invalidate(lsn);
+        }
+    }
+
     ///
     /// Finish this atomic update, writing all the updated keys to the
     /// underlying timeline.
@@ -1389,6 +1518,8 @@ impl<'a> DatadirModification<'a> {
         let pending_nblocks = self.pending_nblocks;
         self.pending_nblocks = 0;
 
+        self.invalidate_basebackup_cache();
+
         if !self.pending_updates.is_empty() {
             writer.put_batch(&self.pending_updates, ctx).await?;
             self.pending_updates.clear();
@@ -1422,29 +1553,46 @@ impl<'a> DatadirModification<'a> {
     // Internal helper functions to batch the modifications
 
     async fn get(&self, key: Key, ctx: &RequestContext) -> Result<Bytes, PageReconstructError> {
+        if let Some(pending) = self.get_pending(key) {
+            return pending;
+        }
+        let lsn = Lsn::max(self.tline.get_last_record_lsn(), self.lsn);
+        self.tline.get(key, lsn, ctx).await
+    }
+
+    /// Like [`Self::get`], for a key of a sparse key space: returns `None` if the key has no
+    /// value. See [`Timeline::get_sparse`].
+    async fn get_sparse(
+        &self,
+        key: Key,
+        ctx: &RequestContext,
+    ) -> Result<Option<Bytes>, PageReconstructError> {
+        if let Some(pending) = self.get_pending(key) {
+            return pending.map(Some);
+        }
+        let lsn = Lsn::max(self.tline.get_last_record_lsn(), self.lsn);
+        self.tline.get_sparse(key, lsn, ctx).await
+    }
+
+    fn get_pending(&self, key: Key) -> Option<Result<Bytes, PageReconstructError>> {
         // Have we already updated the same key? Read the latest pending updated
         // version in that case.
         //
         // Note: we don't check pending_deletions. It is an error to request a
         // value that has been removed, deletion only avoids leaking storage.
-        if let Some(values) = self.pending_updates.get(&key) {
-            if let Some((_, value)) = values.last() {
-                return if let Value::Image(img) = value {
-                    Ok(img.clone())
-                } else {
-                    // Currently, we never need to read back a WAL record that we
-                    // inserted in the same "transaction". All the metadata updates
-                    // work directly with Images, and we never need to read actual
-                    // data pages. We could handle this if we had to, by calling
-                    // the walredo manager, but let's keep it simple for now.
-                    Err(PageReconstructError::from(anyhow::anyhow!(
-                        "unexpected pending WAL record"
-                    )))
-                };
-            }
-        }
-        let lsn = Lsn::max(self.tline.get_last_record_lsn(), self.lsn);
-        self.tline.get(key, lsn, ctx).await
+        let (_, value) = self.pending_updates.get(&key)?.last()?;
+        Some(if let Value::Image(img) = value {
+            Ok(img.clone())
+        } else {
+            // Currently, we never need to read back a WAL record that we
+            // inserted in the same "transaction". All the metadata updates
+            // work directly with Images, and we never need to read actual
+            // data pages. We could handle this if we had to, by calling
+            // the walredo manager, but let's keep it simple for now.
+            Err(PageReconstructError::from(anyhow::anyhow!(
+                "unexpected pending WAL record"
+            )))
+        })
     }
 
     fn put(&mut self, key: Key, val: Value) {
@@ -1490,6 +1638,40 @@ impl<'a> Version<'a> {
         }
     }
 
+    /// Look up all the keys of `keys`, in order. Fails if any of them fails.
+    async fn get_range(
+        &self,
+        timeline: &Timeline,
+        keys: Range<Key>,
+        ctx: &RequestContext,
+    ) -> Result<Vec<Bytes>, PageReconstructError> {
+        let mut values = Vec::with_capacity(key_range_size(&keys) as usize);
+        match self {
+            Version::Lsn(lsn) => {
+                let mut start = keys.start;
+                while start < keys.end {
+                    let end =
+                        std::cmp::min(start.add(Timeline::MAX_GET_VECTORED_KEYS as u32), keys.end);
+                    let keyspace = KeySpace {
+                        ranges: vec![start..end],
+                    };
+                    for (_, value) in timeline.get_vectored(&keyspace, *lsn, ctx).await? {
+                        values.push(value?);
+                    }
+                    start = end;
+                }
+            }
+            Version::Modified(modification) => {
+                let mut key = keys.start;
+                while key < keys.end {
+                    values.push(modification.get(key, ctx).await?);
+                    key = key.next();
+                }
+            }
+        }
+        Ok(values)
+    }
+
     fn get_lsn(&self) -> Lsn {
         match self {
             Version::Lsn(lsn) => *lsn,
@@ -1520,11 +1702,20 @@ struct RelDirectory {
     rels: HashSet<(Oid, u8)>,
 }
 
+/// Legacy storage of all the aux files under [`AUX_FILES_KEY`]. Now each file has a key of
+/// its own, see [`aux_file_key`], and the directory is emptied on the first write.
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct AuxFilesDirectory {
     files: HashMap<String, Bytes>,
 }
 
+/// The files stored under one [`aux_file_key`]: those whose path hashes to the key, normally
+/// just one.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct AuxFileEntries {
+    files: Vec<(String, Bytes)>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct RelSizeEntry {
     nblocks: u32,
@@ -1617,9 +1808,12 @@ static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; BLCKSZ as usize]);
 // Checkpoint:
 // 03 00000000 00000000 00000000 00   00000001
 //
-// AuxFiles:
+// AuxFiles (legacy):
 // 03 00000000 00000000 00000000 00   00000002
 //
+// AuxFile:
+// 04 000000HH HHHHHHHH HHHHHHHH HH   HHHHHHHH (first 14 bytes of the MD5 of the path)
+//
 
 //-- Section 01: relation data and metadata
 
@@ -1853,6 +2047,40 @@ const AUX_FILES_KEY: Key = Key {
     field6: 2,
 };
 
+//-- Section 04: aux files
+
+/// Key of the aux file at `path`. Files whose paths share a hash share the key, see
+/// [`AuxFileEntries`].
+fn aux_file_key(path: &str) -> Key {
+    let hash = md5::compute(path.as_bytes()).0;
+    Key {
+        field1: 0x04,
+        field2: hash[0] as u32,
+        field3: u32::from_be_bytes(hash[1..5].try_into().unwrap()),
+        field4: u32::from_be_bytes(hash[5..9].try_into().unwrap()),
+        field5: hash[9],
+        field6: u32::from_be_bytes(hash[10..14].try_into().unwrap()),
+    }
+}
+
+fn aux_files_key_range() -> Range<Key> {
+    Key {
+        field1: 0x04,
+        field2: 0,
+        field3: 0,
+        field4: 0,
+        field5: 0,
+        field6: 0,
+    }..Key {
+        field1: 0x05,
+        field2: 0,
+        field3: 0,
+        field4: 0,
+        field5: 0,
+        field6: 0,
+    }
+}
+
 // Reverse mappings for a few Keys.
 // These are needed by WAL redo manager.
 
@@ -1860,7 +2088,18 @@ const AUX_FILES_KEY: Key = Key {
 // we don't preserve these on a branch because safekeepers can't follow timeline
 // switch (and generally it likely should be optional), so ignore these.
 pub fn is_inherited_key(key: Key) -> bool {
-    key != AUX_FILES_KEY
+    key != AUX_FILES_KEY && !aux_files_key_range().contains(&key)
+}
+
+/// Is `key` part of the tar entries kept by [`crate::basebackup::BasebackupCache`]? Those are
+/// all the non-relation files except for `pg_control`, which is generated for each basebackup,
+/// plus the init forks of unlogged relations.
+pub(crate) fn is_basebackup_cached_key(key: Key) -> bool {
+    if is_rel_block_key(&key) {
+        key.field5 == INIT_FORKNUM
+    } else {
+        key != CONTROLFILE_KEY && key != CHECKPOINT_KEY
+    }
 }
 
 /// Guaranteed to return `Ok()` if [[is_rel_block_key]] returns `true` for `key`.
@@ -1917,7 +2156,97 @@ fn is_slru_block_key(key: Key) -> bool {
 #[cfg(test)]
 mod tests {
     //use super::repo_harness::*;
-    //use super::*;
+    use super::*;
+    use crate::config::PageServerConf;
+    use crate::tenant::harness::*;
+    use crate::DEFAULT_PG_VERSION;
+    use postgres_ffi::pg_constants::SLRU_PAGES_PER_SEGMENT;
+
+    /// `flush` writes SLRU blocks to the timeline ahead of `commit`, so it must invalidate the
+    /// cached basebackup too.
+    #[tokio::test]
+    async fn test_flush_invalidates_basebackup_cache() -> anyhow::Result<()> {
+        let mut harness = TenantHarness::create("test_flush_invalidates_basebackup_cache")?;
+        harness.conf = Box::leak(Box::new(PageServerConf {
+            basebackup_cache: true,
+            ..harness.conf.clone()
+        }));
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        tline
+            .basebackup_cache
+            .insert(Lsn(0x10), Bytes::from_static(b"at 0x10"));
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        // enough relation blocks for the flush to happen
+        m.put_relmap_file(0, 111, Bytes::from(""), &ctx).await?;
+        let rel = RelTag {
+            spcnode: 0,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: 0,
+        };
+        m.put_rel_creation(rel, 10000, &ctx).await?;
+
+        let nsegments = 10000 / SLRU_PAGES_PER_SEGMENT + 1;
+        for segno in 0..nsegments {
+            m.put_slru_segment_creation(SlruKind::Clog, segno, SLRU_PAGES_PER_SEGMENT, &ctx)
+                .await?;
+            for blknum in 0..SLRU_PAGES_PER_SEGMENT {
+                m.put_slru_page_image(SlruKind::Clog, segno, blknum, ZERO_PAGE.clone())?;
+            }
+        }
+
+        assert!(tline.basebackup_cache.get(Lsn(0x20)).is_some());
+        m.flush(&ctx).await?;
+        assert!(m.pending_updates.keys().all(|key| !is_slru_block_key(*key)));
+        assert_eq!(tline.basebackup_cache.get(Lsn(0x20)), None);
+
+        m.commit(&ctx).await?;
+        assert_eq!(tline.basebackup_cache.get(Lsn(0x20)), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rel_page_batch_beyond_eof() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("test_rel_page_batch_beyond_eof")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_relmap_file(0, 111, Bytes::from(""), &ctx).await?;
+        let rel = RelTag {
+            spcnode: 0,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: 0,
+        };
+        m.put_rel_creation(rel, 2, &ctx).await?;
+        m.put_rel_page_image(rel, 0, TEST_IMG("blk 0"))?;
+        m.put_rel_page_image(rel, 1, TEST_IMG("blk 1"))?;
+        m.commit(&ctx).await?;
+
+        let version = Version::Lsn(Lsn(0x20));
+        let pages = tline
+            .get_rel_page_batch_at_lsn(rel, 1..=3, version, false, &ctx)
+            .await?;
+        assert_eq!(
+            pages,
+            vec![TEST_IMG("blk 1"), ZERO_PAGE.clone(), ZERO_PAGE.clone()]
+        );
+
+        // The block after the last one does not fit in a block number.
+        let pages = tline
+            .get_rel_page_batch_at_lsn(rel, u32::MAX - 1..=u32::MAX, version, false, &ctx)
+            .await?;
+        assert_eq!(pages, vec![ZERO_PAGE.clone(); 2]);
+        Ok(())
+    }
 
     /*
         fn assert_current_logical_size<R: Repository>(timeline: &DatadirTimeline<R>, lsn: Lsn) {
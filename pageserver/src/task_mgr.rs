@@ -261,6 +261,9 @@ pub enum TaskKind {
     /// See [`crate::tenant::secondary`].
     SecondaryUploads,
 
+    /// See [`crate::tenant::secondary`].
+    SecondaryDownloads,
+
     // Initial logical size calculation
     InitialLogicalSizeCalculation,
 
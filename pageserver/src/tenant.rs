@@ -17,6 +17,7 @@ use enumset::EnumSet;
 use futures::stream::FuturesUnordered;
 use futures::FutureExt;
 use futures::StreamExt;
+use pageserver_api::models::TimelineArchivalState;
 use pageserver_api::models::TimelineState;
 use pageserver_api::shard::ShardIdentity;
 use pageserver_api::shard::TenantShardId;
@@ -51,6 +52,7 @@ use self::mgr::GetActiveTenantError;
 use self::mgr::GetTenantError;
 use self::mgr::TenantsMap;
 use self::remote_timeline_client::RemoteTimelineClient;
+use self::timeline::offload::OffloadedTimeline;
 use self::timeline::uninit::TimelineExclusionError;
 use self::timeline::uninit::TimelineUninitMark;
 use self::timeline::uninit::UninitializedTimeline;
@@ -150,6 +152,7 @@ pub mod mgr;
 pub mod secondary;
 pub mod tasks;
 pub mod upload_queue;
+pub(crate) mod vectored_blob_io;
 
 pub(crate) mod timeline;
 
@@ -253,6 +256,10 @@ pub struct Tenant {
 
     timelines: Mutex<HashMap<TimelineId, Arc<Timeline>>>,
 
+    /// Archived timelines, which have no [`Timeline`] object: see [`timeline::offload`].
+    /// **Lock order**: if acquiring both, acquire `timelines` before `timelines_offloaded`
+    timelines_offloaded: Mutex<HashMap<TimelineId, Arc<OffloadedTimeline>>>,
+
     /// During timeline creation, we first insert the TimelineId to the
     /// creating map, then `timelines`, then remove it from the creating map.
     /// **Lock order**: if acquring both, acquire`timelines` before `timelines_creating`
@@ -354,6 +361,36 @@ impl WalRedoManager {
             }
         }
     }
+
+    /// # Cancel-Safety
+    ///
+    /// This method is cancellation-safe.
+    pub async fn request_redo_batch(
+        &self,
+        requests: Vec<crate::walredo::WalRedoRequest>,
+        pg_version: u32,
+    ) -> Vec<anyhow::Result<bytes::Bytes>> {
+        match self {
+            Self::Prod(mgr) => mgr.request_redo_batch(requests, pg_version).await,
+            #[cfg(test)]
+            Self::Test(mgr) => {
+                let mut results = Vec::with_capacity(requests.len());
+                for request in requests {
+                    results.push(
+                        mgr.request_redo(
+                            request.key,
+                            request.lsn,
+                            request.base_img,
+                            request.records,
+                            pg_version,
+                        )
+                        .await,
+                    );
+                }
+                results
+            }
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error, PartialEq, Eq)]
@@ -405,6 +442,21 @@ impl Debug for DeleteTimelineError {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum TimelineArchivalError {
+    #[error("NotFound")]
+    NotFound,
+
+    #[error("HasUnarchivedChildren")]
+    HasUnarchivedChildren(Vec<TimelineId>),
+
+    #[error("ancestor timeline {0} is archived")]
+    AncestorArchived(TimelineId),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
 pub enum SetStoppingError {
     AlreadyStopping(completion::Barrier),
     Broken,
@@ -936,6 +988,22 @@ impl Tenant {
                 }
             };
             match index_part {
+                MaybeDeletedIndexPart::IndexPart(index_part)
+                    if index_part.archived_at.is_some() =>
+                {
+                    // Archived timelines are not loaded, and keep no local state: not being
+                    // in `existent_timelines` gets a leftover local directory purged below.
+                    info!(%timeline_id, "timeline is archived, not loading it");
+                    existent_timelines.remove(&timeline_id);
+                    self.timelines_offloaded.lock().unwrap().insert(
+                        timeline_id,
+                        Arc::new(OffloadedTimeline {
+                            timeline_id,
+                            ancestor_timeline_id: index_part.metadata.ancestor_timeline(),
+                            ancestor_lsn: index_part.metadata.ancestor_lsn(),
+                        }),
+                    );
+                }
                 MaybeDeletedIndexPart::IndexPart(index_part) => {
                     timeline_ancestors.insert(timeline_id, index_part.metadata.clone());
                     remote_index_and_client.insert(timeline_id, (index_part, preload.client));
@@ -1804,11 +1872,102 @@ impl Tenant {
         self: Arc<Self>,
         timeline_id: TimelineId,
     ) -> Result<(), DeleteTimelineError> {
+        self.unoffload_for_deletion(timeline_id).await?;
+
         DeleteTimelineFlow::run(&self, timeline_id, false).await?;
 
         Ok(())
     }
 
+    /// Archive or unarchive a timeline, see [`timeline::offload`].
+    ///
+    /// Both directions are idempotent.
+    pub(crate) async fn apply_timeline_archival_config(
+        &self,
+        timeline_id: TimelineId,
+        state: TimelineArchivalState,
+        broker_client: storage_broker::BrokerClientChannel,
+        ctx: &RequestContext,
+    ) -> Result<(), TimelineArchivalError> {
+        let _gate = self
+            .gate
+            .enter()
+            .map_err(|_| anyhow::anyhow!("tenant is shutting down"))?;
+
+        match state {
+            TimelineArchivalState::Archived => {
+                timeline::offload::archive_and_offload(self, timeline_id).await
+            }
+            TimelineArchivalState::Unarchived => {
+                if self.timelines.lock().unwrap().contains_key(&timeline_id) {
+                    return Ok(());
+                }
+
+                let timeline = timeline::offload::unoffload(self, timeline_id, ctx).await?;
+
+                let remote_client = timeline
+                    .remote_client
+                    .as_ref()
+                    .expect("offloaded timelines are loaded with remote storage");
+                remote_client.schedule_index_upload_for_timeline_archival_state(false)?;
+                remote_client
+                    .wait_completion()
+                    .await
+                    .context("wait for unarchival index upload")?;
+
+                timeline.activate(broker_client, None, ctx);
+                info!("timeline unarchived");
+                Ok(())
+            }
+        }
+    }
+
+    /// Deletion works on loaded timelines: bring an archived timeline back before deleting it.
+    async fn unoffload_for_deletion(
+        &self,
+        timeline_id: TimelineId,
+    ) -> Result<(), DeleteTimelineError> {
+        if !self
+            .timelines_offloaded
+            .lock()
+            .unwrap()
+            .contains_key(&timeline_id)
+        {
+            return Ok(());
+        }
+
+        let ctx = RequestContext::todo_child(TaskKind::MgmtRequest, DownloadBehavior::Warn);
+        match timeline::offload::unoffload(self, timeline_id, &ctx).await {
+            Ok(_) => Ok(()),
+            Err(TimelineArchivalError::NotFound) => Err(DeleteTimelineError::NotFound),
+            Err(e @ TimelineArchivalError::AncestorArchived(_)) => {
+                Err(DeleteTimelineError::Other(anyhow::anyhow!(e)))
+            }
+            Err(e @ TimelineArchivalError::HasUnarchivedChildren(_)) => {
+                Err(DeleteTimelineError::Other(anyhow::anyhow!(e)))
+            }
+            Err(TimelineArchivalError::Other(e)) => Err(DeleteTimelineError::Other(e)),
+        }
+    }
+
+    /// Before deleting the tenant, bring back all archived timelines so that their remote data
+    /// gets deleted along with the others.
+    pub(crate) async fn unoffload_all_for_deletion(&self) -> Result<(), DeleteTimelineError> {
+        let offloaded = self.timelines_offloaded.lock().unwrap().clone();
+        let offloaded_ids: HashSet<TimelineId> = offloaded.keys().copied().collect();
+        // Ancestors first, only ordering among the offloaded timelines matters.
+        let sorted = tree_sort_timelines(offloaded, |t| {
+            t.ancestor_timeline_id
+                .filter(|ancestor_id| offloaded_ids.contains(ancestor_id))
+        })?;
+
+        for (timeline_id, _) in sorted {
+            self.unoffload_for_deletion(timeline_id).await?;
+        }
+
+        Ok(())
+    }
+
     /// perform one garbage collection iteration, removing old data files from disk.
     /// this function is periodically called by gc task.
     /// also it can be explicitly requested through page server api 'do_gc' command.
@@ -2602,6 +2761,7 @@ impl Tenant {
             constructed_at: Instant::now(),
             tenant_conf: Arc::new(RwLock::new(attached_conf)),
             timelines: Mutex::new(HashMap::new()),
+            timelines_offloaded: Mutex::new(HashMap::new()),
             timelines_creating: Mutex::new(HashSet::new()),
             gc_cs: tokio::sync::Mutex::new(()),
             walredo_mgr,
@@ -2943,6 +3103,17 @@ impl Tenant {
                     })
                     .collect::<Vec<_>>()
             };
+
+            // Archived timelines are not loaded, but still read from their ancestors once
+            // unarchived: keep their branch points.
+            for offloaded in self.timelines_offloaded.lock().unwrap().values() {
+                if let Some(ancestor_timeline_id) = offloaded.ancestor_timeline_id {
+                    if target_timeline_id.map_or(true, |t| t == ancestor_timeline_id) {
+                        all_branchpoints.insert((ancestor_timeline_id, offloaded.ancestor_lsn));
+                    }
+                }
+            }
+
             (all_branchpoints, timeline_ids)
         };
 
@@ -3026,6 +3197,13 @@ impl Tenant {
     ) -> Result<Arc<Timeline>, CreateTimelineError> {
         let src_id = src_timeline.timeline_id;
 
+        // Don't branch off a timeline that is being archived, see `archive_and_offload`. Held
+        // until the new timeline is in `self.timelines`.
+        let archived = src_timeline.archival_lock.read().await;
+        if *archived {
+            return Err(CreateTimelineError::AncestorNotActive);
+        }
+
         // We will validate our ancestor LSN in this function.  Acquire the GC lock so that
         // this check cannot race with GC, and the ancestor LSN is guaranteed to remain
         // valid while we are creating the branch.
@@ -3924,6 +4102,7 @@ pub(crate) mod harness {
                 ),
                 gc_feedback: Some(tenant_conf.gc_feedback),
                 heatmap_period: Some(tenant_conf.heatmap_period),
+                blob_compression: Some(tenant_conf.blob_compression),
             }
         }
     }
@@ -3992,6 +4171,8 @@ pub(crate) mod harness {
             std::fs::create_dir_all(&remote_fs_dir).unwrap();
             let config = RemoteStorageConfig {
                 storage: RemoteStorageKind::LocalFs(remote_fs_dir.clone()),
+                encryption: None,
+                cache: None,
             };
             let remote_storage = GenericRemoteStorage::from_config(&config).unwrap();
             let deletion_queue = MockDeletionQueue::new(Some(remote_storage.clone()));
@@ -4155,12 +4336,13 @@ pub(crate) mod harness {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::keyspace::KeySpaceAccum;
+    use crate::keyspace::{KeySpace, KeySpaceAccum};
     use crate::repository::{Key, Value};
     use crate::tenant::harness::*;
+    use crate::tenant::timeline::GetVectoredError;
     use crate::DEFAULT_PG_VERSION;
     use crate::METADATA_FILE_NAME;
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
     use hex_literal::hex;
     use once_cell::sync::Lazy;
     use rand::{thread_rng, Rng};
@@ -4702,6 +4884,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn layer_checksums_are_computed_while_writing() -> anyhow::Result<()> {
+        use pageserver_api::models::CompressionAlgorithm;
+        use remote_timeline_client::layer_file_checksum;
+        use storage_layer::ImageLayerWriter;
+
+        let (tenant, ctx) = TenantHarness::create("layer_checksums_are_computed_while_writing")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+        make_some_layers(tline.as_ref(), Lsn(0x20), &ctx).await?;
+
+        // Images of varying sizes, some compressible, so that blobs straddle blocks and the
+        // index doesn't start right after them.
+        let mut written = Vec::new();
+        for compression in [CompressionAlgorithm::Disabled, CompressionAlgorithm::Zstd] {
+            let key_range = TEST_KEY.add(1000)..TEST_KEY.add(1100);
+            let lsn = Lsn(0x100) + written.len() as u64 * 0x10;
+            let mut writer = ImageLayerWriter::new(
+                tenant.conf,
+                tline.timeline_id,
+                tenant.tenant_shard_id,
+                &key_range,
+                lsn,
+                compression,
+            )
+            .await?;
+            for i in 0..100 {
+                let mut img = vec![i as u8; 100 * i as usize];
+                thread_rng().fill(&mut img[..50 * i as usize]);
+                writer.put_image(TEST_KEY.add(1000 + i), &img).await?;
+            }
+            written.push(writer.finish(&tline).await?);
+        }
+
+        let deltas = {
+            let layers = tline.layers.read().await;
+            layers
+                .layer_map()
+                .iter_historic_layers()
+                .map(|desc| layers.get_from_desc(&desc))
+                .collect::<Vec<_>>()
+        };
+        assert!(!deltas.is_empty());
+        let written = written.iter().map(|layer| layer.as_ref());
+        for layer in deltas.iter().chain(written) {
+            let expected = layer_file_checksum(layer.local_path()).await?;
+            assert_eq!(layer.metadata().checksum, Some(expected), "{layer}");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn corrupt_local_metadata() -> anyhow::Result<()> {
         const TEST_NAME: &str = "corrupt_metadata";
@@ -5101,6 +5338,168 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_vectored() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("test_get_vectored")?.load().await;
+        let mut tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        const NUM_KEYS: usize = 200;
+
+        let mut test_key = Key::from_hex("010000000033333333444444445500000000").unwrap();
+        let keys: Vec<Key> = (0..NUM_KEYS)
+            .map(|blknum| {
+                let mut key = test_key;
+                key.field6 = blknum as u32;
+                key
+            })
+            .collect();
+
+        // Track when each page was last modified.
+        let mut updated = [Lsn(0); NUM_KEYS];
+
+        let mut lsn = Lsn(0x10);
+        #[allow(clippy::needless_range_loop)]
+        for blknum in 0..NUM_KEYS {
+            lsn = Lsn(lsn.0 + 0x10);
+            test_key.field6 = blknum as u32;
+            let writer = tline.writer().await;
+            writer
+                .put(
+                    test_key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                    &ctx,
+                )
+                .await?;
+            writer.finish_write(lsn);
+            updated[blknum] = lsn;
+            drop(writer);
+        }
+
+        // Spread the versions of the keys over branches, in-memory and on-disk layers, so that
+        // the keys of a batch need different layers.
+        for round in 0..10 {
+            if round % 3 == 0 {
+                let new_tline_id = TimelineId::generate();
+                tenant
+                    .branch_timeline_test(&tline, new_tline_id, Some(lsn), &ctx)
+                    .await?;
+                tline = tenant
+                    .get_timeline(new_tline_id, true)
+                    .expect("Should have the branched timeline");
+            }
+
+            for _ in 0..NUM_KEYS / 4 {
+                lsn = Lsn(lsn.0 + 0x10);
+                let blknum = thread_rng().gen_range(0..NUM_KEYS);
+                test_key.field6 = blknum as u32;
+                let writer = tline.writer().await;
+                writer
+                    .put(
+                        test_key,
+                        lsn,
+                        &Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                        &ctx,
+                    )
+                    .await?;
+                writer.finish_write(lsn);
+                drop(writer);
+                updated[blknum] = lsn;
+            }
+
+            for chunk_start in (0..NUM_KEYS).step_by(Timeline::MAX_GET_VECTORED_KEYS) {
+                let chunk_end = (chunk_start + Timeline::MAX_GET_VECTORED_KEYS).min(NUM_KEYS);
+                let keyspace = KeySpace {
+                    ranges: vec![keys[chunk_start]..keys[chunk_end - 1].next()],
+                };
+                let values = tline.get_vectored(&keyspace, lsn, &ctx).await?;
+                assert_eq!(values.len(), chunk_end - chunk_start);
+                for (blknum, (key, value)) in (chunk_start..chunk_end).zip(values) {
+                    assert_eq!(key, keys[blknum]);
+                    let last_lsn = updated[blknum];
+                    assert_eq!(value?, TEST_IMG(&format!("{} at {}", blknum, last_lsn)));
+                }
+            }
+
+            if round % 2 == 1 {
+                tline.freeze_and_flush().await?;
+            }
+        }
+
+        let too_many = KeySpace {
+            ranges: vec![keys[0]..keys[Timeline::MAX_GET_VECTORED_KEYS].next()],
+        };
+        assert!(matches!(
+            tline.get_vectored(&too_many, lsn, &ctx).await,
+            Err(GetVectoredError::Oversized(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aux_files() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("test_aux_files")?.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let mut modification = tline.begin_modification(Lsn(0x20));
+        modification
+            .put_file("pg_logical/mappings/test1", b"first", &ctx)
+            .await?;
+        modification
+            .put_file("pg_logical/mappings/test2", b"second", &ctx)
+            .await?;
+        modification.commit(&ctx).await?;
+
+        tline.freeze_and_flush().await?;
+
+        let mut modification = tline.begin_modification(Lsn(0x30));
+        modification
+            .put_file("pg_logical/mappings/test1", b"", &ctx)
+            .await?;
+        modification
+            .put_file("pg_logical/mappings/test3", b"third", &ctx)
+            .await?;
+        modification.commit(&ctx).await?;
+
+        let files = tline.list_aux_files(Lsn(0x20), &ctx).await?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files.get("pg_logical/mappings/test1"),
+            Some(&Bytes::from_static(b"first"))
+        );
+        assert_eq!(
+            files.get("pg_logical/mappings/test2"),
+            Some(&Bytes::from_static(b"second"))
+        );
+
+        let files = tline.list_aux_files(Lsn(0x30), &ctx).await?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files.get("pg_logical/mappings/test2"),
+            Some(&Bytes::from_static(b"second"))
+        );
+        assert_eq!(
+            files.get("pg_logical/mappings/test3"),
+            Some(&Bytes::from_static(b"third"))
+        );
+
+        // Aux files are not inherited by branches
+        tenant
+            .branch_timeline_test(&tline, NEW_TIMELINE_ID, Some(Lsn(0x30)), &ctx)
+            .await?;
+        let branch = tenant
+            .get_timeline(NEW_TIMELINE_ID, true)
+            .expect("Should have the branched timeline");
+        assert!(branch.list_aux_files(Lsn(0x30), &ctx).await?.is_empty());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_traverse_ancestors() -> anyhow::Result<()> {
         let (tenant, ctx) = TenantHarness::create("test_traverse_ancestors")?
@@ -9,15 +9,120 @@
 //! by peeking at the first byte.
 //!
 //! len <  128: 0XXXXXXX
-//! len >= 128: 1XXXXXXX XXXXXXXX XXXXXXXX XXXXXXXX
+//! len >= 128: 1CCCXXXX XXXXXXXX XXXXXXXX XXXXXXXX
+//!
+//! In files written with [`BlobFormat::Compressed`], the three `C` bits of
+//! a 4-byte header select the compression algorithm of the payload, and the
+//! length is the length of the compressed payload. In older files, they are
+//! part of the length. Short blobs are never compressed.
 //!
 use crate::context::RequestContext;
 use crate::page_cache::PAGE_SZ;
 use crate::tenant::block_io::BlockCursor;
 use crate::virtual_file::VirtualFile;
+use crate::{STORAGE_FORMAT_VERSION, STORAGE_FORMAT_VERSION_UNCOMPRESSED};
+use pageserver_api::models::CompressionAlgorithm;
 use std::cmp::min;
 use std::io::{Error, ErrorKind};
 
+/// Compression bits of a 4-byte length header, see the module comment.
+const LEN_COMPRESSION_BIT_MASK: u8 = 0x70;
+const BYTE_UNCOMPRESSED: u8 = 0x00;
+const BYTE_ZSTD: u8 = 0x10;
+const BYTE_LZ4: u8 = 0x20;
+
+/// Largest blob that can be written with a compression-aware length header.
+const MAX_COMPRESSED_FORMAT_LEN: usize = 0x0fff_ffff;
+
+/// Blobs shorter than this are stored with a 1-byte header, which has no room
+/// for compression bits.
+const MIN_COMPRESSED_BLOB_LEN: usize = 128;
+
+/// Fast levels are good enough for 8 KiB pages; higher levels cost a lot of CPU
+/// during compaction for little gain.
+const ZSTD_COMPRESSION_LEVEL: i32 = 1;
+
+/// How the 4-byte length headers in a file are to be interpreted.
+///
+/// This is derived from the `format_version` in the summary of a layer file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobFormat {
+    /// Plain 31-bit lengths. Used by layer files written with compression
+    /// disabled, and by ephemeral files.
+    Uncompressed,
+    /// The top bits of the length select the compression algorithm.
+    Compressed,
+}
+
+impl BlobFormat {
+    pub fn from_format_version(format_version: u16) -> Self {
+        if format_version > STORAGE_FORMAT_VERSION_UNCOMPRESSED {
+            BlobFormat::Compressed
+        } else {
+            BlobFormat::Uncompressed
+        }
+    }
+
+    /// The format a layer writer uses for the given compression setting.
+    pub fn for_compression(compression: CompressionAlgorithm) -> Self {
+        match compression {
+            CompressionAlgorithm::Disabled => BlobFormat::Uncompressed,
+            CompressionAlgorithm::Zstd | CompressionAlgorithm::Lz4 => BlobFormat::Compressed,
+        }
+    }
+
+    /// The `format_version` to put in the summary of a layer file using this format.
+    pub fn format_version(&self) -> u16 {
+        match self {
+            BlobFormat::Uncompressed => STORAGE_FORMAT_VERSION_UNCOMPRESSED,
+            BlobFormat::Compressed => STORAGE_FORMAT_VERSION,
+        }
+    }
+}
+
+fn decompress(compression_bits: u8, compressed: &[u8]) -> Result<Vec<u8>, Error> {
+    match compression_bits {
+        BYTE_ZSTD => zstd::stream::decode_all(compressed),
+        BYTE_LZ4 => lz4_flex::decompress_size_prepended(compressed)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+        bits => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown blob compression bits {bits:#04x}"),
+        )),
+    }
+}
+
+/// Decodes the blob at the start of `buf`, which holds at least the whole blob, decompressing
+/// it if needed. This is the in-memory counterpart of
+/// [`BlockCursor::read_blob_into_buf_with_format`], for blobs that were read together with
+/// their neighbours, see [`super::vectored_blob_io`].
+pub(crate) fn decode_blob(buf: &[u8], format: BlobFormat) -> Result<Vec<u8>, Error> {
+    let truncated = || Error::new(ErrorKind::UnexpectedEof, "blob extends past the buffer");
+
+    let first_len_byte = *buf.first().ok_or_else(truncated)?;
+    let mut compression_bits = BYTE_UNCOMPRESSED;
+    let (header_len, len) = if first_len_byte < 0x80 {
+        (1, first_len_byte as usize)
+    } else {
+        let mut len_buf: [u8; 4] = buf.get(..4).ok_or_else(truncated)?.try_into().unwrap();
+        len_buf[0] &= 0x7f;
+        if format == BlobFormat::Compressed {
+            compression_bits = len_buf[0] & LEN_COMPRESSION_BIT_MASK;
+            len_buf[0] &= !LEN_COMPRESSION_BIT_MASK;
+        }
+        (4, u32::from_be_bytes(len_buf) as usize)
+    };
+
+    let payload = buf
+        .get(header_len..header_len + len)
+        .ok_or_else(truncated)?;
+    if compression_bits != BYTE_UNCOMPRESSED {
+        decompress(compression_bits, payload)
+    } else {
+        Ok(payload.to_vec())
+    }
+}
+
 impl<'a> BlockCursor<'a> {
     /// Read a blob into a new buffer.
     pub async fn read_blob(
@@ -36,6 +141,33 @@ impl<'a> BlockCursor<'a> {
         offset: u64,
         dstbuf: &mut Vec<u8>,
         ctx: &RequestContext,
+    ) -> Result<(), std::io::Error> {
+        self.read_blob_into_buf_with_format(offset, dstbuf, BlobFormat::Uncompressed, ctx)
+            .await
+    }
+
+    /// Read a blob from a file written in the given [`BlobFormat`] into a new
+    /// buffer, decompressing it if needed.
+    pub async fn read_blob_with_format(
+        &self,
+        offset: u64,
+        format: BlobFormat,
+        ctx: &RequestContext,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let mut buf = Vec::new();
+        self.read_blob_into_buf_with_format(offset, &mut buf, format, ctx)
+            .await?;
+        Ok(buf)
+    }
+
+    /// Like [`Self::read_blob_into_buf`], but for files written in the given
+    /// [`BlobFormat`]: compressed blobs are decompressed into `dstbuf`.
+    pub async fn read_blob_into_buf_with_format(
+        &self,
+        offset: u64,
+        dstbuf: &mut Vec<u8>,
+        format: BlobFormat,
+        ctx: &RequestContext,
     ) -> Result<(), std::io::Error> {
         let mut blknum = (offset / PAGE_SZ as u64) as u32;
         let mut off = (offset % PAGE_SZ as u64) as usize;
@@ -44,6 +176,7 @@ impl<'a> BlockCursor<'a> {
 
         // peek at the first byte, to determine if it's a 1- or 4-byte length
         let first_len_byte = buf[off];
+        let mut compression_bits = BYTE_UNCOMPRESSED;
         let len: usize = if first_len_byte < 0x80 {
             // 1-byte length header
             off += 1;
@@ -64,6 +197,10 @@ impl<'a> BlockCursor<'a> {
                 off += 4;
             }
             len_buf[0] &= 0x7f;
+            if format == BlobFormat::Compressed {
+                compression_bits = len_buf[0] & LEN_COMPRESSION_BIT_MASK;
+                len_buf[0] &= !LEN_COMPRESSION_BIT_MASK;
+            }
             u32::from_be_bytes(len_buf) as usize
         };
 
@@ -86,6 +223,10 @@ impl<'a> BlockCursor<'a> {
             remain -= this_blk_len;
             off += this_blk_len;
         }
+
+        if compression_bits != BYTE_UNCOMPRESSED {
+            *dstbuf = decompress(compression_bits, dstbuf)?;
+        }
         Ok(())
     }
 }
@@ -100,6 +241,8 @@ pub struct BlobWriter<const BUFFERED: bool> {
     offset: u64,
     /// A buffer to save on write calls, only used if BUFFERED=true
     buf: Vec<u8>,
+    /// CRC32C of everything written since `start_offset`, see [`Self::checksum`].
+    checksum: u32,
 }
 
 impl<const BUFFERED: bool> BlobWriter<BUFFERED> {
@@ -108,6 +251,7 @@ impl<const BUFFERED: bool> BlobWriter<BUFFERED> {
             inner,
             offset: start_offset,
             buf: Vec::with_capacity(Self::CAPACITY),
+            checksum: 0,
         }
     }
 
@@ -115,6 +259,12 @@ impl<const BUFFERED: bool> BlobWriter<BUFFERED> {
         self.offset
     }
 
+    /// CRC32C of the bytes written since the `start_offset`, including the ones
+    /// still in the internal buffer.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
     const CAPACITY: usize = if BUFFERED { PAGE_SZ } else { 0 };
 
     #[inline(always)]
@@ -147,6 +297,7 @@ impl<const BUFFERED: bool> BlobWriter<BUFFERED> {
 
     /// Internal, possibly buffered, write function
     async fn write_all(&mut self, mut src_buf: &[u8]) -> Result<(), Error> {
+        self.checksum = crc32c::crc32c_append(self.checksum, src_buf);
         if !BUFFERED {
             assert!(self.buf.is_empty());
             self.write_all_unbuffered(src_buf).await?;
@@ -182,22 +333,67 @@ impl<const BUFFERED: bool> BlobWriter<BUFFERED> {
     /// Write a blob of data. Returns the offset that it was written to,
     /// which can be used to retrieve the data later.
     pub async fn write_blob(&mut self, srcbuf: &[u8]) -> Result<u64, Error> {
+        self.write_blob_with_header(srcbuf, BYTE_UNCOMPRESSED, 0x7fff_ffff)
+            .await
+    }
+
+    /// Write a blob of data in [`BlobFormat::Compressed`], compressing it with
+    /// the given algorithm if that makes it smaller. Returns the offset that
+    /// it was written to, which can be used to retrieve the data later.
+    pub async fn write_blob_compressed(
+        &mut self,
+        srcbuf: &[u8],
+        compression: CompressionAlgorithm,
+    ) -> Result<u64, Error> {
+        let compressed = if srcbuf.len() < MIN_COMPRESSED_BLOB_LEN {
+            None
+        } else {
+            match compression {
+                CompressionAlgorithm::Disabled => None,
+                CompressionAlgorithm::Zstd => Some((
+                    BYTE_ZSTD,
+                    zstd::bulk::compress(srcbuf, ZSTD_COMPRESSION_LEVEL)?,
+                )),
+                CompressionAlgorithm::Lz4 => {
+                    Some((BYTE_LZ4, lz4_flex::compress_prepend_size(srcbuf)))
+                }
+            }
+        };
+
+        match compressed {
+            Some((compression_bits, buf)) if buf.len() < srcbuf.len() => {
+                self.write_blob_with_header(&buf, compression_bits, MAX_COMPRESSED_FORMAT_LEN)
+                    .await
+            }
+            _ => {
+                self.write_blob_with_header(srcbuf, BYTE_UNCOMPRESSED, MAX_COMPRESSED_FORMAT_LEN)
+                    .await
+            }
+        }
+    }
+
+    async fn write_blob_with_header(
+        &mut self,
+        srcbuf: &[u8],
+        compression_bits: u8,
+        max_len: usize,
+    ) -> Result<u64, Error> {
         let offset = self.offset;
 
-        if srcbuf.len() < 128 {
+        if srcbuf.len() < 128 && compression_bits == BYTE_UNCOMPRESSED {
             // Short blob. Write a 1-byte length header
             let len_buf = srcbuf.len() as u8;
             self.write_all(&[len_buf]).await?;
         } else {
             // Write a 4-byte length header
-            if srcbuf.len() > 0x7fff_ffff {
+            if srcbuf.len() > max_len {
                 return Err(Error::new(
                     ErrorKind::Other,
                     format!("blob too large ({} bytes)", srcbuf.len()),
                 ));
             }
             let mut len_buf = ((srcbuf.len()) as u32).to_be_bytes();
-            len_buf[0] |= 0x80;
+            len_buf[0] |= 0x80 | compression_bits;
             self.write_all(&len_buf).await?;
         }
         self.write_all(srcbuf).await?;
@@ -236,8 +432,17 @@ mod tests {
     use super::*;
     use crate::{context::DownloadBehavior, task_mgr::TaskKind, tenant::block_io::BlockReaderRef};
     use rand::{Rng, SeedableRng};
+    use std::io::Read;
 
     async fn round_trip_test<const BUFFERED: bool>(blobs: &[Vec<u8>]) -> Result<(), Error> {
+        round_trip_test_compressed::<BUFFERED>(blobs, CompressionAlgorithm::Disabled).await
+    }
+
+    async fn round_trip_test_compressed<const BUFFERED: bool>(
+        blobs: &[Vec<u8>],
+        compression: CompressionAlgorithm,
+    ) -> Result<(), Error> {
+        let format = BlobFormat::for_compression(compression);
         let temp_dir = camino_tempfile::tempdir()?;
         let pathbuf = temp_dir.path().join("file");
         let ctx = RequestContext::new(TaskKind::UnitTest, DownloadBehavior::Error);
@@ -248,7 +453,10 @@ mod tests {
             let file = VirtualFile::create(pathbuf.as_path()).await?;
             let mut wtr = BlobWriter::<BUFFERED>::new(file, 0);
             for blob in blobs.iter() {
-                let offs = wtr.write_blob(blob).await?;
+                let offs = match format {
+                    BlobFormat::Uncompressed => wtr.write_blob(blob).await?,
+                    BlobFormat::Compressed => wtr.write_blob_compressed(blob, compression).await?,
+                };
                 offsets.push(offs);
             }
             // Write out one page worth of zeros so that we can
@@ -262,12 +470,23 @@ mod tests {
         let rdr = BlockReaderRef::VirtualFile(&file);
         let rdr = BlockCursor::new(rdr);
         for (idx, (blob, offset)) in blobs.iter().zip(offsets.iter()).enumerate() {
-            let blob_read = rdr.read_blob(*offset, &ctx).await?;
+            let blob_read = rdr.read_blob_with_format(*offset, format, &ctx).await?;
             assert_eq!(
                 blob, &blob_read,
                 "mismatch for idx={idx} at offset={offset}"
             );
         }
+
+        // The same blobs, read from memory
+        let mut contents = Vec::new();
+        std::fs::File::open(pathbuf.as_path())?.read_to_end(&mut contents)?;
+        for (idx, (blob, offset)) in blobs.iter().zip(offsets.iter()).enumerate() {
+            let blob_read = decode_blob(&contents[*offset as usize..], format)?;
+            assert_eq!(
+                blob, &blob_read,
+                "mismatch for idx={idx} at offset={offset} in memory"
+            );
+        }
         Ok(())
     }
 
@@ -348,4 +567,40 @@ mod tests {
         round_trip_test::<true>(blobs).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_compressed() -> Result<(), Error> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let blobs = (0..256)
+            .map(|i| match i % 4 {
+                // Incompressible, stored as is
+                0 => random_array(rng.gen_range(0..2 * PAGE_SZ)),
+                // Short, never compressed
+                1 => vec![i as u8; rng.gen_range(0..MIN_COMPRESSED_BLOB_LEN)],
+                // Compressible, like a mostly empty page
+                _ => {
+                    let mut page = vec![0u8; PAGE_SZ];
+                    page[..64].copy_from_slice(&random_array(64));
+                    page
+                }
+            })
+            .collect::<Vec<_>>();
+        for compression in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4] {
+            round_trip_test_compressed::<false>(&blobs, compression).await?;
+            round_trip_test_compressed::<true>(&blobs, compression).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_blob_is_smaller() -> Result<(), Error> {
+        let temp_dir = camino_tempfile::tempdir()?;
+        let pathbuf = temp_dir.path().join("file");
+        let file = VirtualFile::create(pathbuf.as_path()).await?;
+        let mut wtr = BlobWriter::<true>::new(file, 0);
+        wtr.write_blob_compressed(&vec![0u8; PAGE_SZ], CompressionAlgorithm::Zstd)
+            .await?;
+        assert!(wtr.size() < PAGE_SZ as u64 / 8, "size: {}", wtr.size());
+        Ok(())
+    }
 }
@@ -129,6 +129,10 @@ async fn schedule_ordered_timeline_deletions(
     // timelines.lock is currently synchronous so we cant hold it across await point.
     // So just ignore NotFound error if we get it from `run`.
     // Beware: in case it becomes async and we try to hold it here, `run` also locks it, which can create a deadlock.
+    tenant
+        .unoffload_all_for_deletion()
+        .await
+        .map_err(DeleteTenantError::Timeline)?;
     let timelines = tenant.timelines.lock().unwrap().clone();
     let sorted =
         tree_sort_timelines(timelines, |t| t.get_ancestor_timeline_id()).context("tree sort")?;
@@ -588,7 +592,7 @@ impl DeleteTenantFlow {
                             }
                             break;
                         }
-                        TenantsMapRemoveResult::Occupied(TenantSlot::Secondary) => {
+                        TenantsMapRemoveResult::Occupied(TenantSlot::Secondary(_)) => {
                             // This is unexpected: this secondary tenants should not have been created, and we
                             // are not in a position to shut it down from here.
                             tracing::warn!("Tenant transitioned to secondary mode while deleting!");
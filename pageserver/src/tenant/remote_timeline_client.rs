@@ -614,6 +614,30 @@ impl RemoteTimelineClient {
         Ok(())
     }
 
+    /// Launch an index-file upload operation in the background, recording whether the timeline
+    /// is archived.
+    ///
+    /// Like schedule_index_upload_for_metadata_update(), this merely adds the upload to the
+    /// upload queue and returns quickly. Use `wait_completion` to make the change durable.
+    pub(crate) fn schedule_index_upload_for_timeline_archival_state(
+        self: &Arc<Self>,
+        archived: bool,
+    ) -> anyhow::Result<()> {
+        let mut guard = self.upload_queue.lock().unwrap();
+        let upload_queue = guard.initialized_mut()?;
+
+        upload_queue.latest_archived_at = match (archived, upload_queue.latest_archived_at) {
+            // Keep the original archival time if we are asked to archive again.
+            (true, Some(archived_at)) => Some(archived_at),
+            (true, None) => Some(Utc::now().naive_utc()),
+            (false, _) => None,
+        };
+
+        self.schedule_index_upload(upload_queue, upload_queue.latest_metadata.clone());
+
+        Ok(())
+    }
+
     ///
     /// Launch an index-file upload operation in the background, if necessary.
     ///
@@ -649,11 +673,12 @@ impl RemoteTimelineClient {
 
         let disk_consistent_lsn = upload_queue.latest_metadata.disk_consistent_lsn();
 
-        let index_part = IndexPart::new(
+        let mut index_part = IndexPart::new(
             upload_queue.latest_files.clone(),
             disk_consistent_lsn,
             metadata,
         );
+        index_part.archived_at = upload_queue.latest_archived_at;
         let op = UploadOp::UploadMetadata(index_part, disk_consistent_lsn);
         self.calls_unfinished_metric_begin(&op);
         upload_queue.queued_operations.push_back(op);
@@ -1613,6 +1638,7 @@ impl RemoteTimelineClient {
                         latest_files: initialized.latest_files.clone(),
                         latest_files_changes_since_metadata_upload_scheduled: 0,
                         latest_metadata: initialized.latest_metadata.clone(),
+                        latest_archived_at: initialized.latest_archived_at,
                         projected_remote_consistent_lsn: None,
                         visible_remote_consistent_lsn: initialized
                             .visible_remote_consistent_lsn
@@ -1688,6 +1714,11 @@ impl RemoteTimelineClient {
     }
 }
 
+pub fn remote_tenant_path(tenant_shard_id: &TenantShardId) -> RemotePath {
+    let path = format!("tenants/{tenant_shard_id}");
+    RemotePath::from_string(&path).expect("Failed to construct path")
+}
+
 pub fn remote_timelines_path(tenant_shard_id: &TenantShardId) -> RemotePath {
     let path = format!("tenants/{tenant_shard_id}/{TIMELINES_SEGMENT_NAME}");
     RemotePath::from_string(&path).expect("Failed to construct path")
@@ -1788,6 +1819,27 @@ pub fn remote_path(
     })
 }
 
+/// Key of the uploaded layer file object metadata holding the [`layer_file_checksum`], as hex.
+pub(crate) const LAYER_CHECKSUM_METADATA_KEY: &str = "crc32c";
+
+/// Computes the CRC32C of a local layer file, which is stored in [`LayerFileMetadata`] and
+/// verified when the layer is downloaded.
+pub(crate) async fn layer_file_checksum(path: &Utf8Path) -> std::io::Result<u32> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut checksum = 0;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        checksum = crc32c::crc32c_append(checksum, &buf[..read]);
+    }
+    Ok(checksum)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1984,7 +2036,7 @@ mod tests {
                 harness.conf,
                 &timeline,
                 name,
-                LayerFileMetadata::new(contents.len() as u64, generation, shard),
+                LayerFileMetadata::new(contents.len() as u64, generation, shard, None),
             )
         }).collect::<Vec<_>>();
 
@@ -2133,7 +2185,12 @@ mod tests {
             harness.conf,
             &timeline,
             layer_file_name_1.clone(),
-            LayerFileMetadata::new(content_1.len() as u64, harness.generation, harness.shard),
+            LayerFileMetadata::new(
+                content_1.len() as u64,
+                harness.generation,
+                harness.shard,
+                None,
+            ),
         );
 
         #[derive(Debug, PartialEq, Clone, Copy)]
@@ -8,6 +8,7 @@ use std::future::Future;
 
 use anyhow::{anyhow, Context};
 use camino::{Utf8Path, Utf8PathBuf};
+use futures::TryStreamExt;
 use pageserver_api::shard::TenantShardId;
 use tokio::fs::{self, File, OpenOptions};
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
@@ -17,8 +18,10 @@ use utils::timeout::timeout_cancellable;
 use utils::{backoff, crashsafe};
 
 use crate::config::PageServerConf;
+use crate::metrics::REMOTE_LAYER_CHECKSUM_MISMATCHES;
 use crate::tenant::remote_timeline_client::{
     download_cancellable, remote_layer_path, remote_timelines_path, DOWNLOAD_TIMEOUT,
+    LAYER_CHECKSUM_METADATA_KEY,
 };
 use crate::tenant::storage_layer::LayerFileName;
 use crate::tenant::timeline::span::debug_assert_current_span_has_tenant_and_timeline_id;
@@ -37,7 +40,10 @@ use super::{
 
 ///
 /// If 'metadata' is given, we will validate that the downloaded file's size matches that
-/// in the metadata. (In the future, we might do more cross-checks, like CRC validation)
+/// in the metadata.
+///
+/// The downloaded contents are checked against the CRC32C from `layer_metadata`, or from the
+/// object metadata if the index has none. A mismatch is retried like any other download error.
 ///
 /// Returns the size of the downloaded file.
 pub async fn download_layer_file<'a>(
@@ -98,7 +104,19 @@ pub async fn download_layer_file<'a>(
             let mut destination_file =
                 tokio::io::BufWriter::with_capacity(super::BUFFER_SIZE, destination_file);
 
-            let mut reader = tokio_util::io::StreamReader::new(download.download_stream);
+            let expected_checksum = layer_metadata.checksum.or_else(|| {
+                download
+                    .metadata
+                    .as_ref()
+                    .and_then(|m| m.get(LAYER_CHECKSUM_METADATA_KEY))
+                    .and_then(|v| u32::from_str_radix(v, 16).ok())
+            });
+            let mut checksum = 0;
+            let stream = download
+                .download_stream
+                .inspect_ok(|bytes| checksum = crc32c::crc32c_append(checksum, bytes));
+
+            let mut reader = tokio_util::io::StreamReader::new(stream);
 
             // Cancellation safety: it is safe to cancel this future because it is writing into a temporary file,
             // and we will unlink the temporary file if there is an error.  This unlink is important because we
@@ -134,6 +152,20 @@ pub async fn download_layer_file<'a>(
             })
             .map_err(DownloadError::Other)?;
 
+            drop(reader);
+            if let Some(expected) = expected_checksum {
+                if checksum != expected {
+                    REMOTE_LAYER_CHECKSUM_MISMATCHES.inc();
+                    if let Err(e) = tokio::fs::remove_file(&temp_file_path).await {
+                        on_fatal_io_error(&e, &format!("Removing temporary file {temp_file_path}"));
+                    }
+                    return Err(DownloadError::ChecksumMismatch {
+                        expected,
+                        actual: checksum,
+                    });
+                }
+            }
+
             let destination_file = destination_file.into_inner();
 
             Ok((destination_file, bytes_amount))
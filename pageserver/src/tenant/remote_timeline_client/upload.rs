@@ -1,8 +1,10 @@
 //! Helper functions to upload files to remote storage with a RemoteStorage
 
 use anyhow::{bail, Context};
+use bytes::Bytes;
 use camino::Utf8Path;
 use fail::fail_point;
+use futures::{Stream, StreamExt};
 use pageserver_api::shard::TenantShardId;
 use std::io::{ErrorKind, SeekFrom};
 use tokio::fs::{self, File};
@@ -14,10 +16,10 @@ use crate::{
     config::PageServerConf,
     tenant::remote_timeline_client::{
         index::IndexPart, remote_index_path, remote_initdb_archive_path, remote_path,
-        upload_cancellable,
+        upload_cancellable, LAYER_CHECKSUM_METADATA_KEY,
     },
 };
-use remote_storage::GenericRemoteStorage;
+use remote_storage::{GenericRemoteStorage, StorageMetadata};
 use utils::id::{TenantId, TimelineId};
 
 use super::index::LayerFileMetadata;
@@ -108,18 +110,53 @@ pub(super) async fn upload_timeline_layer<'a>(
         bail!("File {source_path:?} has its current FS size {fs_size} diferent from initially determined {metadata_size}");
     }
 
+    let known_checksum = known_metadata.checksum;
+    let metadata = known_checksum.map(|checksum| {
+        StorageMetadata::from([(
+            LAYER_CHECKSUM_METADATA_KEY,
+            format!("{checksum:08x}").as_str(),
+        )])
+    });
+
     let fs_size = usize::try_from(fs_size)
         .with_context(|| format!("convert {source_path:?} size {fs_size} usize"))?;
 
     let reader = tokio_util::io::ReaderStream::with_capacity(source_file, super::BUFFER_SIZE);
+    let reader = verify_checksum(reader, known_checksum);
 
-    upload_cancellable(cancel, storage.upload(reader, fs_size, &storage_path, None))
-        .await
-        .with_context(|| format!("upload layer from local path '{source_path}'"))?;
+    upload_cancellable(
+        cancel,
+        storage.upload(reader, fs_size, &storage_path, metadata),
+    )
+    .await
+    .with_context(|| format!("upload layer from local path '{source_path}'"))?;
 
     Ok(())
 }
 
+/// Computes the CRC32C of the layer file while it is being uploaded, and fails the upload at
+/// the end of the file if it is different from the one the layer was created with.
+fn verify_checksum(
+    reader: tokio_util::io::ReaderStream<File>,
+    expected: Option<u32>,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    futures::stream::try_unfold((reader, 0), move |(mut reader, checksum)| async move {
+        match reader.next().await.transpose()? {
+            Some(bytes) => {
+                let checksum = crc32c::crc32c_append(checksum, &bytes);
+                Ok(Some((bytes, (reader, checksum))))
+            }
+            None => match expected {
+                Some(expected) if expected != checksum => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("layer file has its current checksum {checksum:08x} different from initially determined {expected:08x}"),
+                )),
+                _ => Ok(None),
+            },
+        }
+    })
+}
+
 /// Uploads the given `initdb` data to the remote storage.
 pub(crate) async fn upload_initdb_dir(
     storage: &GenericRemoteStorage,
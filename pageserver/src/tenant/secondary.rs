@@ -1,3 +1,4 @@
+mod downloader;
 pub mod heatmap;
 mod heatmap_uploader;
 
@@ -5,16 +6,19 @@ use std::sync::Arc;
 
 use crate::task_mgr::{self, TaskKind, BACKGROUND_RUNTIME};
 
-use self::heatmap_uploader::heatmap_uploader_task;
+use self::{downloader::downloader_task, heatmap_uploader::heatmap_uploader_task};
 
-use super::mgr::TenantManager;
+use super::{config::SecondaryLocationConfig, mgr::TenantManager};
 
-use pageserver_api::shard::TenantShardId;
+use pageserver_api::{models::SecondaryProgress, shard::TenantShardId};
 use remote_storage::GenericRemoteStorage;
 
 use tokio_util::sync::CancellationToken;
-use utils::completion::Barrier;
+use utils::{completion::Barrier, sync::gate::Gate};
 
+enum DownloadCommand {
+    Download(TenantShardId),
+}
 enum UploadCommand {
     Upload(TenantShardId),
 }
@@ -28,12 +32,77 @@ struct CommandResponse {
     result: anyhow::Result<()>,
 }
 
+/// For a tenant in secondary mode, this is the state that lives in `TenantSlot::Secondary`.  It
+/// owns no background tasks of its own: the downloader task in [`downloader`] does the work of
+/// keeping its local layers in line with the heatmap uploaded by the attached location, and
+/// records its progress here.
+///
+/// Like `Tenant`, a SecondaryTenant must be shut down before it is dropped from the tenant map, so
+/// that no downloads for it may still be writing to the local tenant directory.
+pub(crate) struct SecondaryTenant {
+    /// Cancellation token indicates to the downloader that it should stop doing
+    /// any work for this tenant at the next opportunity.
+    pub(crate) cancel: CancellationToken,
+
+    /// Held by downloads in flight for this tenant: shutdown waits for them.
+    pub(crate) gate: Gate,
+
+    tenant_shard_id: TenantShardId,
+    config: std::sync::Mutex<SecondaryLocationConfig>,
+    progress: std::sync::Mutex<SecondaryProgress>,
+}
+
+impl SecondaryTenant {
+    pub(crate) fn new(
+        tenant_shard_id: TenantShardId,
+        config: &SecondaryLocationConfig,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            cancel: CancellationToken::new(),
+            gate: Gate::new(format!("SecondaryTenant {tenant_shard_id}")),
+            tenant_shard_id,
+            config: std::sync::Mutex::new(config.clone()),
+            progress: std::sync::Mutex::default(),
+        })
+    }
+
+    pub(crate) async fn shutdown(&self) {
+        self.cancel.cancel();
+
+        // Wait for any downloads in flight to notice the cancellation and complete
+        self.gate.close().await;
+    }
+
+    pub(crate) fn set_config(&self, config: &SecondaryLocationConfig) {
+        *self.config.lock().unwrap() = config.clone();
+    }
+
+    pub(crate) fn get_tenant_shard_id(&self) -> &TenantShardId {
+        &self.tenant_shard_id
+    }
+
+    /// Only warm secondary locations download layers: a cold one just holds whatever is
+    /// already on local disk.
+    pub(crate) fn is_warm(&self) -> bool {
+        self.config.lock().unwrap().warm
+    }
+
+    pub(crate) fn get_progress(&self) -> SecondaryProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    fn set_progress(&self, progress: SecondaryProgress) {
+        *self.progress.lock().unwrap() = progress;
+    }
+}
+
 /// The SecondaryController is a pseudo-rpc client for administrative control of secondary mode downloads,
 /// and heatmap uploads.  This is not a hot data path: it's primarily a hook for tests,
 /// where we want to immediately upload/download for a particular tenant.  In normal operation
 /// uploads & downloads are autonomous and not driven by this interface.
 pub struct SecondaryController {
     upload_req_tx: tokio::sync::mpsc::Sender<CommandRequest<UploadCommand>>,
+    download_req_tx: tokio::sync::mpsc::Sender<CommandRequest<DownloadCommand>>,
 }
 
 impl SecondaryController {
@@ -63,6 +132,14 @@ impl SecondaryController {
         self.dispatch(&self.upload_req_tx, UploadCommand::Upload(tenant_shard_id))
             .await
     }
+
+    pub async fn download_tenant(&self, tenant_shard_id: TenantShardId) -> anyhow::Result<()> {
+        self.dispatch(
+            &self.download_req_tx,
+            DownloadCommand::Download(tenant_shard_id),
+        )
+        .await
+    }
 }
 
 pub fn spawn_tasks(
@@ -73,6 +150,31 @@ pub fn spawn_tasks(
 ) -> SecondaryController {
     let (upload_req_tx, upload_req_rx) =
         tokio::sync::mpsc::channel::<CommandRequest<UploadCommand>>(16);
+    let (download_req_tx, download_req_rx) =
+        tokio::sync::mpsc::channel::<CommandRequest<DownloadCommand>>(16);
+
+    let downloader_tenant_manager = tenant_manager.clone();
+    let downloader_remote_storage = remote_storage.clone();
+    let downloader_background_jobs_can_start = background_jobs_can_start.clone();
+    let downloader_cancel = cancel.clone();
+    task_mgr::spawn(
+        BACKGROUND_RUNTIME.handle(),
+        TaskKind::SecondaryDownloads,
+        None,
+        None,
+        "secondary tenant downloads",
+        false,
+        async move {
+            downloader_task(
+                downloader_tenant_manager,
+                downloader_remote_storage,
+                download_req_rx,
+                downloader_background_jobs_can_start,
+                downloader_cancel,
+            )
+            .await
+        },
+    );
 
     task_mgr::spawn(
         BACKGROUND_RUNTIME.handle(),
@@ -93,12 +195,20 @@ pub fn spawn_tasks(
         },
     );
 
-    SecondaryController { upload_req_tx }
+    SecondaryController {
+        upload_req_tx,
+        download_req_tx,
+    }
 }
 
 /// For running with remote storage disabled: a SecondaryController that is connected to nothing.
 pub fn null_controller() -> SecondaryController {
     let (upload_req_tx, _upload_req_rx) =
         tokio::sync::mpsc::channel::<CommandRequest<UploadCommand>>(16);
-    SecondaryController { upload_req_tx }
+    let (download_req_tx, _download_req_rx) =
+        tokio::sync::mpsc::channel::<CommandRequest<DownloadCommand>>(16);
+    SecondaryController {
+        upload_req_tx,
+        download_req_tx,
+    }
 }
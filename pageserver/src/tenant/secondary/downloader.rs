@@ -0,0 +1,617 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Weak},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    config::PageServerConf,
+    metrics::SECONDARY_MODE,
+    tenant::{
+        mgr::TenantManager,
+        remote_timeline_client::{
+            download::download_layer_file, index::LayerFileMetadata, remote_heatmap_path,
+        },
+        secondary::CommandResponse,
+        span::debug_assert_current_span_has_tenant_id,
+        storage_layer::LayerFileName,
+    },
+};
+
+use anyhow::Context;
+use camino::Utf8Path;
+use futures::StreamExt;
+use pageserver_api::{models::SecondaryProgress, shard::TenantShardId};
+use remote_storage::{DownloadError, GenericRemoteStorage};
+
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, instrument, Instrument};
+use utils::{backoff, completion::Barrier, id::TimelineId};
+
+use super::{
+    heatmap::{HeatMapTenant, HeatMapTimeline},
+    CommandRequest, DownloadCommand, SecondaryTenant,
+};
+
+/// Period between downloads for each secondary location: a heatmap is only uploaded when it
+/// changes, and at most once per tenant's `heatmap_period`, so there is little point in
+/// polling much more often than that.
+const DEFAULT_DOWNLOAD_INTERVAL: Duration = Duration::from_millis(60000);
+
+/// Period between the downloader walking secondary locations to look for work to do.
+const DEFAULT_SCHEDULING_INTERVAL: Duration = Duration::from_millis(10000);
+
+struct DownloadInProgress {
+    barrier: Barrier,
+}
+
+struct DownloadComplete {
+    tenant_shard_id: TenantShardId,
+    completed_at: Instant,
+}
+
+/// Per-tenant state of the downloader: when we should next do a download.
+struct DownloaderTenantState {
+    // This Weak only exists to enable culling idle instances of this type
+    // when the SecondaryTenant has been deallocated.
+    tenant: Weak<SecondaryTenant>,
+
+    /// When should we next do a download?
+    next_download: Instant,
+}
+
+/// This type is owned by a single task ([`downloader_task`]) which runs an event
+/// handling loop and mutates it as needed: like the heatmap uploader, there are no locks here.
+struct SecondaryDownloader {
+    tenant_manager: Arc<TenantManager>,
+    remote_storage: GenericRemoteStorage,
+    cancel: CancellationToken,
+
+    tenants: HashMap<TenantShardId, DownloaderTenantState>,
+
+    /// Tenants with work to do, for which tasks should be spawned as soon as concurrency
+    /// limits permit it.
+    tenants_pending: std::collections::VecDeque<Arc<SecondaryTenant>>,
+
+    /// Tenants for which a task in `tasks` has been spawned.
+    tenants_downloading: HashMap<TenantShardId, DownloadInProgress>,
+
+    tasks: JoinSet<()>,
+
+    /// Channel for our child tasks to send results to, see the equivalent in the heatmap uploader.
+    task_result_tx: tokio::sync::mpsc::UnboundedSender<DownloadComplete>,
+    task_result_rx: tokio::sync::mpsc::UnboundedReceiver<DownloadComplete>,
+
+    concurrent_downloads: usize,
+}
+
+/// The downloader task runs a loop that periodically wakes up and schedules downloads for
+/// warm secondary locations whose last download is older than [`DEFAULT_DOWNLOAD_INTERVAL`],
+/// or handles any commands that have been sent into `command_queue`.  No I/O is done in this
+/// loop: that all happens in the tasks we spawn.
+///
+/// Each download fetches the heatmap uploaded by the attached location, downloads any layers
+/// it lists that are missing locally, and deletes local layers and timelines that are no longer
+/// listed.
+pub(super) async fn downloader_task(
+    tenant_manager: Arc<TenantManager>,
+    remote_storage: GenericRemoteStorage,
+    mut command_queue: tokio::sync::mpsc::Receiver<CommandRequest<DownloadCommand>>,
+    background_jobs_can_start: Barrier,
+    cancel: CancellationToken,
+) -> anyhow::Result<()> {
+    let concurrent_downloads = tenant_manager.get_conf().secondary_download_concurrency;
+
+    let (result_tx, result_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut downloader = SecondaryDownloader {
+        tenant_manager,
+        remote_storage,
+        cancel: cancel.clone(),
+        tasks: JoinSet::new(),
+        tenants: HashMap::new(),
+        tenants_pending: std::collections::VecDeque::new(),
+        tenants_downloading: HashMap::new(),
+        task_result_tx: result_tx,
+        task_result_rx: result_rx,
+        concurrent_downloads,
+    };
+
+    tracing::info!("Waiting for background_jobs_can start...");
+    background_jobs_can_start.wait().await;
+    tracing::info!("background_jobs_can is ready, proceeding.");
+
+    while !cancel.is_cancelled() {
+        downloader.schedule_iteration().await;
+
+        let next_scheduling_iteration = Instant::now() + DEFAULT_SCHEDULING_INTERVAL;
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    // We do not simply drop the JoinSet, in order to have an orderly shutdown without cancellation.
+                    tracing::info!("Secondary downloader joining tasks");
+                    while let Some(_r) = downloader.tasks.join_next().await {};
+                    tracing::info!("Secondary downloader terminating");
+
+                    break;
+                },
+                _ = tokio::time::sleep(next_scheduling_iteration.duration_since(Instant::now())) => {
+                    tracing::debug!("downloader_task: woke for scheduling interval");
+                    break;
+                },
+                cmd = command_queue.recv() => {
+                    tracing::debug!("downloader_task: woke for command queue");
+                    let cmd = match cmd {
+                        Some(c) => c,
+                        None => {
+                            // SecondaryController was destroyed, and this has raced with
+                            // our CancellationToken
+                            tracing::info!("Secondary downloader terminating");
+                            cancel.cancel();
+                            break;
+                        }
+                    };
+
+                    let CommandRequest {
+                        response_tx,
+                        payload
+                    } = cmd;
+                    downloader.handle_command(payload, response_tx);
+                },
+                _ = downloader.process_next_completion() => {
+                    if !cancel.is_cancelled() {
+                        downloader.spawn_pending();
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl SecondaryDownloader {
+    /// Periodic execution phase: inspect all secondary locations and schedule any work they require.
+    async fn schedule_iteration(&mut self) {
+        // Cull any entries in self.tenants whose Arc<SecondaryTenant> is gone
+        self.tenants.retain(|_k, v| v.tenant.upgrade().is_some());
+
+        // The priority order of previously scheduled work may be invalidated by current state: drop
+        // all pending work (it will be re-scheduled if still needed)
+        self.tenants_pending.clear();
+
+        // Used a fixed 'now' through the following loop, for efficiency and fairness.
+        let now = Instant::now();
+
+        // While iterating over the potentially-long list of tenants, we will periodically yield
+        // to avoid blocking executor.
+        const YIELD_ITERATIONS: usize = 1000;
+
+        let tenants = self.tenant_manager.get_secondary_tenant_shards();
+        for (i, tenant) in tenants.into_iter().enumerate() {
+            // Process is shutting down, drop out
+            if self.cancel.is_cancelled() {
+                return;
+            }
+
+            // Skip tenants that already have a download in flight
+            if self
+                .tenants_downloading
+                .contains_key(tenant.get_tenant_shard_id())
+            {
+                continue;
+            }
+
+            self.maybe_schedule_download(&now, tenant);
+
+            if (i + 1) % YIELD_ITERATIONS == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        // Spawn tasks for as many of our pending tenants as we can.
+        self.spawn_pending();
+    }
+
+    ///
+    /// Cancellation: this method is cancel-safe.
+    async fn process_next_completion(&mut self) {
+        match self.task_result_rx.recv().await {
+            Some(r) => {
+                self.on_completion(r);
+            }
+            None => {
+                unreachable!("Result sender is stored on Self");
+            }
+        }
+    }
+
+    /// The 'maybe' refers to the tenant's state: whether it is a warm secondary location at
+    /// all, and whether sufficient time has passed since the last download.
+    fn maybe_schedule_download(&mut self, now: &Instant, tenant: Arc<SecondaryTenant>) {
+        // Cold secondary locations keep whatever they have on local disk, but never download.
+        if !tenant.is_warm() {
+            return;
+        }
+
+        let state = self
+            .tenants
+            .entry(*tenant.get_tenant_shard_id())
+            .or_insert_with(|| DownloaderTenantState {
+                tenant: Arc::downgrade(&tenant),
+                next_download: *now,
+            });
+
+        // Decline to do the download if insufficient time has passed
+        if &state.next_download > now {
+            return;
+        }
+
+        self.tenants_pending.push_back(tenant)
+    }
+
+    fn spawn_pending(&mut self) {
+        while !self.tenants_pending.is_empty()
+            && self.tenants_downloading.len() < self.concurrent_downloads
+        {
+            // unwrap: loop condition includes !is_empty()
+            let pending = self.tenants_pending.pop_front().unwrap();
+            self.spawn_download(pending);
+        }
+    }
+
+    fn spawn_download(&mut self, tenant: Arc<SecondaryTenant>) {
+        let conf = self.tenant_manager.get_conf();
+        let remote_storage = self.remote_storage.clone();
+        let tenant_shard_id = *tenant.get_tenant_shard_id();
+        let (completion, barrier) = utils::completion::channel();
+        let result_tx = self.task_result_tx.clone();
+        self.tasks.spawn(async move {
+            // Guard for the barrier in [`DownloadInProgress`]
+            let _completion = completion;
+
+            match download_tenant(conf, &remote_storage, &tenant).await {
+                Ok(()) => {}
+                Err(UpdateError::NoData) => {
+                    tracing::info!(
+                        tenant_id=%tenant_shard_id.tenant_id, shard_id=%tenant_shard_id.shard_slug(),
+                        "No heatmap found for secondary tenant, the attached location has not uploaded one yet"
+                    );
+                }
+                Err(UpdateError::Cancelled) => {
+                    tracing::info!("Cancelled secondary download, shutting down");
+                }
+                Err(UpdateError::Other(e)) => {
+                    tracing::warn!(
+                        tenant_id=%tenant_shard_id.tenant_id, shard_id=%tenant_shard_id.shard_slug(),
+                        "Failed to download for secondary tenant: {e:#}"
+                    );
+                    SECONDARY_MODE.download_errors.inc();
+                }
+            }
+
+            result_tx
+                .send(DownloadComplete {
+                    tenant_shard_id,
+                    completed_at: Instant::now(),
+                })
+                .ok();
+        });
+
+        self.tenants_downloading
+            .insert(tenant_shard_id, DownloadInProgress { barrier });
+    }
+
+    #[instrument(skip_all, fields(tenant_id=%completion.tenant_shard_id.tenant_id, shard_id=%completion.tenant_shard_id.shard_slug()))]
+    fn on_completion(&mut self, completion: DownloadComplete) {
+        tracing::debug!("Secondary download completed");
+        let DownloadComplete {
+            tenant_shard_id,
+            completed_at,
+        } = completion;
+        self.tenants_downloading.remove(&tenant_shard_id);
+        if let Some(state) = self.tenants.get_mut(&tenant_shard_id) {
+            state.next_download = completed_at + DEFAULT_DOWNLOAD_INTERVAL;
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        command: DownloadCommand,
+        response_tx: tokio::sync::oneshot::Sender<CommandResponse>,
+    ) {
+        match command {
+            DownloadCommand::Download(tenant_shard_id) => {
+                // If a download was ongoing for this tenant, let it finish first.
+                let barrier = if let Some(downloading_state) =
+                    self.tenants_downloading.get(&tenant_shard_id)
+                {
+                    tracing::info!(
+                        tenant_id=%tenant_shard_id.tenant_id, shard_id=%tenant_shard_id.shard_slug(),
+                        "Waiting for secondary download to complete");
+                    downloading_state.barrier.clone()
+                } else {
+                    // Spawn the download then immediately wait for it.  This is done even for cold
+                    // secondary locations: the caller asked for it explicitly.
+                    tracing::info!(
+                        tenant_id=%tenant_shard_id.tenant_id, shard_id=%tenant_shard_id.shard_slug(),
+                        "Starting secondary download on command");
+                    let Some(tenant) = self
+                        .tenant_manager
+                        .get_secondary_tenant_shard(tenant_shard_id)
+                    else {
+                        // Drop result of send: we don't care if caller dropped their receiver
+                        drop(response_tx.send(CommandResponse {
+                            result: Err(anyhow::anyhow!(
+                                "Tenant {tenant_shard_id} not found or not in secondary mode"
+                            )),
+                        }));
+                        return;
+                    };
+                    self.spawn_download(tenant);
+                    self.tenants_downloading
+                        .get(&tenant_shard_id)
+                        .expect("We just inserted this")
+                        .barrier
+                        .clone()
+                };
+
+                // This task does no I/O: it only listens for a barrier's completion and then
+                // sends to the command response channel.  It is therefore safe to spawn this without
+                // any gates/task_mgr hooks.
+                tokio::task::spawn(async move {
+                    barrier.wait().await;
+
+                    tracing::info!(
+                        tenant_id=%tenant_shard_id.tenant_id, shard_id=%tenant_shard_id.shard_slug(),
+                        "Secondary download complete");
+
+                    // Drop result of send: we don't care if caller dropped their receiver
+                    drop(response_tx.send(CommandResponse { result: Ok(()) }))
+                });
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum UpdateError {
+    /// The attached location has not uploaded a heatmap yet.
+    #[error("No remote data found")]
+    NoData,
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<DownloadError> for UpdateError {
+    fn from(value: DownloadError) -> Self {
+        match value {
+            DownloadError::NotFound => Self::NoData,
+            DownloadError::Cancelled => Self::Cancelled,
+            e => Self::Other(anyhow::anyhow!(e)),
+        }
+    }
+}
+
+/// Bring the local layers of a secondary location in line with the latest heatmap of its tenant.
+#[instrument(skip_all, fields(tenant_id = %secondary_state.get_tenant_shard_id().tenant_id, shard_id = %secondary_state.get_tenant_shard_id().shard_slug()))]
+async fn download_tenant(
+    conf: &'static PageServerConf,
+    remote_storage: &GenericRemoteStorage,
+    secondary_state: &SecondaryTenant,
+) -> Result<(), UpdateError> {
+    debug_assert_current_span_has_tenant_id();
+
+    // Ensure that SecondaryTenant::shutdown waits for us: otherwise a download in flight
+    // could write into the tenant directory after it has been handed to an attached `Tenant`.
+    let _guard = secondary_state
+        .gate
+        .enter()
+        .map_err(|_| UpdateError::Cancelled)?;
+
+    let tenant_shard_id = secondary_state.get_tenant_shard_id();
+    let cancel = &secondary_state.cancel;
+
+    let heatmap = download_heatmap(remote_storage, tenant_shard_id, cancel).await?;
+    SECONDARY_MODE.download_heatmap.inc();
+    tracing::debug!(
+        "Downloaded heatmap from generation {:?} with {} timelines",
+        heatmap.generation,
+        heatmap.timelines.len()
+    );
+
+    let mut progress = SecondaryProgress::default();
+    let heatmap_timelines: HashSet<TimelineId> =
+        heatmap.timelines.iter().map(|t| t.timeline_id).collect();
+
+    for timeline in heatmap.timelines {
+        if cancel.is_cancelled() {
+            return Err(UpdateError::Cancelled);
+        }
+
+        let span = info_span!("timeline", timeline_id = %timeline.timeline_id);
+        download_timeline(
+            conf,
+            remote_storage,
+            tenant_shard_id,
+            timeline,
+            cancel,
+            &mut progress,
+        )
+        .instrument(span)
+        .await?;
+    }
+
+    // Timelines that were deleted, or that the attached location no longer has: drop them locally.
+    let timelines_path = conf.timelines_path(tenant_shard_id);
+    let mut dir = tokio::fs::read_dir(&timelines_path)
+        .await
+        .with_context(|| format!("read timelines dir {timelines_path}"))?;
+    while let Some(entry) = dir
+        .next_entry()
+        .await
+        .with_context(|| format!("read timelines dir {timelines_path}"))?
+    {
+        let file_name = entry.file_name();
+        let Some(timeline_id) = file_name
+            .to_str()
+            .and_then(|name| TimelineId::from_str(name).ok())
+        else {
+            continue;
+        };
+        if !heatmap_timelines.contains(&timeline_id) {
+            tracing::info!(%timeline_id, "Removing local timeline that is no longer in the heatmap");
+            tokio::fs::remove_dir_all(entry.path())
+                .await
+                .with_context(|| format!("remove timeline {timeline_id}"))?;
+        }
+    }
+
+    progress.last_download = Some(SystemTime::now());
+    secondary_state.set_progress(progress);
+
+    Ok(())
+}
+
+async fn download_heatmap(
+    remote_storage: &GenericRemoteStorage,
+    tenant_shard_id: &TenantShardId,
+    cancel: &CancellationToken,
+) -> Result<HeatMapTenant, UpdateError> {
+    let path = remote_heatmap_path(tenant_shard_id);
+
+    let heatmap_bytes = backoff::retry(
+        || async {
+            let download = remote_storage.download(&path).await?;
+            let mut heatmap_bytes = Vec::new();
+            let mut stream = std::pin::pin!(download.download_stream);
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk
+                    .with_context(|| format!("download heatmap at {path}"))
+                    .map_err(DownloadError::Other)?;
+                heatmap_bytes.extend_from_slice(&chunk[..]);
+            }
+            Ok(heatmap_bytes)
+        },
+        |e| matches!(e, DownloadError::NotFound | DownloadError::BadInput(_)),
+        3,
+        u32::MAX,
+        "Downloading heatmap",
+        backoff::Cancel::new(cancel.clone(), || DownloadError::Cancelled),
+    )
+    .await?;
+
+    let heatmap = serde_json::from_slice::<HeatMapTenant>(&heatmap_bytes)
+        .with_context(|| format!("parse heatmap at {path}"))?;
+
+    Ok(heatmap)
+}
+
+/// Download the layers of one timeline in the heatmap that are missing locally, and delete
+/// local layers that the heatmap no longer lists.
+async fn download_timeline(
+    conf: &'static PageServerConf,
+    remote_storage: &GenericRemoteStorage,
+    tenant_shard_id: &TenantShardId,
+    timeline: HeatMapTimeline,
+    cancel: &CancellationToken,
+    progress: &mut SecondaryProgress,
+) -> Result<(), UpdateError> {
+    let timeline_path = conf.timeline_path(tenant_shard_id, &timeline.timeline_id);
+
+    // Does not need to be fsync'd because local storage is just a cache.
+    tokio::fs::create_dir_all(&timeline_path)
+        .await
+        .with_context(|| format!("create timeline dir {timeline_path}"))?;
+
+    let mut local_layers = scan_local_layers(&timeline_path).await?;
+
+    for layer in timeline.layers {
+        if cancel.is_cancelled() {
+            return Err(UpdateError::Cancelled);
+        }
+
+        let file_size = layer.metadata.file_size;
+        progress.layers_total += 1;
+        progress.bytes_total += file_size;
+
+        if local_layers.remove(&layer.name) == Some(file_size) {
+            // Already downloaded, and not truncated.
+            progress.layers_downloaded += 1;
+            progress.bytes_downloaded += file_size;
+            continue;
+        }
+
+        let metadata = LayerFileMetadata::from(&layer.metadata);
+        match download_layer_file(
+            conf,
+            remote_storage,
+            *tenant_shard_id,
+            timeline.timeline_id,
+            &layer.name,
+            &metadata,
+            cancel,
+        )
+        .await
+        {
+            Ok(downloaded_bytes) => {
+                SECONDARY_MODE.download_layer.inc();
+                SECONDARY_MODE.download_layer_bytes.inc_by(downloaded_bytes);
+                progress.layers_downloaded += 1;
+                progress.bytes_downloaded += downloaded_bytes;
+            }
+            Err(DownloadError::NotFound) => {
+                // The attached location has deleted the layer since uploading the heatmap,
+                // e.g. by compaction or GC: the next heatmap won't list it.
+                tracing::info!("Skipping layer {} which was deleted remotely", layer.name);
+            }
+            Err(DownloadError::Cancelled) => return Err(UpdateError::Cancelled),
+            Err(e) => {
+                // Carry on with other layers: we will retry this one on the next download.
+                tracing::warn!("Failed to download layer {}: {e}", layer.name);
+                SECONDARY_MODE.download_errors.inc();
+            }
+        }
+    }
+
+    // Whatever is left was not in the heatmap: the attached location has evicted or deleted it.
+    for name in local_layers.into_keys() {
+        let path = timeline_path.join(name.file_name());
+        tracing::debug!("Removing layer {name} which is no longer in the heatmap");
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|| format!("remove layer {path}"))?;
+        SECONDARY_MODE.evict_layer.inc();
+    }
+
+    Ok(())
+}
+
+/// Layer files present in a local timeline directory, with their sizes.
+async fn scan_local_layers(
+    timeline_path: &Utf8Path,
+) -> anyhow::Result<HashMap<LayerFileName, u64>> {
+    let mut layers = HashMap::new();
+    let mut dir = tokio::fs::read_dir(timeline_path)
+        .await
+        .with_context(|| format!("read timeline dir {timeline_path}"))?;
+    while let Some(entry) = dir.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(name) = file_name
+            .to_str()
+            .and_then(|name| LayerFileName::from_str(name).ok())
+        else {
+            // Not a layer, e.g. a temporary download: leave it to timeline load to clean up.
+            continue;
+        };
+        let file_size = entry.metadata().await?.len();
+        layers.insert(name, file_size);
+    }
+    Ok(layers)
+}
@@ -8,6 +8,8 @@ pub(crate) mod layer;
 mod layer_desc;
 
 use crate::context::{AccessStatsBehavior, RequestContext};
+use crate::page_cache::PAGE_SZ;
+use crate::repository::Key;
 use crate::task_mgr::TaskKind;
 use crate::walrecord::NeonWalRecord;
 use bytes::Bytes;
@@ -34,6 +36,16 @@ pub use layer_desc::{PersistentLayerDesc, PersistentLayerKey};
 
 pub(crate) use layer::{EvictionError, Layer, ResidentLayer};
 
+/// Computes the CRC32C of a layer file from the `summary` written at the start of its first
+/// block and the CRC32C of the `rest_len` bytes following that block, so that the writers
+/// don't have to read the file back.
+pub(crate) fn layer_file_checksum_from_parts(summary: &[u8], rest: u32, rest_len: u64) -> u32 {
+    let mut first_block = [0u8; PAGE_SZ];
+    let summary_len = summary.len().min(PAGE_SZ);
+    first_block[..summary_len].copy_from_slice(&summary[..summary_len]);
+    crc32c::crc32c_combine(crc32c::crc32c(&first_block), rest, rest_len as usize)
+}
+
 pub fn range_overlaps<T>(a: &Range<T>, b: &Range<T>) -> bool
 where
     T: PartialOrd<T>,
@@ -61,12 +73,24 @@ where
 /// the same ValueReconstructState struct in the next 'get_value_reconstruct_data'
 /// call, to collect more records.
 ///
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ValueReconstructState {
     pub records: Vec<(Lsn, NeonWalRecord)>,
     pub img: Option<(Lsn, Bytes)>,
 }
 
+/// One key of a batched lookup with [`Layer::get_values_reconstruct_data`].
+///
+/// Each key has its own LSN range to look at in the layer, because the layers above it need
+/// not have been the same for all keys of the batch. The layer adds what it finds for the key
+/// to `state`, as `get_value_reconstruct_data` would.
+#[derive(Debug)]
+pub(crate) struct BatchedValueRead {
+    pub(crate) key: Key,
+    pub(crate) lsn_range: Range<Lsn>,
+    pub(crate) state: ValueReconstructState,
+}
+
 /// Return value from [`Layer::get_value_reconstruct_data`]
 #[derive(Clone, Copy, Debug)]
 pub enum ValueReconstructResult {
@@ -31,17 +31,23 @@ use crate::config::PageServerConf;
 use crate::context::{PageContentKind, RequestContext, RequestContextBuilder};
 use crate::page_cache::PAGE_SZ;
 use crate::repository::{Key, Value, KEY_SIZE};
-use crate::tenant::blob_io::BlobWriter;
+use crate::tenant::blob_io::{BlobFormat, BlobWriter};
 use crate::tenant::block_io::{BlockBuf, BlockCursor, BlockLease, BlockReader, FileBlockReader};
 use crate::tenant::disk_btree::{DiskBtreeBuilder, DiskBtreeReader, VisitDirection};
-use crate::tenant::storage_layer::{Layer, ValueReconstructResult, ValueReconstructState};
+use crate::tenant::storage_layer::{
+    layer_file_checksum_from_parts, BatchedValueRead, Layer, ValueReconstructResult,
+    ValueReconstructState,
+};
+use crate::tenant::vectored_blob_io::{
+    VectoredBlobReader, VectoredReadPlanner, MAX_VECTORED_READ_BYTES,
+};
 use crate::tenant::Timeline;
 use crate::virtual_file::VirtualFile;
 use crate::{walrecord, TEMP_FILE_SUFFIX};
-use crate::{DELTA_FILE_MAGIC, STORAGE_FORMAT_VERSION};
+use crate::{DELTA_FILE_MAGIC, STORAGE_FORMAT_VERSION, STORAGE_FORMAT_VERSION_UNCOMPRESSED};
 use anyhow::{bail, ensure, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use pageserver_api::models::LayerAccessKind;
+use pageserver_api::models::{CompressionAlgorithm, LayerAccessKind};
 use pageserver_api::shard::TenantShardId;
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
@@ -208,6 +214,9 @@ pub struct DeltaLayerInner {
     index_start_blk: u32,
     index_root_blk: u32,
 
+    /// How blobs in the file are encoded, derived from the summary's format version.
+    blob_format: BlobFormat,
+
     /// Reader object for reading blocks from the file.
     file: FileBlockReader,
 }
@@ -363,6 +372,7 @@ struct DeltaLayerWriterInner {
 
     key_start: Key,
     lsn_range: Range<Lsn>,
+    compression: CompressionAlgorithm,
 
     tree: DiskBtreeBuilder<BlockBuf, DELTA_KEY_SIZE>,
 
@@ -379,6 +389,7 @@ impl DeltaLayerWriterInner {
         tenant_shard_id: TenantShardId,
         key_start: Key,
         lsn_range: Range<Lsn>,
+        compression: CompressionAlgorithm,
     ) -> anyhow::Result<Self> {
         // Create the file initially with a temporary filename. We don't know
         // the end key yet, so we cannot form the final filename yet. We will
@@ -405,6 +416,7 @@ impl DeltaLayerWriterInner {
             tenant_shard_id,
             key_start,
             lsn_range,
+            compression,
             tree: tree_builder,
             blob_writer,
         })
@@ -429,7 +441,14 @@ impl DeltaLayerWriterInner {
     ) -> anyhow::Result<()> {
         assert!(self.lsn_range.start <= lsn);
 
-        let off = self.blob_writer.write_blob(val).await?;
+        let off = match self.compression {
+            CompressionAlgorithm::Disabled => self.blob_writer.write_blob(val).await?,
+            compression => {
+                self.blob_writer
+                    .write_blob_compressed(val, compression)
+                    .await?
+            }
+        };
 
         let blob_ref = BlobRef::new(off, will_init);
 
@@ -450,6 +469,12 @@ impl DeltaLayerWriterInner {
         let index_start_blk =
             ((self.blob_writer.size() + PAGE_SZ as u64 - 1) / PAGE_SZ as u64) as u32;
 
+        // The checksum of everything after the summary block: the blobs, the padding up to the
+        // index and the index.
+        let padding = (index_start_blk as u64 * PAGE_SZ as u64 - self.blob_writer.size()) as usize;
+        let mut checksum =
+            crc32c::crc32c_append(self.blob_writer.checksum(), &[0; PAGE_SZ][..padding]);
+
         let mut file = self.blob_writer.into_inner().await?;
 
         // Write out the index
@@ -458,12 +483,13 @@ impl DeltaLayerWriterInner {
             .await?;
         for buf in block_buf.blocks {
             file.write_all(buf.as_ref()).await?;
+            checksum = crc32c::crc32c_append(checksum, buf.as_ref());
         }
         assert!(self.lsn_range.start < self.lsn_range.end);
         // Fill in the summary on blk 0
         let summary = Summary {
             magic: DELTA_FILE_MAGIC,
-            format_version: STORAGE_FORMAT_VERSION,
+            format_version: BlobFormat::for_compression(self.compression).format_version(),
             tenant_id: self.tenant_shard_id.tenant_id,
             timeline_id: self.timeline_id,
             key_range: self.key_start..key_end,
@@ -515,7 +541,10 @@ impl DeltaLayerWriterInner {
         // fsync the file
         file.sync_all().await?;
 
-        let layer = Layer::finish_creating(self.conf, timeline, desc, &self.path)?;
+        let checksum =
+            layer_file_checksum_from_parts(&buf, checksum, metadata.len() - PAGE_SZ as u64);
+
+        let layer = Layer::finish_creating(self.conf, timeline, desc, checksum, &self.path)?;
 
         trace!("created delta layer {}", layer.local_path());
 
@@ -559,6 +588,7 @@ impl DeltaLayerWriter {
         tenant_shard_id: TenantShardId,
         key_start: Key,
         lsn_range: Range<Lsn>,
+        compression: CompressionAlgorithm,
     ) -> anyhow::Result<Self> {
         Ok(Self {
             inner: Some(
@@ -568,6 +598,7 @@ impl DeltaLayerWriter {
                     tenant_shard_id,
                     key_start,
                     lsn_range,
+                    compression,
                 )
                 .await?,
             ),
@@ -679,6 +710,28 @@ impl DeltaLayer {
     }
 }
 
+/// Adds a value to `reconstruct_state`, going from the newest value of the key to the oldest.
+/// Returns true if it is a page image or a record that initializes the page, so that no older
+/// values are needed.
+fn push_reconstruct_value(
+    lsn: Lsn,
+    val: Value,
+    reconstruct_state: &mut ValueReconstructState,
+) -> bool {
+    match val {
+        Value::Image(img) => {
+            reconstruct_state.img = Some((lsn, img));
+            true
+        }
+        Value::WalRecord(rec) => {
+            let will_init = rec.will_init();
+            reconstruct_state.records.push((lsn, rec));
+            // A WAL record that initializes the page, so no need to go further back
+            will_init
+        }
+    }
+}
+
 impl DeltaLayerInner {
     /// Returns nested result following Result<Result<_, OpErr>, Critical>:
     /// - inner has the success or transient failure
@@ -707,6 +760,10 @@ impl DeltaLayerInner {
             // production code path
             expected_summary.index_start_blk = actual_summary.index_start_blk;
             expected_summary.index_root_blk = actual_summary.index_root_blk;
+            // Layers written with compression disabled use the older format version.
+            if actual_summary.format_version == STORAGE_FORMAT_VERSION_UNCOMPRESSED {
+                expected_summary.format_version = actual_summary.format_version;
+            }
             if actual_summary != expected_summary {
                 bail!(
                     "in-file summary does not match expected summary. actual = {:?} expected = {:?}",
@@ -720,6 +777,7 @@ impl DeltaLayerInner {
             file,
             index_start_blk: actual_summary.index_start_blk,
             index_root_blk: actual_summary.index_root_blk,
+            blob_format: BlobFormat::from_format_version(actual_summary.format_version),
         }))
     }
 
@@ -730,7 +788,6 @@ impl DeltaLayerInner {
         reconstruct_state: &mut ValueReconstructState,
         ctx: &RequestContext,
     ) -> anyhow::Result<ValueReconstructResult> {
-        let mut need_image = true;
         // Scan the page versions backwards, starting from `lsn`.
         let file = &self.file;
         let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
@@ -770,11 +827,160 @@ impl DeltaLayerInner {
             .build();
 
         // Ok, 'offsets' now contains the offsets of all the entries we need to read
+        self.read_reconstruct_values(&offsets, reconstruct_state, ctx)
+            .await
+    }
+
+    pub(super) async fn get_values_reconstruct_data(
+        &self,
+        reads: &mut [BatchedValueRead],
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<ValueReconstructResult>> {
+        let Some(first) = reads.first() else {
+            return Ok(Vec::new());
+        };
+
+        // Scan the index forwards once, from the oldest version of the first key up to the last
+        // key, instead of searching it separately for each key.
+        let file = &self.file;
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            self.index_start_blk,
+            self.index_root_blk,
+            file,
+        );
+        let search_key = DeltaKey::from_key_lsn(&first.key, Lsn(0));
+
+        let wanted: Vec<(Key, Range<Lsn>)> = reads
+            .iter()
+            .map(|read| (read.key, read.lsn_range.clone()))
+            .collect();
+        // (lsn, start, end) of the blobs to read for each key. The end of a blob is not known
+        // until we see the next entry of the index, see `vectored_blob_io`.
+        let mut blobs: Vec<Vec<(Lsn, u64, u64)>> = vec![Vec::new(); reads.len()];
+        let mut last_wanted: Option<usize> = None;
+        let mut next = 0;
+
+        tree_reader
+            .visit(
+                &search_key.0,
+                VisitDirection::Forwards,
+                |raw_key, value| {
+                    let blob_ref = BlobRef(value);
+                    if let Some(i) = last_wanted.take() {
+                        if let Some(blob) = blobs[i].last_mut() {
+                            blob.2 = blob_ref.pos();
+                        }
+                    }
+
+                    let key = Key::from_slice(&raw_key[..KEY_SIZE]);
+                    while next < wanted.len() && wanted[next].0 < key {
+                        next += 1;
+                    }
+                    if next == wanted.len() {
+                        return false;
+                    }
+                    let (wanted_key, lsn_range) = &wanted[next];
+                    if *wanted_key == key {
+                        let entry_lsn = DeltaKey::extract_lsn_from_buf(raw_key);
+                        if lsn_range.contains(&entry_lsn) {
+                            if blob_ref.will_init() {
+                                // The older versions are not needed to reconstruct the newer ones
+                                blobs[next].clear();
+                            }
+                            blobs[next].push((entry_lsn, blob_ref.pos(), u64::MAX));
+                            last_wanted = Some(next);
+                        }
+                    }
+                    true
+                },
+                &RequestContextBuilder::extend(ctx)
+                    .page_content_kind(PageContentKind::DeltaLayerBtreeNode)
+                    .build(),
+            )
+            .await?;
+
+        // The values of the keys are next to each other, so they can mostly be read with a few
+        // large reads.
+        let index_start = self.index_start_blk as u64 * PAGE_SZ as u64;
+        let mut planned: Vec<(u64, u64, Key, Lsn)> = wanted
+            .iter()
+            .zip(&blobs)
+            .flat_map(|((key, _), blobs)| {
+                // DeltaLayerWriter appends to the index in the order of the values, so the
+                // next entry is the next value, except for the last one before the index.
+                blobs
+                    .iter()
+                    .map(|&(lsn, start, end)| (start, end.min(index_start), *key, lsn))
+            })
+            .collect();
+        planned.sort_unstable_by_key(|(start, ..)| *start);
+        let mut planner = VectoredReadPlanner::new(MAX_VECTORED_READ_BYTES);
+        for (start, end, key, lsn) in planned {
+            planner.handle(key, lsn, start, end);
+        }
+
+        let file = &self.file.file;
+        let reader = VectoredBlobReader::new(file, self.blob_format);
+        let mut values: Vec<Vec<(Lsn, Value)>> = blobs.iter().map(|_| Vec::new()).collect();
+        let mut buf = Vec::new();
+        for read in planner.finish() {
+            let blobs = reader.read_blobs(&read, &mut buf).await.with_context(|| {
+                format!(
+                    "Failed to read {} bytes at {} from virtual file {}",
+                    read.size(),
+                    read.start,
+                    file.path
+                )
+            })?;
+            for (meta, blob) in blobs {
+                let val = Value::des(&blob).with_context(|| {
+                    format!(
+                        "Failed to deserialize file blob from virtual file {}",
+                        file.path
+                    )
+                })?;
+                let i = wanted
+                    .binary_search_by_key(&meta.key, |(key, _)| *key)
+                    .expect("only wanted keys are read");
+                values[i].push((meta.lsn, val));
+            }
+        }
+
+        let mut results = Vec::with_capacity(reads.len());
+        for (read, mut values) in reads.iter_mut().zip(values) {
+            // newest first, like the backwards search of get_value_reconstruct_data
+            values.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+            let mut need_image = true;
+            for (lsn, val) in values {
+                if push_reconstruct_value(lsn, val, &mut read.state) {
+                    need_image = false;
+                    break;
+                }
+            }
+            results.push(if need_image {
+                ValueReconstructResult::Continue
+            } else {
+                ValueReconstructResult::Complete
+            });
+        }
+        Ok(results)
+    }
+
+    /// Reads the values at `offsets`, which are ordered from newest to oldest, into
+    /// `reconstruct_state`, until a page image or a record that initializes the page.
+    async fn read_reconstruct_values(
+        &self,
+        offsets: &[(Lsn, u64)],
+        reconstruct_state: &mut ValueReconstructState,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<ValueReconstructResult> {
+        let mut need_image = true;
+        let file = &self.file;
         let cursor = file.block_cursor();
         let mut buf = Vec::new();
-        for (entry_lsn, pos) in offsets {
+        for &(entry_lsn, pos) in offsets {
             cursor
-                .read_blob_into_buf(pos, &mut buf, ctx)
+                .read_blob_into_buf_with_format(pos, &mut buf, self.blob_format, ctx)
                 .await
                 .with_context(|| {
                     format!("Failed to read blob from virtual file {}", file.file.path)
@@ -785,21 +991,9 @@ impl DeltaLayerInner {
                     file.file.path
                 )
             })?;
-            match val {
-                Value::Image(img) => {
-                    reconstruct_state.img = Some((entry_lsn, img));
-                    need_image = false;
-                    break;
-                }
-                Value::WalRecord(rec) => {
-                    let will_init = rec.will_init();
-                    reconstruct_state.records.push((entry_lsn, rec));
-                    if will_init {
-                        // This WAL record initializes the page, so no need to go further back
-                        need_image = false;
-                        break;
-                    }
-                }
+            if push_reconstruct_value(entry_lsn, val, reconstruct_state) {
+                need_image = false;
+                break;
             }
         }
 
@@ -834,6 +1028,7 @@ impl DeltaLayerInner {
                     let delta_key = DeltaKey::from_slice(key);
                     let val_ref = ValueRef {
                         blob_ref: BlobRef(value),
+                        blob_format: self.blob_format,
                         reader: BlockCursor::new(crate::tenant::block_io::BlockReaderRef::Adapter(
                             Adapter(self),
                         )),
@@ -867,6 +1062,45 @@ impl DeltaLayerInner {
         Ok(all_keys)
     }
 
+    /// Lists the keys in `key_range` that have a value below `lsn_end` in this layer, in order.
+    pub(super) async fn list_keys(
+        &self,
+        key_range: &Range<Key>,
+        lsn_end: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<Vec<Key>> {
+        let file = &self.file;
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            self.index_start_blk,
+            self.index_root_blk,
+            file,
+        );
+
+        let search_key = DeltaKey::from_key_lsn(&key_range.start, Lsn(0));
+        let mut keys: Vec<Key> = Vec::new();
+        tree_reader
+            .visit(
+                &search_key.0,
+                VisitDirection::Forwards,
+                |raw_key, _value| {
+                    let delta_key = DeltaKey::from_slice(raw_key);
+                    let key = delta_key.key();
+                    if key >= key_range.end {
+                        return false;
+                    }
+                    if delta_key.lsn() < lsn_end && keys.last() != Some(&key) {
+                        keys.push(key);
+                    }
+                    true
+                },
+                &RequestContextBuilder::extend(ctx)
+                    .page_content_kind(PageContentKind::DeltaLayerBtreeNode)
+                    .build(),
+            )
+            .await?;
+        Ok(keys)
+    }
+
     pub(super) async fn dump(&self, ctx: &RequestContext) -> anyhow::Result<()> {
         println!(
             "index_start_blk: {}, root {}",
@@ -885,7 +1119,7 @@ impl DeltaLayerInner {
         let keys = self.load_keys(ctx).await?;
 
         async fn dump_blob(val: ValueRef<'_>, ctx: &RequestContext) -> anyhow::Result<String> {
-            let buf = val.reader.read_blob(val.blob_ref.pos(), ctx).await?;
+            let buf = val.load_bytes(ctx).await?;
             let val = Value::des(&buf)?;
             let desc = match val {
                 Value::Image(img) => {
@@ -932,6 +1166,7 @@ pub struct DeltaEntry<'a> {
 /// Reference to an on-disk value
 pub struct ValueRef<'a> {
     blob_ref: BlobRef,
+    blob_format: BlobFormat,
     reader: BlockCursor<'a>,
 }
 
@@ -939,10 +1174,19 @@ impl<'a> ValueRef<'a> {
     /// Loads the value from disk
     pub async fn load(&self, ctx: &RequestContext) -> Result<Value> {
         // theoretically we *could* record an access time for each, but it does not really matter
-        let buf = self.reader.read_blob(self.blob_ref.pos(), ctx).await?;
+        let buf = self.load_bytes(ctx).await?;
         let val = Value::des(&buf)?;
         Ok(val)
     }
+
+    /// Loads the serialized value from disk, decompressing it if needed
+    async fn load_bytes(&self, ctx: &RequestContext) -> Result<Vec<u8>> {
+        let buf = self
+            .reader
+            .read_blob_with_format(self.blob_ref.pos(), self.blob_format, ctx)
+            .await?;
+        Ok(buf)
+    }
 }
 
 pub(crate) struct Adapter<T>(T);
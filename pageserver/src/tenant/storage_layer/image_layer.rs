@@ -27,20 +27,26 @@ use crate::config::PageServerConf;
 use crate::context::{PageContentKind, RequestContext, RequestContextBuilder};
 use crate::page_cache::PAGE_SZ;
 use crate::repository::{Key, KEY_SIZE};
-use crate::tenant::blob_io::BlobWriter;
+use crate::tenant::blob_io::{BlobFormat, BlobWriter};
 use crate::tenant::block_io::{BlockBuf, BlockReader, FileBlockReader};
 use crate::tenant::disk_btree::{DiskBtreeBuilder, DiskBtreeReader, VisitDirection};
 use crate::tenant::storage_layer::{
-    LayerAccessStats, ValueReconstructResult, ValueReconstructState,
+    layer_file_checksum_from_parts, BatchedValueRead, LayerAccessStats, ValueReconstructResult,
+    ValueReconstructState,
+};
+use crate::tenant::vectored_blob_io::{
+    VectoredBlobReader, VectoredReadPlanner, MAX_VECTORED_READ_BYTES,
 };
 use crate::tenant::Timeline;
 use crate::virtual_file::VirtualFile;
-use crate::{IMAGE_FILE_MAGIC, STORAGE_FORMAT_VERSION, TEMP_FILE_SUFFIX};
+use crate::{
+    IMAGE_FILE_MAGIC, STORAGE_FORMAT_VERSION, STORAGE_FORMAT_VERSION_UNCOMPRESSED, TEMP_FILE_SUFFIX,
+};
 use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
 use camino::{Utf8Path, Utf8PathBuf};
 use hex;
-use pageserver_api::models::LayerAccessKind;
+use pageserver_api::models::{CompressionAlgorithm, LayerAccessKind};
 use pageserver_api::shard::TenantShardId;
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
@@ -150,6 +156,9 @@ pub struct ImageLayerInner {
 
     lsn: Lsn,
 
+    /// How blobs in the file are encoded, derived from the summary's format version.
+    blob_format: BlobFormat,
+
     /// Reader object for reading blocks from the file.
     file: FileBlockReader,
 }
@@ -388,6 +397,10 @@ impl ImageLayerInner {
             // production code path
             expected_summary.index_start_blk = actual_summary.index_start_blk;
             expected_summary.index_root_blk = actual_summary.index_root_blk;
+            // Layers written with compression disabled use the older format version.
+            if actual_summary.format_version == STORAGE_FORMAT_VERSION_UNCOMPRESSED {
+                expected_summary.format_version = actual_summary.format_version;
+            }
 
             if actual_summary != expected_summary {
                 bail!(
@@ -402,6 +415,7 @@ impl ImageLayerInner {
             index_start_blk: actual_summary.index_start_blk,
             index_root_blk: actual_summary.index_root_blk,
             lsn,
+            blob_format: BlobFormat::from_format_version(actual_summary.format_version),
             file,
         }))
     }
@@ -428,8 +442,9 @@ impl ImageLayerInner {
         {
             let blob = file
                 .block_cursor()
-                .read_blob(
+                .read_blob_with_format(
                     offset,
+                    self.blob_format,
                     &RequestContextBuilder::extend(ctx)
                         .page_content_kind(PageContentKind::ImageLayerValue)
                         .build(),
@@ -444,6 +459,131 @@ impl ImageLayerInner {
             Ok(ValueReconstructResult::Missing)
         }
     }
+
+    /// Lists the keys of the images in `key_range` in this layer, in order.
+    pub(super) async fn list_keys(
+        &self,
+        key_range: &Range<Key>,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<Key>> {
+        let file = &self.file;
+        let tree_reader = DiskBtreeReader::new(self.index_start_blk, self.index_root_blk, file);
+
+        let mut keybuf: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
+        key_range.start.write_to_byte_slice(&mut keybuf);
+
+        let mut keys = Vec::new();
+        tree_reader
+            .visit(
+                &keybuf,
+                VisitDirection::Forwards,
+                |raw_key, _offset| {
+                    let key = Key::from_slice(raw_key);
+                    if key >= key_range.end {
+                        return false;
+                    }
+                    keys.push(key);
+                    true
+                },
+                &RequestContextBuilder::extend(ctx)
+                    .page_content_kind(PageContentKind::ImageLayerBtreeNode)
+                    .build(),
+            )
+            .await?;
+        Ok(keys)
+    }
+
+    pub(super) async fn get_values_reconstruct_data(
+        &self,
+        reads: &mut [BatchedValueRead],
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<ValueReconstructResult>> {
+        let Some(first) = reads.first() else {
+            return Ok(Vec::new());
+        };
+
+        // Scan the index forwards once, from the first key to the last.
+        let file = &self.file;
+        let tree_reader = DiskBtreeReader::new(self.index_start_blk, self.index_root_blk, file);
+
+        let mut keybuf: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
+        first.key.write_to_byte_slice(&mut keybuf);
+
+        let wanted: Vec<Key> = reads.iter().map(|read| read.key).collect();
+        // (start, end) of the image of each key. The end of an image is not known until we see
+        // the next entry of the index, see `vectored_blob_io`.
+        let mut blobs: Vec<Option<(u64, u64)>> = vec![None; reads.len()];
+        let mut last_wanted: Option<usize> = None;
+        let mut next = 0;
+
+        tree_reader
+            .visit(
+                &keybuf,
+                VisitDirection::Forwards,
+                |raw_key, offset| {
+                    if let Some(i) = last_wanted.take() {
+                        if let Some(blob) = &mut blobs[i] {
+                            blob.1 = offset;
+                        }
+                    }
+
+                    let key = Key::from_slice(raw_key);
+                    while next < wanted.len() && wanted[next] < key {
+                        next += 1;
+                    }
+                    if next == wanted.len() {
+                        return false;
+                    }
+                    if wanted[next] == key {
+                        blobs[next] = Some((offset, u64::MAX));
+                        last_wanted = Some(next);
+                    }
+                    true
+                },
+                &RequestContextBuilder::extend(ctx)
+                    .page_content_kind(PageContentKind::ImageLayerBtreeNode)
+                    .build(),
+            )
+            .await?;
+
+        // ImageLayerWriter appends to the index in the order of the images, so the next entry
+        // is the next image, except for the last one before the index.
+        let index_start = self.index_start_blk as u64 * PAGE_SZ as u64;
+        let mut planner = VectoredReadPlanner::new(MAX_VECTORED_READ_BYTES);
+        for (key, blob) in wanted.iter().zip(&blobs) {
+            if let Some((start, end)) = blob {
+                planner.handle(*key, self.lsn, *start, (*end).min(index_start));
+            }
+        }
+
+        let file = &self.file.file;
+        let reader = VectoredBlobReader::new(file, self.blob_format);
+        let mut buf = Vec::new();
+        for read in planner.finish() {
+            let blobs = reader.read_blobs(&read, &mut buf).await.with_context(|| {
+                format!(
+                    "failed to read {} bytes at {} from {}",
+                    read.size(),
+                    read.start,
+                    file.path
+                )
+            })?;
+            for (meta, blob) in blobs {
+                let i = wanted
+                    .binary_search(&meta.key)
+                    .expect("only wanted keys are read");
+                reads[i].state.img = Some((self.lsn, Bytes::from(blob)));
+            }
+        }
+
+        Ok(blobs
+            .iter()
+            .map(|blob| match blob {
+                Some(_) => ValueReconstructResult::Complete,
+                None => ValueReconstructResult::Missing,
+            })
+            .collect())
+    }
 }
 
 /// A builder object for constructing a new image layer.
@@ -464,6 +604,7 @@ struct ImageLayerWriterInner {
     tenant_shard_id: TenantShardId,
     key_range: Range<Key>,
     lsn: Lsn,
+    compression: CompressionAlgorithm,
 
     blob_writer: BlobWriter<false>,
     tree: DiskBtreeBuilder<BlockBuf, KEY_SIZE>,
@@ -479,6 +620,7 @@ impl ImageLayerWriterInner {
         tenant_shard_id: TenantShardId,
         key_range: &Range<Key>,
         lsn: Lsn,
+        compression: CompressionAlgorithm,
     ) -> anyhow::Result<Self> {
         // Create the file initially with a temporary filename.
         // We'll atomically rename it to the final name when we're done.
@@ -512,6 +654,7 @@ impl ImageLayerWriterInner {
             tenant_shard_id,
             key_range: key_range.clone(),
             lsn,
+            compression,
             tree: tree_builder,
             blob_writer,
         };
@@ -526,7 +669,14 @@ impl ImageLayerWriterInner {
     ///
     async fn put_image(&mut self, key: Key, img: &[u8]) -> anyhow::Result<()> {
         ensure!(self.key_range.contains(&key));
-        let off = self.blob_writer.write_blob(img).await?;
+        let off = match self.compression {
+            CompressionAlgorithm::Disabled => self.blob_writer.write_blob(img).await?,
+            compression => {
+                self.blob_writer
+                    .write_blob_compressed(img, compression)
+                    .await?
+            }
+        };
 
         let mut keybuf: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
         key.write_to_byte_slice(&mut keybuf);
@@ -542,6 +692,12 @@ impl ImageLayerWriterInner {
         let index_start_blk =
             ((self.blob_writer.size() + PAGE_SZ as u64 - 1) / PAGE_SZ as u64) as u32;
 
+        // The checksum of everything after the summary block: the blobs, the padding up to the
+        // index and the index.
+        let padding = (index_start_blk as u64 * PAGE_SZ as u64 - self.blob_writer.size()) as usize;
+        let mut checksum =
+            crc32c::crc32c_append(self.blob_writer.checksum(), &[0; PAGE_SZ][..padding]);
+
         let mut file = self.blob_writer.into_inner();
 
         // Write out the index
@@ -550,12 +706,13 @@ impl ImageLayerWriterInner {
         let (index_root_blk, block_buf) = self.tree.finish()?;
         for buf in block_buf.blocks {
             file.write_all(buf.as_ref()).await?;
+            checksum = crc32c::crc32c_append(checksum, buf.as_ref());
         }
 
         // Fill in the summary on blk 0
         let summary = Summary {
             magic: IMAGE_FILE_MAGIC,
-            format_version: STORAGE_FORMAT_VERSION,
+            format_version: BlobFormat::for_compression(self.compression).format_version(),
             tenant_id: self.tenant_shard_id.tenant_id,
             timeline_id: self.timeline_id,
             key_range: self.key_range.clone(),
@@ -597,7 +754,10 @@ impl ImageLayerWriterInner {
         file.sync_all().await?;
 
         // FIXME: why not carry the virtualfile here, it supports renaming?
-        let layer = Layer::finish_creating(self.conf, timeline, desc, &self.path)?;
+        let checksum =
+            layer_file_checksum_from_parts(&buf, checksum, metadata.len() - PAGE_SZ as u64);
+
+        let layer = Layer::finish_creating(self.conf, timeline, desc, checksum, &self.path)?;
 
         trace!("created image layer {}", layer.local_path());
 
@@ -641,11 +801,19 @@ impl ImageLayerWriter {
         tenant_shard_id: TenantShardId,
         key_range: &Range<Key>,
         lsn: Lsn,
+        compression: CompressionAlgorithm,
     ) -> anyhow::Result<ImageLayerWriter> {
         Ok(Self {
             inner: Some(
-                ImageLayerWriterInner::new(conf, timeline_id, tenant_shard_id, key_range, lsn)
-                    .await?,
+                ImageLayerWriterInner::new(
+                    conf,
+                    timeline_id,
+                    tenant_shard_id,
+                    key_range,
+                    lsn,
+                    compression,
+                )
+                .await?,
             ),
         })
     }
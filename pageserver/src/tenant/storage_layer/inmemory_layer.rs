@@ -9,7 +9,9 @@ use crate::context::{PageContentKind, RequestContext, RequestContextBuilder};
 use crate::repository::{Key, Value};
 use crate::tenant::block_io::BlockReader;
 use crate::tenant::ephemeral_file::EphemeralFile;
-use crate::tenant::storage_layer::{ValueReconstructResult, ValueReconstructState};
+use crate::tenant::storage_layer::{
+    BatchedValueRead, ValueReconstructResult, ValueReconstructState,
+};
 use crate::tenant::Timeline;
 use crate::walrecord;
 use anyhow::{ensure, Result};
@@ -202,6 +204,27 @@ impl InMemoryLayer {
             Ok(ValueReconstructResult::Complete)
         }
     }
+
+    /// Look up given values in the layer, one key after the other.
+    pub(crate) async fn get_values_reconstruct_data(
+        &self,
+        reads: &mut [BatchedValueRead],
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<ValueReconstructResult>> {
+        let mut results = Vec::with_capacity(reads.len());
+        for read in reads.iter_mut() {
+            results.push(
+                self.get_value_reconstruct_data(
+                    read.key,
+                    read.lsn_range.clone(),
+                    &mut read.state,
+                    ctx,
+                )
+                .await?,
+            );
+        }
+        Ok(results)
+    }
 }
 
 impl std::fmt::Display for InMemoryLayer {
@@ -212,6 +235,21 @@ impl std::fmt::Display for InMemoryLayer {
 }
 
 impl InMemoryLayer {
+    /// Lists the keys in `key_range` that have a value below `lsn_end` in this layer, in order.
+    pub(crate) async fn list_keys(&self, key_range: &Range<Key>, lsn_end: Lsn) -> Vec<Key> {
+        let inner = self.inner.read().await;
+        let mut keys: Vec<Key> = inner
+            .index
+            .iter()
+            .filter(|(key, vec_map)| {
+                key_range.contains(key) && !vec_map.slice_range(..lsn_end).is_empty()
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
     /// Get layer size.
     pub async fn size(&self) -> Result<u64> {
         let inner = self.inner.read().await;
@@ -359,6 +397,7 @@ impl InMemoryLayer {
             self.tenant_shard_id,
             Key::MIN,
             self.start_lsn..end_lsn,
+            timeline.get_blob_compression(),
         )
         .await?;
 
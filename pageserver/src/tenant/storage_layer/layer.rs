@@ -20,8 +20,8 @@ use crate::tenant::{remote_timeline_client::LayerFileMetadata, RemoteTimelineCli
 use super::delta_layer::{self, DeltaEntry};
 use super::image_layer;
 use super::{
-    AsLayerDesc, LayerAccessStats, LayerAccessStatsReset, LayerFileName, PersistentLayerDesc,
-    ValueReconstructResult, ValueReconstructState,
+    AsLayerDesc, BatchedValueRead, LayerAccessStats, LayerAccessStatsReset, LayerFileName,
+    PersistentLayerDesc, ValueReconstructResult, ValueReconstructState,
 };
 
 use utils::generation::Generation;
@@ -98,6 +98,7 @@ impl Layer {
             None,
             metadata.generation,
             metadata.shard,
+            metadata.checksum,
         )));
 
         debug_assert!(owner.0.needs_download_blocking().unwrap().is_some());
@@ -139,6 +140,7 @@ impl Layer {
                 Some(inner),
                 metadata.generation,
                 metadata.shard,
+                metadata.checksum,
             )
         }));
 
@@ -155,10 +157,15 @@ impl Layer {
 
     /// Creates a Layer value for freshly written out new layer file by renaming it from a
     /// temporary path.
+    ///
+    /// `checksum` is the [`layer_file_checksum`] of the file at `temp_path`.
+    ///
+    /// [`layer_file_checksum`]: crate::tenant::remote_timeline_client::layer_file_checksum
     pub(crate) fn finish_creating(
         conf: &'static PageServerConf,
         timeline: &Arc<Timeline>,
         desc: PersistentLayerDesc,
+        checksum: u32,
         temp_path: &Utf8Path,
     ) -> anyhow::Result<ResidentLayer> {
         let mut resident = None;
@@ -183,6 +190,7 @@ impl Layer {
                 Some(inner),
                 timeline.generation,
                 timeline.get_shard_index(),
+                Some(checksum),
             )
         }));
 
@@ -264,6 +272,68 @@ impl Layer {
             .with_context(|| format!("get_value_reconstruct_data for layer {self}"))
     }
 
+    /// Look up several keys in this layer at once, see [`BatchedValueRead`].
+    ///
+    /// The layer file is searched once for all of the keys, which must be sorted and unique.
+    /// Returns the result for each of the `reads`, in the same order.
+    ///
+    /// # Cancellation-Safety
+    ///
+    /// This method is cancellation-safe.
+    pub(crate) async fn get_values_reconstruct_data(
+        &self,
+        reads: &mut [BatchedValueRead],
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<ValueReconstructResult>> {
+        use anyhow::ensure;
+
+        let layer = self.0.get_or_maybe_download(true, Some(ctx)).await?;
+        self.0
+            .access_stats
+            .record_access(LayerAccessKind::GetValueReconstructData, ctx);
+
+        for read in reads.iter() {
+            if self.layer_desc().is_delta {
+                ensure!(read.lsn_range.start >= self.layer_desc().lsn_range.start);
+                ensure!(self.layer_desc().key_range.contains(&read.key));
+            } else {
+                ensure!(self.layer_desc().key_range.contains(&read.key));
+                ensure!(read.lsn_range.start >= self.layer_desc().image_layer_lsn());
+                ensure!(read.lsn_range.end >= self.layer_desc().image_layer_lsn());
+            }
+        }
+        ensure!(
+            reads.windows(2).all(|w| w[0].key < w[1].key),
+            "keys of a batched read must be sorted and unique"
+        );
+
+        layer
+            .get_values_reconstruct_data(reads, &self.0, ctx)
+            .instrument(tracing::debug_span!("get_values_reconstruct_data", layer=%self))
+            .await
+            .with_context(|| format!("get_values_reconstruct_data for layer {self}"))
+    }
+
+    /// Lists the keys in `key_range` that have a value below `lsn_end` in this layer, in order.
+    /// Image layers hold a single version of each key, `lsn_end` is not consulted for them.
+    pub(crate) async fn list_keys(
+        &self,
+        key_range: &Range<Key>,
+        lsn_end: Lsn,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<Key>> {
+        let layer = self.0.get_or_maybe_download(true, Some(ctx)).await?;
+        self.0
+            .access_stats
+            .record_access(LayerAccessKind::KeyIter, ctx);
+
+        layer
+            .list_keys(key_range, lsn_end, &self.0, ctx)
+            .instrument(tracing::debug_span!("list_keys", layer=%self))
+            .await
+            .with_context(|| format!("list_keys for layer {self}"))
+    }
+
     /// Download the layer if evicted.
     ///
     /// Will not error when the layer is already downloaded.
@@ -459,6 +529,12 @@ struct LayerInner {
     /// a shard split since the layer was originally written.
     shard: ShardIndex,
 
+    /// CRC32C of the layer file contents.
+    ///
+    /// Always known for layers created in this process; loaded layers have it if the
+    /// [`LayerFileMetadata`] they were loaded from had it.
+    checksum: Option<u32>,
+
     last_evicted_at: std::sync::Mutex<Option<std::time::Instant>>,
 }
 
@@ -562,6 +638,7 @@ impl LayerInner {
         downloaded: Option<Arc<DownloadedLayer>>,
         generation: Generation,
         shard: ShardIndex,
+        checksum: Option<u32>,
     ) -> Self {
         let path = conf
             .timeline_path(&timeline.tenant_shard_id, &timeline.timeline_id)
@@ -590,6 +667,7 @@ impl LayerInner {
             consecutive_failures: AtomicUsize::new(0),
             generation,
             shard,
+            checksum,
             last_evicted_at: std::sync::Mutex::default(),
         }
     }
@@ -1146,7 +1224,12 @@ impl LayerInner {
     }
 
     fn metadata(&self) -> LayerFileMetadata {
-        LayerFileMetadata::new(self.desc.file_size, self.generation, self.shard)
+        LayerFileMetadata::new(
+            self.desc.file_size,
+            self.generation,
+            self.shard,
+            self.checksum,
+        )
     }
 }
 
@@ -1329,6 +1412,35 @@ impl DownloadedLayer {
         }
     }
 
+    async fn get_values_reconstruct_data(
+        &self,
+        reads: &mut [BatchedValueRead],
+        owner: &Arc<LayerInner>,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<ValueReconstructResult>> {
+        use LayerKind::*;
+
+        match self.get(owner, ctx).await? {
+            Delta(d) => d.get_values_reconstruct_data(reads, ctx).await,
+            Image(i) => i.get_values_reconstruct_data(reads, ctx).await,
+        }
+    }
+
+    async fn list_keys(
+        &self,
+        key_range: &Range<Key>,
+        lsn_end: Lsn,
+        owner: &Arc<LayerInner>,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<Key>> {
+        use LayerKind::*;
+
+        match self.get(owner, ctx).await? {
+            Delta(d) => d.list_keys(key_range, lsn_end, ctx).await,
+            Image(i) => i.list_keys(key_range, ctx).await,
+        }
+    }
+
     async fn dump(&self, owner: &Arc<LayerInner>, ctx: &RequestContext) -> anyhow::Result<()> {
         use LayerKind::*;
         match self.get(owner, ctx).await? {
@@ -1,8 +1,10 @@
 pub mod delete;
+pub(crate) mod detach_ancestor;
 mod eviction_task;
 mod init;
 pub mod layer_manager;
 pub(crate) mod logical_size;
+pub(crate) mod offload;
 pub mod span;
 pub mod uninit;
 mod walreceiver;
@@ -15,8 +17,8 @@ use fail::fail_point;
 use itertools::Itertools;
 use pageserver_api::{
     models::{
-        DownloadRemoteLayersTaskInfo, DownloadRemoteLayersTaskSpawnRequest, LayerMapInfo,
-        TimelineState,
+        CompressionAlgorithm, DownloadRemoteLayersTaskInfo, DownloadRemoteLayersTaskSpawnRequest,
+        LayerMapInfo, TimelineState,
     },
     shard::{ShardIdentity, TenantShardId},
 };
@@ -31,10 +33,10 @@ use tokio_util::sync::CancellationToken;
 use tracing::*;
 use utils::sync::gate::Gate;
 
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::ops::{Deref, Range};
 use std::pin::pin;
-use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::time::{Duration, Instant, SystemTime};
 use std::{
@@ -47,9 +49,9 @@ use crate::context::{
 };
 use crate::tenant::storage_layer::delta_layer::DeltaEntry;
 use crate::tenant::storage_layer::{
-    AsLayerDesc, DeltaLayerWriter, EvictionError, ImageLayerWriter, InMemoryLayer, Layer,
-    LayerAccessStatsReset, LayerFileName, ResidentLayer, ValueReconstructResult,
-    ValueReconstructState,
+    range_overlaps, AsLayerDesc, BatchedValueRead, DeltaLayerWriter, EvictionError,
+    ImageLayerWriter, InMemoryLayer, Layer, LayerAccessStatsReset, LayerFileName, ResidentLayer,
+    ValueReconstructResult, ValueReconstructState,
 };
 use crate::tenant::tasks::BackgroundLoopKind;
 use crate::tenant::timeline::logical_size::CurrentLogicalSize;
@@ -60,8 +62,9 @@ use crate::tenant::{
 };
 use crate::{deletion_queue::DeletionQueueClient, tenant::remote_timeline_client::StopError};
 
+use crate::basebackup::BasebackupCache;
 use crate::config::PageServerConf;
-use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceRandomAccum};
+use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceAccum, KeySpaceRandomAccum};
 use crate::metrics::{
     TimelineMetrics, MATERIALIZED_PAGE_CACHE_HIT, MATERIALIZED_PAGE_CACHE_HIT_DIRECT,
 };
@@ -88,6 +91,7 @@ use crate::repository::GcResult;
 use crate::repository::{Key, Value};
 use crate::task_mgr;
 use crate::task_mgr::TaskKind;
+use crate::walredo::WalRedoRequest;
 use crate::ZERO_PAGE;
 
 use self::delete::DeleteTimelineFlow;
@@ -153,7 +157,7 @@ pub struct TimelineResources {
 }
 
 pub struct Timeline {
-    conf: &'static PageServerConf,
+    pub(crate) conf: &'static PageServerConf,
     tenant_conf: Arc<RwLock<AttachedTenantConf>>,
 
     myself: Weak<Self>,
@@ -244,6 +248,14 @@ pub struct Timeline {
     ancestor_timeline: Option<Arc<Timeline>>,
     ancestor_lsn: Lsn,
 
+    /// Set once [`detach_ancestor`] has copied the ancestor's layers over. Until the tenant is
+    /// reset, `ancestor_timeline` is still set, but the ancestor must no longer be persisted.
+    detached_from_ancestor: AtomicBool,
+
+    /// Held for the duration of [`detach_ancestor`], so that concurrent requests do not both
+    /// copy the ancestor's layers. `true` once the detach has completed.
+    detach_ancestor_lock: tokio::sync::Mutex<bool>,
+
     pub(super) metrics: TimelineMetrics,
 
     /// Ensures layers aren't frozen by checkpointer between
@@ -297,6 +309,9 @@ pub struct Timeline {
     /// Relation size cache
     pub rel_size_cache: RwLock<HashMap<RelTag, (Lsn, BlockNumber)>>,
 
+    /// The most recent basebackup, if `basebackup_cache` is enabled in the config.
+    pub(crate) basebackup_cache: BasebackupCache,
+
     download_all_remote_layers_task_info: RwLock<Option<DownloadRemoteLayersTaskInfo>>,
 
     state: watch::Sender<TimelineState>,
@@ -305,6 +320,11 @@ pub struct Timeline {
     /// timeline is being deleted. If 'true', the timeline has already been deleted.
     pub delete_progress: Arc<tokio::sync::Mutex<DeleteTimelineFlow>>,
 
+    /// Held for reading while branching off the timeline, and for writing while archiving it,
+    /// so that no branch is created after the archival has checked that there are none. If
+    /// `true`, the timeline has been archived.
+    pub(crate) archival_lock: tokio::sync::RwLock<bool>,
+
     eviction_task_timeline_state: tokio::sync::Mutex<EvictionTaskTimelineState>,
 
     /// Load or creation time information about the disk_consistent_lsn and when the loading
@@ -402,6 +422,28 @@ enum FlushLayerError {
     Other(#[from] anyhow::Error),
 }
 
+/// An error that fails a [`Timeline::get_vectored`] as a whole.
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum GetVectoredError {
+    #[error("requested too many keys: {0} > {}", Timeline::MAX_GET_VECTORED_KEYS)]
+    Oversized(usize),
+
+    #[error("requested at invalid LSN: {0}")]
+    InvalidLsn(Lsn),
+
+    #[error(transparent)]
+    PageReconstructError(#[from] PageReconstructError),
+}
+
+impl From<GetVectoredError> for PageReconstructError {
+    fn from(e: GetVectoredError) -> Self {
+        match e {
+            GetVectoredError::PageReconstructError(e) => e,
+            e => PageReconstructError::Other(e.into()),
+        }
+    }
+}
+
 impl std::fmt::Debug for PageReconstructError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
@@ -454,13 +496,22 @@ impl std::fmt::Debug for Timeline {
 
 /// Public interface functions
 impl Timeline {
+    /// Maximum number of keys of a [`Self::get_vectored`] request.
+    pub(crate) const MAX_GET_VECTORED_KEYS: usize = 128;
+
     /// Get the LSN where this branch was created
     pub fn get_ancestor_lsn(&self) -> Lsn {
+        if self.detached_from_ancestor.load(AtomicOrdering::Relaxed) {
+            return Lsn(0);
+        }
         self.ancestor_lsn
     }
 
     /// Get the ancestor's timeline id
     pub fn get_ancestor_timeline_id(&self) -> Option<TimelineId> {
+        if self.detached_from_ancestor.load(AtomicOrdering::Relaxed) {
+            return None;
+        }
         self.ancestor_timeline
             .as_ref()
             .map(|ancestor| ancestor.timeline_id)
@@ -571,6 +622,234 @@ impl Timeline {
         res
     }
 
+    /// Look up the values of all the keys of `keyspace` at the same LSN.
+    ///
+    /// Returns the same values as calling [`Self::get`] for each of the keys, but the layers are
+    /// traversed for all of the keys together: each layer on the way is searched once for all
+    /// the keys that need it, and its values are read with a few large reads. The WAL redo
+    /// requests of the keys are also sent together. A key that fails to reconstruct doesn't
+    /// fail the others.
+    ///
+    /// The key space must not have more than [`Self::MAX_GET_VECTORED_KEYS`] keys.
+    ///
+    /// # Cancel-Safety
+    ///
+    /// This method is cancellation-safe.
+    pub(crate) async fn get_vectored(
+        &self,
+        keyspace: &KeySpace,
+        lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<BTreeMap<Key, Result<Bytes, PageReconstructError>>, GetVectoredError> {
+        self.get_vectored_impl(keyspace, lsn, false, ctx).await
+    }
+
+    /// Look up the values of the keys in `key_range` that exist at `lsn`.
+    ///
+    /// Meant for sparse key spaces, like the one of the aux files, where most keys of the
+    /// range have never been written: the candidate keys are listed from the layers that
+    /// may hold them, and their values are then read with [`Self::get_vectored`]. A key that
+    /// has no value at `lsn` is left out of the result rather than being an error.
+    ///
+    /// Keys of a sparse key space are not inherited from the ancestor timeline, see
+    /// [`is_inherited_key`].
+    ///
+    /// # Cancel-Safety
+    ///
+    /// This method is cancellation-safe.
+    pub(crate) async fn scan_sparse(
+        &self,
+        key_range: Range<Key>,
+        lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<BTreeMap<Key, Bytes>, GetVectoredError> {
+        if !lsn.is_valid() {
+            return Err(GetVectoredError::InvalidLsn(lsn));
+        }
+        debug_assert!(!is_inherited_key(key_range.start));
+
+        let lsn_end = Lsn(lsn.0 + 1);
+        let mut in_memory_layers = Vec::new();
+        let mut historic_layers = Vec::new();
+        {
+            let guard = self.layers.read().await;
+            let layers = guard.layer_map();
+            in_memory_layers.extend(
+                layers
+                    .open_layer
+                    .iter()
+                    .chain(layers.frozen_layers.iter())
+                    .filter(|l| l.get_lsn_range().start < lsn_end)
+                    .cloned(),
+            );
+            // Like in the page reconstruction, the search for each part of the range stops
+            // at the newest image layer that covers it: image layers are authoritative for
+            // their key range, the older layers don't have any more keys.
+            let coverage = layers
+                .image_coverage(&key_range, lsn)
+                .map_err(PageReconstructError::Other)?;
+            for (range, image) in coverage {
+                let lsn_floor = image.map_or(Lsn(0), |image| image.lsn_range.start);
+                for desc in layers.iter_historic_layers() {
+                    if range_overlaps(&desc.key_range, &range)
+                        && desc.lsn_range.start < lsn_end
+                        && desc.lsn_range.end > lsn_floor
+                    {
+                        historic_layers.push((guard.get_from_desc(&desc), range.clone()));
+                    }
+                }
+            }
+        }
+
+        let mut keys = BTreeSet::new();
+        for layer in in_memory_layers {
+            keys.extend(layer.list_keys(&key_range, lsn_end).await);
+        }
+        for (layer, range) in historic_layers {
+            let layer_keys = layer
+                .list_keys(&range, lsn_end, ctx)
+                .await
+                .map_err(PageReconstructError::from)?;
+            keys.extend(layer_keys);
+        }
+
+        let mut values = BTreeMap::new();
+        let keys = keys.into_iter().collect::<Vec<_>>();
+        for chunk in keys.chunks(Self::MAX_GET_VECTORED_KEYS) {
+            let mut keyspace = KeySpaceAccum::new();
+            for key in chunk {
+                keyspace.add_key(*key);
+            }
+            let results = self
+                .get_vectored_impl(&keyspace.to_keyspace(), lsn, true, ctx)
+                .await?;
+            for (key, res) in results {
+                values.insert(key, res?);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Look up the value of a single key of a sparse key space, see [`Self::scan_sparse`].
+    /// Returns `None` if the key has no value at `lsn`.
+    pub(crate) async fn get_sparse(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<Option<Bytes>, PageReconstructError> {
+        let keyspace = KeySpace {
+            ranges: vec![key..key.next()],
+        };
+        let mut values = self.get_vectored_impl(&keyspace, lsn, true, ctx).await?;
+        values.remove(&key).transpose()
+    }
+
+    /// Implementation of [`Self::get_vectored`]. With `sparse`, keys that have no value at
+    /// `lsn` are left out of the result instead of failing.
+    async fn get_vectored_impl(
+        &self,
+        keyspace: &KeySpace,
+        lsn: Lsn,
+        sparse: bool,
+        ctx: &RequestContext,
+    ) -> Result<BTreeMap<Key, Result<Bytes, PageReconstructError>>, GetVectoredError> {
+        if !lsn.is_valid() {
+            return Err(GetVectoredError::InvalidLsn(lsn));
+        }
+        let key_count = keyspace.total_size();
+        if key_count > Self::MAX_GET_VECTORED_KEYS {
+            return Err(GetVectoredError::Oversized(key_count));
+        }
+
+        let mut values = BTreeMap::new();
+        let mut traversals = Vec::with_capacity(key_count);
+        for range in &keyspace.ranges {
+            let mut key = range.start;
+            while key < range.end {
+                debug_assert!(!self.shard_identity.is_key_disposable(&key));
+
+                // Same use of the page cache as in `get`.
+                match self.lookup_cached_page(&key, lsn, ctx).await {
+                    Some((cached_lsn, cached_img)) if cached_lsn == lsn => {
+                        MATERIALIZED_PAGE_CACHE_HIT_DIRECT.inc();
+                        values.insert(key, Ok(cached_img));
+                    }
+                    cached_page_img => traversals.push(BatchedKeyTraversal {
+                        key,
+                        cached_lsn: match &cached_page_img {
+                            Some((cached_lsn, _)) => *cached_lsn,
+                            None => Lsn(0),
+                        },
+                        state: ValueReconstructState {
+                            records: Vec::new(),
+                            img: cached_page_img,
+                        },
+                        cont_lsn: Lsn(lsn.0 + 1),
+                        prev_lsn: Lsn(u64::MAX),
+                        sparse,
+                        missing: false,
+                        done: false,
+                    }),
+                }
+                key = key.next();
+            }
+        }
+
+        let timer = crate::metrics::GET_RECONSTRUCT_DATA_TIME.start_timer();
+        self.get_batch_reconstruct_data(&mut traversals, lsn, ctx)
+            .await?;
+        timer.stop_and_record();
+
+        // Values that need WAL redo are reconstructed together, the others right away.
+        let mut redo_keys = Vec::new();
+        let mut redo_requests = Vec::new();
+        for traversal in traversals {
+            if traversal.missing {
+                continue;
+            }
+            match self.plan_reconstruct_value(traversal.key, lsn, traversal.state) {
+                Ok(ReconstructPlan::Image(img)) => {
+                    values.insert(traversal.key, Ok(img));
+                }
+                Ok(ReconstructPlan::Redo {
+                    last_rec_lsn,
+                    request,
+                }) => {
+                    redo_keys.push((traversal.key, last_rec_lsn));
+                    redo_requests.push(request);
+                }
+                Err(e) => {
+                    values.insert(traversal.key, Err(e));
+                }
+            }
+        }
+
+        if !redo_requests.is_empty() {
+            let start = Instant::now();
+            let results = self
+                .walredo_mgr
+                .request_redo_batch(redo_requests, self.pg_version)
+                .await;
+            let elapsed = start.elapsed();
+            for ((key, last_rec_lsn), result) in redo_keys.into_iter().zip(results) {
+                let res = match result.context("Failed to reconstruct a page image:") {
+                    Ok(img) => self
+                        .memorize_reconstructed_page(key, last_rec_lsn, &img)
+                        .await
+                        .map(|()| img),
+                    Err(e) => Err(PageReconstructError::from(e)),
+                };
+                crate::metrics::RECONSTRUCT_TIME
+                    .for_result(&res)
+                    .observe(elapsed.as_secs_f64());
+                values.insert(key, res);
+            }
+        }
+
+        Ok(values)
+    }
+
     /// Get last or prev record separately. Same as get_last_record_rlsn().last/prev.
     pub fn get_last_record_lsn(&self) -> Lsn {
         self.last_record_lsn.load().last
@@ -1208,6 +1487,13 @@ impl Timeline {
             .unwrap_or(self.conf.default_tenant_conf.gc_feedback)
     }
 
+    pub(crate) fn get_blob_compression(&self) -> CompressionAlgorithm {
+        let tenant_conf = &self.tenant_conf.read().unwrap().tenant_conf;
+        tenant_conf
+            .blob_compression
+            .unwrap_or(self.conf.default_tenant_conf.blob_compression)
+    }
+
     pub(super) fn tenant_conf_updated(&self) {
         // NB: Most tenant conf options are read by background loops, so,
         // changes will automatically be picked up.
@@ -1302,6 +1588,8 @@ impl Timeline {
 
                 ancestor_timeline: ancestor,
                 ancestor_lsn: metadata.ancestor_lsn(),
+                detached_from_ancestor: AtomicBool::new(false),
+                detach_ancestor_lock: tokio::sync::Mutex::new(false),
 
                 metrics: TimelineMetrics::new(
                     &tenant_shard_id,
@@ -1342,6 +1630,7 @@ impl Timeline {
 
                 last_received_wal: Mutex::new(None),
                 rel_size_cache: RwLock::new(HashMap::new()),
+                basebackup_cache: BasebackupCache::new(),
 
                 download_all_remote_layers_task_info: RwLock::new(None),
 
@@ -1351,6 +1640,7 @@ impl Timeline {
                     EvictionTaskTimelineState::default(),
                 ),
                 delete_progress: Arc::new(tokio::sync::Mutex::new(DeleteTimelineFlow::default())),
+                archival_lock: tokio::sync::RwLock::new(false),
 
                 cancel,
                 gate: Gate::new(format!("Timeline<{tenant_shard_id}/{timeline_id}>")),
@@ -2250,59 +2540,7 @@ impl Timeline {
                     timeline.ancestor_lsn,
                     cont_lsn
                 );
-                let ancestor = match timeline.get_ancestor_timeline() {
-                    Ok(timeline) => timeline,
-                    Err(e) => return Err(PageReconstructError::from(e)),
-                };
-
-                // It's possible that the ancestor timeline isn't active yet, or
-                // is active but hasn't yet caught up to the branch point. Wait
-                // for it.
-                //
-                // This cannot happen while the pageserver is running normally,
-                // because you cannot create a branch from a point that isn't
-                // present in the pageserver yet. However, we don't wait for the
-                // branch point to be uploaded to cloud storage before creating
-                // a branch. I.e., the branch LSN need not be remote consistent
-                // for the branching operation to succeed.
-                //
-                // Hence, if we try to load a tenant in such a state where
-                // 1. the existence of the branch was persisted (in IndexPart and/or locally)
-                // 2. but the ancestor state is behind branch_lsn because it was not yet persisted
-                // then we will need to wait for the ancestor timeline to
-                // re-stream WAL up to branch_lsn before we access it.
-                //
-                // How can a tenant get in such a state?
-                // - ungraceful pageserver process exit
-                // - detach+attach => this is a bug, https://github.com/neondatabase/neon/issues/4219
-                //
-                // NB: this could be avoided by requiring
-                //   branch_lsn >= remote_consistent_lsn
-                // during branch creation.
-                match ancestor.wait_to_become_active(ctx).await {
-                    Ok(()) => {}
-                    Err(TimelineState::Stopping) => {
-                        return Err(PageReconstructError::AncestorStopping(ancestor.timeline_id));
-                    }
-                    Err(state) => {
-                        return Err(PageReconstructError::Other(anyhow::anyhow!(
-                            "Timeline {} will not become active. Current state: {:?}",
-                            ancestor.timeline_id,
-                            &state,
-                        )));
-                    }
-                }
-                ancestor
-                    .wait_lsn(timeline.ancestor_lsn, ctx)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "wait for lsn {} on ancestor timeline_id={}",
-                            timeline.ancestor_lsn, ancestor.timeline_id
-                        )
-                    })?;
-
-                timeline_owned = ancestor;
+                timeline_owned = timeline.get_ready_ancestor_timeline(ctx).await?;
                 timeline = &*timeline_owned;
                 prev_lsn = Lsn(u64::MAX);
                 continue 'outer;
@@ -2412,6 +2650,169 @@ impl Timeline {
         }
     }
 
+    /// Collects the data to reconstruct each of the `traversals` at `request_lsn`, like
+    /// [`Self::get_reconstruct_data`] does for a single key.
+    ///
+    /// The keys go down the layers in lock-step: in each round, the keys that are not complete
+    /// yet are grouped by the next layer they need, and each layer is then searched once for its
+    /// whole group. Keys that continue on the ancestor timeline wait for the rest, so that the
+    /// ancestor's layers are shared in the same way.
+    ///
+    /// # Cancel-Safety
+    ///
+    /// This method is cancellation-safe.
+    async fn get_batch_reconstruct_data(
+        &self,
+        traversals: &mut [BatchedKeyTraversal],
+        request_lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<(), PageReconstructError> {
+        // Start from the current timeline.
+        let mut timeline_owned;
+        let mut timeline = self;
+
+        let mut read_count = scopeguard::guard(0, |cnt| {
+            crate::metrics::READ_NUM_FS_LAYERS.observe(cnt as f64)
+        });
+
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(PageReconstructError::Cancelled);
+            }
+
+            let mut pending = Vec::new();
+            let mut need_ancestor = false;
+            for (i, traversal) in traversals.iter().enumerate() {
+                if traversal.done {
+                    continue;
+                }
+                if is_inherited_key(traversal.key)
+                    && Lsn(traversal.cont_lsn.0 - 1) <= timeline.ancestor_lsn
+                {
+                    need_ancestor = true;
+                } else {
+                    pending.push(i);
+                }
+            }
+
+            if pending.is_empty() {
+                if !need_ancestor {
+                    return Ok(());
+                }
+                trace!(
+                    "going into ancestor {} with {} keys",
+                    timeline.ancestor_lsn,
+                    traversals.iter().filter(|t| !t.done).count()
+                );
+                timeline_owned = timeline.get_ready_ancestor_timeline(ctx).await?;
+                timeline = &*timeline_owned;
+                for traversal in traversals.iter_mut() {
+                    traversal.prev_lsn = Lsn(u64::MAX);
+                }
+                continue;
+            }
+
+            let guard = timeline.layers.read().await;
+            let layers = guard.layer_map();
+
+            // Find the next layer of every pending key: the open and frozen in-memory layers
+            // first, in order from newest to oldest, then the historic layers.
+            let mut groups: Vec<(BatchedTraversalLayer, Vec<(usize, Lsn)>)> = Vec::new();
+            for i in pending {
+                let traversal = &mut traversals[i];
+                let cont_lsn = traversal.cont_lsn;
+                let next = if let Some(open_layer) = layers
+                    .open_layer
+                    .as_ref()
+                    .filter(|l| cont_lsn > l.get_lsn_range().start)
+                {
+                    Some((
+                        BatchedTraversalLayer::InMemory(Arc::clone(open_layer)),
+                        open_layer.get_lsn_range().start,
+                    ))
+                } else if let Some(frozen_layer) = layers
+                    .frozen_layers
+                    .iter()
+                    .rev()
+                    .find(|l| cont_lsn > l.get_lsn_range().start)
+                {
+                    Some((
+                        BatchedTraversalLayer::InMemory(Arc::clone(frozen_layer)),
+                        frozen_layer.get_lsn_range().start,
+                    ))
+                } else {
+                    layers.search(traversal.key, cont_lsn).map(
+                        |SearchResult { lsn_floor, layer }| {
+                            (
+                                BatchedTraversalLayer::Historic(guard.get_from_desc(&layer)),
+                                lsn_floor,
+                            )
+                        },
+                    )
+                };
+
+                match next {
+                    Some((layer, lsn_floor)) => {
+                        // But if we have an older cached page image, no need to go past that.
+                        let lsn_floor = max(traversal.cached_lsn + 1, lsn_floor);
+                        match groups.iter_mut().find(|(l, _)| l.is_same(&layer)) {
+                            Some((_, members)) => members.push((i, lsn_floor)),
+                            None => groups.push((layer, vec![(i, lsn_floor)])),
+                        }
+                    }
+                    None if timeline.ancestor_timeline.is_some()
+                        && is_inherited_key(traversal.key) =>
+                    {
+                        // Nothing on this timeline. Traverse to parent
+                        traversal.cont_lsn = Lsn(timeline.ancestor_lsn.0 + 1);
+                        traversal.advance(
+                            ValueReconstructResult::Continue,
+                            request_lsn,
+                            timeline,
+                        )?;
+                    }
+                    None => {
+                        // Nothing found
+                        traversal.advance(
+                            ValueReconstructResult::Missing,
+                            request_lsn,
+                            timeline,
+                        )?;
+                    }
+                }
+            }
+
+            for (layer, members) in groups {
+                let mut reads: Vec<BatchedValueRead> = members
+                    .iter()
+                    .map(|&(i, lsn_floor)| {
+                        let traversal = &mut traversals[i];
+                        BatchedValueRead {
+                            key: traversal.key,
+                            lsn_range: lsn_floor..traversal.cont_lsn,
+                            state: std::mem::take(&mut traversal.state),
+                        }
+                    })
+                    .collect();
+                let results = layer
+                    .get_values_reconstruct_data(&mut reads, ctx)
+                    .await
+                    .map_err(PageReconstructError::from)?;
+                // metrics: in-memory layers do not count as fs access
+                if let BatchedTraversalLayer::Historic(_) = layer {
+                    *read_count += 1;
+                }
+
+                for ((&(i, lsn_floor), read), result) in members.iter().zip(reads).zip(results) {
+                    let traversal = &mut traversals[i];
+                    traversal.state = read.state;
+                    traversal.cont_lsn = lsn_floor;
+                    traversal.advance(result, request_lsn, timeline)?;
+                }
+            }
+        }
+    }
+
     /// # Cancel-safety
     ///
     /// This method is cancellation-safe.
@@ -2432,6 +2833,66 @@ impl Timeline {
         Some((lsn, img))
     }
 
+    /// Returns the ancestor timeline, once it is active and has caught up to the branch point.
+    async fn get_ready_ancestor_timeline(
+        &self,
+        ctx: &RequestContext,
+    ) -> Result<Arc<Timeline>, PageReconstructError> {
+        let ancestor = match self.get_ancestor_timeline() {
+            Ok(timeline) => timeline,
+            Err(e) => return Err(PageReconstructError::from(e)),
+        };
+
+        // It's possible that the ancestor timeline isn't active yet, or
+        // is active but hasn't yet caught up to the branch point. Wait
+        // for it.
+        //
+        // This cannot happen while the pageserver is running normally,
+        // because you cannot create a branch from a point that isn't
+        // present in the pageserver yet. However, we don't wait for the
+        // branch point to be uploaded to cloud storage before creating
+        // a branch. I.e., the branch LSN need not be remote consistent
+        // for the branching operation to succeed.
+        //
+        // Hence, if we try to load a tenant in such a state where
+        // 1. the existence of the branch was persisted (in IndexPart and/or locally)
+        // 2. but the ancestor state is behind branch_lsn because it was not yet persisted
+        // then we will need to wait for the ancestor timeline to
+        // re-stream WAL up to branch_lsn before we access it.
+        //
+        // How can a tenant get in such a state?
+        // - ungraceful pageserver process exit
+        // - detach+attach => this is a bug, https://github.com/neondatabase/neon/issues/4219
+        //
+        // NB: this could be avoided by requiring
+        //   branch_lsn >= remote_consistent_lsn
+        // during branch creation.
+        match ancestor.wait_to_become_active(ctx).await {
+            Ok(()) => {}
+            Err(TimelineState::Stopping) => {
+                return Err(PageReconstructError::AncestorStopping(ancestor.timeline_id));
+            }
+            Err(state) => {
+                return Err(PageReconstructError::Other(anyhow::anyhow!(
+                    "Timeline {} will not become active. Current state: {:?}",
+                    ancestor.timeline_id,
+                    &state,
+                )));
+            }
+        }
+        ancestor
+            .wait_lsn(self.ancestor_lsn, ctx)
+            .await
+            .with_context(|| {
+                format!(
+                    "wait for lsn {} on ancestor timeline_id={}",
+                    self.ancestor_lsn, ancestor.timeline_id
+                )
+            })?;
+
+        Ok(ancestor)
+    }
+
     fn get_ancestor_timeline(&self) -> anyhow::Result<Arc<Timeline>> {
         let ancestor = self.ancestor_timeline.as_ref().with_context(|| {
             format!(
@@ -2797,16 +3258,13 @@ impl Timeline {
             None
         };
 
-        let ancestor_timeline_id = self
-            .ancestor_timeline
-            .as_ref()
-            .map(|ancestor| ancestor.timeline_id);
+        let ancestor_timeline_id = self.get_ancestor_timeline_id();
 
         let metadata = TimelineMetadata::new(
             disk_consistent_lsn,
             ondisk_prev_record_lsn,
             ancestor_timeline_id,
-            self.ancestor_lsn,
+            self.get_ancestor_lsn(),
             *self.latest_gc_cutoff_lsn.read(),
             self.initdb_lsn,
             self.pg_version,
@@ -3048,6 +3506,7 @@ impl Timeline {
                     self.tenant_shard_id,
                     &img_range,
                     lsn,
+                    self.get_blob_compression(),
                 )
                 .await?;
 
@@ -3654,6 +4113,7 @@ impl Timeline {
                             debug!("Create new layer {}..{}", lsn_range.start, lsn_range.end);
                             lsn_range.clone()
                         },
+                        self.get_blob_compression(),
                     )
                     .await?,
                 );
@@ -4175,21 +4635,59 @@ impl Timeline {
         &self,
         key: Key,
         request_lsn: Lsn,
-        mut data: ValueReconstructState,
+        data: ValueReconstructState,
     ) -> Result<Bytes, PageReconstructError> {
+        match self.plan_reconstruct_value(key, request_lsn, data)? {
+            ReconstructPlan::Image(img) => Ok(img),
+            ReconstructPlan::Redo {
+                last_rec_lsn,
+                request,
+            } => {
+                let img = match self
+                    .walredo_mgr
+                    .request_redo(
+                        request.key,
+                        request.lsn,
+                        request.base_img,
+                        request.records,
+                        self.pg_version,
+                    )
+                    .await
+                    .context("Failed to reconstruct a page image:")
+                {
+                    Ok(img) => img,
+                    Err(e) => return Err(PageReconstructError::from(e)),
+                };
+
+                self.memorize_reconstructed_page(key, last_rec_lsn, &img)
+                    .await?;
+
+                Ok(img)
+            }
+        }
+    }
+
+    /// Checks that the value can be reconstructed from the page image and WAL records
+    /// collected for it, and tells whether that needs WAL redo.
+    fn plan_reconstruct_value(
+        &self,
+        key: Key,
+        request_lsn: Lsn,
+        mut data: ValueReconstructState,
+    ) -> Result<ReconstructPlan, PageReconstructError> {
         // Perform WAL redo if needed
         data.records.reverse();
 
         // If we have a page image, and no WAL, we're all set
         if data.records.is_empty() {
-            if let Some((img_lsn, img)) = &data.img {
+            if let Some((img_lsn, img)) = data.img {
                 trace!(
                     "found page image for key {} at {}, no WAL redo required, req LSN {}",
                     key,
                     img_lsn,
                     request_lsn,
                 );
-                Ok(img.clone())
+                Ok(ReconstructPlan::Image(img))
             } else {
                 Err(PageReconstructError::from(anyhow!(
                     "base image for {key} at {request_lsn} not found"
@@ -4219,38 +4717,43 @@ impl Timeline {
                     trace!("found {} WAL records that will init the page for {} at {}, performing WAL redo", data.records.len(), key, request_lsn);
                 };
 
-                let last_rec_lsn = data.records.last().unwrap().0;
-
-                let img = match self
-                    .walredo_mgr
-                    .request_redo(key, request_lsn, data.img, data.records, self.pg_version)
-                    .await
-                    .context("Failed to reconstruct a page image:")
-                {
-                    Ok(img) => img,
-                    Err(e) => return Err(PageReconstructError::from(e)),
-                };
-
-                if img.len() == page_cache::PAGE_SZ {
-                    let cache = page_cache::get();
-                    if let Err(e) = cache
-                        .memorize_materialized_page(
-                            self.tenant_shard_id,
-                            self.timeline_id,
-                            key,
-                            last_rec_lsn,
-                            &img,
-                        )
-                        .await
-                        .context("Materialized page memoization failed")
-                    {
-                        return Err(PageReconstructError::from(e));
-                    }
-                }
+                Ok(ReconstructPlan::Redo {
+                    last_rec_lsn: data.records.last().unwrap().0,
+                    request: WalRedoRequest {
+                        key,
+                        lsn: request_lsn,
+                        base_img: data.img,
+                        records: data.records,
+                    },
+                })
+            }
+        }
+    }
 
-                Ok(img)
+    /// Puts a page image that was reconstructed with WAL redo into the page cache.
+    async fn memorize_reconstructed_page(
+        &self,
+        key: Key,
+        last_rec_lsn: Lsn,
+        img: &Bytes,
+    ) -> Result<(), PageReconstructError> {
+        if img.len() == page_cache::PAGE_SZ {
+            let cache = page_cache::get();
+            if let Err(e) = cache
+                .memorize_materialized_page(
+                    self.tenant_shard_id,
+                    self.timeline_id,
+                    key,
+                    last_rec_lsn,
+                    img,
+                )
+                .await
+                .context("Materialized page memoization failed")
+            {
+                return Err(PageReconstructError::from(e));
             }
         }
+        Ok(())
     }
 
     pub(crate) async fn spawn_download_all_remote_layers(
@@ -4509,6 +5012,113 @@ impl Timeline {
     }
 }
 
+/// How to reconstruct a value from the data collected in the layers.
+enum ReconstructPlan {
+    /// The value is a page image.
+    Image(Bytes),
+    /// The value needs WAL redo. Its result is cached as the page at `last_rec_lsn`.
+    Redo {
+        last_rec_lsn: Lsn,
+        request: WalRedoRequest,
+    },
+}
+
+/// The state of one key of [`Timeline::get_vectored`] while the layers are traversed.
+struct BatchedKeyTraversal {
+    key: Key,
+    state: ValueReconstructState,
+    cached_lsn: Lsn,
+    cont_lsn: Lsn,
+    /// The `cont_lsn` of the previous round, to check that every round makes progress.
+    prev_lsn: Lsn,
+    /// Whether a key without any value is left out rather than an error.
+    sparse: bool,
+    /// Set, along with `done`, for a key of a sparse read that has no value.
+    missing: bool,
+    done: bool,
+}
+
+impl BatchedKeyTraversal {
+    /// Takes the result of the layer that was just searched for this key, as the loop of
+    /// [`Timeline::get_reconstruct_data`] does.
+    fn advance(
+        &mut self,
+        result: ValueReconstructResult,
+        request_lsn: Lsn,
+        timeline: &Timeline,
+    ) -> Result<(), PageReconstructError> {
+        match result {
+            ValueReconstructResult::Complete => self.done = true,
+            ValueReconstructResult::Continue => {
+                // If we reached an earlier cached page image, we're done.
+                if self.cont_lsn == self.cached_lsn + 1 {
+                    MATERIALIZED_PAGE_CACHE_HIT.inc_by(1);
+                    self.done = true;
+                } else if self.prev_lsn <= self.cont_lsn {
+                    return Err(layer_traversal_error(
+                        format!(
+                            "could not find layer with more data for key {} at LSN {}, request LSN {}, ancestor {}",
+                            self.key,
+                            Lsn(self.cont_lsn.0 - 1),
+                            request_lsn,
+                            timeline.ancestor_lsn
+                        ),
+                        Vec::new(),
+                    ));
+                } else {
+                    self.prev_lsn = self.cont_lsn;
+                }
+            }
+            ValueReconstructResult::Missing
+                if self.sparse && self.state.img.is_none() && self.state.records.is_empty() =>
+            {
+                self.missing = true;
+                self.done = true;
+            }
+            ValueReconstructResult::Missing => {
+                return Err(layer_traversal_error(
+                    format!(
+                        "could not find data for key {} (shard {:?}) at LSN {}, for request at LSN {}",
+                        self.key,
+                        timeline.shard_identity.get_shard_number(&self.key),
+                        self.cont_lsn,
+                        request_lsn
+                    ),
+                    Vec::new(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The next layer of some keys in [`Timeline::get_batch_reconstruct_data`].
+enum BatchedTraversalLayer {
+    InMemory(Arc<InMemoryLayer>),
+    Historic(Layer),
+}
+
+impl BatchedTraversalLayer {
+    fn is_same(&self, other: &BatchedTraversalLayer) -> bool {
+        match (self, other) {
+            (Self::InMemory(a), Self::InMemory(b)) => Arc::ptr_eq(a, b),
+            (Self::Historic(a), Self::Historic(b)) => a.layer_desc().key() == b.layer_desc().key(),
+            _ => false,
+        }
+    }
+
+    async fn get_values_reconstruct_data(
+        &self,
+        reads: &mut [BatchedValueRead],
+        ctx: &RequestContext,
+    ) -> anyhow::Result<Vec<ValueReconstructResult>> {
+        match self {
+            Self::InMemory(layer) => layer.get_values_reconstruct_data(reads, ctx).await,
+            Self::Historic(layer) => layer.get_values_reconstruct_data(reads, ctx).await,
+        }
+    }
+}
+
 type TraversalPathItem = (
     ValueReconstructResult,
     Lsn,
@@ -479,7 +479,7 @@ impl DeleteTimelineFlow {
 
         // Ensure that there are no child timelines **attached to that pageserver**,
         // because detach removes files, which will break child branches
-        let children: Vec<TimelineId> = timelines
+        let mut children: Vec<TimelineId> = timelines
             .iter()
             .filter_map(|(id, entry)| {
                 if entry.get_ancestor_timeline_id() == Some(timeline_id) {
@@ -490,6 +490,17 @@ impl DeleteTimelineFlow {
             })
             .collect();
 
+        // Archived children need their ancestor as soon as they are unarchived.
+        children.extend(
+            tenant
+                .timelines_offloaded
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|offloaded| offloaded.ancestor_timeline_id == Some(timeline_id))
+                .map(|offloaded| offloaded.timeline_id),
+        );
+
         if !children.is_empty() {
             return Err(DeleteTimelineError::HasChildren(children));
         }
@@ -0,0 +1,458 @@
+//! Detaching a branch from its ancestor.
+//!
+//! A timeline created with [`crate::tenant::Tenant::branch_timeline`] reads everything below its
+//! branch point from the ancestor, which in turn keeps the ancestor's GC from progressing past
+//! the branch point. Detaching makes the branch self-sufficient by copying the ancestor's layers
+//! below the branch point into the branch, after which the branch is persisted without an
+//! ancestor, both locally and in the remote `IndexPart`.
+//!
+//! Layers fully below the branch point are copied as-is, with only the summary rewritten to the
+//! new timeline id. Delta layers which straddle the branch point are rewritten to contain only
+//! the values up to and including the branch point.
+//!
+//! The in-memory [`Timeline`] keeps its `ancestor_timeline` pointer until the tenant is reset,
+//! which callers are expected to do after a successful detach.
+//!
+//! If copying fails, the layers copied so far are removed again; they are only tracked and
+//! uploaded once all of them have been copied. If persisting the metadata fails after that, a
+//! retry adopts the tracked layers and only persists the metadata again.
+
+use std::{
+    collections::HashSet,
+    sync::{atomic::Ordering, Arc},
+};
+
+use anyhow::Context;
+use camino::Utf8PathBuf;
+use tracing::{info, Instrument};
+use utils::{
+    id::TimelineId,
+    lsn::{Lsn, RecordLsn},
+};
+
+use super::Timeline;
+use crate::{
+    context::RequestContext,
+    tenant::{
+        metadata::{save_metadata, TimelineMetadata},
+        par_fsync,
+        remote_timeline_client::layer_file_checksum,
+        storage_layer::{
+            delta_layer, image_layer, AsLayerDesc, DeltaLayer, DeltaLayerWriter, ImageLayer, Layer,
+            PersistentLayerDesc, ResidentLayer,
+        },
+    },
+    TEMP_FILE_SUFFIX,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("timeline has no ancestor")]
+    NoAncestor,
+    #[error("ancestor timeline {0} has an ancestor of its own, detach it first")]
+    TooManyAncestors(TimelineId),
+    #[error("detaching from the ancestor is already in progress")]
+    InProgress,
+    #[error("shutting down, please retry later")]
+    ShuttingDown,
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Copies the layers of the ancestor below the branch point into `detached`, and persists the
+/// timeline metadata without the ancestor.
+///
+/// Returns the id of the former ancestor.
+pub(super) async fn detach_ancestor(
+    detached: &Arc<Timeline>,
+    ctx: &RequestContext,
+) -> Result<TimelineId, Error> {
+    let Some(ancestor) = detached.ancestor_timeline.as_ref() else {
+        return Err(Error::NoAncestor);
+    };
+
+    // Once a detach has completed, further attempts are rejected until the tenant has been
+    // reset.
+    let mut completed = detached
+        .detach_ancestor_lock
+        .try_lock()
+        .map_err(|_| Error::InProgress)?;
+    if *completed {
+        return Err(Error::NoAncestor);
+    }
+
+    if ancestor.ancestor_timeline.is_some() {
+        // Copying layers from a chain of ancestors would need to follow the reads through all
+        // of them; keep this simple and require detaching the ancestor first.
+        return Err(Error::TooManyAncestors(ancestor.timeline_id));
+    }
+
+    let _gate = detached.gate.enter().map_err(|_| Error::ShuttingDown)?;
+
+    if !detached.detached_from_ancestor.load(Ordering::Relaxed) {
+        let mut new_layers = Vec::new();
+        if let Err(e) = copy_ancestor_layers(detached, ancestor, &mut new_layers, ctx).await {
+            // Nothing refers to the copies yet.
+            for layer in new_layers {
+                let _ = tokio::fs::remove_file(layer.local_path()).await;
+            }
+            return Err(e);
+        }
+
+        {
+            let mut guard = detached.layers.write().await;
+            guard.track_copied_layers(&new_layers, &detached.metrics);
+        }
+
+        // From now on, any metadata we write for this timeline is without the ancestor;
+        // flushes happening before the tenant is reset must not reintroduce it.
+        detached
+            .detached_from_ancestor
+            .store(true, Ordering::Relaxed);
+
+        if let Some(remote_client) = detached.remote_client.as_ref() {
+            for layer in new_layers {
+                remote_client.schedule_layer_file_upload(layer)?;
+            }
+        }
+    }
+
+    let disk_consistent_lsn = detached.disk_consistent_lsn.load();
+    let RecordLsn {
+        last: last_record_lsn,
+        prev: prev_record_lsn,
+    } = detached.last_record_lsn.load();
+    let metadata = TimelineMetadata::new(
+        disk_consistent_lsn,
+        (disk_consistent_lsn == last_record_lsn).then_some(prev_record_lsn),
+        None,
+        Lsn(0),
+        *detached.latest_gc_cutoff_lsn.read(),
+        detached.initdb_lsn,
+        detached.pg_version,
+    );
+
+    if let Some(remote_client) = detached.remote_client.as_ref() {
+        remote_client.schedule_index_upload_for_metadata_update(&metadata)?;
+        remote_client
+            .wait_completion()
+            .await
+            .context("wait for uploads of the detached timeline")?;
+    }
+
+    save_metadata(
+        detached.conf,
+        &detached.tenant_shard_id,
+        &detached.timeline_id,
+        &metadata,
+    )
+    .await
+    .context("save_metadata")?;
+
+    *completed = true;
+    Ok(ancestor.timeline_id)
+}
+
+/// Copies the layers of `ancestor` below the branch point into `new_layers`, and makes them
+/// durable on local disk.
+async fn copy_ancestor_layers(
+    detached: &Arc<Timeline>,
+    ancestor: &Arc<Timeline>,
+    new_layers: &mut Vec<ResidentLayer>,
+    ctx: &RequestContext,
+) -> Result<(), Error> {
+    // Everything the branch can see in the ancestor must be in historic layers before we start.
+    ancestor
+        .freeze_and_flush()
+        .await
+        .context("flush ancestor timeline")?;
+
+    // Records at exactly the branch point are visible to the branch.
+    let end_lsn = detached.ancestor_lsn + 1;
+
+    // The branch may already have written e.g. an image layer at the branch point.
+    let existing = detached
+        .layers
+        .read()
+        .await
+        .layer_map()
+        .iter_historic_layers()
+        .map(|desc| desc.key())
+        .collect::<HashSet<_>>();
+
+    let (image_layers, straddling, fully_below) = {
+        let guard = ancestor.layers.read().await;
+
+        let mut image_layers = Vec::new();
+        let mut straddling = Vec::new();
+        let mut fully_below = Vec::new();
+
+        for desc in guard.layer_map().iter_historic_layers() {
+            if desc.get_lsn_range().start >= end_lsn || existing.contains(&desc.key()) {
+                continue;
+            }
+
+            let layer = guard.get_from_desc(&desc);
+            if !desc.is_delta() {
+                image_layers.push(layer);
+            } else if desc.get_lsn_range().end > end_lsn {
+                straddling.push(layer);
+            } else {
+                fully_below.push(layer);
+            }
+        }
+
+        (image_layers, straddling, fully_below)
+    };
+
+    info!(
+        ancestor_timeline_id = %ancestor.timeline_id,
+        images = image_layers.len(),
+        deltas = fully_below.len(),
+        straddling = straddling.len(),
+        "copying ancestor layers below {end_lsn}"
+    );
+
+    for layer in image_layers.into_iter().chain(fully_below) {
+        if detached.cancel.is_cancelled() {
+            return Err(Error::ShuttingDown);
+        }
+        new_layers.push(copy_layer(detached, &layer, ctx).await?);
+    }
+
+    for layer in straddling {
+        if detached.cancel.is_cancelled() {
+            return Err(Error::ShuttingDown);
+        }
+        if let Some(copied) = rewrite_straddling_layer(detached, &layer, end_lsn, ctx).await? {
+            new_layers.push(copied);
+        }
+    }
+
+    let timeline_dir = detached
+        .conf
+        .timeline_path(&detached.tenant_shard_id, &detached.timeline_id);
+    let layer_paths: Vec<Utf8PathBuf> = new_layers
+        .iter()
+        .map(|l| l.local_path().to_owned())
+        .collect();
+    par_fsync::par_fsync_async(&layer_paths)
+        .await
+        .context("fsync copied layers")?;
+    par_fsync::par_fsync_async(&[timeline_dir])
+        .await
+        .context("fsync of timeline dir")?;
+    Ok(())
+}
+
+/// Copies a layer of the ancestor which is entirely below the branch point as-is, only
+/// rewriting the timeline id in its summary.
+async fn copy_layer(
+    detached: &Arc<Timeline>,
+    layer: &Layer,
+    ctx: &RequestContext,
+) -> anyhow::Result<ResidentLayer> {
+    let resident = layer
+        .download_and_keep_resident()
+        .instrument(tracing::debug_span!("download", %layer))
+        .await?;
+
+    let file_name = layer.layer_desc().filename();
+    let temp_path = detached
+        .conf
+        .timeline_path(&detached.tenant_shard_id, &detached.timeline_id)
+        .join(format!("{}.{}", file_name.file_name(), TEMP_FILE_SUFFIX));
+
+    // A hardlink would not do, the summary is rewritten in place.
+    tokio::fs::copy(resident.local_path(), &temp_path)
+        .await
+        .with_context(|| format!("copy {layer} to {temp_path}"))?;
+
+    let timeline_id = detached.timeline_id;
+    let res = if layer.layer_desc().is_delta() {
+        DeltaLayer::rewrite_summary(
+            &temp_path,
+            |summary| delta_layer::Summary {
+                timeline_id,
+                ..summary
+            },
+            ctx,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+    } else {
+        ImageLayer::rewrite_summary(
+            &temp_path,
+            |summary| image_layer::Summary {
+                timeline_id,
+                ..summary
+            },
+            ctx,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+    };
+
+    if let Err(e) = res {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e.context(format!("rewrite summary of copied {layer}")));
+    }
+
+    let desc = PersistentLayerDesc::from_filename(
+        detached.tenant_shard_id,
+        detached.timeline_id,
+        file_name,
+        layer.layer_desc().file_size,
+    );
+
+    let checksum = match layer_file_checksum(&temp_path).await {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(anyhow::Error::new(e).context(format!("checksum copied {layer}")));
+        }
+    };
+
+    Layer::finish_creating(detached.conf, detached, desc, checksum, &temp_path)
+}
+
+/// Rewrites a delta layer of the ancestor which straddles the branch point, keeping only the
+/// values below `end_lsn`.
+///
+/// Returns `None` if the layer has no values below `end_lsn`.
+async fn rewrite_straddling_layer(
+    detached: &Arc<Timeline>,
+    layer: &Layer,
+    end_lsn: Lsn,
+    ctx: &RequestContext,
+) -> anyhow::Result<Option<ResidentLayer>> {
+    let resident = layer
+        .download_and_keep_resident()
+        .instrument(tracing::debug_span!("download", %layer))
+        .await?;
+
+    let desc = layer.layer_desc();
+    let mut writer: Option<DeltaLayerWriter> = None;
+
+    for entry in resident.load_keys(ctx).await? {
+        if entry.lsn >= end_lsn {
+            continue;
+        }
+
+        if writer.is_none() {
+            writer = Some(
+                DeltaLayerWriter::new(
+                    detached.conf,
+                    detached.timeline_id,
+                    detached.tenant_shard_id,
+                    desc.key_range.start,
+                    desc.lsn_range.start..end_lsn,
+                    detached.get_blob_compression(),
+                )
+                .await?,
+            );
+        }
+
+        let value = entry.val.load(ctx).await?;
+        writer
+            .as_mut()
+            .unwrap()
+            .put_value(entry.key, entry.lsn, value)
+            .await?;
+    }
+
+    match writer {
+        Some(writer) => Ok(Some(writer.finish(desc.key_range.end, detached).await?)),
+        None => Ok(None),
+    }
+}
+
+impl Timeline {
+    /// Turns this branch into a root timeline, see the [module documentation](self).
+    pub(crate) async fn detach_from_ancestor(
+        self: &Arc<Timeline>,
+        ctx: &RequestContext,
+    ) -> Result<TimelineId, Error> {
+        detach_ancestor(self, ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::{id::TimelineId, lsn::Lsn};
+
+    use super::Error;
+    use crate::context::{DownloadBehavior, RequestContext};
+    use crate::repository::{Key, Value};
+    use crate::task_mgr::TaskKind;
+    use crate::tenant::harness::{TenantHarness, NEW_TIMELINE_ID, TEST_IMG};
+
+    #[tokio::test]
+    async fn detach_ancestor_rewrites_straddling_delta() {
+        let harness = TenantHarness::create("detach_ancestor_rewrites_straddling_delta").unwrap();
+
+        let ctx = RequestContext::new(TaskKind::UnitTest, DownloadBehavior::Error);
+        let tenant = harness.try_load(&ctx).await.unwrap();
+        let ancestor = tenant
+            .create_test_timeline(TimelineId::generate(), Lsn(0x10), 14, &ctx)
+            .await
+            .unwrap();
+
+        let key = Key::from_hex("110000000033333333444444445500000001").unwrap();
+        let writer = ancestor.writer().await;
+        for lsn in [Lsn(0x20), Lsn(0x30), Lsn(0x40)] {
+            writer
+                .put(
+                    key,
+                    lsn,
+                    &Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                    &ctx,
+                )
+                .await
+                .unwrap();
+            writer.finish_write(lsn);
+        }
+        drop(writer);
+
+        let branch = tenant
+            .branch_timeline_test(&ancestor, NEW_TIMELINE_ID, Some(Lsn(0x30)), &ctx)
+            .await
+            .unwrap();
+
+        let ancestor_timeline_id = branch.detach_from_ancestor(&ctx).await.unwrap();
+        assert_eq!(ancestor_timeline_id, ancestor.timeline_id);
+
+        // The single delta layer of the ancestor covers 0x20..=0x40, so it must have been
+        // rewritten to end right after the branch point.
+        let rewritten = {
+            let layers = branch.layers.read().await;
+            let descs = layers
+                .layer_map()
+                .iter_historic_layers()
+                .filter(|desc| desc.is_delta() && desc.get_key_range().contains(&key))
+                .collect::<Vec<_>>();
+            assert_eq!(descs.len(), 1, "{descs:?}");
+            assert_eq!(descs[0].get_lsn_range().end, Lsn(0x31));
+            assert_eq!(descs[0].timeline_id, branch.timeline_id);
+            layers.get_from_desc(&descs[0])
+        };
+
+        let resident = rewritten.download_and_keep_resident().await.unwrap();
+        let mut values = Vec::new();
+        for entry in resident.load_keys(&ctx).await.unwrap() {
+            if entry.key == key {
+                values.push((entry.lsn, entry.val.load(&ctx).await.unwrap()));
+            }
+        }
+        let expected = [Lsn(0x20), Lsn(0x30)]
+            .into_iter()
+            .map(|lsn| (lsn, Value::Image(TEST_IMG(&format!("foo at {lsn}")))))
+            .collect::<Vec<_>>();
+        assert_eq!(values, expected);
+
+        // Until the tenant is reset, the timeline still points to its ancestor, but detaching
+        // again must not copy the layers a second time.
+        let res = branch.detach_from_ancestor(&ctx).await;
+        assert!(matches!(res, Err(Error::NoAncestor)), "{res:?}");
+    }
+}
@@ -122,9 +122,10 @@ pub(super) fn reconcile(
                 name,
                 // The generation and shard here will be corrected to match IndexPart in the merge below, unless
                 // it is not in IndexPart, in which case using our current generation makes sense
-                // because it will be uploaded in this generation.
+                // because it will be uploaded in this generation. The checksum is likewise taken
+                // from IndexPart: we do not read the local file here to compute it.
                 (
-                    Some(LayerFileMetadata::new(file_size, generation, shard)),
+                    Some(LayerFileMetadata::new(file_size, generation, shard, None)),
                     None,
                 ),
             )
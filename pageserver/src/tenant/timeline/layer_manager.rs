@@ -163,6 +163,21 @@ impl LayerManager {
         updates.flush();
     }
 
+    /// Add layers copied from the ancestor timeline to the layer map, called when detaching
+    /// from the ancestor.
+    pub(crate) fn track_copied_layers(
+        &mut self,
+        copied_layers: &[ResidentLayer],
+        metrics: &TimelineMetrics,
+    ) {
+        let mut updates = self.layer_map.batch_update();
+        for layer in copied_layers {
+            Self::insert_historic_layer(layer.as_ref().clone(), &mut updates, &mut self.layer_fmgr);
+            metrics.record_new_file_metrics(layer.layer_desc().file_size);
+        }
+        updates.flush();
+    }
+
     /// Flush a frozen layer and add the written delta layer to the layer map.
     pub(crate) fn finish_flush_l0_layer(
         &mut self,
@@ -0,0 +1,220 @@
+//! Archival of timelines.
+//!
+//! An archived timeline is offloaded: its [`Timeline`] object is shut down and dropped, and its
+//! local directory is removed. The only thing that remains of it is its remote `IndexPart`,
+//! which carries the `archived_at` marker, and a small [`OffloadedTimeline`] in the tenant
+//! which keeps track of the branch point for GC of the ancestor.
+//!
+//! Unarchiving rehydrates the timeline from its `IndexPart` the same way it would be loaded
+//! when attaching the tenant; layers are then downloaded on demand.
+//!
+//! Reads on a branch go through its ancestor, so a timeline can only be archived once all of its
+//! children are archived, and can only be unarchived while its ancestor is not archived.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use utils::{fs_ext, id::TimelineId, lsn::Lsn};
+
+use super::{Timeline, TimelineResources};
+use crate::{
+    context::RequestContext,
+    tenant::{
+        remote_timeline_client::{MaybeDeletedIndexPart, RemoteTimelineClient},
+        Tenant, TimelineArchivalError,
+    },
+};
+
+/// What the tenant keeps of an archived timeline.
+pub struct OffloadedTimeline {
+    pub timeline_id: TimelineId,
+    pub ancestor_timeline_id: Option<TimelineId>,
+    pub ancestor_lsn: Lsn,
+}
+
+impl OffloadedTimeline {
+    pub(crate) fn from_timeline(timeline: &Timeline) -> Self {
+        Self {
+            timeline_id: timeline.timeline_id,
+            ancestor_timeline_id: timeline.get_ancestor_timeline_id(),
+            ancestor_lsn: timeline.get_ancestor_lsn(),
+        }
+    }
+}
+
+/// Marks the timeline as archived in remote storage, then shuts it down and removes all of its
+/// local state.
+pub(crate) async fn archive_and_offload(
+    tenant: &Tenant,
+    timeline_id: TimelineId,
+) -> Result<(), TimelineArchivalError> {
+    let timeline = {
+        let timelines = tenant.timelines.lock().unwrap();
+        let Some(timeline) = timelines.get(&timeline_id) else {
+            if tenant
+                .timelines_offloaded
+                .lock()
+                .unwrap()
+                .contains_key(&timeline_id)
+            {
+                // Idempotency: archiving an archived timeline is a no-op.
+                return Ok(());
+            }
+            return Err(TimelineArchivalError::NotFound);
+        };
+        Arc::clone(timeline)
+    };
+
+    // Branches are created while holding the lock for reading, and become visible in
+    // `tenant.timelines` before they release it: with the lock held, the check for children
+    // stays valid until the timeline is offloaded.
+    let mut archived = timeline.archival_lock.write().await;
+    if *archived {
+        return Ok(());
+    }
+    {
+        let timelines = tenant.timelines.lock().unwrap();
+        let children: Vec<TimelineId> = timelines
+            .values()
+            .filter(|t| t.get_ancestor_timeline_id() == Some(timeline_id))
+            .map(|t| t.timeline_id)
+            .collect();
+        if !children.is_empty() {
+            return Err(TimelineArchivalError::HasUnarchivedChildren(children));
+        }
+    }
+
+    let Some(remote_client) = timeline.remote_client.clone() else {
+        return Err(TimelineArchivalError::Other(anyhow::anyhow!(
+            "cannot archive a timeline without remote storage"
+        )));
+    };
+
+    // Do not race with timeline deletion, which also removes the timeline from the tenant.
+    let Ok(_delete_guard) = timeline.delete_progress.try_lock() else {
+        return Err(TimelineArchivalError::Other(anyhow::anyhow!(
+            "timeline is being deleted"
+        )));
+    };
+
+    // Everything ingested so far must be in remote storage, it is the only copy we will keep.
+    timeline
+        .freeze_and_flush()
+        .await
+        .context("flush before archival")?;
+    remote_client.schedule_index_upload_for_timeline_archival_state(true)?;
+    remote_client
+        .wait_completion()
+        .await
+        .context("wait for archival index upload")?;
+
+    timeline.shutdown().await;
+
+    *archived = true;
+    {
+        let mut timelines = tenant.timelines.lock().unwrap();
+        timelines.remove(&timeline_id);
+        tenant.timelines_offloaded.lock().unwrap().insert(
+            timeline_id,
+            Arc::new(OffloadedTimeline::from_timeline(&timeline)),
+        );
+    }
+
+    let timeline_path = tenant
+        .conf
+        .timeline_path(&tenant.tenant_shard_id, &timeline_id);
+    tokio::fs::remove_dir_all(&timeline_path)
+        .await
+        .or_else(fs_ext::ignore_not_found)
+        .with_context(|| format!("remove offloaded timeline directory {timeline_path}"))?;
+
+    tracing::info!("timeline archived and offloaded");
+
+    Ok(())
+}
+
+/// Loads an offloaded timeline back from its remote `IndexPart`.
+///
+/// The timeline is not activated, and it keeps its archived marker in remote storage: both are
+/// up to the caller.
+pub(crate) async fn unoffload(
+    tenant: &Tenant,
+    timeline_id: TimelineId,
+    ctx: &RequestContext,
+) -> Result<Arc<Timeline>, TimelineArchivalError> {
+    // Removing the entry claims it, so that concurrent calls don't load the timeline twice.
+    let offloaded = {
+        let mut offloaded_timelines = tenant.timelines_offloaded.lock().unwrap();
+        let Some(offloaded) = offloaded_timelines.get(&timeline_id) else {
+            return Err(TimelineArchivalError::NotFound);
+        };
+        if let Some(ancestor_id) = offloaded.ancestor_timeline_id {
+            if offloaded_timelines.contains_key(&ancestor_id) {
+                return Err(TimelineArchivalError::AncestorArchived(ancestor_id));
+            }
+        }
+        offloaded_timelines
+            .remove(&timeline_id)
+            .expect("checked above")
+    };
+
+    let res = load_offloaded(tenant, timeline_id, ctx).await;
+    if res.is_err() {
+        tenant
+            .timelines_offloaded
+            .lock()
+            .unwrap()
+            .insert(timeline_id, offloaded);
+    }
+    res
+}
+
+async fn load_offloaded(
+    tenant: &Tenant,
+    timeline_id: TimelineId,
+    ctx: &RequestContext,
+) -> Result<Arc<Timeline>, TimelineArchivalError> {
+    let Some(remote_storage) = tenant.remote_storage.as_ref() else {
+        return Err(TimelineArchivalError::Other(anyhow::anyhow!(
+            "cannot unarchive a timeline without remote storage"
+        )));
+    };
+
+    let remote_client = RemoteTimelineClient::new(
+        remote_storage.clone(),
+        tenant.deletion_queue_client.clone(),
+        tenant.conf,
+        tenant.tenant_shard_id,
+        timeline_id,
+        tenant.generation,
+    );
+
+    let index_part = match remote_client
+        .download_index_file(tenant.cancel.clone())
+        .await
+        .context("download index part")?
+    {
+        MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+        MaybeDeletedIndexPart::Deleted(_) => return Err(TimelineArchivalError::NotFound),
+    };
+
+    let remote_metadata = index_part.metadata.clone();
+    tenant
+        .load_remote_timeline(
+            timeline_id,
+            index_part,
+            remote_metadata,
+            TimelineResources {
+                remote_client: Some(remote_client),
+                deletion_queue_client: tenant.deletion_queue_client.clone(),
+            },
+            ctx,
+        )
+        .await?;
+
+    let timeline = tenant
+        .get_timeline(timeline_id, false)
+        .context("timeline just loaded")?;
+
+    Ok(timeline)
+}
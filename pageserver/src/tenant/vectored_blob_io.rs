@@ -0,0 +1,252 @@
+//!
+//! Reading of many blobs of a layer file with few, larger reads.
+//!
+//! The blobs of a layer file are written in the order of their keys (and LSNs, in delta
+//! layers), so the values of a key range tend to be stored next to each other. Instead of
+//! reading them one page at a time through the page cache, like [`super::block_io::BlockCursor`]
+//! does, the [`VectoredReadPlanner`] coalesces adjacent blobs into [`VectoredRead`]s, which
+//! the [`VectoredBlobReader`] reads with a single read each, directly from the
+//! [`VirtualFile`].
+//!
+//! The planner needs to know where each blob ends, which the layer files don't record. The
+//! layers use the offset of the next blob in the file instead, or the start of the index for
+//! the last blob: that is an upper bound, and the header of the blob tells its exact length.
+//!
+use std::io::{Error, ErrorKind};
+
+use pageserver_api::key::Key;
+use utils::lsn::Lsn;
+
+use crate::tenant::blob_io::{decode_blob, BlobFormat};
+use crate::virtual_file::VirtualFile;
+
+/// Upper bound for the size of a single [`VectoredRead`]. A blob that is larger than this is
+/// still read, alone.
+pub(crate) const MAX_VECTORED_READ_BYTES: u64 = 128 * 1024;
+
+/// Identifies a blob of a layer file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlobMeta {
+    pub(crate) key: Key,
+    pub(crate) lsn: Lsn,
+}
+
+/// Blobs stored next to each other in a file, which are read together.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct VectoredRead {
+    pub(crate) start: u64,
+    /// Upper bound of the end of the last blob.
+    pub(crate) end: u64,
+    /// Start offsets of the blobs, in increasing order.
+    pub(crate) blobs_at: Vec<(u64, BlobMeta)>,
+}
+
+impl VectoredRead {
+    pub(crate) fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// Coalesces blobs into [`VectoredRead`]s.
+pub(crate) struct VectoredReadPlanner {
+    max_read_size: u64,
+    reads: Vec<VectoredRead>,
+}
+
+impl VectoredReadPlanner {
+    pub(crate) fn new(max_read_size: u64) -> Self {
+        VectoredReadPlanner {
+            max_read_size,
+            reads: Vec::new(),
+        }
+    }
+
+    /// Adds the blob stored at `start..end`. The blobs must be added in the order of their
+    /// offsets.
+    pub(crate) fn handle(&mut self, key: Key, lsn: Lsn, start: u64, end: u64) {
+        assert!(start < end, "empty blob at {start} for {key} at {lsn}");
+        let meta = BlobMeta { key, lsn };
+        if let Some(read) = self.reads.last_mut() {
+            assert!(
+                read.start < start,
+                "blobs must be added in order of their offsets"
+            );
+            if read.end == start && end - read.start <= self.max_read_size {
+                read.end = end;
+                read.blobs_at.push((start, meta));
+                return;
+            }
+        }
+        self.reads.push(VectoredRead {
+            start,
+            end,
+            blobs_at: vec![(start, meta)],
+        });
+    }
+
+    pub(crate) fn finish(self) -> Vec<VectoredRead> {
+        self.reads
+    }
+}
+
+/// Reads the blobs of [`VectoredRead`]s from a layer file.
+pub(crate) struct VectoredBlobReader<'a> {
+    file: &'a VirtualFile,
+    format: BlobFormat,
+}
+
+impl<'a> VectoredBlobReader<'a> {
+    pub(crate) fn new(file: &'a VirtualFile, format: BlobFormat) -> Self {
+        VectoredBlobReader { file, format }
+    }
+
+    /// Reads the blobs of `read`, reusing `buf` for the read. Returns the decoded (and
+    /// decompressed) blobs, in the order of `read.blobs_at`.
+    pub(crate) async fn read_blobs(
+        &self,
+        read: &VectoredRead,
+        buf: &mut Vec<u8>,
+    ) -> Result<Vec<(BlobMeta, Vec<u8>)>, Error> {
+        buf.clear();
+        buf.resize(read.size() as usize, 0);
+        self.file.read_exact_at(buf, read.start).await?;
+
+        let mut blobs = Vec::with_capacity(read.blobs_at.len());
+        for (at, meta) in &read.blobs_at {
+            let blob_buf = buf
+                .get((at - read.start) as usize..)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "blob outside of read"))?;
+            let blob = decode_blob(blob_buf, self.format).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("failed to decode blob of {} at {at}: {e}", meta.key),
+                )
+            })?;
+            blobs.push((*meta, blob));
+        }
+        Ok(blobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::blob_io::BlobWriter;
+    use pageserver_api::models::CompressionAlgorithm;
+
+    fn meta(i: i128) -> BlobMeta {
+        BlobMeta {
+            key: Key::from_i128(i),
+            lsn: Lsn(0x10),
+        }
+    }
+
+    #[test]
+    fn planner_coalesces_adjacent_blobs() {
+        let mut planner = VectoredReadPlanner::new(100);
+        planner.handle(meta(1).key, meta(1).lsn, 0, 10);
+        planner.handle(meta(2).key, meta(2).lsn, 10, 50);
+        // gap
+        planner.handle(meta(3).key, meta(3).lsn, 60, 70);
+        planner.handle(meta(4).key, meta(4).lsn, 70, 150);
+        // would make the read too large
+        planner.handle(meta(5).key, meta(5).lsn, 150, 170);
+        // larger than the maximum on its own
+        planner.handle(meta(6).key, meta(6).lsn, 170, 1000);
+
+        assert_eq!(
+            planner.finish(),
+            vec![
+                VectoredRead {
+                    start: 0,
+                    end: 50,
+                    blobs_at: vec![(0, meta(1)), (10, meta(2))],
+                },
+                VectoredRead {
+                    start: 60,
+                    end: 150,
+                    blobs_at: vec![(60, meta(3)), (70, meta(4))],
+                },
+                VectoredRead {
+                    start: 150,
+                    end: 170,
+                    blobs_at: vec![(150, meta(5))],
+                },
+                VectoredRead {
+                    start: 170,
+                    end: 1000,
+                    blobs_at: vec![(170, meta(6))],
+                },
+            ]
+        );
+    }
+
+    async fn round_trip(compression: CompressionAlgorithm, max_read_size: u64) {
+        let format = BlobFormat::for_compression(compression);
+        let temp_dir = camino_tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("file");
+
+        let blobs: Vec<Vec<u8>> = (0..100usize)
+            .map(|i| match i % 3 {
+                0 => vec![i as u8; 10],
+                1 => vec![i as u8; 1000],
+                _ => (0..20000).map(|j| (i * j) as u8).collect(),
+            })
+            .collect();
+
+        let mut offsets = Vec::new();
+        {
+            let file = VirtualFile::create(path.as_path()).await.unwrap();
+            let mut wtr = BlobWriter::<true>::new(file, 0);
+            for blob in &blobs {
+                let offset = match format {
+                    BlobFormat::Uncompressed => wtr.write_blob(blob).await.unwrap(),
+                    BlobFormat::Compressed => {
+                        wtr.write_blob_compressed(blob, compression).await.unwrap()
+                    }
+                };
+                offsets.push(offset);
+            }
+            offsets.push(wtr.size());
+            wtr.flush_buffer().await.unwrap();
+        }
+
+        let mut planner = VectoredReadPlanner::new(max_read_size);
+        let mut n_wanted = 0;
+        for i in 0..blobs.len() {
+            // skip some blobs, to leave gaps
+            if i % 7 == 3 {
+                continue;
+            }
+            let meta = meta(i as i128);
+            planner.handle(meta.key, meta.lsn, offsets[i], offsets[i + 1]);
+            n_wanted += 1;
+        }
+        let reads = planner.finish();
+
+        let file = VirtualFile::open(path.as_path()).await.unwrap();
+        let reader = VectoredBlobReader::new(&file, format);
+        let mut buf = Vec::new();
+        let mut n_read = 0;
+        for read in &reads {
+            for (meta, blob) in reader.read_blobs(read, &mut buf).await.unwrap() {
+                let i = meta.key.field6 as usize;
+                assert_eq!(blob, blobs[i], "mismatch for blob {i}");
+                n_read += 1;
+            }
+        }
+        assert_eq!(n_read, n_wanted);
+    }
+
+    #[tokio::test]
+    async fn round_trip_uncompressed() {
+        round_trip(CompressionAlgorithm::Disabled, MAX_VECTORED_READ_BYTES).await;
+        round_trip(CompressionAlgorithm::Disabled, 1).await;
+    }
+
+    #[tokio::test]
+    async fn round_trip_compressed() {
+        round_trip(CompressionAlgorithm::Zstd, MAX_VECTORED_READ_BYTES).await;
+        round_trip(CompressionAlgorithm::Lz4, MAX_VECTORED_READ_BYTES).await;
+    }
+}
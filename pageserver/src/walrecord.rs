@@ -91,8 +91,9 @@ pub struct DecodedBkpBlock {
     pub bimg_info: u8,
 
     /* Buffer holding the rmgr-specific data associated with this block */
-    has_data: bool,
-    data_len: u16,
+    pub has_data: bool,
+    pub data_len: u16,
+    pub data_offset: u32,
 }
 
 impl DecodedBkpBlock {
@@ -426,13 +427,59 @@ pub mod v16 {
                     old_offnum: buf.get_u16_le(),
                     old_infobits_set: buf.get_u8(),
                     flags: buf.get_u8(),
-                    t_cid: buf.get_u32(),
+                    t_cid: buf.get_u32_le(),
                     new_xmax: buf.get_u32_le(),
                     new_offnum: buf.get_u16_le(),
                 }
             }
         }
 
+        /// Header of a tuple in the block data of an insert or update record.
+        #[repr(C)]
+        #[derive(Debug)]
+        pub struct XlNeonHeapHeader {
+            pub t_infomask2: u16,
+            pub t_infomask: u16,
+            pub t_cid: u32,
+            pub t_hoff: u8,
+        }
+
+        impl XlNeonHeapHeader {
+            pub const SIZE: usize = 9;
+
+            pub fn decode(buf: &mut Bytes) -> XlNeonHeapHeader {
+                XlNeonHeapHeader {
+                    t_infomask2: buf.get_u16_le(),
+                    t_infomask: buf.get_u16_le(),
+                    t_cid: buf.get_u32_le(),
+                    t_hoff: buf.get_u8(),
+                }
+            }
+        }
+
+        /// Header of each tuple in the block data of a multi-insert record.
+        #[repr(C)]
+        #[derive(Debug)]
+        pub struct XlNeonMultiInsertTuple {
+            pub datalen: u16,
+            pub t_infomask2: u16,
+            pub t_infomask: u16,
+            pub t_hoff: u8,
+        }
+
+        impl XlNeonMultiInsertTuple {
+            pub const SIZE: usize = 7;
+
+            pub fn decode(buf: &mut Bytes) -> XlNeonMultiInsertTuple {
+                XlNeonMultiInsertTuple {
+                    datalen: buf.get_u16_le(),
+                    t_infomask2: buf.get_u16_le(),
+                    t_infomask: buf.get_u16_le(),
+                    t_hoff: buf.get_u8(),
+                }
+            }
+        }
+
         #[repr(C)]
         #[derive(Debug)]
         pub struct XlNeonHeapLock {
@@ -1028,6 +1075,7 @@ pub fn decode_wal_record(
             ptr += blk.bimg_len as usize;
         }
         if blk.has_data {
+            blk.data_offset = ptr as u32;
             ptr += blk.data_len as usize;
         }
     }
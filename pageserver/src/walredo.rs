@@ -18,6 +18,13 @@
 //! any WAL records, so that even if an attacker hijacks the Postgres
 //! process, he cannot escape out of it.
 //!
+//! Some records are replayed in-process instead: the neon-specific
+//! records, and optionally the most common heap records of PostgreSQL 16
+//! (see the `heap` module and [`WalRedoNativeHeapMode`]).
+//!
+//! By default each tenant has its own WAL redo process. Optionally, all
+//! tenants share a capped pool of processes instead, see the `pool` module.
+//!
 use anyhow::Context;
 use byteorder::{ByteOrder, LittleEndian};
 use bytes::{BufMut, Bytes, BytesMut};
@@ -32,6 +39,7 @@ use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::CommandExt;
 use std::process::Stdio;
 use std::process::{Child, ChildStdin, ChildStdout, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::time::Duration;
 use std::time::Instant;
@@ -39,13 +47,13 @@ use tracing::*;
 use utils::{bin_ser::BeSer, lsn::Lsn, nonblock::set_nonblock};
 
 #[cfg(feature = "testing")]
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::AtomicUsize;
 
 use crate::config::PageServerConf;
 use crate::metrics::{
-    WalRedoKillCause, WAL_REDO_BYTES_HISTOGRAM, WAL_REDO_PROCESS_COUNTERS,
-    WAL_REDO_PROCESS_LAUNCH_DURATION_HISTOGRAM, WAL_REDO_RECORDS_HISTOGRAM,
-    WAL_REDO_RECORD_COUNTER, WAL_REDO_TIME,
+    WalRedoKillCause, WAL_REDO_BYTES_HISTOGRAM, WAL_REDO_NATIVE_HEAP_COUNTERS,
+    WAL_REDO_PROCESS_COUNTERS, WAL_REDO_PROCESS_LAUNCH_DURATION_HISTOGRAM,
+    WAL_REDO_RECORDS_HISTOGRAM, WAL_REDO_RECORD_COUNTER, WAL_REDO_TIME,
 };
 use crate::pgdatadir_mapping::{key_to_rel_block, key_to_slru_block};
 use crate::repository::Key;
@@ -59,6 +67,11 @@ use postgres_ffi::v14::nonrelfile_utils::{
 };
 use postgres_ffi::BLCKSZ;
 
+mod heap;
+pub mod pool;
+
+pub use pool::WalRedoProcessPoolConfig;
+
 ///
 /// `RelTag` + block number (`blknum`) gives us a unique id of the page in the cluster.
 ///
@@ -85,9 +98,9 @@ struct ProcessOutput {
 ///
 /// This is the real implementation that uses a Postgres process to
 /// perform WAL replay. Only one thread can use the process at a time,
-/// that is controlled by the Mutex. In the future, we might want to
-/// launch a pool of processes to allow concurrent replay of multiple
-/// records.
+/// that is controlled by the Mutex. If the pageserver is configured
+/// with a process pool, processes are taken from the pool instead, see
+/// the `pool` module.
 ///
 pub struct PostgresRedoManager {
     tenant_shard_id: TenantShardId,
@@ -96,21 +109,40 @@ pub struct PostgresRedoManager {
     redo_process: RwLock<Option<Arc<WalRedoProcess>>>,
 }
 
+/// Whether the heap records which have a Rust implementation in [`heap`] are replayed
+/// in-process, instead of by the wal-redo postgres process. Only some of the heap records of
+/// PostgreSQL 16 have one; other versions and records always use the wal-redo process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::EnumString, strum_macros::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum WalRedoNativeHeapMode {
+    /// Send all Postgres WAL records to the wal-redo process.
+    Disabled,
+    /// Replay the supported heap records in-process.
+    Enabled,
+    /// Replay the supported heap records both in-process and in the wal-redo process, and
+    /// compare the results. The result of the wal-redo process is used. This is for testing.
+    Differential,
+}
+
 /// Can this request be served by neon redo functions
 /// or we need to pass it to wal-redo postgres process?
-fn can_apply_in_neon(rec: &NeonWalRecord) -> bool {
-    // Currently, we don't have bespoken Rust code to replay any
-    // Postgres WAL records. But everything else is handled in neon.
-    #[allow(clippy::match_like_matches_macro)]
+fn can_apply_in_neon(rec: &NeonWalRecord, native_heap: bool, pg_version: u32) -> bool {
     match rec {
-        NeonWalRecord::Postgres {
-            will_init: _,
-            rec: _,
-        } => false,
+        NeonWalRecord::Postgres { will_init: _, rec } => {
+            native_heap && heap::can_apply(rec, pg_version)
+        }
         _ => true,
     }
 }
 
+/// A request to reconstruct one page, see [`PostgresRedoManager::request_redo_batch`].
+pub struct WalRedoRequest {
+    pub key: Key,
+    pub lsn: Lsn,
+    pub base_img: Option<(Lsn, Bytes)>,
+    pub records: Vec<(Lsn, NeonWalRecord)>,
+}
+
 ///
 /// Public interface of WAL redo manager
 ///
@@ -138,14 +170,23 @@ impl PostgresRedoManager {
 
         let base_img_lsn = base_img.as_ref().map(|p| p.0).unwrap_or(Lsn::INVALID);
         let mut img = base_img.map(|p| p.1);
-        let mut batch_neon = can_apply_in_neon(&records[0].1);
+        let native_heap = self.conf.wal_redo_native_heap != WalRedoNativeHeapMode::Disabled;
+        let mut batch_neon = can_apply_in_neon(&records[0].1, native_heap, pg_version);
         let mut batch_start = 0;
         for (i, record) in records.iter().enumerate().skip(1) {
-            let rec_neon = can_apply_in_neon(&record.1);
+            let rec_neon = can_apply_in_neon(&record.1, native_heap, pg_version);
 
             if rec_neon != batch_neon {
                 let result = if batch_neon {
-                    self.apply_batch_neon(key, lsn, img, &records[batch_start..i])
+                    self.apply_batch_neon(
+                        key,
+                        lsn,
+                        img,
+                        base_img_lsn,
+                        &records[batch_start..i],
+                        pg_version,
+                    )
+                    .await
                 } else {
                     self.apply_batch_postgres(
                         key,
@@ -156,6 +197,7 @@ impl PostgresRedoManager {
                         self.conf.wal_redo_timeout,
                         pg_version,
                     )
+                    .await
                 };
                 img = Some(result?);
 
@@ -165,7 +207,15 @@ impl PostgresRedoManager {
         }
         // last batch
         if batch_neon {
-            self.apply_batch_neon(key, lsn, img, &records[batch_start..])
+            self.apply_batch_neon(
+                key,
+                lsn,
+                img,
+                base_img_lsn,
+                &records[batch_start..],
+                pg_version,
+            )
+            .await
         } else {
             self.apply_batch_postgres(
                 key,
@@ -176,8 +226,88 @@ impl PostgresRedoManager {
                 self.conf.wal_redo_timeout,
                 pg_version,
             )
+            .await
         }
     }
+
+    ///
+    /// Request the WAL redo manager to reconstruct many pages. Returns the results in the
+    /// order of the requests.
+    ///
+    /// The requests that only consist of records for the wal-redo postgres process are sent
+    /// to the same process at once, instead of waiting for each page before sending the
+    /// next request. The others are handled like [`Self::request_redo`] does.
+    ///
+    /// # Cancel-Safety
+    ///
+    /// This method is cancellation-safe.
+    pub async fn request_redo_batch(
+        &self,
+        requests: Vec<WalRedoRequest>,
+        pg_version: u32,
+    ) -> Vec<anyhow::Result<Bytes>> {
+        let native_heap = self.conf.wal_redo_native_heap != WalRedoNativeHeapMode::Disabled;
+        let mut results: Vec<Option<anyhow::Result<Bytes>>> =
+            requests.iter().map(|_| None).collect();
+        let mut batch = Vec::new();
+        let mut others = Vec::new();
+        for (i, request) in requests.into_iter().enumerate() {
+            let postgres_only = !request.records.is_empty()
+                && !request
+                    .records
+                    .iter()
+                    .any(|(_, rec)| can_apply_in_neon(rec, native_heap, pg_version));
+            match key_to_rel_block(request.key) {
+                Ok((rel, blknum)) if postgres_only => {
+                    batch.push((i, BufferTag { rel, blknum }, request))
+                }
+                _ => others.push((i, request)),
+            }
+        }
+
+        if batch.len() > 1 {
+            match self
+                .apply_batch_postgres_pipelined(&batch, pg_version)
+                .await
+            {
+                Ok(pages) => {
+                    for ((i, _, _), page) in batch.iter().zip(pages) {
+                        results[*i] = Some(Ok(page));
+                    }
+                }
+                Err(e) => {
+                    // Retry each request on its own, so that one bad request doesn't fail the
+                    // others.
+                    warn!(
+                        "error applying a batch of {} WAL redo requests, retrying them one by one: {:?}",
+                        batch.len(),
+                        e
+                    );
+                    others.extend(batch.into_iter().map(|(i, _, request)| (i, request)));
+                }
+            }
+        } else {
+            others.extend(batch.into_iter().map(|(i, _, request)| (i, request)));
+        }
+
+        for (i, request) in others {
+            results[i] = Some(
+                self.request_redo(
+                    request.key,
+                    request.lsn,
+                    request.base_img,
+                    request.records,
+                    pg_version,
+                )
+                .await,
+            );
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every request was handled"))
+            .collect()
+    }
 }
 
 impl PostgresRedoManager {
@@ -201,6 +331,10 @@ impl PostgresRedoManager {
     /// rely on our owner calling this function periodically in its own housekeeping
     /// loops.
     pub(crate) fn maybe_quiesce(&self, idle_timeout: Duration) {
+        if let Some(pool) = pool::get() {
+            pool.quiesce(idle_timeout);
+            return;
+        }
         if let Ok(g) = self.last_redo_at.try_lock() {
             if let Some(last_redo_at) = *g {
                 if last_redo_at.elapsed() >= idle_timeout {
@@ -216,7 +350,7 @@ impl PostgresRedoManager {
     /// Process one request for WAL redo using wal-redo postgres
     ///
     #[allow(clippy::too_many_arguments)]
-    fn apply_batch_postgres(
+    async fn apply_batch_postgres(
         &self,
         key: Key,
         lsn: Lsn,
@@ -232,36 +366,7 @@ impl PostgresRedoManager {
         const MAX_RETRY_ATTEMPTS: u32 = 1;
         let mut n_attempts = 0u32;
         loop {
-            // launch the WAL redo process on first use
-            let proc: Arc<WalRedoProcess> = {
-                let proc_guard = self.redo_process.read().unwrap();
-                match &*proc_guard {
-                    None => {
-                        // "upgrade" to write lock to launch the process
-                        drop(proc_guard);
-                        let mut proc_guard = self.redo_process.write().unwrap();
-                        match &*proc_guard {
-                            None => {
-                                let timer =
-                                    WAL_REDO_PROCESS_LAUNCH_DURATION_HISTOGRAM.start_timer();
-                                let proc = Arc::new(
-                                    WalRedoProcess::launch(
-                                        self.conf,
-                                        self.tenant_shard_id,
-                                        pg_version,
-                                    )
-                                    .context("launch walredo process")?,
-                                );
-                                timer.observe_duration();
-                                *proc_guard = Some(Arc::clone(&proc));
-                                proc
-                            }
-                            Some(proc) => Arc::clone(proc),
-                        }
-                    }
-                    Some(proc) => Arc::clone(proc),
-                }
-            };
+            let proc = self.acquire_process(pg_version).await?;
 
             let started_at = std::time::Instant::now();
 
@@ -308,35 +413,7 @@ impl PostgresRedoManager {
                     n_attempts,
                     e,
                 );
-                // Avoid concurrent callers hitting the same issue.
-                // We can't prevent it from happening because we want to enable parallelism.
-                {
-                    let mut guard = self.redo_process.write().unwrap();
-                    match &*guard {
-                        Some(current_field_value) => {
-                            if Arc::ptr_eq(current_field_value, &proc) {
-                                // We're the first to observe an error from `proc`, it's our job to take it out of rotation.
-                                *guard = None;
-                            }
-                        }
-                        None => {
-                            // Another thread was faster to observe the error, and already took the process out of rotation.
-                        }
-                    }
-                }
-                // NB: there may still be other concurrent threads using `proc`.
-                // The last one will send SIGKILL when the underlying Arc reaches refcount 0.
-                // NB: it's important to drop(proc) after drop(guard). Otherwise we'd keep
-                // holding the lock while waiting for the process to exit.
-                // NB: the drop impl blocks the current threads with a wait() system call for
-                // the child process. We dropped the `guard` above so that other threads aren't
-                // affected. But, it's good that the current thread _does_ block to wait.
-                // If we instead deferred the waiting into the background / to tokio, it could
-                // happen that if walredo always fails immediately, we spawn processes faster
-                // than we can SIGKILL & `wait` for them to exit. By doing it the way we do here,
-                // we limit this risk of run-away to at most $num_runtimes * $num_executor_threads.
-                // This probably needs revisiting at some later point.
-                drop(proc);
+                self.discard_process(proc);
             } else if n_attempts != 0 {
                 info!(n_attempts, "retried walredo succeeded");
             }
@@ -347,15 +424,252 @@ impl PostgresRedoManager {
         }
     }
 
+    ///
+    /// Process many requests for WAL redo using the same wal-redo postgres process, without
+    /// waiting for each page before sending the next request
+    ///
+    async fn apply_batch_postgres_pipelined(
+        &self,
+        batch: &[(usize, BufferTag, WalRedoRequest)],
+        pg_version: u32,
+    ) -> anyhow::Result<Vec<Bytes>> {
+        *(self.last_redo_at.lock().unwrap()) = Some(Instant::now());
+
+        let proc = self.acquire_process(pg_version).await?;
+        let started_at = std::time::Instant::now();
+
+        let requests: Vec<_> = batch
+            .iter()
+            .map(|(_, tag, request)| {
+                (
+                    *tag,
+                    request.base_img.as_ref().map(|(_, img)| img.clone()),
+                    request.records.as_slice(),
+                )
+            })
+            .collect();
+        let result = proc
+            .apply_wal_records_batch(&requests, self.conf.wal_redo_timeout)
+            .context("apply_wal_records_batch");
+
+        let duration = started_at.elapsed();
+        WAL_REDO_TIME.observe(duration.as_secs_f64());
+        for (_, _, request) in batch {
+            let nbytes = request.records.iter().fold(0, |acumulator, record| {
+                acumulator
+                    + match &record.1 {
+                        NeonWalRecord::Postgres { rec, .. } => rec.len(),
+                        _ => unreachable!("Only PostgreSQL records are accepted in this batch"),
+                    }
+            });
+            WAL_REDO_RECORDS_HISTOGRAM.observe(request.records.len() as f64);
+            WAL_REDO_BYTES_HISTOGRAM.observe(nbytes as f64);
+        }
+
+        debug!(
+            "postgres reconstructed {} page images in {} us",
+            batch.len(),
+            duration.as_micros(),
+        );
+
+        if result.is_err() {
+            self.discard_process(proc);
+        }
+        result
+    }
+
+    /// Returns the process for a redo request: the tenant's own, or one of the pool.
+    async fn acquire_process(&self, pg_version: u32) -> anyhow::Result<RedoProcess> {
+        Ok(match pool::get() {
+            Some(pool) => RedoProcess::Pooled(
+                pool.acquire(self.tenant_shard_id, pg_version)
+                    .await
+                    .context("get walredo process from the pool")?,
+            ),
+            None => RedoProcess::Dedicated(self.get_or_launch_process(pg_version)?),
+        })
+    }
+
+    /// Takes a process that failed a request out of rotation: we don't know in which state
+    /// it is. The next request will launch a new one.
+    fn discard_process(&self, mut proc: RedoProcess) {
+        match &mut proc {
+            RedoProcess::Pooled(proc) => proc.discard(),
+            RedoProcess::Dedicated(proc) => {
+                // Avoid concurrent callers hitting the same issue.
+                // We can't prevent it from happening because we want to enable parallelism.
+                let mut guard = self.redo_process.write().unwrap();
+                match &*guard {
+                    Some(current_field_value) => {
+                        if Arc::ptr_eq(current_field_value, proc) {
+                            // We're the first to observe an error from `proc`, it's our job to take it out of rotation.
+                            *guard = None;
+                        }
+                    }
+                    None => {
+                        // Another thread was faster to observe the error, and already took the process out of rotation.
+                    }
+                }
+            }
+        }
+        // NB: there may still be other concurrent threads using a dedicated `proc`.
+        // The last one will send SIGKILL when the underlying Arc reaches refcount 0.
+        // NB: it's important to drop(proc) after drop(guard). Otherwise we'd keep
+        // holding the lock while waiting for the process to exit.
+        // NB: the drop impl blocks the current threads with a wait() system call for
+        // the child process. We dropped the `guard` above so that other threads aren't
+        // affected. But, it's good that the current thread _does_ block to wait.
+        // If we instead deferred the waiting into the background / to tokio, it could
+        // happen that if walredo always fails immediately, we spawn processes faster
+        // than we can SIGKILL & `wait` for them to exit. By doing it the way we do here,
+        // we limit this risk of run-away to at most $num_runtimes * $num_executor_threads.
+        // This probably needs revisiting at some later point.
+        drop(proc);
+    }
+
+    /// Returns the tenant's own WAL redo process, launching it on first use.
+    fn get_or_launch_process(&self, pg_version: u32) -> anyhow::Result<Arc<WalRedoProcess>> {
+        let proc_guard = self.redo_process.read().unwrap();
+        if let Some(proc) = &*proc_guard {
+            return Ok(Arc::clone(proc));
+        }
+        // "upgrade" to write lock to launch the process
+        drop(proc_guard);
+        let mut proc_guard = self.redo_process.write().unwrap();
+        match &*proc_guard {
+            None => {
+                let timer = WAL_REDO_PROCESS_LAUNCH_DURATION_HISTOGRAM.start_timer();
+                let proc = Arc::new(
+                    WalRedoProcess::launch(self.conf, self.tenant_shard_id, pg_version)
+                        .context("launch walredo process")?,
+                );
+                timer.observe_duration();
+                *proc_guard = Some(Arc::clone(&proc));
+                Ok(proc)
+            }
+            Some(proc) => Ok(Arc::clone(proc)),
+        }
+    }
+
     ///
     /// Process a batch of WAL records using bespoken Neon code.
     ///
-    fn apply_batch_neon(
+    async fn apply_batch_neon(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        base_img: Option<Bytes>,
+        base_img_lsn: Lsn,
+        records: &[(Lsn, NeonWalRecord)],
+        pg_version: u32,
+    ) -> anyhow::Result<Bytes> {
+        // Heap records are batched with the neon records only when we replay them natively,
+        // and never together with the other kinds, which apply to other pages.
+        let has_postgres_records = records
+            .iter()
+            .any(|(_, rec)| matches!(rec, NeonWalRecord::Postgres { .. }));
+
+        if has_postgres_records
+            && self.conf.wal_redo_native_heap == WalRedoNativeHeapMode::Differential
+        {
+            return self
+                .apply_batch_differential(key, lsn, base_img, base_img_lsn, records, pg_version)
+                .await;
+        }
+
+        let result = self.apply_batch_neon0(key, lsn, base_img.clone(), records, pg_version);
+
+        match result {
+            Err(e) if has_postgres_records => {
+                // Leave it to the wal-redo process to report the error, if it is a genuine one.
+                WAL_REDO_NATIVE_HEAP_COUNTERS.fallbacks.inc();
+                warn!(
+                    "native redo of {} WAL records failed, falling back to wal-redo postgres: {:#}",
+                    records.len(),
+                    e
+                );
+                self.apply_batch_postgres(
+                    key,
+                    lsn,
+                    base_img,
+                    base_img_lsn,
+                    records,
+                    self.conf.wal_redo_timeout,
+                    pg_version,
+                )
+                .await
+            }
+            result => result,
+        }
+    }
+
+    /// Replays a batch of heap records both natively and in the wal-redo process, and compares
+    /// the results. Returns the result of the wal-redo process.
+    async fn apply_batch_differential(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        base_img: Option<Bytes>,
+        base_img_lsn: Lsn,
+        records: &[(Lsn, NeonWalRecord)],
+        pg_version: u32,
+    ) -> anyhow::Result<Bytes> {
+        let native = self.apply_batch_neon0(key, lsn, base_img.clone(), records, pg_version);
+        let postgres = self
+            .apply_batch_postgres(
+                key,
+                lsn,
+                base_img,
+                base_img_lsn,
+                records,
+                self.conf.wal_redo_timeout,
+                pg_version,
+            )
+            .await;
+
+        match (&native, &postgres) {
+            (Ok(native), Ok(postgres)) if native == postgres => {}
+            (Err(_), Err(_)) => {}
+            (Ok(native), Ok(postgres)) => {
+                WAL_REDO_NATIVE_HEAP_COUNTERS.mismatches.inc();
+                let first_diff = native
+                    .iter()
+                    .zip(postgres.iter())
+                    .position(|(a, b)| a != b)
+                    .unwrap_or(native.len().min(postgres.len()));
+                error!(
+                    %key,
+                    %lsn,
+                    %base_img_lsn,
+                    first_diff,
+                    "native redo of {} WAL records differs from wal-redo postgres",
+                    records.len(),
+                );
+            }
+            (native, postgres) => {
+                WAL_REDO_NATIVE_HEAP_COUNTERS.mismatches.inc();
+                error!(
+                    %key,
+                    %lsn,
+                    %base_img_lsn,
+                    native_error = ?native.as_ref().err(),
+                    postgres_error = ?postgres.as_ref().err(),
+                    "native redo of {} WAL records disagrees with wal-redo postgres on failure",
+                    records.len(),
+                );
+            }
+        }
+
+        postgres
+    }
+
+    fn apply_batch_neon0(
         &self,
         key: Key,
         lsn: Lsn,
         base_img: Option<Bytes>,
         records: &[(Lsn, NeonWalRecord)],
+        pg_version: u32,
     ) -> anyhow::Result<Bytes> {
         let start_time = Instant::now();
 
@@ -363,6 +677,9 @@ impl PostgresRedoManager {
         if let Some(fpi) = base_img {
             // If full-page image is provided, then use it...
             page.extend_from_slice(&fpi[..]);
+        } else if records[0].1.will_init() {
+            // ...otherwise the first record initializes the page.
+            page.resize(BLCKSZ as usize, 0);
         } else {
             // All the current WAL record types that we can handle require a base image.
             anyhow::bail!("invalid neon WAL redo request with no base image");
@@ -370,7 +687,7 @@ impl PostgresRedoManager {
 
         // Apply all the WAL records in the batch
         for (record_lsn, record) in records.iter() {
-            self.apply_record_neon(key, &mut page, *record_lsn, record)?;
+            self.apply_record_neon(key, &mut page, *record_lsn, record, pg_version)?;
         }
         // Success!
         let duration = start_time.elapsed();
@@ -392,15 +709,14 @@ impl PostgresRedoManager {
         &self,
         key: Key,
         page: &mut BytesMut,
-        _record_lsn: Lsn,
+        record_lsn: Lsn,
         record: &NeonWalRecord,
+        pg_version: u32,
     ) -> anyhow::Result<()> {
         match record {
-            NeonWalRecord::Postgres {
-                will_init: _,
-                rec: _,
-            } => {
-                anyhow::bail!("tried to pass postgres wal record to neon WAL redo");
+            NeonWalRecord::Postgres { will_init: _, rec } => {
+                heap::apply(key, page, record_lsn, rec, pg_version)?;
+                WAL_REDO_NATIVE_HEAP_COUNTERS.applied.inc();
             }
             NeonWalRecord::ClearVisibilityMapFlags {
                 new_heap_blkno,
@@ -640,10 +956,33 @@ impl<C: CommandExt> CloseFileDescriptors for C {
     }
 }
 
+/// The process serving one redo request: either the tenant's own, or one checked out of the
+/// pool.
+enum RedoProcess {
+    Dedicated(Arc<WalRedoProcess>),
+    Pooled(pool::PooledProcess),
+}
+
+impl Deref for RedoProcess {
+    type Target = WalRedoProcess;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            RedoProcess::Dedicated(proc) => proc,
+            RedoProcess::Pooled(proc) => proc,
+        }
+    }
+}
+
 struct WalRedoProcess {
     #[allow(dead_code)]
     conf: &'static PageServerConf,
+    /// The tenant this process currently serves; pooled processes get reassigned.
     tenant_shard_id: TenantShardId,
+    pg_version: u32,
+    /// Set when the process has been reassigned to another tenant, until the next request
+    /// tells the process to forget the pages of the previous tenant.
+    reset_pending: AtomicBool,
     // Some() on construction, only becomes None on Drop.
     child: Option<NoLeakChild>,
     stdout: Mutex<ProcessOutput>,
@@ -752,6 +1091,8 @@ impl WalRedoProcess {
         Ok(Self {
             conf,
             tenant_shard_id,
+            pg_version,
+            reset_pending: AtomicBool::new(false),
             child: Some(child),
             stdin: Mutex::new(ProcessInput {
                 stdin,
@@ -774,6 +1115,13 @@ impl WalRedoProcess {
             .id()
     }
 
+    /// Hands the process over to another tenant. The next request resets the state that the
+    /// previous tenant left in the process.
+    fn reassign(&mut self, tenant_shard_id: TenantShardId) {
+        self.tenant_shard_id = tenant_shard_id;
+        *self.reset_pending.get_mut() = true;
+    }
+
     // Apply given WAL records ('records') over an old page image. Returns
     // new page image.
     //
@@ -797,25 +1145,13 @@ impl WalRedoProcess {
         // by some other WAL records. Start with a buffer that can hold that
         // comfortably.
         let mut writebuf: Vec<u8> = Vec::with_capacity((BLCKSZ as usize) * 3);
-        build_begin_redo_for_block_msg(tag, &mut writebuf);
-        if let Some(img) = base_img {
-            build_push_page_msg(tag, img, &mut writebuf);
+        if self.reset_pending.swap(false, Ordering::Relaxed) {
+            build_reset_redo_state_msg(&mut writebuf);
         }
-        for (lsn, rec) in records.iter() {
-            if let NeonWalRecord::Postgres {
-                will_init: _,
-                rec: postgres_rec,
-            } = rec
-            {
-                build_apply_record_msg(*lsn, postgres_rec, &mut writebuf);
-            } else {
-                anyhow::bail!("tried to pass neon wal record to postgres WAL redo");
-            }
-        }
-        build_get_page_msg(tag, &mut writebuf);
+        build_redo_msgs(tag, base_img, records, &mut writebuf)?;
         WAL_REDO_RECORD_COUNTER.inc_by(records.len() as u64);
 
-        let res = self.apply_wal_records0(&writebuf, input, wal_redo_timeout);
+        let res = self.apply_wal_records0(&writebuf, 1, input, wal_redo_timeout);
 
         if res.is_err() {
             // not all of these can be caused by this particular input, however these are so rare
@@ -823,15 +1159,47 @@ impl WalRedoProcess {
             self.record_and_log(&writebuf);
         }
 
+        res.map(|mut pages| pages.pop().expect("one page per request"))
+    }
+
+    // Reconstruct many pages, each by applying WAL records over an old page image. The
+    // requests are sent all at once. Returns the new page images in the order of the
+    // requests.
+    //
+    #[instrument(skip_all, fields(tenant_id=%self.tenant_shard_id.tenant_id, shard_id=%self.tenant_shard_id.shard_slug(), pid=%self.id()))]
+    fn apply_wal_records_batch(
+        &self,
+        requests: &[(BufferTag, Option<Bytes>, &[(Lsn, NeonWalRecord)])],
+        wal_redo_timeout: Duration,
+    ) -> anyhow::Result<Vec<Bytes>> {
+        let input = self.stdin.lock().unwrap();
+
+        let mut writebuf: Vec<u8> = Vec::with_capacity((BLCKSZ as usize) * 2 * requests.len());
+        if self.reset_pending.swap(false, Ordering::Relaxed) {
+            build_reset_redo_state_msg(&mut writebuf);
+        }
+        for (tag, base_img, records) in requests {
+            build_redo_msgs(*tag, base_img, records, &mut writebuf)?;
+            WAL_REDO_RECORD_COUNTER.inc_by(records.len() as u64);
+        }
+
+        let res = self.apply_wal_records0(&writebuf, requests.len(), input, wal_redo_timeout);
+
+        if res.is_err() {
+            self.record_and_log(&writebuf);
+        }
+
         res
     }
 
+    /// Sends `writebuf`, which holds `n_pages` requests, and waits for their pages.
     fn apply_wal_records0(
         &self,
         writebuf: &[u8],
+        n_pages: usize,
         input: MutexGuard<ProcessInput>,
         wal_redo_timeout: Duration,
-    ) -> anyhow::Result<Bytes> {
+    ) -> anyhow::Result<Vec<Bytes>> {
         let mut proc = { input }; // TODO: remove this legacy rename, but this keep the patch small.
         let mut nwrite = 0usize;
 
@@ -860,7 +1228,7 @@ impl WalRedoProcess {
             }
         }
         let request_no = proc.n_requests;
-        proc.n_requests += 1;
+        proc.n_requests += n_pages;
         drop(proc);
 
         // To improve walredo performance we separate sending requests and receiving
@@ -878,7 +1246,7 @@ impl WalRedoProcess {
         let mut output = self.stdout.lock().unwrap();
         let mut stdout_pollfds = [PollFd::new(output.stdout.as_raw_fd(), PollFlags::POLLIN)];
         let n_processed_responses = output.n_processed_responses;
-        while n_processed_responses + output.pending_responses.len() <= request_no {
+        while n_processed_responses + output.pending_responses.len() < request_no + n_pages {
             // We expect the WAL redo process to respond with an 8k page image. We read it
             // into this buffer.
             let mut resultbuf = vec![0; BLCKSZ.into()];
@@ -945,9 +1313,16 @@ impl WalRedoProcess {
         // T2: does the while loop below
         // pending_responses now looks like this: Front Back
         // n_processed_responses now has value 25
-        let res = output.pending_responses[request_no - n_processed_responses]
-            .take()
-            .expect("we own this request_no, nobody else is supposed to take it");
+        let first = request_no - n_processed_responses;
+        let res = output
+            .pending_responses
+            .range_mut(first..first + n_pages)
+            .map(|response| {
+                response
+                    .take()
+                    .expect("we own this request_no, nobody else is supposed to take it")
+            })
+            .collect();
         while let Some(front) = output.pending_responses.front() {
             if front.is_none() {
                 output.pending_responses.pop_front();
@@ -1114,6 +1489,33 @@ impl NoLeakChildCommandExt for Command {
 // process. See pgxn/neon_walredo/walredoproc.c for
 // explanation of the protocol.
 
+/// Builds the messages to reconstruct one page: push the base image, if any, apply the records
+/// and get the page.
+fn build_redo_msgs(
+    tag: BufferTag,
+    base_img: &Option<Bytes>,
+    records: &[(Lsn, NeonWalRecord)],
+    buf: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    build_begin_redo_for_block_msg(tag, buf);
+    if let Some(img) = base_img {
+        build_push_page_msg(tag, img, buf);
+    }
+    for (lsn, rec) in records.iter() {
+        if let NeonWalRecord::Postgres {
+            will_init: _,
+            rec: postgres_rec,
+        } = rec
+        {
+            build_apply_record_msg(*lsn, postgres_rec, buf);
+        } else {
+            anyhow::bail!("tried to pass neon wal record to postgres WAL redo");
+        }
+    }
+    build_get_page_msg(tag, buf);
+    Ok(())
+}
+
 fn build_begin_redo_for_block_msg(tag: BufferTag, buf: &mut Vec<u8>) {
     let len = 4 + 1 + 4 * 4;
 
@@ -1154,9 +1556,16 @@ fn build_get_page_msg(tag: BufferTag, buf: &mut Vec<u8>) {
         .expect("serialize BufferTag should always succeed");
 }
 
+fn build_reset_redo_state_msg(buf: &mut Vec<u8>) {
+    let len = 4;
+
+    buf.put_u8(b'R');
+    buf.put_u32(len as u32);
+}
+
 #[cfg(test)]
 mod tests {
-    use super::PostgresRedoManager;
+    use super::{PostgresRedoManager, WalRedoRequest};
     use crate::repository::Key;
     use crate::{config::PageServerConf, walrecord::NeonWalRecord};
     use bytes::Bytes;
@@ -1221,6 +1630,43 @@ mod tests {
         assert_eq!(page, crate::ZERO_PAGE);
     }
 
+    #[tokio::test]
+    async fn short_v14_redo_batch() {
+        let expected = std::fs::read("test_data/short_v14_redo.page").unwrap();
+
+        let h = RedoHarness::new().unwrap();
+
+        let key = Key {
+            field1: 0,
+            field2: 1663,
+            field3: 13010,
+            field4: 1259,
+            field5: 0,
+            field6: 0,
+        };
+        // the same wrong key as in short_v14_fails_for_wrong_key_but_returns_zero_page
+        let wrong_key = Key {
+            field3: 13130,
+            ..key
+        };
+        let requests = [key, wrong_key, key]
+            .into_iter()
+            .map(|key| WalRedoRequest {
+                key,
+                lsn: Lsn::from_str("0/16E2408").unwrap(),
+                base_img: None,
+                records: short_records(),
+            })
+            .collect();
+
+        let pages = h.manager.request_redo_batch(requests, 14).await;
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(&expected, &*pages[0].as_ref().unwrap());
+        assert_eq!(pages[1].as_ref().unwrap(), &crate::ZERO_PAGE);
+        assert_eq!(&expected, &*pages[2].as_ref().unwrap());
+    }
+
     #[tokio::test]
     async fn test_stderr() {
         let h = RedoHarness::new().unwrap();
@@ -1238,7 +1684,7 @@ mod tests {
     }
 
     #[allow(clippy::octal_escapes)]
-    fn short_records() -> Vec<(Lsn, NeonWalRecord)> {
+    pub(super) fn short_records() -> Vec<(Lsn, NeonWalRecord)> {
         vec![
             (
                 Lsn::from_str("0/16A9388").unwrap(),
@@ -0,0 +1,1080 @@
+//!
+//! In-process redo of heap records.
+//!
+//! This is a port of the redo functions in `pgxn/neon_rmgr/neon_rmgr.c`, which is what the
+//! wal-redo postgres process runs for the heap records that PostgreSQL 16 logs through the
+//! Neon RMGR: the equivalents of `XLOG_HEAP_INSERT`, `XLOG_HEAP_DELETE`, `XLOG_HEAP_UPDATE`,
+//! `XLOG_HEAP_HOT_UPDATE`, `XLOG_HEAP_LOCK` and `XLOG_HEAP2_MULTI_INSERT`.
+//!
+//! Nothing else is replayed here, see [`can_apply`]: PostgreSQL 14 and 15 log the records
+//! above with the core heap RMGR, using the record layouts of our PostgreSQL forks, and the
+//! `XLOG_HEAP2_PRUNE`, `XLOG_HEAP2_VACUUM`, `XLOG_HEAP2_FREEZE_PAGE` and `XLOG_HEAP2_VISIBLE`
+//! records of all versions need the page pruning and defragmentation code of PostgreSQL. All
+//! of those are still replayed by the wal-redo process.
+//!
+//! Like the wal-redo process, we only replay the effect of a record on the page that is being
+//! reconstructed. Visibility map bits are cleared by separate
+//! [`NeonWalRecord::ClearVisibilityMapFlags`](crate::walrecord::NeonWalRecord) records, and the
+//! FSM is not WAL-logged at all.
+//!
+//! The result must be identical, byte for byte, to what the wal-redo process produces; the
+//! `differential` mode of [`WalRedoNativeHeapMode`](super::WalRedoNativeHeapMode) checks that.
+//!
+
+use anyhow::{bail, ensure, Context};
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Buf, Bytes};
+use postgres_ffi::pg_constants;
+use postgres_ffi::{page_get_lsn, page_is_new, page_set_lsn, transaction_id_precedes, BLCKSZ};
+use utils::lsn::Lsn;
+
+use crate::pgdatadir_mapping::key_to_rel_block;
+use crate::repository::Key;
+use crate::walrecord::v16::rm_neon::{
+    XlNeonHeapDelete, XlNeonHeapHeader, XlNeonHeapInsert, XlNeonHeapLock, XlNeonHeapMultiInsert,
+    XlNeonHeapUpdate, XlNeonMultiInsertTuple,
+};
+use crate::walrecord::{decode_wal_record, DecodedBkpBlock, DecodedWALRecord};
+
+/// Can this record be replayed by [`apply`]? Only the heap records of the Neon RMGR of
+/// PostgreSQL 16 can.
+pub(super) fn can_apply(rec: &Bytes, pg_version: u32) -> bool {
+    if pg_version != 16 {
+        return false;
+    }
+
+    let mut decoded = DecodedWALRecord::default();
+    if decode_wal_record(rec.clone(), &mut decoded, pg_version).is_err() {
+        return false;
+    }
+    if decoded.xl_rmid != pg_constants::RM_NEON_ID {
+        return false;
+    }
+
+    let supported = matches!(
+        decoded.xl_info & pg_constants::XLOG_HEAP_OPMASK,
+        pg_constants::XLOG_NEON_HEAP_INSERT
+            | pg_constants::XLOG_NEON_HEAP_DELETE
+            | pg_constants::XLOG_NEON_HEAP_UPDATE
+            | pg_constants::XLOG_NEON_HEAP_HOT_UPDATE
+            | pg_constants::XLOG_NEON_HEAP_LOCK
+            | pg_constants::XLOG_NEON_HEAP_MULTI_INSERT
+    );
+
+    // We don't have the decompression code of the wal-redo process for page images.
+    supported
+        && decoded.blocks.iter().all(|blk| {
+            !blk.apply_image
+                || matches!(
+                    postgres_ffi::bkpimage_is_compressed(blk.bimg_info, pg_version),
+                    Ok(false)
+                )
+        })
+}
+
+/// Replays a heap record over `page`, which is the page identified by `key`.
+///
+/// `lsn` is the end LSN of the record, which becomes the LSN of the page.
+pub(super) fn apply(
+    key: Key,
+    page: &mut [u8],
+    lsn: Lsn,
+    rec: &Bytes,
+    pg_version: u32,
+) -> anyhow::Result<()> {
+    ensure!(
+        page.len() == BLCKSZ as usize,
+        "unexpected page size {}",
+        page.len()
+    );
+
+    let mut decoded = DecodedWALRecord::default();
+    decode_wal_record(rec.clone(), &mut decoded, pg_version)?;
+    ensure!(
+        decoded.xl_rmid == pg_constants::RM_NEON_ID,
+        "not a neon rmgr record: rmid {}",
+        decoded.xl_rmid
+    );
+
+    let (rel, blknum) = key_to_rel_block(key).context("invalid record")?;
+    let block_id = decoded
+        .blocks
+        .iter()
+        .position(|blk| {
+            blk.rnode_spcnode == rel.spcnode
+                && blk.rnode_dbnode == rel.dbnode
+                && blk.rnode_relnode == rel.relnode
+                && blk.forknum == rel.forknum
+                && blk.blkno == blknum
+        })
+        .with_context(|| format!("record does not reference {rel} blk {blknum}"))?;
+
+    let mut main_data = decoded.record.slice(decoded.main_data_offset..);
+    let init_page = decoded.xl_info & pg_constants::XLOG_NEON_HEAP_INIT_PAGE != 0;
+
+    let redo = HeapRedo {
+        decoded: &decoded,
+        page,
+        blkno: blknum,
+        lsn,
+    };
+
+    match decoded.xl_info & pg_constants::XLOG_HEAP_OPMASK {
+        pg_constants::XLOG_NEON_HEAP_INSERT => {
+            ensure!(block_id == 0, "insert record for block {block_id}");
+            let xlrec = XlNeonHeapInsert::decode(&mut main_data);
+            redo.insert(&xlrec, init_page)
+        }
+        pg_constants::XLOG_NEON_HEAP_DELETE => {
+            ensure!(block_id == 0, "delete record for block {block_id}");
+            let xlrec = XlNeonHeapDelete::decode(&mut main_data);
+            redo.delete(&xlrec)
+        }
+        info @ (pg_constants::XLOG_NEON_HEAP_UPDATE | pg_constants::XLOG_NEON_HEAP_HOT_UPDATE) => {
+            let xlrec = XlNeonHeapUpdate::decode(&mut main_data);
+            let hot_update = info == pg_constants::XLOG_NEON_HEAP_HOT_UPDATE;
+            // Block 0 is the page of the new tuple, block 1 the page of the old tuple if it
+            // is a different one.
+            let target = match (block_id, decoded.blocks.len()) {
+                (0, 1) => UpdateTarget::SamePage,
+                (0, _) => UpdateTarget::NewPage,
+                _ => UpdateTarget::OldPage,
+            };
+            redo.update(&xlrec, hot_update, init_page, target)
+        }
+        pg_constants::XLOG_NEON_HEAP_LOCK => {
+            ensure!(block_id == 0, "lock record for block {block_id}");
+            let xlrec = XlNeonHeapLock::decode(&mut main_data);
+            redo.lock(&xlrec)
+        }
+        pg_constants::XLOG_NEON_HEAP_MULTI_INSERT => {
+            ensure!(block_id == 0, "multi-insert record for block {block_id}");
+            let xlrec = XlNeonHeapMultiInsert::decode(&mut main_data);
+            redo.multi_insert(&xlrec, main_data, init_page)
+        }
+        info => bail!("unsupported neon rmgr record: info {info:#x}"),
+    }
+}
+
+enum UpdateTarget {
+    SamePage,
+    OldPage,
+    NewPage,
+}
+
+/// Equivalent of `XLogRedoAction`, minus `BLK_NOTFOUND`: we only ever look at the block that
+/// is being reconstructed.
+#[derive(PartialEq, Eq)]
+enum RedoAction {
+    NeedsRedo,
+    Done,
+}
+
+struct HeapRedo<'a> {
+    decoded: &'a DecodedWALRecord,
+    page: &'a mut [u8],
+    blkno: u32,
+    lsn: Lsn,
+}
+
+impl HeapRedo<'_> {
+    fn block(&self, block_id: usize) -> &DecodedBkpBlock {
+        &self.decoded.blocks[block_id]
+    }
+
+    fn block_data(&self, block_id: usize) -> anyhow::Result<Bytes> {
+        let blk = self.block(block_id);
+        ensure!(blk.has_data, "no data for block {block_id}");
+        let start = blk.data_offset as usize;
+        Ok(self
+            .decoded
+            .record
+            .slice(start..start + blk.data_len as usize))
+    }
+
+    /// Equivalent of `XLogReadBufferForRedo`: restores the page image if the record carries
+    /// one, and tells whether the record still needs to be applied to the page.
+    fn read_buffer_for_redo(&mut self, block_id: usize) -> anyhow::Result<RedoAction> {
+        let blk = self.block(block_id);
+        if blk.apply_image {
+            let img_offs = blk.bimg_offset as usize;
+            let img_len = blk.bimg_len as usize;
+            let hole_offset = blk.hole_offset as usize;
+            let hole_length = blk.hole_length as usize;
+            ensure!(
+                img_len + hole_length == BLCKSZ as usize,
+                "unexpected page image length {img_len} with hole {hole_length}"
+            );
+            let img = &self.decoded.record[img_offs..img_offs + img_len];
+
+            self.page[..hole_offset].copy_from_slice(&img[..hole_offset]);
+            self.page[hole_offset..hole_offset + hole_length].fill(0);
+            self.page[hole_offset + hole_length..].copy_from_slice(&img[hole_offset..]);
+
+            // The page may be uninitialized. If so, we can't set the LSN because that would
+            // corrupt the page.
+            if !page_is_new(self.page) {
+                page_set_lsn(self.page, self.lsn);
+            }
+            return Ok(RedoAction::Done);
+        }
+
+        if self.lsn <= page_get_lsn(self.page) {
+            return Ok(RedoAction::Done);
+        }
+        Ok(RedoAction::NeedsRedo)
+    }
+
+    /// Equivalent of `XLogInitBufferForRedo` followed by `PageInit`.
+    fn init_buffer_for_redo(&mut self, block_id: usize) -> anyhow::Result<RedoAction> {
+        // Like XLogInitBufferForRedo, restore the image if there is one; it is overwritten
+        // right away.
+        self.read_buffer_for_redo(block_id)?;
+        page_init(self.page);
+        Ok(RedoAction::NeedsRedo)
+    }
+
+    fn insert(mut self, xlrec: &XlNeonHeapInsert, init_page: bool) -> anyhow::Result<()> {
+        let action = if init_page {
+            self.init_buffer_for_redo(0)?
+        } else {
+            self.read_buffer_for_redo(0)?
+        };
+        if action == RedoAction::Done {
+            return Ok(());
+        }
+
+        if page_get_max_offset_number(self.page) + 1 < xlrec.offnum {
+            bail!("invalid max offset number");
+        }
+
+        let mut data = self.block_data(0)?;
+        ensure!(
+            data.len() > XlNeonHeapHeader::SIZE,
+            "insert record without tuple data"
+        );
+        let xlhdr = XlNeonHeapHeader::decode(&mut data);
+
+        let mut htup = vec![0u8; pg_constants::SIZEOF_HEAP_TUPLE_HEADER];
+        htup.extend_from_slice(&data);
+        set_u16(&mut htup, T_INFOMASK2, xlhdr.t_infomask2);
+        set_u16(&mut htup, T_INFOMASK, xlhdr.t_infomask);
+        htup[T_HOFF] = xlhdr.t_hoff;
+        set_u32(&mut htup, T_XMIN, self.decoded.xl_xid);
+        tuple_set_cid(&mut htup, xlhdr.t_cid);
+        tuple_set_ctid(&mut htup, self.blkno, xlrec.offnum);
+
+        page_add_item(self.page, &htup, xlrec.offnum)?;
+
+        page_set_lsn(self.page, self.lsn);
+
+        if xlrec.flags & pg_constants::XLH_INSERT_ALL_VISIBLE_CLEARED != 0 {
+            page_clear_all_visible(self.page);
+        }
+        // XLH_INSERT_ALL_FROZEN_SET implies that all tuples are visible
+        if xlrec.flags & pg_constants::XLH_INSERT_ALL_FROZEN_SET != 0 {
+            page_set_all_visible(self.page);
+        }
+        Ok(())
+    }
+
+    fn delete(mut self, xlrec: &XlNeonHeapDelete) -> anyhow::Result<()> {
+        if self.read_buffer_for_redo(0)? == RedoAction::Done {
+            return Ok(());
+        }
+
+        let (off, _len) = page_get_normal_item(self.page, xlrec.offnum)?;
+        let htup = &mut self.page[off..];
+
+        let mut infomask = get_u16(htup, T_INFOMASK);
+        let mut infomask2 = get_u16(htup, T_INFOMASK2);
+        infomask &= !(pg_constants::HEAP_XMAX_BITS | pg_constants::HEAP_MOVED);
+        infomask2 &= !pg_constants::HEAP_KEYS_UPDATED;
+        infomask2 &= !pg_constants::HEAP_HOT_UPDATED;
+        fix_infomask_from_infobits(xlrec.infobits_set, &mut infomask, &mut infomask2);
+        set_u16(htup, T_INFOMASK, infomask);
+        set_u16(htup, T_INFOMASK2, infomask2);
+
+        if xlrec.flags & pg_constants::XLH_DELETE_IS_SUPER == 0 {
+            set_u32(htup, T_XMAX, xlrec.xmax);
+        } else {
+            set_u32(htup, T_XMIN, pg_constants::INVALID_TRANSACTION_ID);
+        }
+        tuple_set_cid(htup, xlrec.t_cid);
+
+        // Make sure t_ctid is set correctly
+        if xlrec.flags & pg_constants::XLH_DELETE_IS_PARTITION_MOVE != 0 {
+            tuple_set_ctid(
+                htup,
+                pg_constants::MOVED_PARTITIONS_BLOCK_NUMBER,
+                pg_constants::MOVED_PARTITIONS_OFFSET_NUMBER,
+            );
+        } else {
+            tuple_set_ctid(htup, self.blkno, xlrec.offnum);
+        }
+
+        // Mark the page as a candidate for pruning
+        page_set_prunable(self.page, self.decoded.xl_xid);
+
+        if xlrec.flags & pg_constants::XLH_DELETE_ALL_VISIBLE_CLEARED != 0 {
+            page_clear_all_visible(self.page);
+        }
+
+        page_set_lsn(self.page, self.lsn);
+        Ok(())
+    }
+
+    fn update(
+        mut self,
+        xlrec: &XlNeonHeapUpdate,
+        hot_update: bool,
+        init_page: bool,
+        target: UpdateTarget,
+    ) -> anyhow::Result<()> {
+        let new_tid_blkno = match target {
+            UpdateTarget::SamePage | UpdateTarget::NewPage => self.blkno,
+            UpdateTarget::OldPage => self.block(0).blkno,
+        };
+
+        // Deal with old tuple version
+        let mut oldtup = None;
+        if let UpdateTarget::SamePage | UpdateTarget::OldPage = target {
+            let block_id = match target {
+                UpdateTarget::SamePage => 0,
+                _ => 1,
+            };
+            if self.read_buffer_for_redo(block_id)? == RedoAction::Done {
+                // If the old tuple was already there, so is the new one on the same page.
+                return Ok(());
+            }
+
+            let (off, len) = page_get_normal_item(self.page, xlrec.old_offnum)?;
+            oldtup = Some((off, len));
+            let htup = &mut self.page[off..];
+
+            let mut infomask = get_u16(htup, T_INFOMASK);
+            let mut infomask2 = get_u16(htup, T_INFOMASK2);
+            infomask &= !(pg_constants::HEAP_XMAX_BITS | pg_constants::HEAP_MOVED);
+            infomask2 &= !pg_constants::HEAP_KEYS_UPDATED;
+            if hot_update {
+                infomask2 |= pg_constants::HEAP_HOT_UPDATED;
+            } else {
+                infomask2 &= !pg_constants::HEAP_HOT_UPDATED;
+            }
+            fix_infomask_from_infobits(xlrec.old_infobits_set, &mut infomask, &mut infomask2);
+            set_u16(htup, T_INFOMASK, infomask);
+            set_u16(htup, T_INFOMASK2, infomask2);
+            set_u32(htup, T_XMAX, xlrec.old_xmax);
+            tuple_set_cid(htup, xlrec.t_cid);
+            // Set forward chain link in t_ctid
+            tuple_set_ctid(htup, new_tid_blkno, xlrec.new_offnum);
+
+            // Mark the page as a candidate for pruning
+            page_set_prunable(self.page, self.decoded.xl_xid);
+
+            if xlrec.flags & pg_constants::XLH_UPDATE_OLD_ALL_VISIBLE_CLEARED != 0 {
+                page_clear_all_visible(self.page);
+            }
+
+            page_set_lsn(self.page, self.lsn);
+        }
+
+        match target {
+            UpdateTarget::OldPage => return Ok(()),
+            UpdateTarget::SamePage => {}
+            UpdateTarget::NewPage => {
+                let action = if init_page {
+                    self.init_buffer_for_redo(0)?
+                } else {
+                    self.read_buffer_for_redo(0)?
+                };
+                if action == RedoAction::Done {
+                    return Ok(());
+                }
+            }
+        }
+
+        // Deal with new tuple
+        let mut recdata = self.block_data(0)?;
+
+        if page_get_max_offset_number(self.page) + 1 < xlrec.new_offnum {
+            bail!("invalid max offset number");
+        }
+
+        let mut prefixlen = 0;
+        let mut suffixlen = 0;
+        if xlrec.flags & pg_constants::XLH_UPDATE_PREFIX_FROM_OLD != 0 {
+            ensure!(oldtup.is_some(), "prefix from old tuple on another page");
+            prefixlen = recdata.get_u16_le() as usize;
+        }
+        if xlrec.flags & pg_constants::XLH_UPDATE_SUFFIX_FROM_OLD != 0 {
+            ensure!(oldtup.is_some(), "suffix from old tuple on another page");
+            suffixlen = recdata.get_u16_le() as usize;
+        }
+
+        ensure!(
+            recdata.len() >= XlNeonHeapHeader::SIZE,
+            "update record without tuple data"
+        );
+        let xlhdr = XlNeonHeapHeader::decode(&mut recdata);
+        let tuplen = recdata.len();
+
+        let mut htup = vec![0u8; pg_constants::SIZEOF_HEAP_TUPLE_HEADER];
+        htup.reserve(tuplen + prefixlen + suffixlen);
+
+        // Reconstruct the new tuple using the prefix and/or suffix from the old tuple, and the
+        // data stored in the WAL record.
+        if prefixlen > 0 {
+            let (old_off, _) = oldtup.expect("checked above");
+            // copy bitmap [+ padding] [+ oid] from WAL record
+            let len = (xlhdr.t_hoff as usize)
+                .checked_sub(pg_constants::SIZEOF_HEAP_TUPLE_HEADER)
+                .context("invalid t_hoff")?;
+            ensure!(len <= recdata.len(), "invalid t_hoff");
+            htup.extend_from_slice(&recdata[..len]);
+
+            // copy prefix from old tuple
+            let old_hoff = old_off + self.page[old_off + T_HOFF] as usize;
+            htup.extend_from_slice(&self.page[old_hoff..old_hoff + prefixlen]);
+
+            // copy new tuple data from WAL record
+            htup.extend_from_slice(&recdata[len..]);
+        } else {
+            // copy bitmap [+ padding] [+ oid] + data from record, all in one go
+            htup.extend_from_slice(&recdata);
+        }
+
+        // copy suffix from old tuple
+        if suffixlen > 0 {
+            let (old_off, old_len) = oldtup.expect("checked above");
+            let old_end = old_off + old_len;
+            htup.extend_from_slice(&self.page[old_end - suffixlen..old_end]);
+        }
+
+        set_u16(&mut htup, T_INFOMASK2, xlhdr.t_infomask2);
+        set_u16(&mut htup, T_INFOMASK, xlhdr.t_infomask);
+        htup[T_HOFF] = xlhdr.t_hoff;
+        set_u32(&mut htup, T_XMIN, self.decoded.xl_xid);
+        tuple_set_cid(&mut htup, xlhdr.t_cid);
+        set_u32(&mut htup, T_XMAX, xlrec.new_xmax);
+        // Make sure there is no forward chain link in t_ctid
+        tuple_set_ctid(&mut htup, self.blkno, xlrec.new_offnum);
+
+        page_add_item(self.page, &htup, xlrec.new_offnum)?;
+
+        if xlrec.flags & pg_constants::XLH_UPDATE_NEW_ALL_VISIBLE_CLEARED != 0 {
+            page_clear_all_visible(self.page);
+        }
+
+        page_set_lsn(self.page, self.lsn);
+        Ok(())
+    }
+
+    fn lock(mut self, xlrec: &XlNeonHeapLock) -> anyhow::Result<()> {
+        if self.read_buffer_for_redo(0)? == RedoAction::Done {
+            return Ok(());
+        }
+
+        let (off, _len) = page_get_normal_item(self.page, xlrec.offnum)?;
+        let htup = &mut self.page[off..];
+
+        let mut infomask = get_u16(htup, T_INFOMASK);
+        let mut infomask2 = get_u16(htup, T_INFOMASK2);
+        infomask &= !(pg_constants::HEAP_XMAX_BITS | pg_constants::HEAP_MOVED);
+        infomask2 &= !pg_constants::HEAP_KEYS_UPDATED;
+        fix_infomask_from_infobits(xlrec.infobits_set, &mut infomask, &mut infomask2);
+
+        // Clear relevant update flags, but only if the modified infomask says there's no
+        // update.
+        if heap_xmax_is_locked_only(infomask) {
+            infomask2 &= !pg_constants::HEAP_HOT_UPDATED;
+            // Make sure there is no forward chain link in t_ctid
+            tuple_set_ctid(htup, self.blkno, xlrec.offnum);
+        }
+        set_u16(htup, T_INFOMASK, infomask);
+        set_u16(htup, T_INFOMASK2, infomask2);
+        set_u32(htup, T_XMAX, xlrec.locking_xid);
+        tuple_set_cid(htup, xlrec.t_cid);
+
+        page_set_lsn(self.page, self.lsn);
+        Ok(())
+    }
+
+    fn multi_insert(
+        mut self,
+        xlrec: &XlNeonHeapMultiInsert,
+        mut offsets: Bytes,
+        init_page: bool,
+    ) -> anyhow::Result<()> {
+        let action = if init_page {
+            self.init_buffer_for_redo(0)?
+        } else {
+            self.read_buffer_for_redo(0)?
+        };
+        if action == RedoAction::Done {
+            return Ok(());
+        }
+
+        // Tuples are stored as block data
+        let tupdata = self.block_data(0)?;
+        let mut pos = 0;
+
+        for i in 0..xlrec.ntuples {
+            // If we're reinitializing the page, the tuples are stored in order from
+            // FirstOffsetNumber. Otherwise there's an array of offsets in the WAL record, and
+            // the tuples come after that.
+            let offnum = if init_page {
+                1 + i
+            } else {
+                ensure!(offsets.remaining() >= 2, "offsets array too short");
+                offsets.get_u16_le()
+            };
+            if page_get_max_offset_number(self.page) + 1 < offnum {
+                bail!("invalid max offset number");
+            }
+
+            // SHORTALIGN; the block data itself starts at a MAXALIGNed address.
+            pos = (pos + 1) & !1;
+            ensure!(
+                pos + XlNeonMultiInsertTuple::SIZE <= tupdata.len(),
+                "total tuple length mismatch"
+            );
+            let mut buf = tupdata.slice(pos..);
+            let xlhdr = XlNeonMultiInsertTuple::decode(&mut buf);
+            pos += XlNeonMultiInsertTuple::SIZE;
+
+            let datalen = xlhdr.datalen as usize;
+            ensure!(
+                pos + datalen <= tupdata.len(),
+                "total tuple length mismatch"
+            );
+
+            let mut htup = vec![0u8; pg_constants::SIZEOF_HEAP_TUPLE_HEADER];
+            htup.extend_from_slice(&tupdata[pos..pos + datalen]);
+            pos += datalen;
+
+            set_u16(&mut htup, T_INFOMASK2, xlhdr.t_infomask2);
+            set_u16(&mut htup, T_INFOMASK, xlhdr.t_infomask);
+            htup[T_HOFF] = xlhdr.t_hoff;
+            set_u32(&mut htup, T_XMIN, self.decoded.xl_xid);
+            tuple_set_cid(&mut htup, xlrec.t_cid);
+            tuple_set_ctid(&mut htup, self.blkno, offnum);
+
+            page_add_item(self.page, &htup, offnum)?;
+        }
+        ensure!(pos == tupdata.len(), "total tuple length mismatch");
+
+        page_set_lsn(self.page, self.lsn);
+
+        if xlrec.flags & pg_constants::XLH_INSERT_ALL_VISIBLE_CLEARED != 0 {
+            page_clear_all_visible(self.page);
+        }
+        // XLH_INSERT_ALL_FROZEN_SET implies that all tuples are visible
+        if xlrec.flags & pg_constants::XLH_INSERT_ALL_FROZEN_SET != 0 {
+            page_set_all_visible(self.page);
+        }
+        Ok(())
+    }
+}
+
+//
+// Page layout, from bufpage.h and itemid.h
+//
+
+const PD_FLAGS: usize = 10;
+const PD_LOWER: usize = 12;
+const PD_UPPER: usize = 14;
+const PD_SPECIAL: usize = 16;
+const PD_PAGESIZE_VERSION: usize = 18;
+const PD_PRUNE_XID: usize = 20;
+const PD_LINP: usize = pg_constants::SIZE_OF_PAGE_HEADER as usize;
+const SIZEOF_ITEM_ID: usize = 4;
+
+//
+// Tuple header layout, from htup_details.h
+//
+
+const T_XMIN: usize = 0;
+const T_XMAX: usize = 4;
+const T_CID: usize = 8;
+const T_CTID: usize = 12;
+const T_INFOMASK2: usize = 18;
+const T_INFOMASK: usize = 20;
+const T_HOFF: usize = 22;
+
+fn get_u16(buf: &[u8], off: usize) -> u16 {
+    LittleEndian::read_u16(&buf[off..off + 2])
+}
+
+fn set_u16(buf: &mut [u8], off: usize, val: u16) {
+    LittleEndian::write_u16(&mut buf[off..off + 2], val)
+}
+
+fn set_u32(buf: &mut [u8], off: usize, val: u32) {
+    LittleEndian::write_u32(&mut buf[off..off + 4], val)
+}
+
+const fn maxalign(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// Port of `PageInit`, for a page without special space.
+fn page_init(page: &mut [u8]) {
+    page.fill(0);
+    set_u16(page, PD_LOWER, pg_constants::SIZE_OF_PAGE_HEADER);
+    set_u16(page, PD_UPPER, BLCKSZ);
+    set_u16(page, PD_SPECIAL, BLCKSZ);
+    set_u16(
+        page,
+        PD_PAGESIZE_VERSION,
+        BLCKSZ | pg_constants::PG_PAGE_LAYOUT_VERSION,
+    );
+}
+
+fn page_get_max_offset_number(page: &[u8]) -> u16 {
+    let lower = get_u16(page, PD_LOWER) as usize;
+    if lower <= PD_LINP {
+        0
+    } else {
+        ((lower - PD_LINP) / SIZEOF_ITEM_ID) as u16
+    }
+}
+
+/// Returns `(lp_off, lp_flags, lp_len)` of the line pointer `offnum`.
+fn page_get_item_id(page: &[u8], offnum: u16) -> (usize, u8, usize) {
+    let pos = PD_LINP + (offnum as usize - 1) * SIZEOF_ITEM_ID;
+    let item_id = LittleEndian::read_u32(&page[pos..pos + SIZEOF_ITEM_ID]);
+    (
+        (item_id & 0x7fff) as usize,
+        ((item_id >> 15) & 0x3) as u8,
+        (item_id >> 17) as usize,
+    )
+}
+
+fn page_set_item_id_normal(page: &mut [u8], offnum: u16, off: usize, len: usize) {
+    let pos = PD_LINP + (offnum as usize - 1) * SIZEOF_ITEM_ID;
+    let item_id =
+        (off as u32 & 0x7fff) | ((pg_constants::LP_NORMAL as u32) << 15) | ((len as u32) << 17);
+    LittleEndian::write_u32(&mut page[pos..pos + SIZEOF_ITEM_ID], item_id);
+}
+
+/// Returns the offset and length of the tuple at `offnum`, which must have a normal line
+/// pointer.
+fn page_get_normal_item(page: &[u8], offnum: u16) -> anyhow::Result<(usize, usize)> {
+    if offnum == 0 || page_get_max_offset_number(page) < offnum {
+        bail!("invalid lp");
+    }
+    let (off, flags, len) = page_get_item_id(page, offnum);
+    if flags != pg_constants::LP_NORMAL || off + len > BLCKSZ as usize {
+        bail!("invalid lp");
+    }
+    Ok((off, len))
+}
+
+/// Port of `PageAddItem` with `overwrite` and `is_heap` set.
+fn page_add_item(page: &mut [u8], item: &[u8], offnum: u16) -> anyhow::Result<()> {
+    let pd_lower = get_u16(page, PD_LOWER) as usize;
+    let pd_upper = get_u16(page, PD_UPPER) as usize;
+    let pd_special = get_u16(page, PD_SPECIAL) as usize;
+
+    // Be wary about corrupted page pointers
+    if pd_lower < PD_LINP
+        || pd_lower > pd_upper
+        || pd_upper > pd_special
+        || pd_special > BLCKSZ as usize
+    {
+        bail!("corrupted page pointers: lower = {pd_lower}, upper = {pd_upper}, special = {pd_special}");
+    }
+
+    let limit = page_get_max_offset_number(page) + 1;
+    if offnum == 0 {
+        bail!("failed to add tuple: invalid offset number");
+    }
+    if offnum < limit {
+        let (_, flags, len) = page_get_item_id(page, offnum);
+        if flags != pg_constants::LP_UNUSED || len != 0 {
+            bail!("failed to add tuple: will not overwrite a used ItemId");
+        }
+    }
+    if offnum > limit {
+        bail!("failed to add tuple: specified item offset is too large");
+    }
+    if offnum > pg_constants::MAX_HEAP_TUPLES_PER_PAGE {
+        bail!("failed to add tuple: can't put more than MaxHeapTuplesPerPage items in a heap page");
+    }
+
+    let lower = if offnum == limit {
+        pd_lower + SIZEOF_ITEM_ID
+    } else {
+        pd_lower
+    };
+    let upper = pd_upper as isize - maxalign(item.len()) as isize;
+    if lower as isize > upper {
+        bail!("failed to add tuple: not enough free space");
+    }
+    let upper = upper as usize;
+
+    page_set_item_id_normal(page, offnum, upper, item.len());
+    page[upper..upper + item.len()].copy_from_slice(item);
+
+    set_u16(page, PD_LOWER, lower as u16);
+    set_u16(page, PD_UPPER, upper as u16);
+    Ok(())
+}
+
+fn page_set_prunable(page: &mut [u8], xid: u32) {
+    let prune_xid = LittleEndian::read_u32(&page[PD_PRUNE_XID..PD_PRUNE_XID + 4]);
+    if prune_xid == pg_constants::INVALID_TRANSACTION_ID || transaction_id_precedes(xid, prune_xid)
+    {
+        set_u32(page, PD_PRUNE_XID, xid);
+    }
+}
+
+fn page_clear_all_visible(page: &mut [u8]) {
+    let flags = get_u16(page, PD_FLAGS);
+    set_u16(page, PD_FLAGS, flags & !pg_constants::PD_ALL_VISIBLE);
+}
+
+fn page_set_all_visible(page: &mut [u8]) {
+    let flags = get_u16(page, PD_FLAGS);
+    set_u16(page, PD_FLAGS, flags | pg_constants::PD_ALL_VISIBLE);
+}
+
+/// `HeapTupleHeaderSetCmin` and `HeapTupleHeaderSetCmax` without a combo CID.
+fn tuple_set_cid(htup: &mut [u8], cid: u32) {
+    set_u32(htup, T_CID, cid);
+    let infomask = get_u16(htup, T_INFOMASK);
+    set_u16(htup, T_INFOMASK, infomask & !pg_constants::HEAP_COMBOCID);
+}
+
+fn tuple_set_ctid(htup: &mut [u8], blkno: u32, offnum: u16) {
+    set_u16(htup, T_CTID, (blkno >> 16) as u16);
+    set_u16(htup, T_CTID + 2, blkno as u16);
+    set_u16(htup, T_CTID + 4, offnum);
+}
+
+fn heap_xmax_is_locked_only(infomask: u16) -> bool {
+    infomask & pg_constants::HEAP_XMAX_LOCK_ONLY != 0
+        || infomask & (pg_constants::HEAP_XMAX_IS_MULTI | pg_constants::HEAP_LOCK_MASK)
+            == pg_constants::HEAP_XMAX_EXCL_LOCK
+}
+
+/// Given an "infobits" field from an XLog record, set the correct bits in the given infomask
+/// and infomask2 for the tuple touched by the record. Port of the function of the same name in
+/// heapam.c.
+fn fix_infomask_from_infobits(infobits: u8, infomask: &mut u16, infomask2: &mut u16) {
+    *infomask &= !(pg_constants::HEAP_XMAX_IS_MULTI
+        | pg_constants::HEAP_XMAX_LOCK_ONLY
+        | pg_constants::HEAP_XMAX_KEYSHR_LOCK
+        | pg_constants::HEAP_XMAX_EXCL_LOCK);
+    *infomask2 &= !pg_constants::HEAP_KEYS_UPDATED;
+
+    if infobits & pg_constants::XLHL_XMAX_IS_MULTI != 0 {
+        *infomask |= pg_constants::HEAP_XMAX_IS_MULTI;
+    }
+    if infobits & pg_constants::XLHL_XMAX_LOCK_ONLY != 0 {
+        *infomask |= pg_constants::HEAP_XMAX_LOCK_ONLY;
+    }
+    if infobits & pg_constants::XLHL_XMAX_EXCL_LOCK != 0 {
+        *infomask |= pg_constants::HEAP_XMAX_EXCL_LOCK;
+    }
+    // note HEAP_XMAX_SHR_LOCK isn't considered here
+    if infobits & pg_constants::XLHL_XMAX_KEYSHR_LOCK != 0 {
+        *infomask |= pg_constants::HEAP_XMAX_KEYSHR_LOCK;
+    }
+
+    if infobits & pg_constants::XLHL_KEYS_UPDATED != 0 {
+        *infomask2 |= pg_constants::HEAP_KEYS_UPDATED;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgdatadir_mapping::rel_block_to_key;
+    use bytes::BufMut;
+    use pageserver_api::reltag::RelTag;
+    use postgres_ffi::XLogRecord;
+
+    const REL: RelTag = RelTag {
+        spcnode: 1663,
+        dbnode: 5,
+        relnode: 16384,
+        forknum: 0,
+    };
+    const BLKNO: u32 = 3;
+
+    /// Builds a neon rmgr record touching a single block of [`REL`].
+    fn neon_record(
+        info: u8,
+        xid: u32,
+        will_init: bool,
+        block_data: Option<&[u8]>,
+        main_data: &[u8],
+    ) -> Bytes {
+        record(
+            pg_constants::RM_NEON_ID,
+            info,
+            xid,
+            will_init,
+            block_data,
+            main_data,
+        )
+    }
+
+    /// Builds a record of the given RMGR touching a single block of [`REL`].
+    fn record(
+        rmid: u8,
+        info: u8,
+        xid: u32,
+        will_init: bool,
+        block_data: Option<&[u8]>,
+        main_data: &[u8],
+    ) -> Bytes {
+        let mut fork_flags = 0;
+        if block_data.is_some() {
+            fork_flags |= pg_constants::BKPBLOCK_HAS_DATA;
+        }
+        if will_init {
+            fork_flags |= pg_constants::BKPBLOCK_WILL_INIT;
+        }
+        let block_data = block_data.unwrap_or_default();
+
+        let mut body = Vec::new();
+        body.put_u8(0);
+        body.put_u8(fork_flags);
+        body.put_u16_le(block_data.len() as u16);
+        body.put_u32_le(REL.spcnode);
+        body.put_u32_le(REL.dbnode);
+        body.put_u32_le(REL.relnode);
+        body.put_u32_le(BLKNO);
+        body.put_u8(pg_constants::XLR_BLOCK_ID_DATA_SHORT);
+        body.put_u8(main_data.len() as u8);
+        body.extend_from_slice(block_data);
+        body.extend_from_slice(main_data);
+
+        let header = XLogRecord {
+            xl_tot_len: (postgres_ffi::XLOG_SIZE_OF_XLOG_RECORD + body.len()) as u32,
+            xl_xid: xid,
+            xl_prev: 0,
+            xl_info: info,
+            xl_rmid: rmid,
+            __bindgen_padding_0: [0u8; 2usize],
+            xl_crc: 0,
+        };
+        let mut rec = header.encode().unwrap().to_vec();
+        rec.extend_from_slice(&body);
+        Bytes::from(rec)
+    }
+
+    /// Block data of an insert or update: the tuple header, then the tuple after the fixed
+    /// size header, which here is a byte of padding and `data`.
+    fn tuple_data(infomask: u16, cid: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u16_le(1); // t_infomask2: one attribute
+        buf.put_u16_le(infomask);
+        buf.put_u32_le(cid);
+        buf.put_u8(24); // t_hoff
+        buf.put_u8(0);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn insert_record(xid: u32, offnum: u16, init: bool, data: &[u8]) -> Bytes {
+        let mut main_data = Vec::new();
+        main_data.put_u16_le(offnum);
+        main_data.put_u8(0); // flags
+        let info = if init {
+            pg_constants::XLOG_NEON_HEAP_INSERT | pg_constants::XLOG_NEON_HEAP_INIT_PAGE
+        } else {
+            pg_constants::XLOG_NEON_HEAP_INSERT
+        };
+        neon_record(
+            info,
+            xid,
+            init,
+            Some(&tuple_data(pg_constants::HEAP_XMAX_INVALID, 0, data)),
+            &main_data,
+        )
+    }
+
+    fn apply_record(page: &mut [u8], lsn: Lsn, rec: &Bytes) {
+        assert!(can_apply(rec, 16));
+        apply(rel_block_to_key(REL, BLKNO), page, lsn, rec, 16).unwrap();
+    }
+
+    fn item(page: &[u8], offnum: u16) -> &[u8] {
+        let (off, len) = page_get_normal_item(page, offnum).unwrap();
+        &page[off..off + len]
+    }
+
+    fn ctid(htup: &[u8]) -> (u32, u16) {
+        (
+            ((get_u16(htup, T_CTID) as u32) << 16) | get_u16(htup, T_CTID + 2) as u32,
+            get_u16(htup, T_CTID + 4),
+        )
+    }
+
+    #[test]
+    fn only_neon_rmgr_records_of_v16() {
+        use pg_constants::{
+            RM_HEAP2_ID, RM_HEAP_ID, XLOG_HEAP2_FREEZE_PAGE, XLOG_HEAP2_MULTI_INSERT,
+            XLOG_HEAP2_PRUNE, XLOG_HEAP2_VACUUM, XLOG_HEAP2_VISIBLE, XLOG_HEAP_DELETE,
+            XLOG_HEAP_HOT_UPDATE, XLOG_HEAP_INSERT, XLOG_HEAP_LOCK, XLOG_HEAP_UPDATE,
+        };
+
+        let rec = insert_record(1000, 1, true, b"abcd");
+        for (pg_version, expected) in [(14, false), (15, false), (16, true)] {
+            assert_eq!(can_apply(&rec, pg_version), expected, "v{pg_version}");
+        }
+
+        // The core heap records, which is what v14 and v15 log, and the heap2 records that
+        // aren't replayed natively on any version.
+        let core_records = [
+            (RM_HEAP_ID, XLOG_HEAP_INSERT),
+            (RM_HEAP_ID, XLOG_HEAP_DELETE),
+            (RM_HEAP_ID, XLOG_HEAP_UPDATE),
+            (RM_HEAP_ID, XLOG_HEAP_HOT_UPDATE),
+            (RM_HEAP_ID, XLOG_HEAP_LOCK),
+            (RM_HEAP2_ID, XLOG_HEAP2_PRUNE),
+            (RM_HEAP2_ID, XLOG_HEAP2_VACUUM),
+            (RM_HEAP2_ID, XLOG_HEAP2_FREEZE_PAGE),
+            (RM_HEAP2_ID, XLOG_HEAP2_VISIBLE),
+            (RM_HEAP2_ID, XLOG_HEAP2_MULTI_INSERT),
+        ];
+        for pg_version in [14, 15, 16] {
+            for (rmid, info) in core_records {
+                let rec = record(rmid, info, 1000, false, Some(&[0; 16]), &[0; 8]);
+                assert!(
+                    !can_apply(&rec, pg_version),
+                    "v{pg_version} rmid {rmid} info {info:#x}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn insert_and_delete() {
+        let mut page = vec![0u8; BLCKSZ as usize];
+
+        apply_record(
+            &mut page,
+            Lsn(0x1000),
+            &insert_record(1000, 1, true, b"abcd"),
+        );
+
+        assert_eq!(page_get_lsn(&page), Lsn(0x1000));
+        assert_eq!(page_get_max_offset_number(&page), 1);
+        assert_eq!(get_u16(&page, PD_UPPER), BLCKSZ - 32);
+
+        let htup = item(&page, 1);
+        assert_eq!(htup.len(), 28);
+        assert_eq!(LittleEndian::read_u32(&htup[T_XMIN..]), 1000);
+        assert_eq!(ctid(htup), (BLKNO, 1));
+        assert_eq!(htup[T_HOFF], 24);
+        assert_eq!(&htup[24..], b"abcd");
+
+        let mut main_data = Vec::new();
+        main_data.put_u32_le(1001); // xmax
+        main_data.put_u16_le(1); // offnum
+        main_data.put_u8(pg_constants::XLHL_XMAX_EXCL_LOCK | pg_constants::XLHL_KEYS_UPDATED);
+        main_data.put_u8(0); // flags
+        main_data.put_u32_le(7); // t_cid
+        let delete = neon_record(
+            pg_constants::XLOG_NEON_HEAP_DELETE,
+            1001,
+            false,
+            None,
+            &main_data,
+        );
+        apply_record(&mut page, Lsn(0x2000), &delete);
+
+        let htup = item(&page, 1);
+        assert_eq!(LittleEndian::read_u32(&htup[T_XMAX..]), 1001);
+        assert_eq!(LittleEndian::read_u32(&htup[T_CID..]), 7);
+        assert_eq!(get_u16(htup, T_INFOMASK), pg_constants::HEAP_XMAX_EXCL_LOCK);
+        assert_eq!(
+            get_u16(htup, T_INFOMASK2) & pg_constants::HEAP_KEYS_UPDATED,
+            pg_constants::HEAP_KEYS_UPDATED
+        );
+        assert_eq!(
+            LittleEndian::read_u32(&page[PD_PRUNE_XID..PD_PRUNE_XID + 4]),
+            1001
+        );
+        assert_eq!(page_get_lsn(&page), Lsn(0x2000));
+    }
+
+    #[test]
+    fn skips_records_already_on_the_page() {
+        let mut page = vec![0u8; BLCKSZ as usize];
+        apply_record(
+            &mut page,
+            Lsn(0x2000),
+            &insert_record(1000, 1, true, b"abcd"),
+        );
+        let before = page.clone();
+
+        apply_record(
+            &mut page,
+            Lsn(0x2000),
+            &insert_record(1000, 2, false, b"efgh"),
+        );
+        assert_eq!(page, before);
+
+        apply_record(
+            &mut page,
+            Lsn(0x3000),
+            &insert_record(1000, 2, false, b"efgh"),
+        );
+        assert_eq!(page_get_max_offset_number(&page), 2);
+    }
+
+    #[test]
+    fn hot_update_with_prefix_and_suffix() {
+        let mut page = vec![0u8; BLCKSZ as usize];
+        apply_record(
+            &mut page,
+            Lsn(0x1000),
+            &insert_record(1000, 1, true, b"abcdefgh"),
+        );
+
+        let mut main_data = Vec::new();
+        main_data.put_u32_le(1001); // old_xmax
+        main_data.put_u16_le(1); // old_offnum
+        main_data.put_u8(0); // old_infobits_set
+        main_data.put_u8(
+            pg_constants::XLH_UPDATE_PREFIX_FROM_OLD | pg_constants::XLH_UPDATE_SUFFIX_FROM_OLD,
+        );
+        main_data.put_u32_le(2); // t_cid
+        main_data.put_u32_le(0); // new_xmax
+        main_data.put_u16_le(2); // new_offnum
+
+        let mut block_data = Vec::new();
+        block_data.put_u16_le(2); // prefixlen
+        block_data.put_u16_le(3); // suffixlen
+        block_data.extend_from_slice(&tuple_data(pg_constants::HEAP_XMAX_INVALID, 2, b"XY"));
+
+        let update = neon_record(
+            pg_constants::XLOG_NEON_HEAP_HOT_UPDATE,
+            1001,
+            false,
+            Some(&block_data),
+            &main_data,
+        );
+        apply_record(&mut page, Lsn(0x2000), &update);
+
+        let old = item(&page, 1);
+        assert_eq!(LittleEndian::read_u32(&old[T_XMAX..]), 1001);
+        assert_ne!(
+            get_u16(old, T_INFOMASK2) & pg_constants::HEAP_HOT_UPDATED,
+            0
+        );
+        assert_eq!(ctid(old), (BLKNO, 2));
+
+        let new = item(&page, 2);
+        assert_eq!(&new[24..], b"abXYfgh");
+        assert_eq!(LittleEndian::read_u32(&new[T_XMIN..]), 1001);
+        assert_eq!(LittleEndian::read_u32(&new[T_CID..]), 2);
+        assert_eq!(ctid(new), (BLKNO, 2));
+    }
+}
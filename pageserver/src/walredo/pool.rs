@@ -0,0 +1,357 @@
+//!
+//! A pageserver-wide pool of WAL redo processes.
+//!
+//! Without the pool, every tenant launches its own `postgres --wal-redo` process on first use,
+//! and keeps it until it has been idle for a while, see
+//! [`PostgresRedoManager::maybe_quiesce`](super::PostgresRedoManager::maybe_quiesce). On a
+//! pageserver with thousands of active tenants that means thousands of processes.
+//!
+//! With the pool, the number of processes is capped by
+//! [`WalRedoProcessPoolConfig::max_processes`], and a process is checked out for the duration
+//! of one redo request. An idle process remembers the tenant it last served, so that a tenant
+//! which keeps doing redo keeps getting the same process (a hit). Otherwise a new process is
+//! launched if we are below the cap, or the least recently used idle process is reset and
+//! reassigned (a miss). If every process is busy, the request waits asynchronously for one to be
+//! returned: every checked-out process holds one of the `max_processes` permits of a semaphore.
+//!
+//! Before a process serves another tenant, it is told to forget all the pages of its previous
+//! requests: relations of different tenants can have the same relfilenodes.
+//!
+
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use pageserver_api::shard::TenantShardId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::*;
+
+use super::WalRedoProcess;
+use crate::config::PageServerConf;
+use crate::metrics::{WAL_REDO_PROCESS_LAUNCH_DURATION_HISTOGRAM, WAL_REDO_PROCESS_POOL_METRICS};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalRedoProcessPoolConfig {
+    /// Maximum number of WAL redo processes, across all tenants and PostgreSQL versions.
+    pub max_processes: NonZeroUsize,
+    /// How long a redo request waits for a process when all of them are busy.
+    #[serde(with = "humantime_serde", default = "default_acquire_timeout")]
+    pub acquire_timeout: Duration,
+}
+
+fn default_acquire_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+static POOL: OnceCell<WalRedoProcessPool> = OnceCell::new();
+
+///
+/// Initialize the pool, if it is configured. This must be called once at page server startup.
+///
+pub fn init(conf: &'static PageServerConf) {
+    if let Some(config) = conf.wal_redo_process_pool.clone() {
+        if POOL.set(WalRedoProcessPool::new(conf, config)).is_err() {
+            panic!("wal redo process pool already initialized");
+        }
+    }
+}
+
+/// Get the pool, if WAL redo processes are pooled.
+pub(super) fn get() -> Option<&'static WalRedoProcessPool> {
+    POOL.get()
+}
+
+pub(super) struct WalRedoProcessPool {
+    conf: &'static PageServerConf,
+    config: WalRedoProcessPoolConfig,
+    state: Mutex<PoolState>,
+    /// A permit for each checked-out process. With a permit, there is always either an idle
+    /// process or room to launch one.
+    permits: Arc<Semaphore>,
+}
+
+struct PoolState {
+    /// Processes that are not checked out, least recently used first.
+    idle: VecDeque<IdleProcess>,
+    /// Number of processes that are idle, checked out, or being launched.
+    n_processes: usize,
+}
+
+struct IdleProcess {
+    process: WalRedoProcess,
+    idle_since: Instant,
+}
+
+impl WalRedoProcessPool {
+    pub(super) fn new(conf: &'static PageServerConf, config: WalRedoProcessPoolConfig) -> Self {
+        WalRedoProcessPool {
+            conf,
+            state: Mutex::new(PoolState {
+                idle: VecDeque::new(),
+                n_processes: 0,
+            }),
+            permits: Arc::new(Semaphore::new(config.max_processes.get())),
+            config,
+        }
+    }
+
+    /// Checks out a process for a redo request of `tenant_shard_id`, waiting until one is
+    /// available if all of them are busy.
+    pub(super) async fn acquire(
+        &'static self,
+        tenant_shard_id: TenantShardId,
+        pg_version: u32,
+    ) -> anyhow::Result<PooledProcess> {
+        let metrics = &*WAL_REDO_PROCESS_POOL_METRICS;
+        let _timer = metrics.acquire_duration.start_timer();
+
+        let permit = match tokio::time::timeout(
+            self.config.acquire_timeout,
+            Arc::clone(&self.permits).acquire_owned(),
+        )
+        .await
+        {
+            Ok(permit) => permit.expect("we never close the semaphore"),
+            Err(_) => {
+                metrics.acquire_timeouts.inc();
+                anyhow::bail!(
+                    "timed out waiting for one of the {} pooled wal-redo processes",
+                    self.config.max_processes
+                );
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        // The most recently used process of this tenant, which is the most likely to be
+        // warmed up for it.
+        if let Some(i) = state.idle.iter().rposition(|idle| {
+            idle.process.tenant_shard_id == tenant_shard_id && idle.process.pg_version == pg_version
+        }) {
+            let idle = state.idle.remove(i).expect("found above");
+            metrics.hits.inc();
+            return Ok(PooledProcess::new(self, idle.process, permit));
+        }
+
+        if state.n_processes < self.config.max_processes.get() {
+            state.n_processes += 1;
+            metrics.processes.set(state.n_processes as u64);
+            drop(state);
+            metrics.misses.inc();
+            return self.launch(tenant_shard_id, pg_version, permit);
+        }
+
+        if let Some(i) = state
+            .idle
+            .iter()
+            .position(|idle| idle.process.pg_version == pg_version)
+        {
+            let mut process = state.idle.remove(i).expect("found above").process;
+            drop(state);
+            info!(
+                pid = process.id(),
+                from_tenant_id = %process.tenant_shard_id.tenant_id,
+                from_shard_id = %process.tenant_shard_id.shard_slug(),
+                "reassigning wal-redo process"
+            );
+            process.reassign(tenant_shard_id);
+            metrics.misses.inc();
+            metrics.reassignments.inc();
+            return Ok(PooledProcess::new(self, process, permit));
+        }
+
+        // Only processes of other PostgreSQL versions are idle: replace the least recently
+        // used one.
+        if let Some(idle) = state.idle.pop_front() {
+            drop(state);
+            // kills and waits for the process
+            drop(idle);
+            metrics.misses.inc();
+            return self.launch(tenant_shard_id, pg_version, permit);
+        }
+        unreachable!(
+            "{} processes and none idle although we hold one of {} permits",
+            state.n_processes, self.config.max_processes
+        );
+    }
+
+    /// Launches a process into a slot that has already been counted in `n_processes`.
+    fn launch(
+        &'static self,
+        tenant_shard_id: TenantShardId,
+        pg_version: u32,
+        permit: OwnedSemaphorePermit,
+    ) -> anyhow::Result<PooledProcess> {
+        let timer = WAL_REDO_PROCESS_LAUNCH_DURATION_HISTOGRAM.start_timer();
+        match WalRedoProcess::launch(self.conf, tenant_shard_id, pg_version) {
+            Ok(process) => {
+                timer.observe_duration();
+                Ok(PooledProcess::new(self, process, permit))
+            }
+            Err(e) => {
+                timer.stop_and_discard();
+                self.release_slot();
+                Err(e)
+            }
+        }
+    }
+
+    fn release_slot(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.n_processes -= 1;
+        WAL_REDO_PROCESS_POOL_METRICS
+            .processes
+            .set(state.n_processes as u64);
+    }
+
+    fn release(&self, process: WalRedoProcess) {
+        let mut state = self.state.lock().unwrap();
+        state.idle.push_back(IdleProcess {
+            process,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Stops the processes that have been idle for at least `idle_timeout`.
+    pub(super) fn quiesce(&self, idle_timeout: Duration) {
+        let mut stopped = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            while let Some(idle) = state.idle.front() {
+                if idle.idle_since.elapsed() < idle_timeout {
+                    break;
+                }
+                stopped.push(state.idle.pop_front().expect("checked above"));
+                state.n_processes -= 1;
+            }
+            WAL_REDO_PROCESS_POOL_METRICS
+                .processes
+                .set(state.n_processes as u64);
+        }
+        // kill and wait for the processes outside of the lock
+        drop(stopped);
+    }
+}
+
+/// A process checked out of the pool. It is returned to the pool on drop, unless it was
+/// [discarded](Self::discard).
+pub(super) struct PooledProcess {
+    pool: &'static WalRedoProcessPool,
+    process: Option<WalRedoProcess>,
+    discarded: bool,
+    /// Released after the process is back in the pool, or its slot is freed.
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledProcess {
+    fn new(
+        pool: &'static WalRedoProcessPool,
+        process: WalRedoProcess,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        PooledProcess {
+            pool,
+            process: Some(process),
+            discarded: false,
+            _permit: permit,
+        }
+    }
+
+    /// Don't return the process to the pool, but kill it when dropped: it failed a request,
+    /// and we don't know in which state it is.
+    pub(super) fn discard(&mut self) {
+        self.discarded = true;
+    }
+}
+
+impl std::ops::Deref for PooledProcess {
+    type Target = WalRedoProcess;
+
+    fn deref(&self) -> &Self::Target {
+        self.process.as_ref().expect("must not use from drop")
+    }
+}
+
+impl Drop for PooledProcess {
+    fn drop(&mut self) {
+        let process = self.process.take().expect("we only do this once");
+        if self.discarded {
+            self.pool.release_slot();
+            // NB: this blocks on wait() for the child, same as when a tenant's own process fails.
+            drop(process);
+        } else {
+            self.pool.release(process);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    use pageserver_api::shard::TenantShardId;
+    use utils::id::TenantId;
+
+    use super::{WalRedoProcessPool, WalRedoProcessPoolConfig};
+    use crate::config::PageServerConf;
+    use crate::walredo::tests::short_records;
+    use crate::walredo::BufferTag;
+
+    #[tokio::test]
+    async fn reassigns_least_recently_used_process() {
+        crate::tenant::harness::setup_logging();
+
+        let repo_dir = camino_tempfile::tempdir().unwrap();
+        let conf = PageServerConf::dummy_conf(repo_dir.path().to_path_buf());
+        let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+        let pool: &'static WalRedoProcessPool = Box::leak(Box::new(WalRedoProcessPool::new(
+            conf,
+            WalRedoProcessPoolConfig {
+                max_processes: NonZeroUsize::new(1).unwrap(),
+                acquire_timeout: Duration::from_millis(100),
+            },
+        )));
+
+        let expected = std::fs::read("test_data/short_v14_redo.page").unwrap();
+        let tag = BufferTag {
+            rel: pageserver_api::reltag::RelTag {
+                spcnode: 1663,
+                dbnode: 13010,
+                relnode: 1259,
+                forknum: 0,
+            },
+            blknum: 0,
+        };
+
+        let tenant_a = TenantShardId::unsharded(TenantId::generate());
+        let tenant_b = TenantShardId::unsharded(TenantId::generate());
+
+        let process = pool.acquire(tenant_a, 14).await.unwrap();
+        let pid = process.id();
+        let page = process
+            .apply_wal_records(tag, &None, &short_records(), conf.wal_redo_timeout)
+            .unwrap();
+        assert_eq!(&expected, &*page);
+
+        // The only process is checked out.
+        assert!(pool.acquire(tenant_b, 14).await.is_err());
+        drop(process);
+
+        // Tenant B gets the process of tenant A, and its results don't depend on what tenant A
+        // left behind in it.
+        let process = pool.acquire(tenant_b, 14).await.unwrap();
+        assert_eq!(process.id(), pid);
+        assert_eq!(process.tenant_shard_id, tenant_b);
+        let page = process
+            .apply_wal_records(tag, &None, &short_records(), conf.wal_redo_timeout)
+            .unwrap();
+        assert_eq!(&expected, &*page);
+        drop(process);
+
+        pool.quiesce(Duration::ZERO);
+        assert_eq!(pool.state.lock().unwrap().n_processes, 0);
+    }
+}
@@ -989,6 +989,7 @@ nm_pack_request(NeonRequest *msg)
 		case T_NeonGetPageResponse:
 		case T_NeonErrorResponse:
 		case T_NeonDbSizeResponse:
+		case T_NeonGetPageBatchResponse:
 		default:
 			elog(ERROR, "unexpected neon message tag 0x%02x", msg->tag);
 			break;
@@ -1084,6 +1085,7 @@ nm_unpack_response(StringInfo s)
 		case T_NeonNblocksRequest:
 		case T_NeonGetPageRequest:
 		case T_NeonDbSizeRequest:
+		case T_NeonGetPageBatchRequest:
 		default:
 			elog(ERROR, "unexpected neon message tag 0x%02x", tag);
 			break;
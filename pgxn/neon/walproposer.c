@@ -70,9 +70,11 @@ static bool SendAppendRequests(Safekeeper *sk);
 static bool RecvAppendResponses(Safekeeper *sk);
 static XLogRecPtr CalculateMinFlushLsn(WalProposer *wp);
 static XLogRecPtr GetAcknowledgedByQuorumWALPosition(WalProposer *wp);
+static bool IsQuorum(WalProposer *wp, NNodeId *nodes, int n_nodes);
 static void HandleSafekeeperResponse(WalProposer *wp);
 static bool AsyncRead(Safekeeper *sk, char **buf, int *buf_size);
 static bool AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage *anymsg);
+static bool ParseMembershipConfiguration(StringInfo s, MembershipConfiguration *mconf);
 static bool BlockingWrite(Safekeeper *sk, void *msg, size_t msg_size, SafekeeperState success_state);
 static bool AsyncWrite(Safekeeper *sk, void *msg, size_t msg_size, SafekeeperState flush_state);
 static bool AsyncFlush(Safekeeper *sk);
@@ -690,6 +692,13 @@ RecvAcceptorGreeting(Safekeeper *sk)
 
 	wp_log(LOG, "received AcceptorGreeting from safekeeper %s:%s", sk->host, sk->port);
 
+	if (sk->greetResponse.mconf.generation > wp->mconf.generation)
+	{
+		wp->mconf = sk->greetResponse.mconf;
+		wp_log(LOG, "learned membership configuration generation %u from safekeeper %s:%s, joint=%d",
+			   wp->mconf.generation, sk->host, sk->port, wp->mconf.has_new_members);
+	}
+
 	/* Protocol is all good, move to voting. */
 	sk->state = SS_VOTING;
 
@@ -775,6 +784,7 @@ static void
 RecvVoteResponse(Safekeeper *sk)
 {
 	WalProposer *wp = sk->wp;
+	int			i;
 
 	sk->voteResponse.apm.tag = 'v';
 	if (!AsyncReadMessage(sk, (AcceptorProposerMessage *) &sk->voteResponse))
@@ -793,7 +803,7 @@ RecvVoteResponse(Safekeeper *sk)
 	 * we are not elected yet and thus need the vote.
 	 */
 	if ((!sk->voteResponse.voteGiven) &&
-		(sk->voteResponse.term > wp->propTerm || wp->n_votes < wp->quorum))
+		(sk->voteResponse.term > wp->propTerm || !wp->elected))
 	{
 		wp_log(FATAL, "WAL acceptor %s:%s with term " INT64_FORMAT " rejects our connection request with term " INT64_FORMAT "",
 			   sk->host, sk->port,
@@ -802,15 +812,24 @@ RecvVoteResponse(Safekeeper *sk)
 	Assert(sk->voteResponse.term == wp->propTerm);
 
 	/* Handshake completed, do we have quorum? */
-	wp->n_votes++;
-	if (wp->n_votes < wp->quorum)
-	{
-		sk->state = SS_IDLE;	/* can't do much yet, no quorum */
-	}
-	else if (wp->n_votes > wp->quorum)
+	if (wp->elected)
 	{
 		/* already elected, start streaming */
 		SendProposerElected(sk);
+		return;
+	}
+
+	for (i = 0; i < wp->n_votes; i++)
+	{
+		if (wp->voters[i] == sk->greetResponse.nodeId)
+			break;
+	}
+	if (i == wp->n_votes)
+		wp->voters[wp->n_votes++] = sk->greetResponse.nodeId;
+
+	if (!IsQuorum(wp, wp->voters, wp->n_votes))
+	{
+		sk->state = SS_IDLE;	/* can't do much yet, no quorum */
 	}
 	else
 	{
@@ -818,6 +837,8 @@ RecvVoteResponse(Safekeeper *sk)
 		/* Idle state waits for read-ready events */
 		wp->api.update_event_set(sk, WL_SOCKET_READABLE);
 
+		wp->elected = true;
+
 		HandleElectedProposer(sk->wp);
 	}
 }
@@ -1541,6 +1562,71 @@ CalculateMinFlushLsn(WalProposer *wp)
 	return lsn;
 }
 
+/*
+ * Whether the nodes form a majority of the member set.
+ */
+static bool
+IsMajorityOf(MemberSet *set, NNodeId *nodes, int n_nodes)
+{
+	uint32		n_members = 0;
+
+	for (uint32 i = 0; i < set->len; i++)
+	{
+		for (int j = 0; j < n_nodes; j++)
+		{
+			if (nodes[j] == set->ids[i])
+			{
+				n_members++;
+				break;
+			}
+		}
+	}
+	return n_members >= set->len / 2 + 1;
+}
+
+/*
+ * Whether the nodes form a quorum: a majority of the safekeepers, or, once
+ * the membership configuration is known, a majority of its members and of its
+ * new members if the configuration is joint.
+ */
+static bool
+IsQuorum(WalProposer *wp, NNodeId *nodes, int n_nodes)
+{
+	if (wp->mconf.generation == INVALID_GENERATION)
+		return n_nodes >= wp->quorum;
+
+	if (!IsMajorityOf(&wp->mconf.members, nodes, n_nodes))
+		return false;
+	if (wp->mconf.has_new_members && !IsMajorityOf(&wp->mconf.new_members, nodes, n_nodes))
+		return false;
+	return true;
+}
+
+/*
+ * Get the highest LSN acknowledged by a majority of the member set.
+ * Safekeepers of the set we aren't connected to acknowledged nothing.
+ */
+static XLogRecPtr
+GetAcknowledgedByMajorityOf(WalProposer *wp, MemberSet *set, XLogRecPtr *acked)
+{
+	XLogRecPtr	responses[MAX_SAFEKEEPERS];
+
+	if (set->len == 0)
+		return InvalidXLogRecPtr;
+
+	for (uint32 i = 0; i < set->len; i++)
+	{
+		responses[i] = InvalidXLogRecPtr;
+		for (int j = 0; j < wp->n_safekeepers; j++)
+		{
+			if (wp->safekeeper[j].greetResponse.nodeId == set->ids[i])
+				responses[i] = Max(responses[i], acked[j]);
+		}
+	}
+	qsort(responses, set->len, sizeof(XLogRecPtr), CompareLsn);
+	return responses[set->len - (set->len / 2 + 1)];
+}
+
 /*
  * Calculate WAL position acknowledged by quorum
  */
@@ -1548,6 +1634,7 @@ static XLogRecPtr
 GetAcknowledgedByQuorumWALPosition(WalProposer *wp)
 {
 	XLogRecPtr	responses[MAX_SAFEKEEPERS];
+	XLogRecPtr	lsn;
 
 	/*
 	 * Sort acknowledged LSNs
@@ -1560,6 +1647,16 @@ GetAcknowledgedByQuorumWALPosition(WalProposer *wp)
 		 */
 		responses[i] = wp->safekeeper[i].appendResponse.flushLsn >= wp->propEpochStartLsn ? wp->safekeeper[i].appendResponse.flushLsn : 0;
 	}
+
+	if (wp->mconf.generation != INVALID_GENERATION)
+	{
+		/* The LSN must be acknowledged by a majority of each member set. */
+		lsn = GetAcknowledgedByMajorityOf(wp, &wp->mconf.members, responses);
+		if (wp->mconf.has_new_members)
+			lsn = Min(lsn, GetAcknowledgedByMajorityOf(wp, &wp->mconf.new_members, responses));
+		return lsn;
+	}
+
 	qsort(responses, wp->n_safekeepers, sizeof(XLogRecPtr), CompareLsn);
 
 	/*
@@ -1580,7 +1677,7 @@ GetDonor(WalProposer *wp, XLogRecPtr *donor_lsn)
 	Safekeeper *donor = NULL;
 	int			i;
 
-	if (wp->n_votes < wp->quorum)
+	if (!wp->elected)
 	{
 		wp_log(WARNING, "GetDonor called before elections are won");
 		return NULL;
@@ -1656,6 +1753,7 @@ HandleSafekeeperResponse(WalProposer *wp)
 	if (wp->config->syncSafekeepers)
 	{
 		int			n_synced;
+		NNodeId		synced_ids[MAX_SAFEKEEPERS];
 
 		n_synced = 0;
 		for (int i = 0; i < wp->n_safekeepers; i++)
@@ -1667,10 +1765,10 @@ HandleSafekeeperResponse(WalProposer *wp)
 			if (sk->state != SS_OFFLINE && !synced)
 				return;
 			if (synced)
-				n_synced++;
+				synced_ids[n_synced++] = sk->greetResponse.nodeId;
 		}
 
-		if (n_synced >= wp->quorum)
+		if (IsQuorum(wp, synced_ids, n_synced))
 		{
 			/* A quorum of safekeepers has been synced! */
 
@@ -1764,6 +1862,12 @@ AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage *anymsg)
 
 				msg->term = pq_getmsgint64_le(&s);
 				msg->nodeId = pq_getmsgint64_le(&s);
+				if (!ParseMembershipConfiguration(&s, &msg->mconf))
+				{
+					wp_log(WARNING, "malformed membership configuration from node %s:%s", sk->host, sk->port);
+					ResetConnection(sk);
+					return false;
+				}
 				pq_getmsgend(&s);
 				return true;
 			}
@@ -1812,6 +1916,41 @@ AsyncReadMessage(Safekeeper *sk, AcceptorProposerMessage *anymsg)
 	}
 }
 
+/*
+ * Parse a member set: the number of members, then for each of them node id,
+ * NUL-terminated host and pg port. Only the ids are kept.
+ */
+static bool
+ParseMemberSet(StringInfo s, MemberSet *set)
+{
+	set->len = pq_getmsgint32_le(s);
+	if (set->len > MAX_SAFEKEEPERS)
+		return false;
+	for (uint32 i = 0; i < set->len; i++)
+	{
+		set->ids[i] = pq_getmsgint64_le(s);
+		(void) pq_getmsgstring(s);
+		(void) pq_getmsgbytes(s, 2);
+	}
+	return true;
+}
+
+/*
+ * Parse the membership configuration of AcceptorGreeting: generation, member
+ * set and a flag telling whether the set of new members follows.
+ */
+static bool
+ParseMembershipConfiguration(StringInfo s, MembershipConfiguration *mconf)
+{
+	mconf->generation = pq_getmsgint32_le(s);
+	if (!ParseMemberSet(s, &mconf->members))
+		return false;
+	mconf->has_new_members = pq_getmsgbyte(s) != 0;
+	if (mconf->has_new_members)
+		return ParseMemberSet(s, &mconf->new_members);
+	return true;
+}
+
 /*
  * Blocking equivalent to AsyncWrite.
  *
@@ -12,7 +12,7 @@
 #include "neon_walreader.h"
 
 #define SK_MAGIC 0xCafeCeefu
-#define SK_PROTOCOL_VERSION 2
+#define SK_PROTOCOL_VERSION 3
 
 #define MAX_SAFEKEEPERS 32
 #define MAX_SEND_SIZE (XLOG_BLCKSZ * 16)	/* max size of a single* WAL
@@ -142,6 +142,33 @@ typedef uint64 term_t;
 /* neon storage node id */
 typedef uint64 NNodeId;
 
+/* Generation of a membership configuration, 0 if membership isn't tracked. */
+typedef uint32 Generation;
+
+#define INVALID_GENERATION 0
+
+/*
+ * Set of safekeepers of a membership configuration. Only ids are kept, hosts
+ * and ports come from the walproposer config.
+ */
+typedef struct MemberSet
+{
+	uint32		len;
+	NNodeId		ids[MAX_SAFEKEEPERS];
+} MemberSet;
+
+/*
+ * Membership configuration of the timeline. While it is joint, a quorum is a
+ * majority of both the members and the new members.
+ */
+typedef struct MembershipConfiguration
+{
+	Generation	generation;
+	MemberSet	members;
+	bool		has_new_members;
+	MemberSet	new_members;
+} MembershipConfiguration;
+
 /*
  * Proposer <-> Acceptor messaging.
  */
@@ -166,13 +193,15 @@ typedef struct AcceptorProposerMessage
 } AcceptorProposerMessage;
 
 /*
- * Acceptor -> Proposer initial response: the highest term acceptor voted for.
+ * Acceptor -> Proposer initial response: the highest term acceptor voted for,
+ * and the membership configuration it has.
  */
 typedef struct AcceptorGreeting
 {
 	AcceptorProposerMessage apm;
 	term_t		term;
 	NNodeId		nodeId;
+	MembershipConfiguration mconf;
 } AcceptorGreeting;
 
 /*
@@ -678,6 +707,18 @@ typedef struct WalProposer
 	/* number of votes collected from safekeepers */
 	int			n_votes;
 
+	/* node ids of the safekeepers which voted, n_votes of them */
+	NNodeId		voters[MAX_SAFEKEEPERS];
+
+	/* whether the votes have formed a quorum */
+	bool		elected;
+
+	/*
+	 * Membership configuration with the highest generation among the ones
+	 * safekeepers have sent in greetings.
+	 */
+	MembershipConfiguration mconf;
+
 	/* number of successful connections over the lifetime of walproposer */
 	int			n_connected;
 
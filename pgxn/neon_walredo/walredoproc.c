@@ -18,12 +18,13 @@
  *                // 'msgtype', in network byte order
  * <payload>
  *
- * There are three message types:
+ * There are five message types:
  *
  * BeginRedoForBlock ('B'): Prepare for WAL replay for given block
  * PushPage ('P'): Copy a page image (in the payload) to buffer cache
  * ApplyRecord ('A'): Apply a WAL record (in the payload)
  * GetPage ('G'): Return a page image from buffer cache.
+ * ResetRedoState ('R'): Forget all pages of earlier requests (no payload)
  *
  * Currently, you only get a response to GetPage requests; the response is
  * simply a 8k page, without any headers. Errors are logged to stderr.
@@ -130,6 +131,7 @@ static void ApplyRecord(StringInfo input_message);
 static void apply_error_callback(void *arg);
 static bool redo_block_filter(XLogReaderState *record, uint8 block_id);
 static void GetPage(StringInfo input_message);
+static void ResetRedoState(StringInfo input_message);
 static ssize_t buffered_read(void *buf, size_t count);
 static void CreateFakeSharedMemoryAndSemaphores();
 
@@ -353,6 +355,10 @@ WalRedoMain(int argc, char *argv[])
 				GetPage(&input_message);
 				break;
 
+			case 'R':			/* ResetRedoState */
+				ResetRedoState(&input_message);
+				break;
+
 				/*
 				 * EOF means we're done. Perform normal shutdown.
 				 */
@@ -1015,6 +1021,33 @@ GetPage(StringInfo input_message)
 }
 
 
+/*
+ * Forget all pages left behind by earlier requests: buffers that were not
+ * the target of a GetPage, and pages that the in-memory smgr holds.
+ *
+ * The pageserver sends this before handing the process over to another
+ * tenant, whose relations may have the same relfilenodes.
+ */
+static void
+ResetRedoState(StringInfo input_message)
+{
+	pq_getmsgend(input_message);
+
+	for (int i = 0; i < NLocBuffer; i++)
+	{
+		BufferDesc *bufHdr = GetLocalBufferDescriptor(i);
+
+		if (pg_atomic_read_u32(&bufHdr->state) & BM_TAG_VALID)
+			DropRelationAllLocalBuffers(BufTagGetNRelFileInfo(bufHdr->tag));
+	}
+	smgrcloseall();
+	smgr_init_inmem();
+	wal_redo_buffer = InvalidBuffer;
+
+	elog(TRACE, "redo state reset");
+}
+
+
 /* Buffer used by buffered_read() */
 static char stdin_buf[16 * 1024];
 static size_t stdin_len = 0;	/* # of bytes in buffer */
@@ -4,7 +4,10 @@ pub mod backend;
 pub use backend::BackendType;
 
 mod credentials;
-pub use credentials::{check_peer_addr_is_in_list, ClientCredentials};
+pub use credentials::{
+    check_peer_addr_is_in_list, check_vpc_endpoint_id_is_in_list, is_valid_ip_pattern,
+    ClientCredentials,
+};
 
 mod password_hack;
 pub use password_hack::parse_endpoint_param;
@@ -53,6 +56,9 @@ pub enum AuthErrorImpl {
     #[error("password authentication failed for user '{0}'")]
     AuthFailed(Box<str>),
 
+    #[error(transparent)]
+    Jwt(#[from] backend::JwtError),
+
     /// Errors produced by e.g. [`crate::stream::PqStream`].
     #[error(transparent)]
     Io(#[from] io::Error),
@@ -63,8 +69,14 @@ pub enum AuthErrorImpl {
     )]
     IpAddressNotAllowed,
 
-    #[error("Too many connections to this endpoint. Please try again later.")]
-    TooManyConnections,
+    #[error(
+        "This endpoint only accepts connections through its allowed private links. \
+        Please add the VPC endpoint to the allowed list in the Neon console."
+    )]
+    VpcEndpointIdNotAllowed,
+
+    #[error(transparent)]
+    RateLimit(#[from] crate::rate_limiter::RateLimitError),
 }
 
 #[derive(Debug, Error)]
@@ -84,8 +96,8 @@ impl AuthError {
         AuthErrorImpl::IpAddressNotAllowed.into()
     }
 
-    pub fn too_many_connections() -> Self {
-        AuthErrorImpl::TooManyConnections.into()
+    pub fn vpc_endpoint_id_not_allowed() -> Self {
+        AuthErrorImpl::VpcEndpointIdNotAllowed.into()
     }
 
     pub fn is_auth_failed(&self) -> bool {
@@ -108,12 +120,14 @@ impl UserFacingError for AuthError {
             WakeCompute(e) => e.to_string_client(),
             Sasl(e) => e.to_string_client(),
             AuthFailed(_) => self.to_string(),
+            Jwt(e) => e.to_string_client(),
             BadAuthMethod(_) => self.to_string(),
             MalformedPassword(_) => self.to_string(),
             MissingEndpointName => self.to_string(),
             Io(_) => "Internal error".to_string(),
             IpAddressNotAllowed => self.to_string(),
-            TooManyConnections => self.to_string(),
+            VpcEndpointIdNotAllowed => self.to_string(),
+            RateLimit(e) => e.to_string_client(),
         }
     }
 }
@@ -1,15 +1,19 @@
 mod classic;
 mod hacks;
+pub(super) mod jwt;
 mod link;
 
+pub use jwt::{AuthRule, JwkCache, JwtError};
+
 pub use link::LinkAuthError;
 use smol_str::SmolStr;
 use tokio_postgres::config::AuthKeys;
 
-use crate::auth::credentials::check_peer_addr_is_in_list;
+use crate::auth::credentials::{check_peer_addr_is_in_list, check_vpc_endpoint_id_is_in_list};
 use crate::auth::validate_password_and_exchange;
 use crate::console::errors::GetAuthInfoError;
 use crate::console::AuthSecret;
+use crate::protocol2::ConnectionInfoExtra;
 use crate::proxy::connect_compute::handle_try_wake;
 use crate::proxy::retry::retry_after;
 use crate::scram;
@@ -23,6 +27,7 @@ use crate::{
         Api,
     },
     metrics::LatencyTimer,
+    rate_limiter::EndpointLimits,
     stream, url,
 };
 use futures::TryFutureExt;
@@ -44,6 +49,8 @@ use tracing::{error, info, warn};
 pub enum BackendType<'a, T> {
     /// Current Cloud API (V2).
     Console(Cow<'a, console::provider::neon::Api>, T),
+    /// Stand-in for Cloud API (V2) which reads a local config file.
+    File(Cow<'a, console::provider::file::Api>, T),
     /// Local mock of Cloud API (V2).
     #[cfg(feature = "testing")]
     Postgres(Cow<'a, console::provider::mock::Api>, T),
@@ -57,6 +64,11 @@ pub enum BackendType<'a, T> {
 pub trait TestBackend: Send + Sync + 'static {
     fn wake_compute(&self) -> Result<CachedNodeInfo, console::errors::WakeComputeError>;
     fn get_allowed_ips(&self) -> Result<Arc<Vec<String>>, console::errors::GetAuthInfoError>;
+    fn get_allowed_vpc_endpoint_ids(
+        &self,
+    ) -> Result<Arc<Vec<String>>, console::errors::GetAuthInfoError>;
+    fn get_endpoint_jwks(&self) -> Result<Arc<Vec<AuthRule>>, console::errors::GetAuthInfoError>;
+    fn get_role_password(&self) -> Result<SmolStr, console::errors::GetAuthInfoError>;
 }
 
 impl std::fmt::Display for BackendType<'_, ()> {
@@ -64,6 +76,7 @@ impl std::fmt::Display for BackendType<'_, ()> {
         use BackendType::*;
         match self {
             Console(endpoint, _) => fmt.debug_tuple("Console").field(&endpoint.url()).finish(),
+            File(api, _) => fmt.debug_tuple("File").field(&api.path()).finish(),
             #[cfg(feature = "testing")]
             Postgres(endpoint, _) => fmt.debug_tuple("Postgres").field(&endpoint.url()).finish(),
             Link(url) => fmt.debug_tuple("Link").field(&url.as_str()).finish(),
@@ -80,6 +93,7 @@ impl<T> BackendType<'_, T> {
         use BackendType::*;
         match self {
             Console(c, x) => Console(Cow::Borrowed(c), x),
+            File(c, x) => File(Cow::Borrowed(c), x),
             #[cfg(feature = "testing")]
             Postgres(c, x) => Postgres(Cow::Borrowed(c), x),
             Link(c) => Link(Cow::Borrowed(c)),
@@ -97,6 +111,7 @@ impl<'a, T> BackendType<'a, T> {
         use BackendType::*;
         match self {
             Console(c, x) => Console(c, f(x)),
+            File(c, x) => File(c, f(x)),
             #[cfg(feature = "testing")]
             Postgres(c, x) => Postgres(c, f(x)),
             Link(c) => Link(c),
@@ -113,6 +128,7 @@ impl<'a, T, E> BackendType<'a, Result<T, E>> {
         use BackendType::*;
         match self {
             Console(c, x) => x.map(|x| Console(c, x)),
+            File(c, x) => x.map(|x| File(c, x)),
             #[cfg(feature = "testing")]
             Postgres(c, x) => x.map(|x| Postgres(c, x)),
             Link(c) => Ok(Link(c)),
@@ -131,6 +147,7 @@ pub struct ComputeUserInfoNoEndpoint {
     pub user: SmolStr,
     pub peer_addr: IpAddr,
     pub cache_key: SmolStr,
+    pub extra: Option<ConnectionInfoExtra>,
 }
 
 pub struct ComputeUserInfo {
@@ -142,6 +159,9 @@ pub enum ComputeCredentialKeys {
     #[cfg(feature = "testing")]
     Password(Vec<u8>),
     AuthKeys(AuthKeys),
+    /// The client authenticated with a JWT, which the compute can't verify.
+    /// The proxy logs in with a password issued by the console instead.
+    Jwt,
 }
 
 impl TryFrom<ClientCredentials> for ComputeUserInfo {
@@ -153,6 +173,7 @@ impl TryFrom<ClientCredentials> for ComputeUserInfo {
             user: creds.user,
             peer_addr: creds.peer_addr,
             cache_key: creds.cache_key,
+            extra: creds.extra,
         };
         match creds.project {
             None => Err(inner),
@@ -192,7 +213,17 @@ async fn auth_quirks(
     if !check_peer_addr_is_in_list(&info.inner.peer_addr, &allowed_ips) {
         return Err(auth::AuthError::ip_address_not_allowed());
     }
+    let allowed_vpc_endpoint_ids = api.get_allowed_vpc_endpoint_ids(extra, &info).await?;
+    if !check_vpc_endpoint_id_is_in_list(info.inner.extra.as_ref(), &allowed_vpc_endpoint_ids) {
+        return Err(auth::AuthError::vpc_endpoint_id_not_allowed());
+    }
     let cached_secret = api.get_role_secret(extra, &info).await?;
+    // JWTs are only accepted by the cleartext flow, like passwords of websocket clients.
+    let jwt_rules = if allow_cleartext {
+        api.get_endpoint_jwks(extra, &info).await?
+    } else {
+        Arc::new(vec![])
+    };
 
     let secret = cached_secret.clone().unwrap_or_else(|| {
         // If we don't have an authentication secret, we mock one to
@@ -207,6 +238,7 @@ async fn auth_quirks(
         client,
         unauthenticated_password,
         allow_cleartext,
+        &jwt_rules,
         config,
         latency_timer,
     )
@@ -223,12 +255,14 @@ async fn auth_quirks(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn authenticate_with_secret(
     secret: AuthSecret,
     info: ComputeUserInfo,
     client: &mut stream::PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin>>,
     unauthenticated_password: Option<Vec<u8>>,
     allow_cleartext: bool,
+    jwt_rules: &[AuthRule],
     config: &'static AuthenticationConfig,
     latency_timer: &mut LatencyTimer,
 ) -> auth::Result<ComputeCredentials<ComputeCredentialKeys>> {
@@ -253,7 +287,15 @@ async fn authenticate_with_secret(
     // Perform cleartext auth if we're allowed to do that.
     // Currently, we use it for websocket connections (latency).
     if allow_cleartext {
-        return hacks::authenticate_cleartext(info, client, latency_timer, secret).await;
+        return hacks::authenticate_cleartext(
+            info,
+            client,
+            latency_timer,
+            secret,
+            jwt_rules,
+            config,
+        )
+        .await;
     }
 
     // Finally, proceed with the main auth flow (SCRAM-based).
@@ -305,6 +347,12 @@ async fn auth_and_wake_compute(
         #[cfg(feature = "testing")]
         ComputeCredentialKeys::Password(password) => node.config.password(password),
         ComputeCredentialKeys::AuthKeys(auth_keys) => node.config.auth_keys(auth_keys),
+        ComputeCredentialKeys::Jwt => {
+            let password = api
+                .get_role_password(extra, &compute_credentials.info)
+                .await?;
+            node.config.password(password.as_bytes())
+        }
     };
 
     Ok((node, compute_credentials.info))
@@ -316,7 +364,7 @@ impl<'a> BackendType<'a, ClientCredentials> {
         use BackendType::*;
 
         match self {
-            Console(_, creds) => creds.project.clone(),
+            Console(_, creds) | File(_, creds) => creds.project.clone(),
             #[cfg(feature = "testing")]
             Postgres(_, creds) => creds.project.clone(),
             Link(_) => Some("link".into()),
@@ -330,7 +378,7 @@ impl<'a> BackendType<'a, ClientCredentials> {
         use BackendType::*;
 
         match self {
-            Console(_, creds) => &creds.user,
+            Console(_, creds) | File(_, creds) => &creds.user,
             #[cfg(feature = "testing")]
             Postgres(_, creds) => &creds.user,
             Link(_) => "link",
@@ -371,6 +419,25 @@ impl<'a> BackendType<'a, ClientCredentials> {
                 .await?;
                 (cache_info, BackendType::Console(api, user_info))
             }
+            File(api, creds) => {
+                info!(
+                    user = &*creds.user,
+                    project = creds.project(),
+                    "performing authentication using the auth config file"
+                );
+
+                let (cache_info, user_info) = auth_and_wake_compute(
+                    &*api,
+                    extra,
+                    creds,
+                    client,
+                    allow_cleartext,
+                    config,
+                    latency_timer,
+                )
+                .await?;
+                (cache_info, BackendType::File(api, user_info))
+            }
             #[cfg(feature = "testing")]
             Postgres(api, creds) => {
                 info!(
@@ -414,6 +481,20 @@ impl<'a> BackendType<'a, ClientCredentials> {
 }
 
 impl BackendType<'_, ComputeUserInfo> {
+    /// Get the user info, which the link auth flow doesn't have.
+    pub fn get_user_info(&self) -> Option<&ComputeUserInfo> {
+        use BackendType::*;
+
+        match self {
+            Console(_, creds) | File(_, creds) => Some(creds),
+            #[cfg(feature = "testing")]
+            Postgres(_, creds) => Some(creds),
+            Link(_) => None,
+            #[cfg(test)]
+            Test(_) => None,
+        }
+    }
+
     pub async fn get_allowed_ips(
         &self,
         extra: &ConsoleReqExtra,
@@ -421,6 +502,7 @@ impl BackendType<'_, ComputeUserInfo> {
         use BackendType::*;
         match self {
             Console(api, creds) => api.get_allowed_ips(extra, creds).await,
+            File(api, creds) => api.get_allowed_ips(extra, creds).await,
             #[cfg(feature = "testing")]
             Postgres(api, creds) => api.get_allowed_ips(extra, creds).await,
             Link(_) => Ok(Arc::new(vec![])),
@@ -429,6 +511,70 @@ impl BackendType<'_, ComputeUserInfo> {
         }
     }
 
+    pub async fn get_allowed_vpc_endpoint_ids(
+        &self,
+        extra: &ConsoleReqExtra,
+    ) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+        use BackendType::*;
+        match self {
+            Console(api, creds) => api.get_allowed_vpc_endpoint_ids(extra, creds).await,
+            File(api, creds) => api.get_allowed_vpc_endpoint_ids(extra, creds).await,
+            #[cfg(feature = "testing")]
+            Postgres(api, creds) => api.get_allowed_vpc_endpoint_ids(extra, creds).await,
+            Link(_) => Ok(Arc::new(vec![])),
+            #[cfg(test)]
+            Test(x) => x.get_allowed_vpc_endpoint_ids(),
+        }
+    }
+
+    pub async fn get_endpoint_jwks(
+        &self,
+        extra: &ConsoleReqExtra,
+    ) -> Result<Arc<Vec<AuthRule>>, GetAuthInfoError> {
+        use BackendType::*;
+        match self {
+            Console(api, creds) => api.get_endpoint_jwks(extra, creds).await,
+            File(api, creds) => api.get_endpoint_jwks(extra, creds).await,
+            #[cfg(feature = "testing")]
+            Postgres(api, creds) => api.get_endpoint_jwks(extra, creds).await,
+            Link(_) => Ok(Arc::new(vec![])),
+            #[cfg(test)]
+            Test(x) => x.get_endpoint_jwks(),
+        }
+    }
+
+    pub async fn get_role_password(
+        &self,
+        extra: &ConsoleReqExtra,
+    ) -> Result<SmolStr, GetAuthInfoError> {
+        use BackendType::*;
+        match self {
+            Console(api, creds) => api.get_role_password(extra, creds).await,
+            File(api, creds) => api.get_role_password(extra, creds).await,
+            #[cfg(feature = "testing")]
+            Postgres(api, creds) => api.get_role_password(extra, creds).await,
+            Link(_) => Err(GetAuthInfoError::NoRolePassword),
+            #[cfg(test)]
+            Test(x) => x.get_role_password(),
+        }
+    }
+
+    pub async fn get_endpoint_limits(
+        &self,
+        extra: &ConsoleReqExtra,
+    ) -> Result<Arc<EndpointLimits>, GetAuthInfoError> {
+        use BackendType::*;
+        match self {
+            Console(api, creds) => api.get_endpoint_limits(extra, creds).await,
+            File(api, creds) => api.get_endpoint_limits(extra, creds).await,
+            #[cfg(feature = "testing")]
+            Postgres(api, creds) => api.get_endpoint_limits(extra, creds).await,
+            Link(_) => Ok(Arc::new(EndpointLimits::default())),
+            #[cfg(test)]
+            Test(_) => Ok(Arc::new(EndpointLimits::default())),
+        }
+    }
+
     /// When applicable, wake the compute node, gaining its connection info in the process.
     /// The link auth flow doesn't support this, so we return [`None`] in that case.
     pub async fn wake_compute(
@@ -439,6 +585,7 @@ impl BackendType<'_, ComputeUserInfo> {
 
         match self {
             Console(api, creds) => api.wake_compute(extra, creds).map_ok(Some).await,
+            File(api, creds) => api.wake_compute(extra, creds).map_ok(Some).await,
             #[cfg(feature = "testing")]
             Postgres(api, creds) => api.wake_compute(extra, creds).map_ok(Some).await,
             Link(_) => Ok(None),
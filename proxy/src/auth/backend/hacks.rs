@@ -1,8 +1,9 @@
 use super::{
-    ComputeCredentialKeys, ComputeCredentials, ComputeUserInfo, ComputeUserInfoNoEndpoint,
+    AuthRule, ComputeCredentialKeys, ComputeCredentials, ComputeUserInfo, ComputeUserInfoNoEndpoint,
 };
 use crate::{
     auth::{self, AuthFlow},
+    config::AuthenticationConfig,
     console::AuthSecret,
     metrics::LatencyTimer,
     sasl,
@@ -15,22 +16,39 @@ use tracing::{info, warn};
 /// one round trip and *expensive* computations (>= 4096 HMAC iterations).
 /// These properties are benefical for serverless JS workers, so we
 /// use this mechanism for websocket connections.
+///
+/// If the endpoint trusts any identity providers, the client may send
+/// a JWT instead of the password.
 pub async fn authenticate_cleartext(
     info: ComputeUserInfo,
     client: &mut stream::PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin>>,
     latency_timer: &mut LatencyTimer,
     secret: AuthSecret,
+    jwt_rules: &[AuthRule],
+    config: &'static AuthenticationConfig,
 ) -> auth::Result<ComputeCredentials<ComputeCredentialKeys>> {
     warn!("cleartext auth flow override is enabled, proceeding");
 
     // pause the timer while we communicate with the client
     let _paused = latency_timer.pause();
 
-    let auth_outcome = AuthFlow::new(client)
-        .begin(auth::CleartextPassword(secret))
+    let flow = AuthFlow::new(client);
+    let auth_outcome = if jwt_rules.is_empty() {
+        flow.begin(auth::CleartextPassword(secret))
+            .await?
+            .authenticate()
+            .await?
+    } else {
+        flow.begin(auth::CleartextPasswordOrJwt {
+            secret,
+            role: &info.inner.user,
+            rules: jwt_rules,
+            jwks: &config.jwks_cache,
+        })
         .await?
         .authenticate()
-        .await?;
+        .await?
+    };
 
     let keys = match auth_outcome {
         sasl::Outcome::Success(key) => key,
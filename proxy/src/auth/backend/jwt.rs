@@ -0,0 +1,367 @@
+//! Authentication with JSON Web Tokens.
+//!
+//! An endpoint can trust the JWKS of some identity providers, each for a set
+//! of roles. A token is accepted for a role if it's signed by a key from one
+//! of the JWKS trusted for that role, hasn't expired, was issued for the
+//! configured audience, and names the role in its `role` claim.
+//!
+//! The compute node doesn't know about the identity providers, so once the
+//! token is verified, the proxy logs in to it with a password that the console
+//! issues for the role.
+
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use smol_str::SmolStr;
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::{error::UserFacingError, http};
+
+/// How long a fetched JWKS is used before it's fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// A token signed with a key missing from the cached JWKS makes us fetch it
+/// again right away, as the keys might have been rotated. This limits how
+/// often unknown keys can do that.
+const JWKS_MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Symmetric algorithms would need a shared secret, which a JWKS doesn't have.
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// An identity provider trusted by an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRule {
+    pub id: String,
+    pub jwks_url: String,
+    /// Expected `aud` claim of the tokens, if any.
+    pub audience: Option<String>,
+    /// Roles the tokens of this provider may log in as.
+    pub role_names: Vec<SmolStr>,
+}
+
+#[derive(Debug, Error)]
+pub enum JwtError {
+    #[error("malformed JWT: {0}")]
+    Malformed(jsonwebtoken::errors::Error),
+
+    #[error("JWT signing algorithm {0:?} is not supported")]
+    UnsupportedAlgorithm(Algorithm),
+
+    #[error("JWT is missing the key id")]
+    MissingKeyId,
+
+    #[error("JWT authentication is not allowed for role '{0}'")]
+    RoleNotAllowed(SmolStr),
+
+    #[error("JWT is signed with an unknown key '{0}'")]
+    UnknownKey(String),
+
+    #[error("invalid JWT: {0}")]
+    Invalid(jsonwebtoken::errors::Error),
+
+    #[error("JWT is not valid for role '{0}'")]
+    RoleMismatch(SmolStr),
+
+    #[error("failed to fetch JWKS: {0}")]
+    FetchJwks(#[from] http::Error),
+}
+
+impl UserFacingError for JwtError {
+    fn to_string_client(&self) -> String {
+        use JwtError::*;
+        match self {
+            FetchJwks(_) => "Failed to fetch the JWKS of the identity provider".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Does the password look like a compact JWS, as opposed to a regular password?
+pub fn looks_like_jwt(password: &[u8]) -> bool {
+    // base64 of `{"`, which every header starts with
+    password.starts_with(b"eyJ") && password.iter().filter(|&&b| b == b'.').count() == 2
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    role: Option<String>,
+}
+
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+/// Fetches and caches the JWKS of identity providers.
+pub struct JwkCache {
+    client: http::ClientWithMiddleware,
+    jwks: DashMap<String, Arc<CachedJwks>>,
+}
+
+impl Default for JwkCache {
+    fn default() -> Self {
+        Self::new(http::new_client_with_timeout(Duration::from_secs(10)))
+    }
+}
+
+impl JwkCache {
+    pub fn new(client: http::ClientWithMiddleware) -> Self {
+        Self {
+            client,
+            jwks: DashMap::new(),
+        }
+    }
+
+    /// Check that the token lets the client log in as the role, according to
+    /// the rules of the endpoint.
+    pub async fn check_jwt(
+        &self,
+        jwt: &str,
+        rules: &[AuthRule],
+        role: &SmolStr,
+    ) -> Result<(), JwtError> {
+        let header = jsonwebtoken::decode_header(jwt).map_err(JwtError::Malformed)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(JwtError::UnsupportedAlgorithm(header.alg));
+        }
+        let kid = header.kid.ok_or(JwtError::MissingKeyId)?;
+
+        let mut rules = rules
+            .iter()
+            .filter(|rule| rule.role_names.contains(role))
+            .peekable();
+        if rules.peek().is_none() {
+            return Err(JwtError::RoleNotAllowed(role.clone()));
+        }
+
+        for rule in rules {
+            let jwks = self.get_jwks(&rule.jwks_url, &kid).await?;
+            let Some(jwk) = jwks.keys.find(&kid) else {
+                continue;
+            };
+            let key = DecodingKey::from_jwk(jwk).map_err(JwtError::Invalid)?;
+
+            let mut validation = Validation::new(header.alg);
+            validation.validate_nbf = true;
+            match &rule.audience {
+                Some(audience) => validation.set_audience(&[audience]),
+                None => validation.validate_aud = false,
+            }
+
+            let claims = jsonwebtoken::decode::<Claims>(jwt, &key, &validation)
+                .map_err(JwtError::Invalid)?
+                .claims;
+            if claims.role.as_deref() != Some(role.as_str()) {
+                return Err(JwtError::RoleMismatch(role.clone()));
+            }
+
+            info!(rule = %rule.id, "JWT is valid");
+            return Ok(());
+        }
+
+        Err(JwtError::UnknownKey(kid))
+    }
+
+    async fn get_jwks(&self, url: &str, kid: &str) -> Result<Arc<CachedJwks>, JwtError> {
+        if let Some(cached) = self.jwks.get(url).map(|entry| entry.clone()) {
+            let age = cached.fetched_at.elapsed();
+            let has_key = cached.keys.find(kid).is_some();
+            if age < JWKS_TTL && (has_key || age < JWKS_MIN_REFETCH_INTERVAL) {
+                return Ok(cached);
+            }
+        }
+
+        info!(url, "fetching JWKS");
+        let keys: JwkSet = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(http::Error::from)?
+            .json()
+            .await
+            .map_err(http::Error::from)?;
+
+        let cached = Arc::new(CachedJwks {
+            keys,
+            fetched_at: Instant::now(),
+        });
+        self.jwks.insert(url.to_owned(), cached.clone());
+        Ok(cached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::SystemTime;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    struct Signer {
+        kid: &'static str,
+        key: EncodingKey,
+        jwk: serde_json::Value,
+    }
+
+    impl Signer {
+        fn new(kid: &'static str) -> Self {
+            let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+            // uncompressed point: 0x04 || x || y
+            let point = key_pair.public_key_raw();
+            let jwk = json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": kid,
+                "alg": "ES256",
+                "use": "sig",
+                "x": base64::encode_config(&point[1..33], base64::URL_SAFE_NO_PAD),
+                "y": base64::encode_config(&point[33..], base64::URL_SAFE_NO_PAD),
+            });
+            let key = EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+            Self { kid, key, jwk }
+        }
+
+        fn sign(&self, claims: serde_json::Value) -> String {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some(self.kid.to_owned());
+            jsonwebtoken::encode(&header, &claims, &self.key).unwrap()
+        }
+    }
+
+    /// A local identity provider serving a JWKS, counting the fetches.
+    fn serve_jwks(jwks: serde_json::Value) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!(
+            "http://{}/.well-known/jwks.json",
+            listener.local_addr().unwrap()
+        );
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let body = jwks.to_string();
+        let server_hits = hits.clone();
+        let make_svc = make_service_fn(move |_| {
+            let body = body.clone();
+            let hits = server_hits.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |_req| {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    let body = body.clone();
+                    async move { Ok::<_, std::convert::Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+        let server = hyper::Server::from_tcp(listener).unwrap().serve(make_svc);
+        tokio::spawn(server);
+
+        (url, hits)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn rule(jwks_url: &str) -> AuthRule {
+        AuthRule {
+            id: "idp".to_owned(),
+            jwks_url: jwks_url.to_owned(),
+            audience: Some("neon".to_owned()),
+            role_names: vec!["anonymous".into(), "authenticated".into()],
+        }
+    }
+
+    #[test]
+    fn jwt_detection() {
+        assert!(looks_like_jwt(
+            b"eyJhbGciOiJFUzI1NiJ9.eyJyb2xlIjoiYSJ9.c2ln"
+        ));
+        assert!(!looks_like_jwt(b"eyJ-but.not-quite"));
+        assert!(!looks_like_jwt(b"password"));
+    }
+
+    #[tokio::test]
+    async fn check_jwt() {
+        let signer = Signer::new("key-1");
+        let (url, hits) = serve_jwks(json!({ "keys": [signer.jwk.clone()] }));
+        let rules = [rule(&url)];
+        let cache = JwkCache::default();
+        let role = SmolStr::from("authenticated");
+
+        let jwt =
+            signer.sign(json!({ "role": "authenticated", "aud": "neon", "exp": now() + 600 }));
+        cache.check_jwt(&jwt, &rules, &role).await.unwrap();
+        // the JWKS is cached
+        cache.check_jwt(&jwt, &rules, &role).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let expired =
+            signer.sign(json!({ "role": "authenticated", "aud": "neon", "exp": now() - 600 }));
+        let err = cache.check_jwt(&expired, &rules, &role).await.unwrap_err();
+        assert!(matches!(err, JwtError::Invalid(_)), "{err}");
+
+        let wrong_audience =
+            signer.sign(json!({ "role": "authenticated", "aud": "other", "exp": now() + 600 }));
+        let err = cache
+            .check_jwt(&wrong_audience, &rules, &role)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, JwtError::Invalid(_)), "{err}");
+
+        let other_role =
+            signer.sign(json!({ "role": "anonymous", "aud": "neon", "exp": now() + 600 }));
+        let err = cache
+            .check_jwt(&other_role, &rules, &role)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, JwtError::RoleMismatch(_)), "{err}");
+
+        let err = cache
+            .check_jwt(&jwt, &rules, &SmolStr::from("postgres"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, JwtError::RoleNotAllowed(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn check_jwt_unknown_key() {
+        let signer = Signer::new("key-1");
+        let (url, hits) = serve_jwks(json!({ "keys": [signer.jwk.clone()] }));
+        let rules = [rule(&url)];
+        let cache = JwkCache::default();
+        let role = SmolStr::from("anonymous");
+
+        let jwt = signer.sign(json!({ "role": "anonymous", "aud": "neon", "exp": now() + 600 }));
+        cache.check_jwt(&jwt, &rules, &role).await.unwrap();
+
+        let unknown = Signer::new("key-2");
+        let jwt = unknown.sign(json!({ "role": "anonymous", "aud": "neon", "exp": now() + 600 }));
+        let err = cache.check_jwt(&jwt, &rules, &role).await.unwrap_err();
+        assert!(matches!(err, JwtError::UnknownKey(_)), "{err}");
+        // the JWKS was just fetched, so unknown keys don't refetch it
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
@@ -2,7 +2,8 @@
 
 use crate::{
     auth::password_hack::parse_endpoint_param, error::UserFacingError,
-    metrics::NUM_CONNECTION_ACCEPTED_BY_SNI, proxy::neon_options_str,
+    metrics::NUM_CONNECTION_ACCEPTED_BY_SNI, protocol2::ConnectionInfoExtra,
+    proxy::neon_options_str,
 };
 use itertools::Itertools;
 use pq_proto::StartupMessageParams;
@@ -45,6 +46,8 @@ pub struct ClientCredentials {
 
     pub cache_key: SmolStr,
     pub peer_addr: IpAddr,
+    /// The private link the client came through, from the PROXY protocol header.
+    pub extra: Option<ConnectionInfoExtra>,
 }
 
 impl ClientCredentials {
@@ -52,6 +55,10 @@ impl ClientCredentials {
     pub fn project(&self) -> Option<&str> {
         self.project.as_deref()
     }
+
+    pub fn with_extra(self, extra: Option<ConnectionInfoExtra>) -> Self {
+        Self { extra, ..self }
+    }
 }
 
 impl ClientCredentials {
@@ -148,10 +155,28 @@ impl ClientCredentials {
             project,
             cache_key,
             peer_addr,
+            extra: None,
         })
     }
 }
 
+/// Whether a client that came through the private link `extra` (if any) may
+/// connect to an endpoint that only allows the links of `allowed_ids`. An
+/// empty list allows any connection, like in [`check_peer_addr_is_in_list`].
+pub fn check_vpc_endpoint_id_is_in_list(
+    extra: Option<&ConnectionInfoExtra>,
+    allowed_ids: &[String],
+) -> bool {
+    if allowed_ids.is_empty() {
+        return true;
+    }
+    let Some(extra) = extra else {
+        return false;
+    };
+    let id = extra.vpc_endpoint_id();
+    allowed_ids.iter().any(|allowed| *allowed == id)
+}
+
 pub fn check_peer_addr_is_in_list(peer_addr: &IpAddr, ip_list: &Vec<String>) -> bool {
     if ip_list.is_empty() {
         return true;
@@ -171,6 +196,12 @@ pub fn check_peer_addr_is_in_list(peer_addr: &IpAddr, ip_list: &Vec<String>) ->
     false
 }
 
+/// Whether `pattern` is an entry of an allowed IPs list that [`check_peer_addr_is_in_list`]
+/// understands: an address, a subnet or a range of addresses.
+pub fn is_valid_ip_pattern(pattern: &str) -> bool {
+    parse_ip_pattern(pattern).is_ok()
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 enum IpPattern {
     Subnet(ipnet::IpNet),
@@ -428,6 +459,27 @@ mod tests {
             &vec!["88.8.8".into(), "127.0.0.1".into()]
         ));
     }
+
+    #[test]
+    fn test_check_vpc_endpoint_id_is_in_list() {
+        let aws = ConnectionInfoExtra::Aws {
+            vpce_id: "vpce-1".into(),
+        };
+        let azure = ConnectionInfoExtra::Azure { link_id: 42 };
+        assert!(check_vpc_endpoint_id_is_in_list(None, &[]));
+        assert!(check_vpc_endpoint_id_is_in_list(Some(&aws), &[]));
+
+        let allowed = ["vpce-1".to_owned(), "42".to_owned()];
+        assert!(check_vpc_endpoint_id_is_in_list(Some(&aws), &allowed));
+        assert!(check_vpc_endpoint_id_is_in_list(Some(&azure), &allowed));
+        assert!(!check_vpc_endpoint_id_is_in_list(None, &allowed));
+        assert!(!check_vpc_endpoint_id_is_in_list(
+            Some(&ConnectionInfoExtra::Aws {
+                vpce_id: "vpce-2".into()
+            }),
+            &allowed
+        ));
+    }
     #[test]
     fn test_parse_ip_v4() -> anyhow::Result<()> {
         let peer_addr = IpAddr::from([127, 0, 0, 1]);
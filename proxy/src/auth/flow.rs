@@ -1,6 +1,9 @@
 //! Main authentication flow.
 
-use super::{backend::ComputeCredentialKeys, AuthErrorImpl, PasswordHackPayload};
+use super::{
+    backend::{jwt, AuthRule, ComputeCredentialKeys, JwkCache},
+    AuthErrorImpl, PasswordHackPayload,
+};
 use crate::{
     config::TlsServerEndPoint,
     console::AuthSecret,
@@ -8,6 +11,7 @@ use crate::{
     stream::{PqStream, Stream},
 };
 use pq_proto::{BeAuthenticationSaslMessage, BeMessage, BeMessage as Be};
+use smol_str::SmolStr;
 use std::io;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::info;
@@ -60,6 +64,22 @@ impl AuthMethod for CleartextPassword {
     }
 }
 
+/// Like [`CleartextPassword`], but the endpoint also accepts JWTs of its
+/// identity providers in place of the password.
+pub struct CleartextPasswordOrJwt<'a> {
+    pub secret: AuthSecret,
+    pub role: &'a SmolStr,
+    pub rules: &'a [AuthRule],
+    pub jwks: &'a JwkCache,
+}
+
+impl AuthMethod for CleartextPasswordOrJwt<'_> {
+    #[inline(always)]
+    fn first_message(&self, _channel_binding: bool) -> BeMessage<'_> {
+        Be::AuthenticationCleartextPassword
+    }
+}
+
 /// This wrapper for [`PqStream`] performs client authentication.
 #[must_use]
 pub struct AuthFlow<'a, S, State> {
@@ -134,6 +154,32 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, CleartextPassword> {
     }
 }
 
+impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, CleartextPasswordOrJwt<'_>> {
+    /// Perform user authentication. Raise an error in case authentication failed.
+    pub async fn authenticate(self) -> super::Result<sasl::Outcome<ComputeCredentialKeys>> {
+        let msg = self.stream.read_password_message().await?;
+        let password = msg
+            .strip_suffix(&[0])
+            .ok_or(AuthErrorImpl::MalformedPassword("missing terminator"))?;
+
+        let outcome = if jwt::looks_like_jwt(password) {
+            let token = std::str::from_utf8(password)
+                .map_err(|_| AuthErrorImpl::MalformedPassword("invalid JWT"))?;
+            let state = self.state;
+            state.jwks.check_jwt(token, state.rules, state.role).await?;
+            sasl::Outcome::Success(ComputeCredentialKeys::Jwt)
+        } else {
+            validate_password_and_exchange(password, self.state.secret)?
+        };
+
+        if let sasl::Outcome::Success(_) = &outcome {
+            self.stream.write_message_noflush(&Be::AuthenticationOk)?;
+        }
+
+        Ok(outcome)
+    }
+}
+
 /// Stream wrapper for handling [SCRAM](crate::scram) auth.
 impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, Scram<'_>> {
     /// Perform user authentication. Raise an error in case authentication failed.
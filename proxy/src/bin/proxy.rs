@@ -1,20 +1,26 @@
 use futures::future::Either;
 use proxy::auth;
+use proxy::cancellation;
+use proxy::cancellation::{CancelMap, RedisCancelKeyStore, SharedCancelKeys};
 use proxy::config::AuthenticationConfig;
 use proxy::config::CacheOptions;
 use proxy::config::HttpConfig;
 use proxy::console;
 use proxy::console::provider::AllowedIpsCache;
+use proxy::console::provider::AllowedVpcEndpointIdsCache;
+use proxy::console::provider::EndpointJwksCache;
+use proxy::console::provider::EndpointLimitsCache;
 use proxy::console::provider::NodeInfoCache;
 use proxy::console::provider::RoleSecretCache;
 use proxy::http;
+use proxy::proxy::transaction_pool::TransactionPoolOptions;
 use proxy::rate_limiter::EndpointRateLimiter;
 use proxy::rate_limiter::RateBucketInfo;
 use proxy::rate_limiter::RateLimiterConfig;
 use proxy::serverless::GlobalConnPoolOptions;
 use proxy::usage_metrics;
 
-use anyhow::bail;
+use anyhow::{bail, ensure};
 use proxy::config::{self, ProxyConfig};
 use proxy::serverless;
 use std::pin::pin;
@@ -35,6 +41,7 @@ use clap::{Parser, ValueEnum};
 #[derive(Clone, Debug, ValueEnum)]
 enum AuthBackend {
     Console,
+    File,
     #[cfg(feature = "testing")]
     Postgres,
     Link,
@@ -61,7 +68,8 @@ struct ProxyCliArgs {
     /// redirect unauthenticated users to the given uri in case of link auth
     #[clap(short, long, default_value = "http://localhost:3000/psql_session/")]
     uri: String,
-    /// cloud API endpoint for authenticating users
+    /// cloud API endpoint for authenticating users, or the path of the config
+    /// file with the endpoints and roles for the `file` auth backend
     #[clap(
         short,
         long,
@@ -117,6 +125,7 @@ struct ProxyCliArgs {
     ///
     /// Provided in the form '<Requests Per Second>@<Bucket Duration Size>'.
     /// Can be given multiple times for different bucket sizes.
+    /// Endpoints with limits set by the control plane use those instead.
     #[clap(long, default_values_t = RateBucketInfo::DEFAULT_SET)]
     endpoint_rps_limit: Vec<RateBucketInfo>,
     /// Initial limit for dynamic rate limiter. Makes sense only if `rate_limit_algorithm` is *not* `None`.
@@ -133,6 +142,62 @@ struct ProxyCliArgs {
     /// disable ip check for http requests. If it is too time consuming, it could be turned off.
     #[clap(long, default_value_t = false, value_parser = clap::builder::BoolishValueParser::new(), action = clap::ArgAction::Set)]
     disable_ip_check_for_http: bool,
+    #[clap(flatten)]
+    cancellation: CancellationArgs,
+    #[clap(flatten)]
+    tcp_pool: TcpPoolArgs,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TcpPoolMode {
+    /// Each client has a compute connection of its own
+    Session,
+    /// Clients borrow pooled compute connections for each transaction
+    Transaction,
+}
+
+#[derive(clap::Args, Clone, Copy, Debug)]
+struct TcpPoolArgs {
+    /// How compute connections of postgres clients are pooled. In transaction mode,
+    /// session state (SET, named prepared statements, LISTEN...) doesn't survive
+    /// between transactions.
+    #[clap(value_enum, long, default_value_t = TcpPoolMode::Session)]
+    tcp_pool_mode: TcpPoolMode,
+
+    /// How many compute connections to pool for each endpoint, role and database
+    /// in transaction mode. Clients wait for a connection when all are in use
+    #[clap(long, default_value_t = 20)]
+    tcp_pool_max_conns_per_endpoint: usize,
+
+    /// How long pooled connections should remain idle for before closing
+    #[clap(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    tcp_pool_idle_timeout: tokio::time::Duration,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct CancellationArgs {
+    /// redis url (redis://[:password@]host:port) of the store where proxies share the
+    /// query cancellation keys of their sessions. Without it, a CancelRequest is only
+    /// served by the proxy of the session.
+    #[clap(long, requires = "cancellation_peer_addr")]
+    redis_cancellation_url: Option<String>,
+
+    /// listen for query cancellation requests forwarded by other proxies on ip:port
+    #[clap(long, default_value = "127.0.0.1:7002")]
+    cancellation_listen: String,
+
+    /// address (host:port) other proxies forward query cancellation requests to, required with
+    /// redis-cancellation-url
+    #[clap(long)]
+    cancellation_peer_addr: Option<String>,
+
+    /// how long a published query cancellation key lives in the store
+    #[clap(long, default_value = "1d", value_parser = humantime::parse_duration)]
+    cancellation_key_ttl: tokio::time::Duration,
+
+    /// timeout for requests to the cancellation key store
+    #[clap(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    redis_cancellation_timeout: tokio::time::Duration,
 }
 
 #[derive(clap::Args, Clone, Copy, Debug)]
@@ -163,6 +228,15 @@ struct SqlOverHttpArgs {
     /// increase memory used by the pool
     #[clap(long, default_value_t = 128)]
     sql_over_http_pool_shards: usize,
+
+    /// How long an interactive transaction can stay idle between requests before it's rolled back
+    #[clap(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    sql_over_http_transaction_idle_timeout: tokio::time::Duration,
+
+    /// How long a streamed response can go without progress, i.e. without a row from compute
+    /// or the client reading the body, before it's aborted
+    #[clap(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    sql_over_http_stream_idle_timeout: tokio::time::Duration,
 }
 
 #[tokio::main]
@@ -196,6 +270,17 @@ async fn main() -> anyhow::Result<()> {
 
     let endpoint_rate_limiter = Arc::new(EndpointRateLimiter::new(&config.endpoint_rps_limit));
 
+    let shared_cancel_keys = build_shared_cancel_keys(&args.cancellation)?;
+    let cancellation_listener = match shared_cancel_keys {
+        Some(_) => {
+            let cancellation_address: SocketAddr = args.cancellation.cancellation_listen.parse()?;
+            info!("Starting cancellation peer listener on {cancellation_address}");
+            Some(TcpListener::bind(cancellation_address).await?)
+        }
+        None => None,
+    };
+    let cancel_map = Arc::new(CancelMap::new(shared_cancel_keys));
+
     // client facing tasks. these will exit on error or on cancellation
     // cancellation returns Ok(())
     let mut client_tasks = JoinSet::new();
@@ -204,8 +289,17 @@ async fn main() -> anyhow::Result<()> {
         proxy_listener,
         cancellation_token.clone(),
         endpoint_rate_limiter.clone(),
+        cancel_map.clone(),
     ));
 
+    if let Some(cancellation_listener) = cancellation_listener {
+        client_tasks.spawn(cancellation::peer_task_main(
+            cancel_map,
+            cancellation_listener,
+            cancellation_token.clone(),
+        ));
+    }
+
     // TODO: rename the argument to something like serverless.
     // It now covers more than just websockets, it also covers SQL over HTTP.
     if let Some(serverless_address) = args.wss {
@@ -255,6 +349,43 @@ async fn main() -> anyhow::Result<()> {
     match maintenance {}
 }
 
+fn build_shared_cancel_keys(args: &CancellationArgs) -> anyhow::Result<Option<SharedCancelKeys>> {
+    let Some(url) = &args.redis_cancellation_url else {
+        return Ok(None);
+    };
+    let client = proxy::redis::Client::new(url, args.redis_cancellation_timeout)?;
+    let Some(peer_addr) = args.cancellation_peer_addr.clone() else {
+        bail!("cancellation-peer-addr is required with redis-cancellation-url");
+    };
+    validate_cancellation_peer_addr(&peer_addr)?;
+    info!("Sharing query cancellation keys in redis, peer address {peer_addr}");
+    Ok(Some(SharedCancelKeys {
+        store: Arc::new(RedisCancelKeyStore::new(client, args.cancellation_key_ttl)),
+        peer_addr,
+    }))
+}
+
+/// Other proxies connect to the peer address, so it can't be one that only works locally.
+fn validate_cancellation_peer_addr(peer_addr: &str) -> anyhow::Result<()> {
+    let Some((host, port)) = peer_addr.rsplit_once(':') else {
+        bail!("cancellation-peer-addr {peer_addr} must be host:port");
+    };
+    ensure!(
+        !host.is_empty() && port.parse::<u16>().is_ok(),
+        "cancellation-peer-addr {peer_addr} must be host:port"
+    );
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let local = match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+        Err(_) => host.eq_ignore_ascii_case("localhost"),
+    };
+    ensure!(
+        !local,
+        "cancellation-peer-addr {peer_addr} is not reachable from other proxies"
+    );
+    Ok(())
+}
+
 /// ProxyConfig is created at proxy startup, and lives forever.
 fn build_config(args: &ProxyCliArgs) -> anyhow::Result<&'static ProxyConfig> {
     let tls_config = match (&args.tls_key, &args.tls_cert) {
@@ -315,6 +446,24 @@ fn build_config(args: &ProxyCliArgs) -> anyhow::Result<&'static ProxyConfig> {
                     allowed_ips_cache_config.ttl,
                     false,
                 ),
+                allowed_vpc_endpoint_ids: AllowedVpcEndpointIdsCache::new(
+                    "allowed_vpc_endpoint_ids_cache",
+                    allowed_ips_cache_config.size,
+                    allowed_ips_cache_config.ttl,
+                    false,
+                ),
+                endpoint_jwks: EndpointJwksCache::new(
+                    "endpoint_jwks_cache",
+                    allowed_ips_cache_config.size,
+                    allowed_ips_cache_config.ttl,
+                    false,
+                ),
+                endpoint_limits: EndpointLimitsCache::new(
+                    "endpoint_limits_cache",
+                    allowed_ips_cache_config.size,
+                    allowed_ips_cache_config.ttl,
+                    false,
+                ),
                 role_secret: RoleSecretCache::new(
                     "role_secret_cache",
                     role_secret_cache_config.size,
@@ -342,6 +491,11 @@ fn build_config(args: &ProxyCliArgs) -> anyhow::Result<&'static ProxyConfig> {
             let api = console::provider::neon::Api::new(endpoint, caches, locks);
             auth::BackendType::Console(Cow::Owned(api), ())
         }
+        AuthBackend::File => {
+            let api = console::provider::file::Api::new(args.auth_endpoint.clone().into())?;
+            tokio::spawn(api.clone().reload_worker());
+            auth::BackendType::File(Cow::Owned(api), ())
+        }
         #[cfg(feature = "testing")]
         AuthBackend::Postgres => {
             let url = args.auth_endpoint.parse()?;
@@ -362,14 +516,31 @@ fn build_config(args: &ProxyCliArgs) -> anyhow::Result<&'static ProxyConfig> {
             idle_timeout: args.sql_over_http.sql_over_http_idle_timeout,
             opt_in: args.sql_over_http.sql_over_http_pool_opt_in,
         },
+        transaction_idle_timeout: args.sql_over_http.sql_over_http_transaction_idle_timeout,
+        stream_idle_timeout: args.sql_over_http.sql_over_http_stream_idle_timeout,
     };
     let authentication_config = AuthenticationConfig {
         scram_protocol_timeout: args.scram_protocol_timeout,
+        jwks_cache: auth::backend::JwkCache::default(),
     };
 
     let mut endpoint_rps_limit = args.endpoint_rps_limit.clone();
     RateBucketInfo::validate(&mut endpoint_rps_limit)?;
 
+    let transaction_pool = match args.tcp_pool.tcp_pool_mode {
+        TcpPoolMode::Session => None,
+        TcpPoolMode::Transaction => {
+            ensure!(
+                args.tcp_pool.tcp_pool_max_conns_per_endpoint > 0,
+                "tcp-pool-max-conns-per-endpoint must be positive"
+            );
+            Some(TransactionPoolOptions {
+                max_conns_per_endpoint: args.tcp_pool.tcp_pool_max_conns_per_endpoint,
+                idle_timeout: args.tcp_pool.tcp_pool_idle_timeout,
+            })
+        }
+    };
+
     let config = Box::leak(Box::new(ProxyConfig {
         tls_config,
         auth_backend,
@@ -380,6 +551,7 @@ fn build_config(args: &ProxyCliArgs) -> anyhow::Result<&'static ProxyConfig> {
         require_client_ip: args.require_client_ip,
         disable_ip_check_for_http: args.disable_ip_check_for_http,
         endpoint_rps_limit,
+        transaction_pool,
     }));
 
     Ok(config)
@@ -410,4 +582,28 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn cancellation_peer_addr() {
+        // required with redis
+        assert!(super::ProxyCliArgs::try_parse_from([
+            "proxy",
+            "--redis-cancellation-url",
+            "redis://redis:6379",
+        ])
+        .is_err());
+
+        for addr in [
+            "127.0.0.1:7002",
+            "0.0.0.0:7002",
+            "[::1]:7002",
+            "localhost:7002",
+            "proxy",
+        ] {
+            super::validate_cancellation_peer_addr(addr).unwrap_err();
+        }
+        for addr in ["10.0.0.1:7002", "[fd00::1]:7002", "proxy-0.proxy:7002"] {
+            super::validate_cancellation_peer_addr(addr).unwrap();
+        }
+    }
 }
@@ -1,21 +1,88 @@
 use anyhow::{bail, Context};
+use async_trait::async_trait;
 use dashmap::DashMap;
-use pq_proto::CancelKeyData;
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use futures::TryFutureExt;
+use pq_proto::{CancelKeyData, FeStartupPacket};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
 use tokio_postgres::{CancelToken, NoTls};
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    metrics::NUM_CANCELLATION_REQUESTS,
+    proxy::run_until_cancelled,
+    redis::{self, Reply},
+    stream::PqStream,
+};
 
 /// Enables serving `CancelRequest`s.
+///
+/// A `CancelRequest` comes on a connection of its own, which often lands on
+/// another proxy than the session it cancels. With [`SharedCancelKeys`], the
+/// keys of our sessions are published for the other proxies, and requests
+/// for the keys of theirs are forwarded to them.
 #[derive(Default)]
-pub struct CancelMap(DashMap<CancelKeyData, Option<CancelClosure>>);
+pub struct CancelMap {
+    map: DashMap<CancelKeyData, Option<CancelClosure>>,
+    shared: Option<SharedCancelKeys>,
+}
+
+/// Where a proxy shares the cancellation keys of its sessions.
+pub struct SharedCancelKeys {
+    pub store: Arc<dyn CancelKeyStore>,
+    /// The address the other proxies forward `CancelRequest`s for our sessions
+    /// to, served by [`peer_task_main`].
+    pub peer_addr: String,
+}
 
 impl CancelMap {
-    /// Cancel a running query for the corresponding connection.
+    pub fn new(shared: Option<SharedCancelKeys>) -> Self {
+        Self {
+            map: DashMap::new(),
+            shared,
+        }
+    }
+
+    /// Cancel a running query for the corresponding connection, or forward
+    /// the request to the proxy that owns the session.
     pub async fn cancel_session(&self, key: CancelKeyData) -> anyhow::Result<()> {
+        if self.map.contains_key(&key) {
+            NUM_CANCELLATION_REQUESTS
+                .with_label_values(&["client", "found"])
+                .inc();
+            return self.cancel_local(key).await;
+        }
+
+        if let Some(shared) = &self.shared {
+            let owner = shared
+                .store
+                .lookup(key)
+                .await
+                .context("failed to look up query cancellation key")?;
+            if let Some(owner) = owner.filter(|owner| *owner != shared.peer_addr) {
+                info!("forwarding query cancellation for key {key} to {owner}");
+                NUM_CANCELLATION_REQUESTS
+                    .with_label_values(&["client", "forwarded"])
+                    .inc();
+                return forward_cancel_request(&owner, key).await;
+            }
+        }
+
+        NUM_CANCELLATION_REQUESTS
+            .with_label_values(&["client", "not_found"])
+            .inc();
+        bail!("query cancellation key not found: {key}")
+    }
+
+    /// Cancel a running query of one of our sessions.
+    async fn cancel_local(&self, key: CancelKeyData) -> anyhow::Result<()> {
         // NB: we should immediately release the lock after cloning the token.
         let cancel_closure = self
-            .0
+            .map
             .get(&key)
             .and_then(|x| x.clone())
             .with_context(|| format!("query cancellation key not found: {key}"))?;
@@ -39,7 +106,7 @@ impl CancelMap {
 
         // Random key collisions are unlikely to happen here, but they're still possible,
         // which is why we have to take care not to rewrite an existing key.
-        match self.0.entry(key) {
+        match self.map.entry(key) {
             dashmap::mapref::entry::Entry::Occupied(_) => {
                 bail!("query cancellation key already exists: {key}")
             }
@@ -51,7 +118,17 @@ impl CancelMap {
         // This will guarantee that the session gets dropped
         // as soon as the future is finished.
         scopeguard::defer! {
-            self.0.remove(&key);
+            self.map.remove(&key);
+            // With transaction pooling, the cancel closure is unset between
+            // transactions, so the key may be published even if it's unset now.
+            if let Some(shared) = &self.shared {
+                let store = Arc::clone(&shared.store);
+                tokio::spawn(async move {
+                    if let Err(e) = store.remove(key).await {
+                        warn!("failed to remove published query cancellation key {key}: {e:#}");
+                    }
+                });
+            }
             info!("dropped query cancellation key {key}");
         }
 
@@ -62,15 +139,140 @@ impl CancelMap {
 
     #[cfg(test)]
     fn contains(&self, session: &Session) -> bool {
-        self.0.contains_key(&session.key)
+        self.map.contains_key(&session.key)
     }
 
     #[cfg(test)]
     fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.map.is_empty()
+    }
+}
+
+/// Store of the cancellation keys of all the proxies of a region, so that
+/// any of them can serve a `CancelRequest`, see [`CancelMap`].
+#[async_trait]
+pub trait CancelKeyStore: Send + Sync + 'static {
+    /// Record that the session of `key` is served by the proxy at `owner`.
+    async fn publish(&self, key: CancelKeyData, owner: &str) -> anyhow::Result<()>;
+
+    /// Find the proxy that serves the session of `key`.
+    async fn lookup(&self, key: CancelKeyData) -> anyhow::Result<Option<String>>;
+
+    /// Forget `key`, once its session is over.
+    async fn remove(&self, key: CancelKeyData) -> anyhow::Result<()>;
+}
+
+/// [`CancelKeyStore`] in Redis.
+///
+/// The keys expire after `ttl`, in case a proxy goes away without removing
+/// them. Sessions that last longer can only be cancelled through their own
+/// proxy.
+pub struct RedisCancelKeyStore {
+    client: redis::Client,
+    ttl: Duration,
+}
+
+impl RedisCancelKeyStore {
+    pub fn new(client: redis::Client, ttl: Duration) -> Self {
+        Self { client, ttl }
+    }
+
+    fn redis_key(key: CancelKeyData) -> String {
+        format!(
+            "cancel_key:{:08x}{:08x}",
+            key.backend_pid as u32, key.cancel_key as u32
+        )
     }
 }
 
+#[async_trait]
+impl CancelKeyStore for RedisCancelKeyStore {
+    async fn publish(&self, key: CancelKeyData, owner: &str) -> anyhow::Result<()> {
+        let redis_key = Self::redis_key(key);
+        let ttl = self.ttl.as_secs().max(1).to_string();
+        self.client
+            .query(&[
+                b"SET",
+                redis_key.as_bytes(),
+                owner.as_bytes(),
+                b"EX",
+                ttl.as_bytes(),
+            ])
+            .await?;
+        Ok(())
+    }
+
+    async fn lookup(&self, key: CancelKeyData) -> anyhow::Result<Option<String>> {
+        let redis_key = Self::redis_key(key);
+        match self.client.query(&[b"GET", redis_key.as_bytes()]).await? {
+            Reply::Bulk(Some(owner)) => Ok(Some(String::from_utf8(owner.to_vec())?)),
+            Reply::Bulk(None) => Ok(None),
+            reply => bail!("unexpected reply to GET: {reply:?}"),
+        }
+    }
+
+    async fn remove(&self, key: CancelKeyData) -> anyhow::Result<()> {
+        let redis_key = Self::redis_key(key);
+        self.client.query(&[b"DEL", redis_key.as_bytes()]).await?;
+        Ok(())
+    }
+}
+
+/// Send a `CancelRequest` for `key` to the proxy at `peer_addr`.
+async fn forward_cancel_request(peer_addr: &str, key: CancelKeyData) -> anyhow::Result<()> {
+    // Same packet as a client sends, see `FeStartupPacket::parse`.
+    const CANCEL_REQUEST_CODE: i32 = (1234 << 16) | 5678;
+
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&16i32.to_be_bytes());
+    packet.extend_from_slice(&CANCEL_REQUEST_CODE.to_be_bytes());
+    packet.extend_from_slice(&key.backend_pid.to_be_bytes());
+    packet.extend_from_slice(&key.cancel_key.to_be_bytes());
+
+    let mut socket = TcpStream::connect(peer_addr).await?;
+    socket.write_all(&packet).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Serve the `CancelRequest`s that the other proxies forward to us, see
+/// [`SharedCancelKeys`]. Those are not forwarded any further.
+pub async fn peer_task_main(
+    cancel_map: Arc<CancelMap>,
+    listener: TcpListener,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    scopeguard::defer! {
+        info!("cancellation peer listener has shut down");
+    }
+
+    while let Some(accept_result) =
+        run_until_cancelled(listener.accept(), &cancellation_token).await
+    {
+        let (socket, peer_addr) = accept_result?;
+        let cancel_map = Arc::clone(&cancel_map);
+
+        tokio::spawn(
+            async move {
+                let mut stream = PqStream::new(socket);
+                match stream.read_startup_packet().await? {
+                    FeStartupPacket::CancelRequest(key) => {
+                        NUM_CANCELLATION_REQUESTS
+                            .with_label_values(&["peer", "received"])
+                            .inc();
+                        cancel_map.cancel_local(key).await
+                    }
+                    msg => bail!("unexpected message from a peer proxy: {msg:?}"),
+                }
+            }
+            .instrument(info_span!("forwarded_cancel", %peer_addr))
+            .unwrap_or_else(|e| warn!("failed to serve a forwarded cancellation request: {e:#}")),
+        );
+    }
+
+    Ok(())
+}
+
 /// This should've been a [`std::future::Future`], but
 /// it's impossible to name a type of an unboxed future
 /// (we'd need something like `#![feature(type_alias_impl_trait)]`).
@@ -114,12 +316,40 @@ impl<'a> Session<'a> {
 impl Session<'_> {
     /// Store the cancel token for the given session.
     /// This enables query cancellation in `crate::proxy::prepare_client_connection`.
-    pub fn enable_query_cancellation(self, cancel_closure: CancelClosure) -> CancelKeyData {
+    ///
+    /// The key is published to the other proxies in the background, the
+    /// client doesn't wait for it.
+    pub fn enable_query_cancellation(&self, cancel_closure: CancelClosure) -> CancelKeyData {
         info!("enabling query cancellation for this session");
-        self.cancel_map.0.insert(self.key, Some(cancel_closure));
+        self.set_cancel_closure(Some(cancel_closure));
+
+        if let Some(shared) = &self.cancel_map.shared {
+            let store = Arc::clone(&shared.store);
+            let owner = shared.peer_addr.clone();
+            let key = self.key;
+            // The session works without it, only cancellation through other
+            // proxies doesn't. Should the session end before this finishes,
+            // the key outlives it until it expires, and requests for it are
+            // forwarded to us only to find nothing to cancel.
+            tokio::spawn(
+                async move {
+                    if let Err(e) = store.publish(key, &owner).await {
+                        warn!("failed to publish query cancellation key {key}: {e:#}");
+                    }
+                }
+                .in_current_span(),
+            );
+        }
 
         self.key
     }
+
+    /// Point the session's key at the compute connection it currently uses,
+    /// or at none. The key of a transaction-pooled session moves between
+    /// compute connections, it must not cancel the queries of other clients.
+    pub fn set_cancel_closure(&self, cancel_closure: Option<CancelClosure>) {
+        self.cancel_map.map.insert(self.key, cancel_closure);
+    }
 }
 
 #[cfg(test)]
@@ -156,4 +386,39 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn forward_to_owning_proxy() -> anyhow::Result<()> {
+        let url = crate::redis::tests::spawn_fake_redis().await;
+        let client = redis::Client::new(&url, Duration::from_secs(5))?;
+        let store = Arc::new(RedisCancelKeyStore::new(client, Duration::from_secs(60)));
+
+        // The proxy that owns the session only receives the forwarded request here.
+        let owner = TcpListener::bind("127.0.0.1:0").await?;
+        let owner_addr = owner.local_addr()?.to_string();
+
+        let cancel_map = CancelMap::new(Some(SharedCancelKeys {
+            store: store.clone(),
+            peer_addr: "127.0.0.1:1".to_owned(),
+        }));
+
+        let key: CancelKeyData = rand::random();
+        assert!(cancel_map.cancel_session(key).await.is_err());
+
+        store.publish(key, &owner_addr).await?;
+        assert_eq!(store.lookup(key).await?, Some(owner_addr));
+        cancel_map.cancel_session(key).await?;
+
+        let (socket, _) = owner.accept().await?;
+        let mut stream = PqStream::new(socket);
+        match stream.read_startup_packet().await? {
+            FeStartupPacket::CancelRequest(received) => assert_eq!(received, key),
+            msg => anyhow::bail!("unexpected message {msg:?}"),
+        }
+
+        store.remove(key).await?;
+        assert_eq!(store.lookup(key).await?, None);
+
+        Ok(())
+    }
 }
@@ -1,4 +1,7 @@
-use crate::{auth, rate_limiter::RateBucketInfo, serverless::GlobalConnPoolOptions};
+use crate::{
+    auth, proxy::transaction_pool::TransactionPoolOptions, rate_limiter::RateBucketInfo,
+    serverless::GlobalConnPoolOptions,
+};
 use anyhow::{bail, ensure, Context, Ok};
 use rustls::{sign, Certificate, PrivateKey};
 use sha2::{Digest, Sha256};
@@ -21,6 +24,9 @@ pub struct ProxyConfig {
     pub require_client_ip: bool,
     pub disable_ip_check_for_http: bool,
     pub endpoint_rps_limit: Vec<RateBucketInfo>,
+    /// Pool compute connections of TCP clients per transaction, instead of
+    /// giving each client a connection of its own.
+    pub transaction_pool: Option<TransactionPoolOptions>,
 }
 
 #[derive(Debug)]
@@ -38,10 +44,16 @@ pub struct TlsConfig {
 pub struct HttpConfig {
     pub request_timeout: tokio::time::Duration,
     pub pool_options: GlobalConnPoolOptions,
+    /// How long an interactive transaction may wait for its next request.
+    pub transaction_idle_timeout: tokio::time::Duration,
+    /// How long a streamed response may go without progress.
+    pub stream_idle_timeout: tokio::time::Duration,
 }
 
 pub struct AuthenticationConfig {
     pub scram_protocol_timeout: tokio::time::Duration,
+    /// JWKS of the identity providers trusted by endpoints.
+    pub jwks_cache: auth::backend::JwkCache,
 }
 
 impl TlsConfig {
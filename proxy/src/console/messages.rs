@@ -1,3 +1,4 @@
+use crate::rate_limiter::RateBucketInfo;
 use serde::Deserialize;
 use smol_str::SmolStr;
 use std::fmt;
@@ -15,6 +16,25 @@ pub struct ConsoleError {
 pub struct GetRoleSecret {
     pub role_secret: Box<str>,
     pub allowed_ips: Option<Vec<Box<str>>>,
+    pub allowed_vpc_endpoint_ids: Option<Vec<Box<str>>>,
+    pub limits: Option<EndpointLimitSettings>,
+}
+
+/// Limits of an endpoint and its project.
+/// Rates are lists of `<rps>@<interval>` buckets, like the proxy's own.
+#[derive(Debug, Deserialize)]
+pub struct EndpointLimitSettings {
+    #[serde(flatten)]
+    pub endpoint: LimitSettings,
+    pub project_id: Option<SmolStr>,
+    pub project: Option<LimitSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LimitSettings {
+    pub connection_rate: Option<Vec<RateBucketInfo>>,
+    pub max_connections: Option<u32>,
+    pub http_query_rate: Option<Vec<RateBucketInfo>>,
 }
 
 // Manually implement debug to omit sensitive info.
@@ -24,6 +44,36 @@ impl fmt::Debug for GetRoleSecret {
     }
 }
 
+/// Response which holds the identity providers whose JWTs an endpoint accepts.
+/// Returned by the `/proxy_get_endpoint_jwks` API method.
+#[derive(Debug, Deserialize)]
+pub struct GetEndpointJwks {
+    pub jwks: Vec<JwksSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JwksSettings {
+    pub id: String,
+    pub jwks_url: String,
+    pub jwt_audience: Option<String>,
+    pub role_names: Vec<SmolStr>,
+}
+
+/// Response which holds the password that the proxy logs in to the compute
+/// with as a role, on behalf of clients which authenticated with a JWT.
+/// Returned by the `/proxy_get_role_password` API method.
+#[derive(Deserialize)]
+pub struct GetRolePassword {
+    pub password: Box<str>,
+}
+
+// Manually implement debug to omit sensitive info.
+impl fmt::Debug for GetRolePassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GetRolePassword").finish_non_exhaustive()
+    }
+}
+
 /// Response which holds compute node's `host:port` pair.
 /// Returned by the `/proxy_wake_compute` API method.
 #[derive(Debug, Deserialize)]
@@ -213,6 +263,69 @@ mod tests {
             "allowed_ips": ["8.8.8.8"],
         });
         let _: GetRoleSecret = serde_json::from_str(&json.to_string())?;
+        // With `allowed_vpc_endpoint_ids` field.
+        let json = json!({
+            "role_secret": "secret",
+            "allowed_ips": ["8.8.8.8"],
+            "allowed_vpc_endpoint_ids": ["vpce-0123456789abcdef0"],
+        });
+        let s: GetRoleSecret = serde_json::from_str(&json.to_string())?;
+        assert_eq!(
+            s.allowed_vpc_endpoint_ids.as_deref(),
+            Some(&["vpce-0123456789abcdef0".into()][..])
+        );
+        // With `limits` field.
+        let json = json!({
+            "role_secret": "secret",
+            "limits": {
+                "connection_rate": ["1000@1s", "500@1m"],
+                "max_connections": 100,
+                "project_id": "project",
+                "project": {
+                    "max_connections": 500,
+                    "http_query_rate": ["100@1s"],
+                },
+            },
+        });
+        let s: GetRoleSecret = serde_json::from_str(&json.to_string())?;
+        let limits = s.limits.unwrap();
+        assert_eq!(
+            limits.endpoint.connection_rate,
+            Some(vec!["1000@1s".parse()?, "500@1m".parse()?])
+        );
+        assert_eq!(limits.endpoint.max_connections, Some(100));
+        assert_eq!(limits.endpoint.http_query_rate, None);
+        assert_eq!(limits.project_id.as_deref(), Some("project"));
+        assert_eq!(limits.project.unwrap().max_connections, Some(500));
+        // Invalid rate.
+        let json = json!({
+            "role_secret": "secret",
+            "limits": { "connection_rate": ["fast"] },
+        });
+        assert!(serde_json::from_str::<GetRoleSecret>(&json.to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_get_endpoint_jwks() -> anyhow::Result<()> {
+        let json = json!({
+            "jwks": [
+                {
+                    "id": "idp",
+                    "jwks_url": "https://example.com/.well-known/jwks.json",
+                    "jwt_audience": null,
+                    "role_names": ["anonymous", "authenticated"],
+                },
+            ],
+        });
+        let s: GetEndpointJwks = serde_json::from_str(&json.to_string())?;
+        assert_eq!(s.jwks[0].role_names, ["anonymous", "authenticated"]);
+        assert_eq!(s.jwks[0].jwt_audience, None);
+
+        // No identity providers.
+        let json = json!({ "jwks": [] });
+        let _: GetEndpointJwks = serde_json::from_str(&json.to_string())?;
 
         Ok(())
     }
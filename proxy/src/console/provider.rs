@@ -1,12 +1,15 @@
+pub mod file;
 #[cfg(feature = "testing")]
 pub mod mock;
 pub mod neon;
 
 use super::messages::MetricsAuxInfo;
 use crate::{
-    auth::backend::ComputeUserInfo,
+    auth::backend::{AuthRule, ComputeUserInfo},
     cache::{timed_lru, TimedLru},
-    compute, scram,
+    compute,
+    rate_limiter::EndpointLimits,
+    scram,
 };
 use async_trait::async_trait;
 use dashmap::DashMap;
@@ -128,6 +131,9 @@ pub mod errors {
         #[error("Console responded with a malformed auth secret")]
         BadSecret,
 
+        #[error("Console has no password to log in to the compute as this role")]
+        NoRolePassword,
+
         #[error(transparent)]
         ApiError(ApiError),
     }
@@ -145,6 +151,7 @@ pub mod errors {
             match self {
                 // We absolutely should not leak any secrets!
                 BadSecret => REQUEST_FAILED.to_owned(),
+                NoRolePassword => REQUEST_FAILED.to_owned(),
                 // However, API might return a meaningful error.
                 ApiError(e) => e.to_string_client(),
             }
@@ -232,6 +239,10 @@ pub struct AuthInfo {
     pub secret: Option<AuthSecret>,
     /// List of IP addresses allowed for the autorization.
     pub allowed_ips: Vec<String>,
+    /// List of VPC endpoint (private link) ids allowed for the authorization.
+    pub allowed_vpc_endpoint_ids: Vec<String>,
+    /// Limits of the endpoint and its project.
+    pub limits: EndpointLimits,
 }
 
 /// Info for establishing a connection to a compute node.
@@ -253,6 +264,9 @@ pub struct NodeInfo {
 pub type NodeInfoCache = TimedLru<Arc<str>, NodeInfo>;
 pub type CachedNodeInfo = timed_lru::Cached<&'static NodeInfoCache>;
 pub type AllowedIpsCache = TimedLru<SmolStr, Arc<Vec<String>>>;
+pub type AllowedVpcEndpointIdsCache = TimedLru<SmolStr, Arc<Vec<String>>>;
+pub type EndpointJwksCache = TimedLru<SmolStr, Arc<Vec<AuthRule>>>;
+pub type EndpointLimitsCache = TimedLru<SmolStr, Arc<EndpointLimits>>;
 pub type RoleSecretCache = TimedLru<(SmolStr, SmolStr), Option<AuthSecret>>;
 pub type CachedRoleSecret = timed_lru::Cached<&'static RoleSecretCache>;
 
@@ -273,6 +287,38 @@ pub trait Api {
         creds: &ComputeUserInfo,
     ) -> Result<Arc<Vec<String>>, errors::GetAuthInfoError>;
 
+    /// Get the VPC endpoint ids the endpoint may be reached through.
+    /// An empty list means that private links are not enforced.
+    async fn get_allowed_vpc_endpoint_ids(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<String>>, errors::GetAuthInfoError>;
+
+    /// Get the identity providers whose JWTs the endpoint accepts.
+    /// An empty list means that JWT authentication is disabled.
+    async fn get_endpoint_jwks(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<AuthRule>>, errors::GetAuthInfoError>;
+
+    /// Get the password to log in to the compute as the role, once the client
+    /// has authenticated with a JWT. The compute doesn't know about the
+    /// identity providers of the endpoint, so the token can't be passed on.
+    async fn get_role_password(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<SmolStr, errors::GetAuthInfoError>;
+
+    /// Get the limits of the endpoint and its project.
+    async fn get_endpoint_limits(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<EndpointLimits>, errors::GetAuthInfoError>;
+
     /// Wake up the compute node and return the corresponding connection info.
     async fn wake_compute(
         &self,
@@ -287,6 +333,12 @@ pub struct ApiCaches {
     pub node_info: NodeInfoCache,
     /// Cache for the `get_allowed_ips`. TODO(anna): use notifications listener instead.
     pub allowed_ips: AllowedIpsCache,
+    /// Cache for the `get_allowed_vpc_endpoint_ids`.
+    pub allowed_vpc_endpoint_ids: AllowedVpcEndpointIdsCache,
+    /// Cache for the `get_endpoint_jwks`.
+    pub endpoint_jwks: EndpointJwksCache,
+    /// Cache for the `get_endpoint_limits`.
+    pub endpoint_limits: EndpointLimitsCache,
     /// Cache for the `get_role_secret`. TODO(anna): use notifications listener instead.
     pub role_secret: RoleSecretCache,
 }
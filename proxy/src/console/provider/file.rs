@@ -0,0 +1,576 @@
+//! Console backend which serves endpoints from a local config file, for
+//! self-hosted proxies which run without a control plane.
+//!
+//! The file is TOML, or JSON if its name ends with `.json`:
+//!
+//! ```toml
+//! [[endpoints]]
+//! id = "ep-example-123456"
+//! compute = "compute-1.internal:5432"
+//! # optional: "disable", "prefer" (the default) or "require"
+//! ssl_mode = "require"
+//! # optional, an empty list allows everyone
+//! allowed_ips = ["10.0.0.0/8", "192.168.1.10", "192.168.2.1-192.168.2.99"]
+//!
+//! [[endpoints.roles]]
+//! name = "alice"
+//! secret = "SCRAM-SHA-256$4096:<salt>$<stored key>:<server key>"
+//! ```
+//!
+//! The role secrets are in the format of `pg_authid.rolpassword`, so that
+//! the proxy and the compute agree on the passwords. The file is read again
+//! whenever it changes and when the proxy receives SIGHUP. If the new version
+//! is invalid, the proxy keeps serving the previous one.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{
+    errors::{ApiError, GetAuthInfoError, WakeComputeError},
+    neon::parse_host_port,
+    AuthSecret, CachedNodeInfo, CachedRoleSecret, ConsoleReqExtra, NodeInfo,
+};
+use crate::{
+    auth::{
+        backend::{AuthRule, ComputeUserInfo},
+        is_valid_ip_pattern,
+    },
+    compute,
+    console::messages::MetricsAuxInfo,
+    http::StatusCode,
+    rate_limiter::EndpointLimits,
+    scram,
+};
+use async_trait::async_trait;
+use notify::{RecursiveMode, Watcher};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use smol_str::SmolStr;
+use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_postgres::config::SslMode;
+use tracing::{error, info, warn};
+
+/// Editors and config management tools tend to write a file in several steps,
+/// so we wait for the changes to settle before reading it.
+const RELOAD_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("failed to parse {}: {message}", .path.display())]
+    Parse { path: PathBuf, message: String },
+
+    #[error("endpoint {0} is listed more than once")]
+    DuplicateEndpoint(SmolStr),
+
+    #[error("endpoint {endpoint} has a malformed compute address: {address}")]
+    BadComputeAddress { endpoint: SmolStr, address: String },
+
+    #[error("endpoint {endpoint} has a malformed allowed IP: {ip}")]
+    BadAllowedIp { endpoint: SmolStr, ip: String },
+
+    #[error("role {role} of endpoint {endpoint} is listed more than once")]
+    DuplicateRole { endpoint: SmolStr, role: SmolStr },
+
+    // We shouldn't include the actual secret here.
+    #[error("role {role} of endpoint {endpoint} has a malformed SCRAM secret")]
+    BadSecret { endpoint: SmolStr, role: SmolStr },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    endpoints: Vec<EndpointConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EndpointConfig {
+    id: SmolStr,
+    /// Address of the compute, as `host:port`.
+    compute: String,
+    #[serde(default)]
+    ssl_mode: ComputeSslMode,
+    /// Labels for proxy's metrics, the endpoint id if not set.
+    project_id: Option<SmolStr>,
+    branch_id: Option<SmolStr>,
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+    #[serde(default)]
+    allowed_vpc_endpoint_ids: Vec<String>,
+    #[serde(default)]
+    roles: Vec<RoleConfig>,
+}
+
+/// Whether the connections to the compute use TLS, like libpq's `sslmode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ComputeSslMode {
+    Disable,
+    #[default]
+    Prefer,
+    Require,
+}
+
+impl From<ComputeSslMode> for SslMode {
+    fn from(mode: ComputeSslMode) -> Self {
+        match mode {
+            ComputeSslMode::Disable => SslMode::Disable,
+            ComputeSslMode::Prefer => SslMode::Prefer,
+            ComputeSslMode::Require => SslMode::Require,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleConfig {
+    name: SmolStr,
+    secret: String,
+}
+
+struct Endpoint {
+    host: String,
+    port: u16,
+    ssl_mode: SslMode,
+    aux: MetricsAuxInfo,
+    allowed_ips: Arc<Vec<String>>,
+    allowed_vpc_endpoint_ids: Arc<Vec<String>>,
+    roles: HashMap<SmolStr, AuthSecret>,
+}
+
+/// Validated contents of the config file.
+struct Endpoints(HashMap<SmolStr, Endpoint>);
+
+impl Endpoints {
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        let parse_error = |message: String| ConfigError::Parse {
+            path: path.to_owned(),
+            message,
+        };
+        let file: ConfigFile = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents).map_err(|e| parse_error(e.to_string()))?
+        } else {
+            toml::from_str(&contents).map_err(|e| parse_error(e.to_string()))?
+        };
+        Self::from_config(file)
+    }
+
+    fn from_config(file: ConfigFile) -> Result<Self, ConfigError> {
+        let mut endpoints = HashMap::with_capacity(file.endpoints.len());
+        for endpoint in file.endpoints {
+            let id = endpoint.id;
+            let Some((host, port)) = parse_host_port(&endpoint.compute) else {
+                return Err(ConfigError::BadComputeAddress {
+                    endpoint: id,
+                    address: endpoint.compute,
+                });
+            };
+
+            if let Some(ip) = endpoint
+                .allowed_ips
+                .iter()
+                .find(|ip| !is_valid_ip_pattern(ip))
+            {
+                return Err(ConfigError::BadAllowedIp {
+                    endpoint: id,
+                    ip: ip.clone(),
+                });
+            }
+
+            let mut roles = HashMap::with_capacity(endpoint.roles.len());
+            for role in endpoint.roles {
+                let Some(secret) = scram::ServerSecret::parse(&role.secret) else {
+                    return Err(ConfigError::BadSecret {
+                        endpoint: id,
+                        role: role.name,
+                    });
+                };
+                if roles
+                    .insert(role.name.clone(), AuthSecret::Scram(secret))
+                    .is_some()
+                {
+                    return Err(ConfigError::DuplicateRole {
+                        endpoint: id,
+                        role: role.name,
+                    });
+                }
+            }
+
+            let parsed = Endpoint {
+                host: host.to_owned(),
+                port,
+                ssl_mode: endpoint.ssl_mode.into(),
+                aux: MetricsAuxInfo {
+                    endpoint_id: id.clone(),
+                    project_id: endpoint.project_id.unwrap_or_else(|| id.clone()),
+                    branch_id: endpoint.branch_id.unwrap_or_else(|| id.clone()),
+                },
+                allowed_ips: Arc::new(endpoint.allowed_ips),
+                allowed_vpc_endpoint_ids: Arc::new(endpoint.allowed_vpc_endpoint_ids),
+                roles,
+            };
+            if endpoints.insert(id.clone(), parsed).is_some() {
+                return Err(ConfigError::DuplicateEndpoint(id));
+            }
+        }
+        Ok(Self(endpoints))
+    }
+}
+
+#[derive(Clone)]
+pub struct Api {
+    path: Arc<Path>,
+    endpoints: Arc<RwLock<Arc<Endpoints>>>,
+}
+
+impl Api {
+    /// Load the config file. Unlike reloads, this fails if it's invalid.
+    pub fn new(path: PathBuf) -> Result<Self, ConfigError> {
+        let endpoints = Endpoints::load(&path)?;
+        info!(
+            path = %path.display(),
+            endpoints = endpoints.0.len(),
+            "loaded the auth config file"
+        );
+        Ok(Self {
+            path: path.into(),
+            endpoints: Arc::new(RwLock::new(Arc::new(endpoints))),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the config file again. The previous version stays in use if the
+    /// new one is invalid.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let endpoints = Endpoints::load(&self.path)?;
+        info!(
+            path = %self.path.display(),
+            endpoints = endpoints.0.len(),
+            "reloaded the auth config file"
+        );
+        *self.endpoints.write() = Arc::new(endpoints);
+        Ok(())
+    }
+
+    /// Reload the config file whenever it changes or we receive SIGHUP.
+    pub async fn reload_worker(self) {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        // Watch the directory rather than the file itself: the file is often
+        // replaced by a rename, which would end a watch on the old file.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let _ = tx.send(res);
+        })
+        .and_then(|mut watcher| {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        // The watcher stops once it's dropped.
+        let watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!(
+                    "failed to watch the auth config file, it will only be reloaded on SIGHUP: {e}"
+                );
+                None
+            }
+        };
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                warn!("failed to listen for SIGHUP, the auth config file will only be reloaded on changes: {e}");
+                None
+            }
+        };
+
+        let mut watching = watcher.is_some();
+        loop {
+            tokio::select! {
+                Some(()) = async { hangup.as_mut()?.recv().await }, if hangup.is_some() => {
+                    info!("received SIGHUP, reloading the auth config file");
+                }
+                event = rx.recv(), if watching => match event {
+                    Some(Ok(event)) => {
+                        let file_name = self.path.file_name();
+                        if !event.paths.iter().any(|p| p.file_name() == file_name) {
+                            continue;
+                        }
+                        tokio::time::sleep(RELOAD_DELAY).await;
+                        // The file is read once for all the changes so far.
+                        while rx.try_recv().is_ok() {}
+                    }
+                    Some(Err(e)) => {
+                        warn!("error while watching the auth config file: {e}");
+                        continue;
+                    }
+                    None => {
+                        warn!("stopped watching the auth config file");
+                        watching = false;
+                        continue;
+                    }
+                },
+                else => return,
+            }
+
+            if let Err(e) = self.reload() {
+                error!("failed to reload the auth config file, keeping the previous version: {e}");
+            }
+        }
+    }
+
+    fn endpoints(&self) -> Arc<Endpoints> {
+        Arc::clone(&self.endpoints.read())
+    }
+}
+
+fn endpoint_not_found(endpoint: &str) -> ApiError {
+    // Mirrors the response of the console, which users get to see.
+    ApiError::Console {
+        status: StatusCode::NOT_FOUND,
+        text: format!("endpoint {endpoint} is not in the auth config file").into(),
+    }
+}
+
+#[async_trait]
+impl super::Api for Api {
+    async fn get_role_secret(
+        &self,
+        _extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<CachedRoleSecret, GetAuthInfoError> {
+        let endpoints = self.endpoints();
+        let secret = endpoints
+            .0
+            .get(&creds.endpoint)
+            .and_then(|endpoint| endpoint.roles.get(&creds.inner.user))
+            .cloned();
+        Ok(CachedRoleSecret::new_uncached(secret))
+    }
+
+    async fn get_allowed_ips(
+        &self,
+        _extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+        let endpoints = self.endpoints();
+        let endpoint = endpoints.0.get(&creds.endpoint);
+        Ok(endpoint.map_or_else(Default::default, |e| Arc::clone(&e.allowed_ips)))
+    }
+
+    async fn get_allowed_vpc_endpoint_ids(
+        &self,
+        _extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+        let endpoints = self.endpoints();
+        let endpoint = endpoints.0.get(&creds.endpoint);
+        Ok(endpoint.map_or_else(Default::default, |e| {
+            Arc::clone(&e.allowed_vpc_endpoint_ids)
+        }))
+    }
+
+    async fn get_endpoint_jwks(
+        &self,
+        _extra: &ConsoleReqExtra,
+        _creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<AuthRule>>, GetAuthInfoError> {
+        Ok(Arc::new(vec![]))
+    }
+
+    async fn get_role_password(
+        &self,
+        _extra: &ConsoleReqExtra,
+        _creds: &ComputeUserInfo,
+    ) -> Result<SmolStr, GetAuthInfoError> {
+        // No identity providers are trusted, see `get_endpoint_jwks`.
+        Err(GetAuthInfoError::NoRolePassword)
+    }
+
+    async fn get_endpoint_limits(
+        &self,
+        _extra: &ConsoleReqExtra,
+        _creds: &ComputeUserInfo,
+    ) -> Result<Arc<EndpointLimits>, GetAuthInfoError> {
+        Ok(Arc::new(EndpointLimits::default()))
+    }
+
+    async fn wake_compute(
+        &self,
+        _extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<CachedNodeInfo, WakeComputeError> {
+        let endpoints = self.endpoints();
+        let Some(endpoint) = endpoints.0.get(&creds.endpoint) else {
+            return Err(endpoint_not_found(&creds.endpoint).into());
+        };
+
+        let mut config = compute::ConnCfg::new();
+        config
+            .host(&endpoint.host)
+            .port(endpoint.port)
+            .ssl_mode(endpoint.ssl_mode);
+
+        Ok(CachedNodeInfo::new_uncached(NodeInfo {
+            config,
+            aux: endpoint.aux.clone(),
+            allow_self_signed_compute: false,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "SCRAM-SHA-256$4096:XiWDEU0b7GGCRzhe8JIpzg==$pB2bt1BTB5CeftAoC1R6Gw2o+7w6PYP8uFFNrkhi0N8=:i8Hb15fnmfgxJHG+7B3JHc6NbL+g3tRWdFGFJjJkZFw=";
+
+    fn parse_toml(input: &str) -> Result<Endpoints, ConfigError> {
+        Endpoints::from_config(toml::from_str(input).unwrap())
+    }
+
+    #[test]
+    fn parse_config() {
+        let endpoints = parse_toml(&format!(
+            r#"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "[2001:db8::1]:5433"
+            ssl_mode = "require"
+            allowed_ips = ["10.0.0.0/8"]
+
+            [[endpoints.roles]]
+            name = "alice"
+            secret = "{SECRET}"
+
+            [[endpoints]]
+            id = "ep-bar"
+            compute = "compute-bar:5432"
+            project_id = "bar-project"
+            "#
+        ))
+        .unwrap();
+
+        let foo = &endpoints.0["ep-foo"];
+        assert_eq!((foo.host.as_str(), foo.port), ("2001:db8::1", 5433));
+        assert_eq!(foo.ssl_mode, SslMode::Require);
+        assert_eq!(*foo.allowed_ips, ["10.0.0.0/8"]);
+        assert!(matches!(foo.roles["alice"], AuthSecret::Scram(_)));
+        assert_eq!(foo.aux.project_id, "ep-foo");
+
+        let bar = &endpoints.0["ep-bar"];
+        assert!(bar.roles.is_empty() && bar.allowed_ips.is_empty());
+        assert_eq!(bar.ssl_mode, SslMode::Prefer);
+        assert_eq!(bar.aux.project_id, "bar-project");
+        assert_eq!(bar.aux.branch_id, "ep-bar");
+    }
+
+    #[test]
+    fn parse_json_config() {
+        let file: ConfigFile = serde_json::from_value(serde_json::json!({
+            "endpoints": [{
+                "id": "ep-foo",
+                "compute": "localhost:5432",
+                "roles": [{ "name": "alice", "secret": SECRET }],
+            }]
+        }))
+        .unwrap();
+        let endpoints = Endpoints::from_config(file).unwrap();
+        assert!(endpoints.0["ep-foo"].roles.contains_key("alice"));
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let err = parse_toml(
+            r#"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "localhost"
+            "#,
+        );
+        assert!(matches!(err, Err(ConfigError::BadComputeAddress { .. })));
+
+        let err = parse_toml(
+            r#"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "localhost:5432"
+            allowed_ips = ["10.0.0.1", "10.0.0.0/33"]
+            "#,
+        );
+        assert!(matches!(err, Err(ConfigError::BadAllowedIp { ip, .. }) if ip == "10.0.0.0/33"));
+
+        let err = parse_toml(
+            r#"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "localhost:5432"
+            roles = [{ name = "alice", secret = "md5abcdef" }]
+            "#,
+        );
+        assert!(matches!(err, Err(ConfigError::BadSecret { .. })));
+
+        let err = parse_toml(&format!(
+            r#"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "localhost:5432"
+            roles = [{{ name = "alice", secret = "{SECRET}" }}, {{ name = "alice", secret = "{SECRET}" }}]
+            "#
+        ));
+        assert!(matches!(err, Err(ConfigError::DuplicateRole { .. })));
+
+        let err = parse_toml(
+            r#"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "localhost:5432"
+            [[endpoints]]
+            id = "ep-foo"
+            compute = "localhost:5433"
+            "#,
+        );
+        assert!(matches!(err, Err(ConfigError::DuplicateEndpoint(_))));
+    }
+
+    #[test]
+    fn reload_keeps_valid_config() {
+        let dir = camino_tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.toml").into_std_path_buf();
+        let config =
+            |address: &str| format!("[[endpoints]]\nid = \"ep-foo\"\ncompute = \"{address}\"\n");
+
+        std::fs::write(&path, config("localhost:5432")).unwrap();
+        let api = Api::new(path.clone()).unwrap();
+        assert_eq!(api.endpoints().0["ep-foo"].port, 5432);
+
+        std::fs::write(&path, config("localhost:5433")).unwrap();
+        api.reload().unwrap();
+        assert_eq!(api.endpoints().0["ep-foo"].port, 5433);
+
+        std::fs::write(&path, config("localhost")).unwrap();
+        assert!(api.reload().is_err());
+        assert_eq!(api.endpoints().0["ep-foo"].port, 5433);
+    }
+}
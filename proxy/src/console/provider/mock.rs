@@ -7,9 +7,17 @@ use super::{
     AuthInfo, AuthSecret, CachedNodeInfo, ConsoleReqExtra, NodeInfo,
 };
 use crate::console::provider::CachedRoleSecret;
-use crate::{auth::backend::ComputeUserInfo, compute, error::io_error, scram, url::ApiUrl};
+use crate::{
+    auth::backend::{AuthRule, ComputeUserInfo},
+    compute,
+    error::io_error,
+    rate_limiter::EndpointLimits,
+    scram,
+    url::ApiUrl,
+};
 use async_trait::async_trait;
 use futures::TryFutureExt;
+use smol_str::SmolStr;
 use thiserror::Error;
 use tokio_postgres::{config::SslMode, Client};
 use tracing::{error, info, info_span, warn, Instrument};
@@ -50,7 +58,7 @@ impl Api {
         &self,
         creds: &ComputeUserInfo,
     ) -> Result<AuthInfo, GetAuthInfoError> {
-        let (secret, allowed_ips) = async {
+        let (secret, allowed_ips, allowed_vpc_endpoint_ids) = async {
             // Perhaps we could persist this connection, but then we'd have to
             // write more code for reopening it if it got closed, which doesn't
             // seem worth it.
@@ -90,8 +98,25 @@ impl Api {
                 }
                 None => vec![],
             };
+            let allowed_vpc_endpoint_ids = match get_execute_postgres_query(
+                &client,
+                "select coalesce(allowed_vpc_endpoint_ids, '') as allowed_vpc_endpoint_ids from neon_control_plane.endpoints where endpoint_id = $1",
+                &[&creds.endpoint.as_str()],
+                "allowed_vpc_endpoint_ids",
+            )
+            .await?
+            {
+                Some(s) => {
+                    info!("got allowed_vpc_endpoint_ids: {s}");
+                    s.split(',')
+                        .filter(|id| !id.is_empty())
+                        .map(String::from)
+                        .collect()
+                }
+                None => vec![],
+            };
 
-            Ok((secret, allowed_ips))
+            Ok((secret, allowed_ips, allowed_vpc_endpoint_ids))
         }
         .map_err(crate::error::log_error::<GetAuthInfoError>)
         .instrument(info_span!("postgres", url = self.endpoint.as_str()))
@@ -99,6 +124,8 @@ impl Api {
         Ok(AuthInfo {
             secret,
             allowed_ips,
+            allowed_vpc_endpoint_ids,
+            limits: EndpointLimits::default(),
         })
     }
 
@@ -161,6 +188,41 @@ impl super::Api for Api {
         Ok(Arc::new(self.do_get_auth_info(creds).await?.allowed_ips))
     }
 
+    async fn get_allowed_vpc_endpoint_ids(
+        &self,
+        _extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+        Ok(Arc::new(
+            self.do_get_auth_info(creds).await?.allowed_vpc_endpoint_ids,
+        ))
+    }
+
+    async fn get_endpoint_jwks(
+        &self,
+        _extra: &ConsoleReqExtra,
+        _creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<AuthRule>>, GetAuthInfoError> {
+        Ok(Arc::new(vec![]))
+    }
+
+    async fn get_role_password(
+        &self,
+        _extra: &ConsoleReqExtra,
+        _creds: &ComputeUserInfo,
+    ) -> Result<SmolStr, GetAuthInfoError> {
+        // No identity providers are trusted, see `get_endpoint_jwks`.
+        Err(GetAuthInfoError::NoRolePassword)
+    }
+
+    async fn get_endpoint_limits(
+        &self,
+        _extra: &ConsoleReqExtra,
+        _creds: &ComputeUserInfo,
+    ) -> Result<Arc<EndpointLimits>, GetAuthInfoError> {
+        Ok(Arc::new(EndpointLimits::default()))
+    }
+
     #[tracing::instrument(skip_all)]
     async fn wake_compute(
         &self,
@@ -1,16 +1,25 @@
 //! Production console backend.
 
 use super::{
-    super::messages::{ConsoleError, GetRoleSecret, WakeCompute},
+    super::messages::{
+        ConsoleError, EndpointLimitSettings, GetEndpointJwks, GetRolePassword, GetRoleSecret,
+        LimitSettings, WakeCompute,
+    },
     errors::{ApiError, GetAuthInfoError, WakeComputeError},
     ApiCaches, ApiLocks, AuthInfo, AuthSecret, CachedNodeInfo, CachedRoleSecret, ConsoleReqExtra,
     NodeInfo,
 };
 use crate::metrics::{ALLOWED_IPS_BY_CACHE_OUTCOME, ALLOWED_IPS_NUMBER};
-use crate::{auth::backend::ComputeUserInfo, compute, http, scram};
+use crate::{
+    auth::backend::{AuthRule, ComputeUserInfo},
+    compute, http,
+    rate_limiter::{EndpointLimits, Limits, RateBucketInfo},
+    scram,
+};
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use itertools::Itertools;
+use smol_str::SmolStr;
 use std::sync::Arc;
 use tokio::time::Instant;
 use tokio_postgres::config::SslMode;
@@ -90,9 +99,17 @@ impl Api {
                 .map(String::from)
                 .collect_vec();
             ALLOWED_IPS_NUMBER.observe(allowed_ips.len() as f64);
+            let allowed_vpc_endpoint_ids = body
+                .allowed_vpc_endpoint_ids
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect_vec();
             Ok(AuthInfo {
                 secret: Some(secret),
                 allowed_ips,
+                allowed_vpc_endpoint_ids,
+                limits: body.limits.map(endpoint_limits).unwrap_or_default(),
             })
         }
         .map_err(crate::error::log_error)
@@ -100,6 +117,97 @@ impl Api {
         .await
     }
 
+    async fn do_get_endpoint_jwks(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Vec<AuthRule>, GetAuthInfoError> {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        async {
+            let request = self
+                .endpoint
+                .get("proxy_get_endpoint_jwks")
+                .header("X-Request-ID", &request_id)
+                .header("Authorization", format!("Bearer {}", &self.jwt))
+                .query(&[("session_id", extra.session_id)])
+                .query(&[
+                    ("application_name", extra.application_name.as_str()),
+                    ("project", creds.endpoint.as_str()),
+                ])
+                .build()?;
+
+            info!(url = request.url().as_str(), "sending http request");
+            let start = Instant::now();
+            let response = self.endpoint.execute(request).await?;
+            info!(duration = ?start.elapsed(), "received http response");
+            let body = match parse_body::<GetEndpointJwks>(response).await {
+                Ok(body) => body,
+                // Error 404 is special: the endpoint doesn't trust any identity providers.
+                Err(e) => match e.http_status_code() {
+                    Some(http::StatusCode::NOT_FOUND) => return Ok(vec![]),
+                    _otherwise => return Err(e.into()),
+                },
+            };
+
+            let rules = body
+                .jwks
+                .into_iter()
+                .map(|s| AuthRule {
+                    id: s.id,
+                    jwks_url: s.jwks_url,
+                    audience: s.jwt_audience,
+                    role_names: s.role_names,
+                })
+                .collect();
+            Ok(rules)
+        }
+        .map_err(crate::error::log_error)
+        .instrument(info_span!("http", id = request_id))
+        .await
+    }
+
+    async fn do_get_role_password(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<SmolStr, GetAuthInfoError> {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        async {
+            let request = self
+                .endpoint
+                .get("proxy_get_role_password")
+                .header("X-Request-ID", &request_id)
+                .header("Authorization", format!("Bearer {}", &self.jwt))
+                .query(&[("session_id", extra.session_id)])
+                .query(&[
+                    ("application_name", extra.application_name.as_str()),
+                    ("project", creds.endpoint.as_str()),
+                    ("role", creds.inner.user.as_str()),
+                ])
+                .build()?;
+
+            info!(url = request.url().as_str(), "sending http request");
+            let start = Instant::now();
+            let response = self.endpoint.execute(request).await?;
+            info!(duration = ?start.elapsed(), "received http response");
+            let body = match parse_body::<GetRolePassword>(response).await {
+                Ok(body) => body,
+                // Error 404 is special: the role can't be logged in to on behalf of JWTs.
+                Err(e) => match e.http_status_code() {
+                    Some(http::StatusCode::NOT_FOUND) => {
+                        return Err(GetAuthInfoError::NoRolePassword)
+                    }
+                    _otherwise => return Err(e.into()),
+                },
+            };
+
+            Ok(SmolStr::from(&*body.password))
+        }
+        .map_err(crate::error::log_error)
+        .instrument(info_span!("http", id = request_id))
+        .await
+    }
+
     async fn do_wake_compute(
         &self,
         extra: &ConsoleReqExtra,
@@ -177,7 +285,13 @@ impl super::Api for Api {
             .insert((ep.clone(), user), auth_info.secret.clone());
         self.caches
             .allowed_ips
-            .insert(ep, Arc::new(auth_info.allowed_ips));
+            .insert(ep.clone(), Arc::new(auth_info.allowed_ips));
+        self.caches
+            .allowed_vpc_endpoint_ids
+            .insert(ep.clone(), Arc::new(auth_info.allowed_vpc_endpoint_ids));
+        self.caches
+            .endpoint_limits
+            .insert(ep, Arc::new(auth_info.limits));
         Ok(secret)
     }
 
@@ -202,10 +316,93 @@ impl super::Api for Api {
         self.caches
             .role_secret
             .insert((ep.clone(), user), auth_info.secret);
+        self.caches
+            .allowed_vpc_endpoint_ids
+            .insert(ep.clone(), Arc::new(auth_info.allowed_vpc_endpoint_ids));
+        self.caches
+            .endpoint_limits
+            .insert(ep.clone(), Arc::new(auth_info.limits));
         self.caches.allowed_ips.insert(ep, allowed_ips.clone());
         Ok(allowed_ips)
     }
 
+    async fn get_allowed_vpc_endpoint_ids(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+        if let Some(ids) = self.caches.allowed_vpc_endpoint_ids.get(&creds.endpoint) {
+            return Ok(Arc::new(ids.to_vec()));
+        }
+        let auth_info = self.do_get_auth_info(extra, creds).await?;
+        let allowed_vpc_endpoint_ids = Arc::new(auth_info.allowed_vpc_endpoint_ids);
+        let ep = creds.endpoint.clone();
+        let user = creds.inner.user.clone();
+        self.caches
+            .role_secret
+            .insert((ep.clone(), user), auth_info.secret);
+        self.caches
+            .allowed_ips
+            .insert(ep.clone(), Arc::new(auth_info.allowed_ips));
+        self.caches
+            .endpoint_limits
+            .insert(ep.clone(), Arc::new(auth_info.limits));
+        self.caches
+            .allowed_vpc_endpoint_ids
+            .insert(ep, allowed_vpc_endpoint_ids.clone());
+        Ok(allowed_vpc_endpoint_ids)
+    }
+
+    async fn get_endpoint_limits(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<EndpointLimits>, GetAuthInfoError> {
+        if let Some(limits) = self.caches.endpoint_limits.get(&creds.endpoint) {
+            return Ok(Arc::clone(&*limits));
+        }
+        let auth_info = self.do_get_auth_info(extra, creds).await?;
+        let limits = Arc::new(auth_info.limits);
+        let ep = creds.endpoint.clone();
+        let user = creds.inner.user.clone();
+        self.caches
+            .role_secret
+            .insert((ep.clone(), user), auth_info.secret);
+        self.caches
+            .allowed_ips
+            .insert(ep.clone(), Arc::new(auth_info.allowed_ips));
+        self.caches
+            .allowed_vpc_endpoint_ids
+            .insert(ep.clone(), Arc::new(auth_info.allowed_vpc_endpoint_ids));
+        self.caches.endpoint_limits.insert(ep, limits.clone());
+        Ok(limits)
+    }
+
+    async fn get_endpoint_jwks(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<Arc<Vec<AuthRule>>, GetAuthInfoError> {
+        if let Some(rules) = self.caches.endpoint_jwks.get(&creds.endpoint) {
+            return Ok(Arc::new(rules.to_vec()));
+        }
+        let rules = Arc::new(self.do_get_endpoint_jwks(extra, creds).await?);
+        self.caches
+            .endpoint_jwks
+            .insert(creds.endpoint.clone(), rules.clone());
+        Ok(rules)
+    }
+
+    // Only asked for after a JWT is verified, so it's not cached: the console
+    // may issue short-lived passwords.
+    async fn get_role_password(
+        &self,
+        extra: &ConsoleReqExtra,
+        creds: &ComputeUserInfo,
+    ) -> Result<SmolStr, GetAuthInfoError> {
+        self.do_get_role_password(extra, creds).await
+    }
+
     #[tracing::instrument(skip_all)]
     async fn wake_compute(
         &self,
@@ -244,6 +441,37 @@ impl super::Api for Api {
     }
 }
 
+fn endpoint_limits(settings: EndpointLimitSettings) -> EndpointLimits {
+    let project = match (settings.project_id, settings.project) {
+        (Some(project_id), Some(project)) => Some((project_id, limits(project))),
+        _ => None,
+    };
+    EndpointLimits {
+        endpoint: limits(settings.endpoint),
+        project,
+    }
+}
+
+fn limits(settings: LimitSettings) -> Limits {
+    Limits {
+        connection_rate: rate_limit(settings.connection_rate),
+        max_connections: settings.max_connections,
+        http_query_rate: rate_limit(settings.http_query_rate),
+    }
+}
+
+fn rate_limit(rate: Option<Vec<RateBucketInfo>>) -> Option<Vec<RateBucketInfo>> {
+    let mut rate = rate?;
+    match RateBucketInfo::validate(&mut rate) {
+        Ok(()) => Some(rate),
+        Err(e) => {
+            // fall back to the defaults rather than failing the connection
+            warn!("ignoring rate limit from the console: {e}");
+            None
+        }
+    }
+}
+
 /// Parse http response body, taking status code into account.
 async fn parse_body<T: for<'a> serde::Deserialize<'a>>(
     response: http::Response,
@@ -269,7 +497,7 @@ async fn parse_body<T: for<'a> serde::Deserialize<'a>>(
     Err(ApiError::Console { status, text })
 }
 
-fn parse_host_port(input: &str) -> Option<(&str, u16)> {
+pub(super) fn parse_host_port(input: &str) -> Option<(&str, u16)> {
     let (host, port) = input.rsplit_once(':')?;
     let ipv6_brackets: &[_] = &['[', ']'];
     Some((host.trim_matches(ipv6_brackets), port.parse().ok()?))
@@ -5,7 +5,7 @@ use std::convert::Infallible;
 use anyhow::{bail, Context};
 use tokio::task::JoinError;
 use tokio_util::sync::CancellationToken;
-use tracing::warn;
+use tracing::{info, warn};
 
 pub mod auth;
 pub mod cache;
@@ -21,6 +21,7 @@ pub mod parse;
 pub mod protocol2;
 pub mod proxy;
 pub mod rate_limiter;
+pub mod redis;
 pub mod sasl;
 pub mod scram;
 pub mod serverless;
@@ -39,9 +40,10 @@ pub async fn handle_signals(token: CancellationToken) -> anyhow::Result<Infallib
 
     loop {
         tokio::select! {
-            // Hangup is commonly used for config reload.
+            // Hangup is commonly used for config reload. Only the `file` auth
+            // backend supports it, and listens for it on its own.
             _ = hangup.recv() => {
-                warn!("received SIGHUP; config reload is not supported");
+                info!("received SIGHUP");
             }
             // Shut down the whole application.
             _ = interrupt.recv() => {
@@ -223,6 +223,24 @@ pub static NUM_BYTES_PROXIED_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
     .unwrap()
 });
 
+pub static NUM_CANCELLATION_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_cancellation_requests_total",
+        "Number of query cancellation requests (per source and outcome).",
+        &["source", "kind"],
+    )
+    .unwrap()
+});
+
+pub static NUM_TCP_POOL_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_tcp_pool_borrowed_connections_total",
+        "Number of compute connections borrowed by transaction pooled clients (per kind: reused or new).",
+        &["kind"],
+    )
+    .unwrap()
+});
+
 pub const fn bool_to_str(x: bool) -> &'static str {
     if x {
         "true"
@@ -12,6 +12,7 @@ use std::{
 use bytes::{Buf, BytesMut};
 use hyper::server::conn::{AddrIncoming, AddrStream};
 use pin_project_lite::pin_project;
+use smol_str::SmolStr;
 use tls_listener::AsyncAccept;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 
@@ -24,8 +25,26 @@ pin_project! {
         #[pin]
         pub inner: T,
         buf: BytesMut,
-        tlv_bytes: u16,
         state: ProxyParse,
+        extra: Option<ConnectionInfoExtra>,
+    }
+}
+
+/// What the TLVs of the header tell about the connection, the private link it
+/// came through in particular.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionInfoExtra {
+    Aws { vpce_id: SmolStr },
+    Azure { link_id: u32 },
+}
+
+impl ConnectionInfoExtra {
+    /// The id of the private link, as the console lists the allowed ones.
+    pub fn vpc_endpoint_id(&self) -> String {
+        match self {
+            ConnectionInfoExtra::Aws { vpce_id } => vpce_id.to_string(),
+            ConnectionInfoExtra::Azure { link_id } => link_id.to_string(),
+        }
     }
 }
 
@@ -77,8 +96,8 @@ impl<T> WithClientIp<T> {
         WithClientIp {
             inner,
             buf: BytesMut::with_capacity(128),
-            tlv_bytes: 0,
             state: ProxyParse::NotStarted,
+            extra: None,
         }
     }
 
@@ -88,6 +107,11 @@ impl<T> WithClientIp<T> {
             _ => None,
         }
     }
+
+    /// Available once the header is parsed, e.g. after [`Self::wait_for_addr`].
+    pub fn extra(&self) -> Option<&ConnectionInfoExtra> {
+        self.extra.as_ref()
+    }
 }
 
 impl<T: AsyncRead + Unpin> WithClientIp<T> {
@@ -113,6 +137,13 @@ const HEADER: [u8; 12] = [
     0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
 ];
 
+/// Types of the TLVs we use, defined by the load balancers of the clouds.
+/// The value starts with a subtype.
+const PP2_TYPE_AWS: u8 = 0xEA;
+const PP2_SUBTYPE_AWS_VPCE_ID: u8 = 0x01;
+const PP2_TYPE_AZURE: u8 = 0xEE;
+const PP2_SUBTYPE_AZURE_PRIVATEENDPOINT_LINKID: u8 = 0x01;
+
 impl<T: AsyncRead> WithClientIp<T> {
     /// implementation of <https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt>
     /// Version 2 (Binary Format)
@@ -207,12 +238,18 @@ impl<T: AsyncRead> WithClientIp<T> {
             )));
         }
 
-        while self.buf.len() < 16 + address_length as usize {
+        // The TLVs follow the addresses, read them all.
+        let header_length = 16 + remaining_length as usize;
+        if self.buf.len() < header_length {
+            let additional = header_length - self.buf.len();
+            self.as_mut().project().buf.reserve(additional);
+        }
+        while self.buf.len() < header_length {
             let mut this = self.as_mut().project();
             if ready!(pin!(this.inner.read_buf(this.buf)).poll(cx)?) == 0 {
                 return Poll::Ready(Err(io::Error::new(
                     io::ErrorKind::UnexpectedEof,
-                    "stream closed while waiting for proxy protocol addresses",
+                    "stream closed while waiting for proxy protocol header",
                 )));
             }
         }
@@ -244,8 +281,15 @@ impl<T: AsyncRead> WithClientIp<T> {
             _ => None,
         };
 
-        *this.tlv_bytes = remaining_length - address_length;
-        self.as_mut().skip_tlv_inner();
+        let tlvs = this
+            .buf
+            .split_to((remaining_length - address_length) as usize);
+        *this.extra = parse_tlvs(&tlvs);
+
+        // Release the header's allocation if that's all there was.
+        if this.buf.is_empty() {
+            *this.buf = BytesMut::new();
+        }
 
         Poll::Ready(Ok(socket))
     }
@@ -259,31 +303,6 @@ impl<T: AsyncRead> WithClientIp<T> {
         }
         Poll::Ready(Ok(()))
     }
-
-    #[cold]
-    fn skip_tlv(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        let mut this = self.as_mut().project();
-        // we know that this.buf is empty
-        debug_assert_eq!(this.buf.len(), 0);
-
-        this.buf.reserve((*this.tlv_bytes).clamp(0, 1024) as usize);
-        ready!(pin!(this.inner.read_buf(this.buf)).poll(cx)?);
-        self.skip_tlv_inner();
-
-        Poll::Ready(Ok(()))
-    }
-
-    fn skip_tlv_inner(self: Pin<&mut Self>) {
-        let tlv_bytes_read = match u16::try_from(self.buf.len()) {
-            // we read more than u16::MAX therefore we must have read the full tlv_bytes
-            Err(_) => self.tlv_bytes,
-            // we might not have read the full tlv bytes yet
-            Ok(n) => u16::min(n, self.tlv_bytes),
-        };
-        let this = self.project();
-        *this.tlv_bytes -= tlv_bytes_read;
-        this.buf.advance(tlv_bytes_read as usize);
-    }
 }
 
 impl<T: AsyncRead> AsyncRead for WithClientIp<T> {
@@ -293,7 +312,7 @@ impl<T: AsyncRead> AsyncRead for WithClientIp<T> {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        // I'm assuming these 3 comparisons will be easy to branch predict.
+        // I'm assuming these comparisons will be easy to branch predict.
         // especially with the cold attributes
         // which should make this read wrapper almost invisible
 
@@ -301,17 +320,10 @@ impl<T: AsyncRead> AsyncRead for WithClientIp<T> {
             ready!(self.as_mut().read_ip(cx)?);
         }
 
-        while self.tlv_bytes > 0 {
-            ready!(self.as_mut().skip_tlv(cx)?)
-        }
-
         let this = self.project();
         if this.buf.is_empty() {
             this.inner.poll_read(cx, buf)
         } else {
-            // we know that tlv_bytes is 0
-            debug_assert_eq!(*this.tlv_bytes, 0);
-
             let write = usize::min(this.buf.len(), buf.remaining());
             let slice = this.buf.split_to(write).freeze();
             buf.put_slice(&slice);
@@ -326,6 +338,38 @@ impl<T: AsyncRead> AsyncRead for WithClientIp<T> {
     }
 }
 
+/// Parse the TLVs of the header. Those we don't know, and malformed ones, are ignored.
+fn parse_tlvs(mut tlvs: &[u8]) -> Option<ConnectionInfoExtra> {
+    let mut extra = None;
+    while tlvs.len() >= 3 {
+        let kind = tlvs[0];
+        let length = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        let Some(value) = tlvs.get(3..3 + length) else {
+            break;
+        };
+        tlvs = &tlvs[3 + length..];
+
+        match (kind, value.split_first()) {
+            (PP2_TYPE_AWS, Some((&PP2_SUBTYPE_AWS_VPCE_ID, vpce_id))) => {
+                if let Ok(vpce_id) = std::str::from_utf8(vpce_id) {
+                    extra = Some(ConnectionInfoExtra::Aws {
+                        vpce_id: vpce_id.into(),
+                    });
+                }
+            }
+            (PP2_TYPE_AZURE, Some((&PP2_SUBTYPE_AZURE_PRIVATEENDPOINT_LINKID, link_id))) => {
+                if let Ok(link_id) = <[u8; 4]>::try_from(link_id) {
+                    extra = Some(ConnectionInfoExtra::Azure {
+                        link_id: u32::from_le_bytes(link_id),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    extra
+}
+
 impl AsyncAccept for ProxyProtocolAccept {
     type Connection = WithClientIp<AddrStream>;
 
@@ -350,7 +394,7 @@ mod tests {
 
     use tokio::io::AsyncReadExt;
 
-    use crate::protocol2::{ProxyParse, WithClientIp};
+    use crate::protocol2::{ConnectionInfoExtra, ProxyParse, WithClientIp};
 
     #[tokio::test]
     async fn test_ipv4() {
@@ -476,4 +520,55 @@ mod tests {
             ProxyParse::Finished(([55, 56, 57, 58], 65535).into())
         );
     }
+
+    #[tokio::test]
+    async fn test_private_link_tlvs() {
+        let vpce_id = b"vpce-0123456789abcdef0";
+        let mut tlvs = vec![];
+        // PP2_TYPE_ALPN, ignored
+        tlvs.extend_from_slice(&[0x01, 0, 2, b'h', b'2']);
+        // PP2_TYPE_AWS, PP2_SUBTYPE_AWS_VPCE_ID
+        tlvs.extend_from_slice(&[0xEA, 0, vpce_id.len() as u8 + 1, 0x01]);
+        tlvs.extend_from_slice(vpce_id);
+        let len = (12 + tlvs.len() as u16).to_be_bytes();
+
+        let header = super::HEADER
+            // Proxy command, IPV4 | TCP
+            .chain([(2 << 4) | 1, (1 << 4) | 1].as_slice())
+            .chain(len.as_slice())
+            // src ip
+            .chain([10, 0, 0, 1].as_slice())
+            // dst ip
+            .chain([192, 168, 0, 1].as_slice())
+            // src port
+            .chain([255, 255].as_slice())
+            // dst port
+            .chain([1, 1].as_slice())
+            .chain(tlvs.as_slice());
+
+        let extra_data = [0x55; 256];
+
+        let mut read = pin!(WithClientIp::new(header.chain(extra_data.as_slice())));
+
+        let mut bytes = vec![];
+        read.read_to_end(&mut bytes).await.unwrap();
+
+        assert_eq!(bytes, extra_data);
+        assert_eq!(
+            read.extra(),
+            Some(&ConnectionInfoExtra::Aws {
+                vpce_id: "vpce-0123456789abcdef0".into()
+            })
+        );
+
+        // PP2_TYPE_AZURE, PP2_SUBTYPE_AZURE_PRIVATEENDPOINT_LINKID
+        let tlvs = [0xEE, 0, 5, 0x01, 0x78, 0x56, 0x34, 0x12];
+        assert_eq!(
+            super::parse_tlvs(&tlvs),
+            Some(ConnectionInfoExtra::Azure {
+                link_id: 0x12345678
+            })
+        );
+        assert_eq!(super::parse_tlvs(&tlvs[..6]), None);
+    }
 }
@@ -3,6 +3,7 @@ mod tests;
 
 pub mod connect_compute;
 pub mod retry;
+pub mod transaction_pool;
 
 use crate::{
     auth,
@@ -14,8 +15,8 @@ use crate::{
         LatencyTimer, NUM_BYTES_PROXIED_COUNTER, NUM_BYTES_PROXIED_PER_CLIENT_COUNTER,
         NUM_CLIENT_CONNECTION_GAUGE, NUM_CONNECTION_REQUESTS_GAUGE,
     },
-    protocol2::WithClientIp,
-    rate_limiter::EndpointRateLimiter,
+    protocol2::{ConnectionInfoExtra, WithClientIp},
+    rate_limiter::{EndpointRateLimiter, RateLimitError},
     stream::{PqStream, Stream},
     usage_metrics::{Ids, USAGE_METRICS},
 };
@@ -31,7 +32,10 @@ use tokio_util::sync::CancellationToken;
 use tracing::{error, info, info_span, Instrument};
 use utils::measured_stream::MeasuredStream;
 
-use self::connect_compute::{connect_to_compute, TcpMechanism};
+use self::{
+    connect_compute::{connect_to_compute, TcpMechanism},
+    transaction_pool::{pass_transactions, PoolKey, TransactionPool},
+};
 
 const ERR_INSECURE_CONNECTION: &str = "connection is insecure (try using `sslmode=require`)";
 const ERR_PROTO_VIOLATION: &str = "protocol violation";
@@ -56,6 +60,7 @@ pub async fn task_main(
     listener: tokio::net::TcpListener,
     cancellation_token: CancellationToken,
     endpoint_rate_limiter: Arc<EndpointRateLimiter>,
+    cancel_map: Arc<CancelMap>,
 ) -> anyhow::Result<()> {
     scopeguard::defer! {
         info!("proxy has shut down");
@@ -66,7 +71,14 @@ pub async fn task_main(
     socket2::SockRef::from(&listener).set_keepalive(true)?;
 
     let connections = tokio_util::task::task_tracker::TaskTracker::new();
-    let cancel_map = Arc::new(CancelMap::default());
+
+    let transaction_pool = config.transaction_pool.map(|options| {
+        info!("pooling compute connections of tcp clients per transaction");
+        let pool = TransactionPool::new(options);
+        let pool2 = Arc::clone(&pool);
+        tokio::spawn(async move { pool2.gc_worker().await });
+        pool
+    });
 
     while let Some(accept_result) =
         run_until_cancelled(listener.accept(), &cancellation_token).await
@@ -76,6 +88,7 @@ pub async fn task_main(
         let session_id = uuid::Uuid::new_v4();
         let cancel_map = Arc::clone(&cancel_map);
         let endpoint_rate_limiter = endpoint_rate_limiter.clone();
+        let transaction_pool = transaction_pool.clone();
 
         connections.spawn(
             async move {
@@ -89,6 +102,7 @@ pub async fn task_main(
                 } else if config.require_client_ip {
                     bail!("missing required client IP");
                 }
+                let extra = socket.extra().cloned();
 
                 socket
                     .inner
@@ -102,7 +116,9 @@ pub async fn task_main(
                     socket,
                     ClientMode::Tcp,
                     peer_addr.ip(),
+                    extra,
                     endpoint_rate_limiter,
+                    transaction_pool,
                 )
                 .await
             }
@@ -171,6 +187,7 @@ impl ClientMode {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
     config: &'static ProxyConfig,
     cancel_map: &CancelMap,
@@ -178,7 +195,9 @@ pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
     stream: S,
     mode: ClientMode,
     peer_addr: IpAddr,
+    extra: Option<ConnectionInfoExtra>,
     endpoint_rate_limiter: Arc<EndpointRateLimiter>,
+    transaction_pool: Option<Arc<TransactionPool>>,
 ) -> anyhow::Result<()> {
     info!(
         protocol = mode.protocol_label(),
@@ -208,7 +227,10 @@ pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
         let result = config
             .auth_backend
             .as_ref()
-            .map(|_| auth::ClientCredentials::parse(&params, hostname, common_names, peer_addr))
+            .map(|_| {
+                auth::ClientCredentials::parse(&params, hostname, common_names, peer_addr)
+                    .map(|creds| creds.with_extra(extra))
+            })
             .transpose();
 
         match result {
@@ -224,6 +246,7 @@ pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
         session_id,
         mode.allow_self_signed_compute(config),
         endpoint_rate_limiter,
+        transaction_pool,
     );
     cancel_map
         .with_session(|session| client.connect_to_db(session, mode, &config.authentication_config))
@@ -319,7 +342,7 @@ async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
 #[tracing::instrument(skip_all)]
 async fn prepare_client_connection(
     node: &compute::PostgresConnection,
-    session: cancellation::Session<'_>,
+    session: &cancellation::Session<'_>,
     stream: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin>,
 ) -> anyhow::Result<()> {
     // Register compute's query cancellation token and produce a new, unique one.
@@ -403,6 +426,8 @@ struct Client<'a, S> {
     allow_self_signed_compute: bool,
     /// Rate limiter for endpoints
     endpoint_rate_limiter: Arc<EndpointRateLimiter>,
+    /// Pool of compute connections, in transaction pooling mode.
+    transaction_pool: Option<Arc<TransactionPool>>,
 }
 
 impl<'a, S> Client<'a, S> {
@@ -414,6 +439,7 @@ impl<'a, S> Client<'a, S> {
         session_id: uuid::Uuid,
         allow_self_signed_compute: bool,
         endpoint_rate_limiter: Arc<EndpointRateLimiter>,
+        transaction_pool: Option<Arc<TransactionPool>>,
     ) -> Self {
         Self {
             stream,
@@ -422,6 +448,7 @@ impl<'a, S> Client<'a, S> {
             session_id,
             allow_self_signed_compute,
             endpoint_rate_limiter,
+            transaction_pool,
         }
     }
 }
@@ -444,14 +471,14 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Client<'_, S> {
             session_id,
             allow_self_signed_compute,
             endpoint_rate_limiter,
+            transaction_pool,
         } = self;
 
         // check rate limit
         if let Some(ep) = creds.get_endpoint() {
             if !endpoint_rate_limiter.check(ep) {
-                return stream
-                    .throw_error(auth::AuthError::too_many_connections())
-                    .await;
+                let e = RateLimitError::ConnectionRate("endpoint");
+                return stream.throw_error(auth::AuthError::from(e)).await;
             }
         }
 
@@ -465,6 +492,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Client<'_, S> {
             ),
             options: neon_options(params),
         };
+
         let mut latency_timer = LatencyTimer::new(proto);
 
         let user = creds.get_user().to_owned();
@@ -490,20 +518,64 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Client<'_, S> {
 
         let (mut node_info, creds) = auth_result;
 
+        // check the limits of the control plane, which came with the auth info.
+        // The connection counts towards the concurrency limits until it's closed.
+        let _limits_guard = match creds.get_user_info() {
+            Some(info) => {
+                let limits = match creds.get_endpoint_limits(&extra).await {
+                    Ok(limits) => limits,
+                    Err(e) => return stream.throw_error(auth::AuthError::from(e)).await,
+                };
+                match endpoint_rate_limiter.check_connection(info.endpoint.clone(), &limits) {
+                    Ok(guard) => Some(guard),
+                    Err(e) => return stream.throw_error(auth::AuthError::from(e)).await,
+                }
+            }
+            None => None,
+        };
+
         node_info.allow_self_signed_compute = allow_self_signed_compute;
 
         let aux = node_info.aux.clone();
-        let mut node = connect_to_compute(
-            &TcpMechanism { params, proto },
-            node_info,
-            &extra,
-            &creds,
-            latency_timer,
-        )
-        .or_else(|e| stream.throw_error(e))
-        .await?;
+        let mechanism = TcpMechanism { params, proto };
+
+        let pool = transaction_pool.and_then(|pool| {
+            let info = creds.get_user_info()?;
+            let key = PoolKey::new(info.endpoint.clone(), info.inner.user.clone(), params)?;
+            Some(pool.get_or_create_pool(key))
+        });
+        if let Some(pool) = pool {
+            // The first connection is made with the node info we've got, and
+            // the later ones with a copy of it.
+            let (mechanism, extra, creds) = (&mechanism, &extra, &creds);
+            let template = console::NodeInfo::clone(&node_info);
+            let mut first = Some((node_info, latency_timer));
+            let mut connect = move || {
+                let (node_info, latency_timer) = first.take().unwrap_or_else(|| {
+                    let node_info = console::CachedNodeInfo::new_uncached(template.clone());
+                    (node_info, LatencyTimer::new(proto))
+                });
+                connect_to_compute(mechanism, node_info, extra, creds, latency_timer)
+            };
+
+            let conn = pool
+                .borrow(&mut connect)
+                .or_else(|e| stream.throw_error(e))
+                .await?;
+            prepare_client_connection(conn.get(), &session, &mut stream).await?;
+            // The client gets a connection once it starts a transaction.
+            session.set_cancel_closure(None);
+            conn.release_unused();
+
+            let (stream, read_buf) = stream.into_inner();
+            return pass_transactions(stream, read_buf, &pool, &session, connect, aux).await;
+        }
+
+        let mut node = connect_to_compute(&mechanism, node_info, &extra, &creds, latency_timer)
+            .or_else(|e| stream.throw_error(e))
+            .await?;
 
-        prepare_client_connection(&node, session, &mut stream).await?;
+        prepare_client_connection(&node, &session, &mut stream).await?;
         // Before proxy passing, forward to compute whatever data is left in the
         // PqStream input buffer. Normally there is none, but our serverless npm
         // driver in pipeline mode sends startup, password and first query
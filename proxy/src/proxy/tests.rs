@@ -472,6 +472,22 @@ impl TestBackend for TestConnectMechanism {
     fn get_allowed_ips(&self) -> Result<Arc<Vec<String>>, console::errors::GetAuthInfoError> {
         unimplemented!("not used in tests")
     }
+
+    fn get_allowed_vpc_endpoint_ids(
+        &self,
+    ) -> Result<Arc<Vec<String>>, console::errors::GetAuthInfoError> {
+        unimplemented!("not used in tests")
+    }
+
+    fn get_endpoint_jwks(
+        &self,
+    ) -> Result<Arc<Vec<auth::backend::AuthRule>>, console::errors::GetAuthInfoError> {
+        unimplemented!("not used in tests")
+    }
+
+    fn get_role_password(&self) -> Result<smol_str::SmolStr, console::errors::GetAuthInfoError> {
+        unimplemented!("not used in tests")
+    }
 }
 
 fn helper_create_cached_node_info() -> CachedNodeInfo {
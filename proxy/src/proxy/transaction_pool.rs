@@ -0,0 +1,633 @@
+//! Transaction-level pooling of compute connections for TCP clients.
+//!
+//! By default, a client has a compute connection of its own for the whole
+//! session. In transaction mode, compute connections are pooled per endpoint,
+//! role, database and startup parameters, and a client only borrows one for a
+//! transaction: the proxy follows the client's messages and the transaction
+//! status of the `ReadyForQuery` messages of the compute, and once the
+//! connection is idle with no queries of the client in flight, resets it with
+//! `DISCARD ALL` and returns it to the pool for the next borrower.
+//!
+//! Like in pgbouncer's transaction mode, session state doesn't survive between
+//! transactions: session-level `SET`s, named prepared statements, `LISTEN`,
+//! temporary tables and session-level advisory locks can't be used.
+
+use std::{future::Future, io, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use dashmap::DashMap;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use pq_proto::{BeMessage as Be, ProtocolError, StartupMessageParams};
+use smol_str::SmolStr;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use tracing::{debug, info, warn, Instrument};
+use utils::measured_stream::MeasuredStream;
+
+use crate::{
+    cancellation::Session,
+    compute::{self, PostgresConnection},
+    console::messages::MetricsAuxInfo,
+    error::UserFacingError,
+    metrics::{
+        NUM_BYTES_PROXIED_COUNTER, NUM_BYTES_PROXIED_PER_CLIENT_COUNTER, NUM_TCP_POOL_CONNECTIONS,
+    },
+    usage_metrics::{Ids, USAGE_METRICS},
+};
+
+/// How long resetting a connection for the next borrower may take.
+const RESET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest message we accept. Postgres doesn't send or accept larger ones
+/// either, see `PQ_LARGE_MESSAGE_LIMIT`.
+const MAX_MESSAGE_LEN: usize = 0x3fff_fffe;
+
+/// How much to read at once while a message is incomplete.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A `DISCARD ALL` simple query message.
+const DISCARD_ALL: &[u8] = b"Q\0\0\0\x10DISCARD ALL\0";
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionPoolOptions {
+    /// Maximum number of compute connections per endpoint, role, database and
+    /// options. Clients wait for a connection while all of them are borrowed.
+    pub max_conns_per_endpoint: usize,
+
+    /// How long pooled connections remain idle for before closing.
+    pub idle_timeout: Duration,
+}
+
+/// Clients with the same key can share compute connections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub endpoint: SmolStr,
+    pub user: SmolStr,
+    pub dbname: SmolStr,
+    pub options: Option<String>,
+    /// The other startup parameters, sorted by name. Like `options`, they set
+    /// the values that `DISCARD ALL` resets the settings of a connection to.
+    pub params: Vec<(SmolStr, SmolStr)>,
+}
+
+impl PoolKey {
+    /// Returns [`None`] for the connections that can't be pooled.
+    pub fn new(endpoint: SmolStr, user: SmolStr, params: &StartupMessageParams) -> Option<Self> {
+        // Replication connections aren't made of transactions.
+        if params.get("replication").is_some() {
+            return None;
+        }
+
+        let dbname = params
+            .get("database")
+            .map_or_else(|| user.clone(), SmolStr::from);
+        let mut other_params: Vec<_> = params
+            .iter()
+            .filter(|(name, _)| !matches!(*name, "user" | "database" | "options"))
+            .map(|(name, value)| (SmolStr::from(name), SmolStr::from(value)))
+            .collect();
+        other_params.sort();
+        Some(Self {
+            endpoint,
+            user,
+            dbname,
+            options: compute::filtered_options(params),
+            params: other_params,
+        })
+    }
+}
+
+pub struct TransactionPool {
+    options: TransactionPoolOptions,
+    pools: DashMap<PoolKey, Arc<EndpointPool>>,
+}
+
+impl TransactionPool {
+    pub fn new(options: TransactionPoolOptions) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            pools: DashMap::new(),
+        })
+    }
+
+    pub fn get_or_create_pool(&self, key: PoolKey) -> Arc<EndpointPool> {
+        let pool = self
+            .pools
+            .entry(key)
+            .or_insert_with(|| Arc::new(EndpointPool::new(self.options)));
+        Arc::clone(&pool)
+    }
+
+    /// Close the connections that are idle for too long, and drop the pools
+    /// nobody uses anymore.
+    pub async fn gc_worker(&self) {
+        let mut interval = tokio::time::interval(self.options.idle_timeout);
+        loop {
+            interval.tick().await;
+            self.gc();
+        }
+    }
+
+    fn gc(&self) {
+        self.pools.retain(|key, pool| {
+            pool.clear_expired();
+            // Clients and borrowed connections hold references to the pool.
+            if Arc::strong_count(pool) == 1 && pool.idle.lock().is_empty() {
+                debug!(?key, "tcp pool: discarding pool");
+                return false;
+            }
+            true
+        });
+    }
+}
+
+/// The compute connections of one [`PoolKey`].
+pub struct EndpointPool {
+    /// A permit for each borrowed connection. A connection is only opened when
+    /// there are no idle ones, so there are never more connections than permits.
+    permits: Arc<Semaphore>,
+    /// Idle connections, the most recently returned last.
+    idle: Mutex<Vec<IdleConnection>>,
+    idle_timeout: Duration,
+}
+
+struct IdleConnection {
+    conn: PostgresConnection,
+    since: Instant,
+}
+
+impl EndpointPool {
+    fn new(options: TransactionPoolOptions) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(options.max_conns_per_endpoint)),
+            idle: Mutex::new(Vec::new()),
+            idle_timeout: options.idle_timeout,
+        }
+    }
+
+    /// Borrow an idle connection, or open a new one with `connect`. Waits
+    /// while all the connections of the pool are borrowed.
+    pub async fn borrow<F>(
+        self: &Arc<Self>,
+        connect: impl FnOnce() -> F,
+    ) -> Result<BorrowedConnection, compute::ConnectionError>
+    where
+        F: Future<Output = Result<PostgresConnection, compute::ConnectionError>>,
+    {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+
+        let conn = match self.take_idle() {
+            Some(conn) => {
+                NUM_TCP_POOL_CONNECTIONS
+                    .with_label_values(&["reused"])
+                    .inc();
+                conn
+            }
+            None => {
+                let conn = connect().await?;
+                NUM_TCP_POOL_CONNECTIONS.with_label_values(&["new"]).inc();
+                conn
+            }
+        };
+
+        Ok(BorrowedConnection {
+            conn,
+            buf: BytesMut::new(),
+            pool: Arc::clone(self),
+            _permit: permit,
+        })
+    }
+
+    fn take_idle(&self) -> Option<PostgresConnection> {
+        loop {
+            let IdleConnection { mut conn, since } = self.idle.lock().pop()?;
+            if since.elapsed() < self.idle_timeout && is_open(&mut conn) {
+                return Some(conn);
+            }
+            debug!("tcp pool: discarding expired or closed connection");
+        }
+    }
+
+    fn put(&self, conn: PostgresConnection) {
+        self.idle.lock().push(IdleConnection {
+            conn,
+            since: Instant::now(),
+        });
+    }
+
+    fn clear_expired(&self) {
+        self.idle
+            .lock()
+            .retain(|idle| idle.since.elapsed() < self.idle_timeout);
+    }
+}
+
+/// The compute has nothing to say on an idle connection, unless it is
+/// closing it, e.g. on shutdown.
+fn is_open(conn: &mut PostgresConnection) -> bool {
+    let mut buf = [0; 1];
+    conn.stream.read(&mut buf).now_or_never().is_none()
+}
+
+/// A compute connection lent to a client. Dropping it closes the connection,
+/// [`BorrowedConnection::release`] returns it to the pool.
+pub struct BorrowedConnection {
+    conn: PostgresConnection,
+    /// Messages of the compute, read but not forwarded yet.
+    buf: BytesMut,
+    pool: Arc<EndpointPool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl BorrowedConnection {
+    pub fn get(&self) -> &PostgresConnection {
+        &self.conn
+    }
+
+    /// Return a connection nothing has run on to the pool.
+    pub fn release_unused(self) {
+        self.pool.put(self.conn);
+    }
+
+    /// Reset the session state of the connection and return it to the pool.
+    /// This happens in the background, the borrow ends once it's done.
+    pub fn release(self) {
+        let Self {
+            mut conn,
+            mut buf,
+            pool,
+            _permit,
+        } = self;
+        tokio::spawn(
+            async move {
+                let reset = tokio::time::timeout(RESET_TIMEOUT, reset(&mut conn, &mut buf));
+                match reset.await {
+                    Ok(Ok(())) => pool.put(conn),
+                    Ok(Err(e)) => warn!("tcp pool: failed to reset connection, closing it: {e}"),
+                    Err(_) => warn!("tcp pool: timed out resetting connection, closing it"),
+                }
+                drop(_permit);
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Run `DISCARD ALL` on an idle connection.
+async fn reset(conn: &mut PostgresConnection, buf: &mut BytesMut) -> io::Result<()> {
+    if !buf.is_empty() {
+        return Err(invalid_data("unexpected message from compute"));
+    }
+    conn.stream.write_all(DISCARD_ALL).await?;
+    conn.stream.flush().await?;
+
+    let mut failed = false;
+    loop {
+        let msg = read_message(&mut conn.stream, buf)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        match msg[0] {
+            b'E' => failed = true,
+            b'Z' if failed || msg.get(5) != Some(&b'I') => {
+                return Err(invalid_data("DISCARD ALL failed"))
+            }
+            b'Z' => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Where a client is in its conversation with a compute connection.
+#[derive(Debug, Default)]
+struct TransactionState {
+    /// Number of the client's messages that the compute is yet to answer
+    /// with `ReadyForQuery`.
+    pending: usize,
+    /// Whether the client has sent extended query messages after its last `Sync`.
+    unsynced: bool,
+    /// Whether the last message of the compute was `ReadyForQuery` with the
+    /// idle transaction status.
+    idle: bool,
+}
+
+impl TransactionState {
+    /// Returns `false` if the message is `Terminate`.
+    fn on_client_message(&mut self, tag: u8) -> bool {
+        match tag {
+            b'X' => return false,
+            // Query, FunctionCall
+            b'Q' | b'F' => self.pending += 1,
+            // Sync
+            b'S' => {
+                self.pending += 1;
+                self.unsynced = false;
+            }
+            // Parse, Bind, Describe, Execute, Close, Flush
+            b'P' | b'B' | b'D' | b'E' | b'C' | b'H' => self.unsynced = true,
+            _ => {}
+        }
+        self.idle = false;
+        true
+    }
+
+    fn on_server_message(&mut self, msg: &[u8]) {
+        self.idle = false;
+        if msg[0] == b'Z' {
+            self.pending = self.pending.saturating_sub(1);
+            self.idle = msg.get(5) == Some(&b'I');
+        }
+    }
+
+    /// Whether the connection can be passed to another client.
+    fn can_release(&self) -> bool {
+        self.idle && self.pending == 0 && !self.unsynced
+    }
+}
+
+/// Forward the traffic of a client to connections borrowed from `pool` for
+/// each transaction. `client_buf` is what has been read from the client already.
+pub async fn pass_transactions<S, C, F>(
+    client: S,
+    mut client_buf: BytesMut,
+    pool: &Arc<EndpointPool>,
+    session: &Session<'_>,
+    mut connect: C,
+    aux: MetricsAuxInfo,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: FnMut() -> F,
+    F: Future<Output = Result<PostgresConnection, compute::ConnectionError>>,
+{
+    let usage = USAGE_METRICS.register(Ids {
+        endpoint_id: aux.endpoint_id.clone(),
+        branch_id: aux.branch_id.clone(),
+    });
+    let m_sent = NUM_BYTES_PROXIED_COUNTER.with_label_values(&["tx"]);
+    let m_sent2 = NUM_BYTES_PROXIED_PER_CLIENT_COUNTER.with_label_values(&aux.traffic_labels("tx"));
+    let m_recv = NUM_BYTES_PROXIED_COUNTER.with_label_values(&["rx"]);
+    let m_recv2 = NUM_BYTES_PROXIED_PER_CLIENT_COUNTER.with_label_values(&aux.traffic_labels("rx"));
+    let mut client = MeasuredStream::new(
+        client,
+        |cnt| {
+            // Number of bytes the client sent to the compute node (inbound).
+            m_recv.inc_by(cnt as u64);
+            m_recv2.inc_by(cnt as u64);
+        },
+        |cnt| {
+            // Number of bytes we sent to the client (outbound).
+            m_sent.inc_by(cnt as u64);
+            m_sent2.inc_by(cnt as u64);
+            usage.record_egress(cnt as u64);
+        },
+    );
+
+    info!("performing the transaction pooled proxy pass...");
+    let mut server: Option<BorrowedConnection> = None;
+    let mut state = TransactionState::default();
+    loop {
+        // Forward the complete messages of the client.
+        let mut out = BytesMut::new();
+        let mut terminated = false;
+        while let Some(msg) = split_message(&mut client_buf)? {
+            if !state.on_client_message(msg[0]) {
+                terminated = true;
+                break;
+            }
+            out.extend_from_slice(&msg);
+        }
+        if !out.is_empty() {
+            if server.is_none() {
+                match pool.borrow(&mut connect).await {
+                    Ok(conn) => {
+                        session.set_cancel_closure(Some(conn.conn.cancel_closure.clone()));
+                        server = Some(conn);
+                    }
+                    Err(e) => {
+                        let msg = e.to_string_client();
+                        let mut buf = BytesMut::new();
+                        Be::write(&mut buf, &Be::ErrorResponse(&msg, None))
+                            .map_err(ProtocolError::into_io_error)?;
+                        client.write_all(&buf).await?;
+                        client.flush().await?;
+                        return Err(e.into());
+                    }
+                }
+            }
+            let conn = server.as_mut().expect("borrowed above");
+            conn.conn.stream.write_all(&out).await?;
+            conn.conn.stream.flush().await?;
+        }
+        if terminated {
+            // Idle compute connections outlive the client, a connection left
+            // in a transaction is closed on drop.
+            return Ok(());
+        }
+
+        tokio::select! {
+            read = client.read_buf(&mut client_buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+            msg = read_server_message(&mut server) => {
+                let Some(msg) = msg? else {
+                    anyhow::bail!("compute closed the connection");
+                };
+                let conn = server.as_mut().expect("only read from a borrowed connection");
+
+                // Forward all the complete messages of the compute at once.
+                let mut out = BytesMut::new();
+                let mut next = Some(msg);
+                while let Some(msg) = next {
+                    state.on_server_message(&msg);
+                    out.extend_from_slice(&msg);
+                    next = split_message(&mut conn.buf)?;
+                }
+                client.write_all(&out).await?;
+                client.flush().await?;
+
+                if state.can_release() {
+                    session.set_cancel_closure(None);
+                    server.take().expect("checked above").release();
+                }
+            }
+        }
+    }
+}
+
+/// Read a message from the borrowed connection, if any.
+async fn read_server_message(
+    server: &mut Option<BorrowedConnection>,
+) -> io::Result<Option<BytesMut>> {
+    match server {
+        Some(conn) => read_message(&mut conn.conn.stream, &mut conn.buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Read a message of the postgres protocol past the startup phase. Returns
+/// [`None`] if the stream ends between messages.
+///
+/// Cancel safe: anything that's read stays in `buf` for the next call.
+async fn read_message<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+) -> io::Result<Option<BytesMut>> {
+    loop {
+        if let Some(msg) = split_message(buf)? {
+            return Ok(Some(msg));
+        }
+        if stream.read_buf(buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Split the first message off `buf`, if it's complete. A message is a tag
+/// byte followed by its length, which includes the length itself.
+fn split_message(buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    let len = i32::from_be_bytes(buf[1..5].try_into().expect("slice of 4 bytes"));
+    if len < 4 || len as usize > MAX_MESSAGE_LEN {
+        return Err(invalid_data(format!("invalid message length {len}")));
+    }
+    let total = len as usize + 1;
+    if buf.len() < total {
+        // Grow the buffer as the message arrives rather than trusting its length.
+        buf.reserve(std::cmp::min(total - buf.len(), READ_CHUNK_SIZE));
+        return Ok(None);
+    }
+    Ok(Some(buf.split_to(total)))
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![tag];
+        msg.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    #[tokio::test]
+    async fn read_messages() {
+        let mut input = message(b'Q', b"select 1\0");
+        input.extend(message(b'S', b""));
+        let mut reader = &input[..];
+        let mut buf = BytesMut::new();
+
+        let msg = read_message(&mut reader, &mut buf).await.unwrap().unwrap();
+        assert_eq!(&msg[..], &message(b'Q', b"select 1\0")[..]);
+        let msg = read_message(&mut reader, &mut buf).await.unwrap().unwrap();
+        assert_eq!(&msg[..], &message(b'S', b"")[..]);
+        assert!(read_message(&mut reader, &mut buf).await.unwrap().is_none());
+
+        // A message cut short.
+        let mut reader = &input[..7];
+        assert!(read_message(&mut reader, &mut buf).await.is_err());
+
+        let mut buf = BytesMut::from(&b"Q\0\0\0\x02"[..]);
+        assert!(split_message(&mut buf).is_err());
+
+        // Too large to be a postgres message.
+        let mut buf = BytesMut::from(&b"Q\x7f\xff\xff\xff"[..]);
+        assert!(split_message(&mut buf).is_err());
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn pool_key_params() {
+        let key = |params| PoolKey::new("ep".into(), "user".into(), &params);
+
+        let a = key(StartupMessageParams::new([
+            ("user", "user"),
+            ("client_encoding", "UTF8"),
+            ("TimeZone", "UTC"),
+        ]));
+        let b = key(StartupMessageParams::new([
+            ("TimeZone", "UTC"),
+            ("client_encoding", "UTF8"),
+            ("user", "user"),
+        ]));
+        assert_eq!(a, b);
+        assert_eq!(a.as_ref().unwrap().dbname, "user");
+
+        let c = key(StartupMessageParams::new([
+            ("user", "user"),
+            ("client_encoding", "LATIN1"),
+            ("TimeZone", "UTC"),
+        ]));
+        assert_ne!(a, c);
+
+        let replication = key(StartupMessageParams::new([
+            ("user", "user"),
+            ("replication", "true"),
+        ]));
+        assert_eq!(replication, None);
+    }
+
+    #[test]
+    fn release_after_transactions() {
+        let mut state = TransactionState::default();
+
+        // A simple query outside of a transaction.
+        assert!(state.on_client_message(b'Q'));
+        assert!(!state.can_release());
+        state.on_server_message(&message(b'C', b"SELECT 1\0"));
+        state.on_server_message(&message(b'Z', b"I"));
+        assert!(state.can_release());
+
+        // An explicit transaction.
+        state.on_client_message(b'Q');
+        state.on_server_message(&message(b'Z', b"T"));
+        assert!(!state.can_release());
+        state.on_client_message(b'Q');
+        state.on_server_message(&message(b'Z', b"E"));
+        assert!(!state.can_release());
+        state.on_client_message(b'Q');
+        state.on_server_message(&message(b'Z', b"I"));
+        assert!(state.can_release());
+
+        // Pipelined extended queries: release after the last `Sync` only.
+        for tag in [b'P', b'B', b'E', b'S', b'B', b'E', b'S'] {
+            state.on_client_message(tag);
+        }
+        state.on_server_message(&message(b'Z', b"I"));
+        assert!(!state.can_release());
+        state.on_server_message(&message(b'Z', b"I"));
+        assert!(state.can_release());
+
+        // A query that is sent without a `Sync` yet.
+        state.on_client_message(b'Q');
+        state.on_client_message(b'P');
+        state.on_server_message(&message(b'Z', b"I"));
+        assert!(!state.can_release());
+        state.on_client_message(b'S');
+        state.on_server_message(&message(b'Z', b"I"));
+        assert!(state.can_release());
+
+        assert!(!state.on_client_message(b'X'));
+    }
+}
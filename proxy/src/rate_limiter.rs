@@ -4,4 +4,6 @@ mod limiter;
 pub use aimd::Aimd;
 pub use limit_algorithm::{AimdConfig, Fixed, RateLimitAlgorithm, RateLimiterConfig};
 pub use limiter::Limiter;
-pub use limiter::{EndpointRateLimiter, RateBucketInfo};
+pub use limiter::{
+    ConnectionGuard, EndpointLimits, EndpointRateLimiter, Limits, RateBucketInfo, RateLimitError,
+};
@@ -12,6 +12,7 @@ use dashmap::DashMap;
 use itertools::Itertools;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use smol_str::SmolStr;
+use thiserror::Error;
 use tokio::sync::{Mutex as AsyncMutex, Semaphore, SemaphorePermit};
 use tokio::time::{timeout, Duration, Instant};
 use tracing::info;
@@ -20,6 +21,7 @@ use super::{
     limit_algorithm::{LimitAlgorithm, Sample},
     RateLimiterConfig,
 };
+use crate::error::UserFacingError;
 
 // Simple per-endpoint rate limiter.
 //
@@ -32,13 +34,81 @@ use super::{
 // saw SNI, before doing TLS handshake. User-side error messages in that case
 // does not look very nice (`SSL SYSCALL error: Undefined error: 0`), so for now
 // I went with a more expensive way that yields user-friendlier error messages.
+//
+// The control plane can set limits of its own for an endpoint and its project
+// (see [`EndpointLimits`]), including the number of concurrent connections and
+// the rate of SQL-over-HTTP queries. Those come with the auth info of the
+// endpoint, so they are checked in addition to the connection rate of `info`,
+// which is checked first as it needs no requests to the control plane.
 pub struct EndpointRateLimiter<Rand = StdRng, Hasher = RandomState> {
-    map: DashMap<SmolStr, Vec<RateBucket>, Hasher>,
+    map: DashMap<(RateLimitKind, LimitScope), Vec<RateBucket>, Hasher>,
     info: &'static [RateBucketInfo],
+    /// Number of open client connections per scope.
+    connections: DashMap<LimitScope, u32, Hasher>,
     access_count: AtomicUsize,
     rand: Mutex<Rand>,
 }
 
+/// What a limit applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LimitScope {
+    Endpoint(SmolStr),
+    Project(SmolStr),
+}
+
+impl LimitScope {
+    fn name(&self) -> &'static str {
+        match self {
+            LimitScope::Endpoint(_) => "endpoint",
+            LimitScope::Project(_) => "project",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateLimitKind {
+    /// Connections, limited by `info`.
+    Connections,
+    /// Connections, limited by the control plane.
+    ControlPlaneConnections,
+    HttpQueries,
+}
+
+/// Limits of an endpoint and its project, set by the control plane.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointLimits {
+    pub endpoint: Limits,
+    /// The project of the endpoint, if it has limits of its own.
+    pub project: Option<(SmolStr, Limits)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Rate of new connections, in addition to the limits of the proxy.
+    pub connection_rate: Option<Vec<RateBucketInfo>>,
+    /// Number of concurrent client connections.
+    pub max_connections: Option<u32>,
+    /// Rate of SQL-over-HTTP queries.
+    pub http_query_rate: Option<Vec<RateBucketInfo>>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RateLimitError {
+    #[error("Too many connections to this {0}. Please try again later.")]
+    ConnectionRate(&'static str),
+
+    #[error(
+        "Too many concurrent connections to this {scope}, the limit is {limit}. \
+        Please close some connections or try again later."
+    )]
+    ConcurrentConnections { scope: &'static str, limit: u32 },
+
+    #[error("Too many queries to this {0}. Please try again later.")]
+    HttpQueryRate(&'static str),
+}
+
+impl UserFacingError for RateLimitError {}
+
 #[derive(Clone, Copy)]
 struct RateBucket {
     start: Instant,
@@ -96,6 +166,13 @@ impl std::str::FromStr for RateBucketInfo {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for RateBucketInfo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl RateBucketInfo {
     pub const DEFAULT_SET: [Self; 3] = [
         Self::new(300, Duration::from_secs(1)),
@@ -132,6 +209,76 @@ impl EndpointRateLimiter {
     pub fn new(info: &'static [RateBucketInfo]) -> Self {
         Self::new_with_rand_and_hasher(info, StdRng::from_entropy(), RandomState::new())
     }
+
+    /// Check the limits that the control plane has set for a new client
+    /// connection to the endpoint, after [`Self::check`]. The connection
+    /// counts towards the concurrency limits until the guard is dropped.
+    pub fn check_connection(
+        self: &Arc<Self>,
+        endpoint: SmolStr,
+        limits: &EndpointLimits,
+    ) -> Result<ConnectionGuard, RateLimitError> {
+        let mut scopes = vec![(LimitScope::Endpoint(endpoint), &limits.endpoint)];
+        if let Some((project, project_limits)) = &limits.project {
+            scopes.push((LimitScope::Project(project.clone()), project_limits));
+        }
+
+        for (scope, limits) in &scopes {
+            let Some(rate) = &limits.connection_rate else {
+                continue;
+            };
+            if !self.check_rate(RateLimitKind::ControlPlaneConnections, scope.clone(), rate) {
+                return Err(RateLimitError::ConnectionRate(scope.name()));
+            }
+        }
+
+        let mut guard = ConnectionGuard {
+            limiter: Arc::clone(self),
+            scopes: Vec::with_capacity(scopes.len()),
+        };
+        for (scope, limits) in scopes {
+            if !self.acquire_connection(&scope, limits.max_connections) {
+                return Err(RateLimitError::ConcurrentConnections {
+                    scope: scope.name(),
+                    limit: limits.max_connections.unwrap_or_default(),
+                });
+            }
+            guard.scopes.push(scope);
+        }
+        Ok(guard)
+    }
+
+    fn acquire_connection(&self, scope: &LimitScope, max_connections: Option<u32>) -> bool {
+        let mut count = self.connections.entry(scope.clone()).or_insert(0);
+        if max_connections.is_some_and(|max| *count >= max) {
+            drop(count);
+            self.connections.remove_if(scope, |_, count| *count == 0);
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    fn release_connection(&self, scope: &LimitScope) {
+        if let Some(mut count) = self.connections.get_mut(scope) {
+            *count = count.saturating_sub(1);
+        }
+        self.connections.remove_if(scope, |_, count| *count == 0);
+    }
+}
+
+/// Holds the place of a client connection within the concurrency limits.
+pub struct ConnectionGuard {
+    limiter: Arc<EndpointRateLimiter>,
+    scopes: Vec<LimitScope>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        for scope in &self.scopes {
+            self.limiter.release_connection(scope);
+        }
+    }
 }
 
 impl<R: Rng, S: BuildHasher + Clone> EndpointRateLimiter<R, S> {
@@ -139,7 +286,8 @@ impl<R: Rng, S: BuildHasher + Clone> EndpointRateLimiter<R, S> {
         info!(buckets = ?info, "endpoint rate limiter");
         Self {
             info,
-            map: DashMap::with_hasher_and_shard_amount(hasher, 64),
+            map: DashMap::with_hasher_and_shard_amount(hasher.clone(), 64),
+            connections: DashMap::with_hasher(hasher),
             access_count: AtomicUsize::new(1), // start from 1 to avoid GC on the first request
             rand: Mutex::new(rand),
         }
@@ -147,6 +295,37 @@ impl<R: Rng, S: BuildHasher + Clone> EndpointRateLimiter<R, S> {
 
     /// Check that number of connections to the endpoint is below `max_rps` rps.
     pub fn check(&self, endpoint: SmolStr) -> bool {
+        self.check_rate(
+            RateLimitKind::Connections,
+            LimitScope::Endpoint(endpoint),
+            self.info,
+        )
+    }
+
+    /// Check the SQL-over-HTTP query rate of the endpoint. Unlike connections,
+    /// queries are only limited if the control plane has set a limit.
+    pub fn check_http_query(
+        &self,
+        endpoint: SmolStr,
+        limits: &EndpointLimits,
+    ) -> Result<(), RateLimitError> {
+        let mut scopes = vec![(LimitScope::Endpoint(endpoint), &limits.endpoint)];
+        if let Some((project, project_limits)) = &limits.project {
+            scopes.push((LimitScope::Project(project.clone()), project_limits));
+        }
+        for (scope, limits) in scopes {
+            let Some(rate) = &limits.http_query_rate else {
+                continue;
+            };
+            let name = scope.name();
+            if !self.check_rate(RateLimitKind::HttpQueries, scope, rate) {
+                return Err(RateLimitError::HttpQueryRate(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_rate(&self, kind: RateLimitKind, scope: LimitScope, info: &[RateBucketInfo]) -> bool {
         // do a partial GC every 2k requests. This cleans up ~ 1/64th of the map.
         // worst case memory usage is about:
         //    = 2 * 2048 * 64 * (48B + 72B)
@@ -156,19 +335,24 @@ impl<R: Rng, S: BuildHasher + Clone> EndpointRateLimiter<R, S> {
         }
 
         let now = Instant::now();
-        let mut entry = self.map.entry(endpoint).or_insert_with(|| {
+        let new_buckets = || {
             vec![
                 RateBucket {
                     start: now,
                     count: 0,
                 };
-                self.info.len()
+                info.len()
             ]
-        });
+        };
+        let mut entry = self.map.entry((kind, scope)).or_insert_with(new_buckets);
+        // the control plane has changed the limits
+        if entry.len() != info.len() {
+            *entry = new_buckets();
+        }
 
         let should_allow_request = entry
             .iter_mut()
-            .zip(self.info)
+            .zip(info)
             .all(|(bucket, info)| bucket.should_allow_request(info, now));
 
         if should_allow_request {
@@ -488,7 +672,7 @@ impl reqwest_middleware::Middleware for Limiter {
 
 #[cfg(test)]
 mod tests {
-    use std::{hash::BuildHasherDefault, pin::pin, task::Context, time::Duration};
+    use std::{hash::BuildHasherDefault, pin::pin, sync::Arc, task::Context, time::Duration};
 
     use futures::{task::noop_waker_ref, Future};
     use rand::SeedableRng;
@@ -496,7 +680,7 @@ mod tests {
     use smol_str::SmolStr;
     use tokio::time;
 
-    use super::{EndpointRateLimiter, Limiter, Outcome};
+    use super::{EndpointLimits, EndpointRateLimiter, Limiter, Limits, Outcome, RateLimitError};
     use crate::rate_limiter::{RateBucketInfo, RateLimitAlgorithm};
 
     #[tokio::test]
@@ -707,4 +891,77 @@ mod tests {
         }
         assert!(limiter.map.len() < 150_000);
     }
+
+    #[tokio::test]
+    async fn test_endpoint_limits() {
+        let limiter = Arc::new(EndpointRateLimiter::new(&RateBucketInfo::DEFAULT_SET));
+        let endpoint = SmolStr::from("ep-my-endpoint-1234");
+        let limits = EndpointLimits {
+            endpoint: Limits {
+                connection_rate: Some(vec!["100@1s".parse().unwrap()]),
+                max_connections: Some(2),
+                http_query_rate: Some(vec!["10@1s".parse().unwrap()]),
+            },
+            project: Some((
+                "my-project".into(),
+                Limits {
+                    max_connections: Some(3),
+                    ..Default::default()
+                },
+            )),
+        };
+
+        time::pause();
+
+        // the limit of the control plane applies on top of the 300rps default
+        for _ in 0..100 {
+            assert!(limiter.check(endpoint.clone()));
+            assert!(limiter.check_connection(endpoint.clone(), &limits).is_ok());
+        }
+        assert!(limiter.check(endpoint.clone()));
+        assert_eq!(
+            limiter.check_connection(endpoint.clone(), &limits).err(),
+            Some(RateLimitError::ConnectionRate("endpoint"))
+        );
+        time::advance(time::Duration::from_secs(1)).await;
+
+        let first = limiter.check_connection(endpoint.clone(), &limits).unwrap();
+        let second = limiter.check_connection(endpoint.clone(), &limits).unwrap();
+        assert_eq!(
+            limiter.check_connection(endpoint.clone(), &limits).err(),
+            Some(RateLimitError::ConcurrentConnections {
+                scope: "endpoint",
+                limit: 2
+            })
+        );
+        drop(first);
+        let third = limiter.check_connection(endpoint.clone(), &limits).unwrap();
+
+        // another endpoint of the project
+        let other = SmolStr::from("ep-my-endpoint-5678");
+        let fourth = limiter.check_connection(other.clone(), &limits).unwrap();
+        assert_eq!(
+            limiter.check_connection(other.clone(), &limits).err(),
+            Some(RateLimitError::ConcurrentConnections {
+                scope: "project",
+                limit: 3
+            })
+        );
+        drop((second, third, fourth));
+        assert!(limiter.connections.is_empty());
+
+        for _ in 0..10 {
+            limiter.check_http_query(endpoint.clone(), &limits).unwrap();
+        }
+        assert_eq!(
+            limiter.check_http_query(endpoint.clone(), &limits),
+            Err(RateLimitError::HttpQueryRate("endpoint"))
+        );
+        // queries are not limited by default
+        for _ in 0..100 {
+            limiter
+                .check_http_query(other.clone(), &EndpointLimits::default())
+                .unwrap();
+        }
+    }
 }
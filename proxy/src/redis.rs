@@ -0,0 +1,346 @@
+//! A minimal client of the Redis protocol (RESP2), for the few commands
+//! the proxy needs: `SET`, `GET` and `DEL` of string values.
+//!
+//! Commands run concurrently over a small pool of connections, which are
+//! opened on demand and kept open for the following commands.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::Semaphore,
+};
+use tracing::warn;
+
+/// The most connections a [`Client`] opens to the server at once. Commands
+/// beyond that wait for a connection to become free.
+const MAX_CONNECTIONS: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum RedisError {
+    #[error("redis connection failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("redis returned an error: {0}")]
+    Reply(String),
+
+    #[error("redis request timed out")]
+    Timeout,
+}
+
+/// A reply to a command. Error replies are returned as [`RedisError::Reply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    /// `None` is the nil reply, e.g. of a `GET` of a missing key.
+    Bulk(Option<Bytes>),
+}
+
+pub struct Client {
+    /// `host:port` of the server.
+    addr: String,
+    password: Option<String>,
+    timeout: Duration,
+    /// One permit per connection, in use or idle.
+    connections: Semaphore,
+    /// Connections that are not running a command.
+    idle: std::sync::Mutex<Vec<BufStream<TcpStream>>>,
+}
+
+impl Client {
+    /// Parse a `redis://[:password@]host[:port]` url. Connects lazily, on the first command.
+    pub fn new(url: &str, timeout: Duration) -> anyhow::Result<Self> {
+        let url: ::url::Url = url.parse()?;
+        anyhow::ensure!(
+            url.scheme() == "redis",
+            "unsupported redis url scheme: {}",
+            url.scheme()
+        );
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("redis url has no host"))?;
+        let port = url.port().unwrap_or(6379);
+        Ok(Self {
+            addr: format!("{host}:{port}"),
+            password: url.password().map(|p| p.to_owned()),
+            timeout,
+            connections: Semaphore::new(MAX_CONNECTIONS),
+            idle: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Run a command, e.g. `client.query(&[b"GET", b"key"])`.
+    ///
+    /// The timeout covers waiting for a free connection as well.
+    pub async fn query(&self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        // A connection that times out is dropped rather than returned to the
+        // pool: its reply may still arrive.
+        tokio::time::timeout(self.timeout, self.query_pooled(args))
+            .await
+            .map_err(|_| RedisError::Timeout)?
+    }
+
+    async fn query_pooled(&self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        let _permit = self
+            .connections
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+
+        // An idle connection may have been closed by the server, retry once
+        // with a new one.
+        let idle = self.idle.lock().unwrap().pop();
+        if let Some(mut stream) = idle {
+            match roundtrip(&mut stream, args).await {
+                Ok(reply) => {
+                    self.idle.lock().unwrap().push(stream);
+                    return reply;
+                }
+                Err(e) => warn!(
+                    "redis connection to {} failed, reconnecting: {e}",
+                    self.addr
+                ),
+            }
+        }
+
+        let mut stream = self.connect().await?;
+        let reply = roundtrip(&mut stream, args).await?;
+        self.idle.lock().unwrap().push(stream);
+        reply
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>, RedisError> {
+        let socket = TcpStream::connect(&self.addr).await?;
+        socket.set_nodelay(true)?;
+        let mut stream = BufStream::new(socket);
+        if let Some(password) = &self.password {
+            roundtrip(&mut stream, &[b"AUTH", password.as_bytes()]).await??;
+        }
+        Ok(stream)
+    }
+}
+
+/// Send a command and read its reply. The outer error means the connection is broken.
+async fn roundtrip(
+    stream: &mut BufStream<TcpStream>,
+    args: &[&[u8]],
+) -> std::io::Result<Result<Reply, RedisError>> {
+    stream.write_all(&encode_command(args)).await?;
+    stream.flush().await?;
+    read_reply(stream).await
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+/// Read one reply. Arrays are not supported, none of the commands we use return them.
+async fn read_reply<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Result<Reply, RedisError>> {
+    let line = read_line(reader).await?;
+    let mut chars = line.chars();
+    let kind = chars.next();
+    let rest = chars.as_str();
+    Ok(match kind {
+        Some('+') => Ok(Reply::Status(rest.to_owned())),
+        Some('-') => Err(RedisError::Reply(rest.to_owned())),
+        Some(':') => Ok(Reply::Integer(parse_int(rest)?)),
+        Some('$') => {
+            let len = parse_int(rest)?;
+            if len < 0 {
+                Ok(Reply::Bulk(None))
+            } else {
+                let mut buf = vec![0; len as usize + 2];
+                reader.read_exact(&mut buf).await?;
+                if !buf.ends_with(b"\r\n") {
+                    return Err(invalid_data("bulk string is not terminated"));
+                }
+                buf.truncate(len as usize);
+                Ok(Reply::Bulk(Some(Bytes::from(buf))))
+            }
+        }
+        _ => return Err(invalid_data(format!("unsupported reply: {line:?}"))),
+    })
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    reader.read_until(b'\n', &mut buf).await?;
+    if buf.len() < 3 || !buf.ends_with(b"\r\n") {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    buf.truncate(buf.len() - 2);
+    String::from_utf8(buf).map_err(invalid_data)
+}
+
+fn parse_int(s: &str) -> std::io::Result<i64> {
+    s.parse().map_err(invalid_data)
+}
+
+fn invalid_data<E>(e: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    /// A stand-in for a Redis server, that serves `SET` (ignoring expiry), `GET` and `DEL`.
+    /// Returns its url.
+    pub(crate) async fn spawn_fake_redis() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = Arc::new(std::sync::Mutex::new(HashMap::<Bytes, Bytes>::new()));
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_fake_redis(socket, Arc::clone(&data)));
+            }
+        });
+        format!("redis://{addr}")
+    }
+
+    async fn serve_fake_redis(
+        socket: TcpStream,
+        data: Arc<std::sync::Mutex<HashMap<Bytes, Bytes>>>,
+    ) -> std::io::Result<()> {
+        let mut stream = BufStream::new(socket);
+        loop {
+            let header = read_line(&mut stream).await?;
+            let count: usize = header
+                .trim_start_matches('*')
+                .parse()
+                .map_err(invalid_data)?;
+            let mut args = Vec::with_capacity(count);
+            for _ in 0..count {
+                match read_reply(&mut stream).await? {
+                    Ok(Reply::Bulk(Some(arg))) => args.push(arg),
+                    other => return Err(invalid_data(format!("bad argument: {other:?}"))),
+                }
+            }
+            let reply: &[u8] = &match (&args[0][..], &args[1..]) {
+                (b"SET", [key, value, ..]) => {
+                    data.lock().unwrap().insert(key.clone(), value.clone());
+                    b"+OK\r\n".to_vec()
+                }
+                (b"GET", [key]) => match data.lock().unwrap().get(key) {
+                    Some(value) => {
+                        let mut reply = format!("${}\r\n", value.len()).into_bytes();
+                        reply.extend_from_slice(value);
+                        reply.extend_from_slice(b"\r\n");
+                        reply
+                    }
+                    None => b"$-1\r\n".to_vec(),
+                },
+                (b"DEL", keys) => {
+                    let mut data = data.lock().unwrap();
+                    let removed = keys.iter().filter(|k| data.remove(*k).is_some()).count();
+                    format!(":{removed}\r\n").into_bytes()
+                }
+                _ => b"-ERR unknown command\r\n".to_vec(),
+            };
+            stream.write_all(reply).await?;
+            stream.flush().await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn read_replies() {
+        let input: &[u8] = b"+OK\r\n:42\r\n$5\r\nhello\r\n$-1\r\n-ERR wrong type\r\n";
+        let mut reader = BufReader::new(input);
+        assert_eq!(
+            read_reply(&mut reader).await.unwrap().unwrap(),
+            Reply::Status("OK".to_owned())
+        );
+        assert_eq!(
+            read_reply(&mut reader).await.unwrap().unwrap(),
+            Reply::Integer(42)
+        );
+        assert_eq!(
+            read_reply(&mut reader).await.unwrap().unwrap(),
+            Reply::Bulk(Some(Bytes::from_static(b"hello")))
+        );
+        assert_eq!(
+            read_reply(&mut reader).await.unwrap().unwrap(),
+            Reply::Bulk(None)
+        );
+        assert!(matches!(
+            read_reply(&mut reader).await.unwrap(),
+            Err(RedisError::Reply(msg)) if msg == "ERR wrong type"
+        ));
+        assert!(read_reply(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn set_get_del() {
+        let url = spawn_fake_redis().await;
+        let client = Client::new(&url, Duration::from_secs(5)).unwrap();
+
+        assert_eq!(
+            client.query(&[b"GET", b"key"]).await.unwrap(),
+            Reply::Bulk(None)
+        );
+        assert_eq!(
+            client.query(&[b"SET", b"key", b"value"]).await.unwrap(),
+            Reply::Status("OK".to_owned())
+        );
+        assert_eq!(
+            client.query(&[b"GET", b"key"]).await.unwrap(),
+            Reply::Bulk(Some(Bytes::from_static(b"value")))
+        );
+        assert_eq!(
+            client.query(&[b"DEL", b"key"]).await.unwrap(),
+            Reply::Integer(1)
+        );
+        assert!(matches!(
+            client.query(&[b"PING"]).await,
+            Err(RedisError::Reply(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_queries() {
+        let url = spawn_fake_redis().await;
+        let client = Arc::new(Client::new(&url, Duration::from_secs(5)).unwrap());
+
+        let tasks = (0..2 * MAX_CONNECTIONS)
+            .map(|i| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move {
+                    let key = format!("key{i}");
+                    client
+                        .query(&[b"SET", key.as_bytes(), key.as_bytes()])
+                        .await
+                        .unwrap();
+                    client.query(&[b"GET", key.as_bytes()]).await.unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(
+                task.await.unwrap(),
+                Reply::Bulk(Some(Bytes::from(format!("key{i}"))))
+            );
+        }
+        assert!(client.idle.lock().unwrap().len() <= MAX_CONNECTIONS);
+    }
+}
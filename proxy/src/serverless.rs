@@ -7,6 +7,7 @@ mod sql_over_http;
 mod websocket;
 
 pub use conn_pool::GlobalConnPoolOptions;
+use sql_over_http::HttpTransactions;
 
 use anyhow::bail;
 use hyper::StatusCode;
@@ -56,6 +57,14 @@ pub async fn task_main(
         conn_pool2.gc_worker(StdRng::from_entropy()).await;
     });
 
+    let transactions = HttpTransactions::new(config.http_config.transaction_idle_timeout);
+    tokio::spawn({
+        let transactions = Arc::clone(&transactions);
+        async move {
+            transactions.gc_worker().await;
+        }
+    });
+
     // shutdown the connection pool
     tokio::spawn({
         let cancellation_token = cancellation_token.clone();
@@ -102,6 +111,7 @@ pub async fn task_main(
             let remote_addr = io.inner.remote_addr();
             let sni_name = tls.server_name().map(|s| s.to_string());
             let conn_pool = conn_pool.clone();
+            let transactions = transactions.clone();
             let ws_connections = ws_connections.clone();
             let endpoint_rate_limiter = endpoint_rate_limiter.clone();
 
@@ -115,6 +125,7 @@ pub async fn task_main(
                     move |req: Request<Body>| {
                         let sni_name = sni_name.clone();
                         let conn_pool = conn_pool.clone();
+                        let transactions = transactions.clone();
                         let ws_connections = ws_connections.clone();
                         let endpoint_rate_limiter = endpoint_rate_limiter.clone();
 
@@ -126,6 +137,7 @@ pub async fn task_main(
                                 req,
                                 config,
                                 conn_pool,
+                                transactions,
                                 ws_connections,
                                 cancel_map,
                                 session_id,
@@ -195,6 +207,7 @@ async fn request_handler(
     mut request: Request<Body>,
     config: &'static ProxyConfig,
     conn_pool: Arc<conn_pool::GlobalConnPool>,
+    transactions: Arc<HttpTransactions>,
     ws_connections: TaskTracker,
     cancel_map: Arc<CancelMap>,
     session_id: uuid::Uuid,
@@ -245,6 +258,8 @@ async fn request_handler(
             session_id,
             peer_addr,
             &config.http_config,
+            transactions,
+            endpoint_rate_limiter,
         )
         .await
     } else if request.uri().path() == "/sql" && request.method() == Method::OPTIONS {
@@ -253,7 +268,7 @@ async fn request_handler(
             .header("Access-Control-Allow-Origin", "*")
             .header(
                 "Access-Control-Allow-Headers",
-                "Neon-Connection-String, Neon-Raw-Text-Output, Neon-Array-Mode, Neon-Pool-Opt-In, Neon-Batch-Read-Only, Neon-Batch-Isolation-Level",
+                "Neon-Connection-String, Neon-Raw-Text-Output, Neon-Array-Mode, Neon-Pool-Opt-In, Neon-Batch-Read-Only, Neon-Batch-Isolation-Level, Neon-Response-Format, Neon-Transaction, Neon-Transaction-End, Authorization",
             )
             .header("Access-Control-Max-Age", "86400" /* 24 hours */)
             .status(StatusCode::OK) // 204 is also valid, but see: https://developer.mozilla.org/en-US/docs/Web/HTTP/Methods/OPTIONS#status_code
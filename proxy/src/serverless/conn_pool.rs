@@ -26,10 +26,14 @@ use tokio::time::{self, Instant};
 use tokio_postgres::{AsyncMessage, ReadyForQueryStatus};
 
 use crate::{
-    auth::{self, backend::ComputeUserInfo, check_peer_addr_is_in_list},
+    auth::{
+        self, backend::ComputeUserInfo, check_peer_addr_is_in_list,
+        check_vpc_endpoint_id_is_in_list,
+    },
     console,
     metrics::{LatencyTimer, NUM_DB_CONNECTIONS_GAUGE},
     proxy::{connect_compute::ConnectMechanism, neon_options},
+    rate_limiter::EndpointRateLimiter,
     usage_metrics::{Ids, MetricCounter, USAGE_METRICS},
 };
 use crate::{compute, config};
@@ -39,15 +43,22 @@ use tracing::{info, info_span, Instrument};
 
 pub const APP_NAME: &str = "/sql_over_http";
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ConnInfo {
     pub username: SmolStr,
     pub dbname: SmolStr,
     pub hostname: SmolStr,
-    pub password: SmolStr,
+    pub auth: AuthData,
     pub options: Option<SmolStr>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthData {
+    Password(SmolStr),
+    /// A JWT of one of the identity providers of the endpoint.
+    Jwt(SmolStr),
+}
+
 impl ConnInfo {
     // hm, change to hasher to avoid cloning?
     pub fn db_and_user(&self) -> (SmolStr, SmolStr) {
@@ -317,6 +328,16 @@ impl GlobalConnPool {
         let mut client: Option<ClientInner> = None;
         let mut latency_timer = LatencyTimer::new("http");
 
+        // Unlike passwords, the proxy checks JWTs itself. Only a valid token
+        // can get a pooled connection of the role.
+        let password = match &conn_info.auth {
+            AuthData::Password(password) => Some(password.clone()),
+            AuthData::Jwt(jwt) => {
+                check_jwt(self.proxy_config, &conn_info, jwt, peer_addr).await?;
+                None
+            }
+        };
+
         let mut hash_valid = false;
         let mut endpoint_pool = Weak::new();
         if !force_new {
@@ -334,22 +355,31 @@ impl GlobalConnPool {
                 }
             }
 
-            // a connection exists in the pool, verify the password hash
-            if let Some(hash) = hash {
-                let pw = conn_info.password.clone();
-                let validate = tokio::task::spawn_blocking(move || {
-                    Pbkdf2.verify_password(pw.as_bytes(), &hash.password_hash())
-                })
-                .await?;
-
-                // if the hash is invalid, don't error
-                // we will continue with the regular connection flow
-                if validate.is_ok() {
-                    hash_valid = true;
+            match (&password, hash) {
+                // the token was checked already
+                (None, _) => {
                     if let Some(entry) = pool.write().get_conn_entry(conn_info.db_and_user()) {
                         client = Some(entry.conn)
                     }
                 }
+                // a connection exists in the pool, verify the password hash
+                (Some(pw), Some(hash)) => {
+                    let pw = pw.clone();
+                    let validate = tokio::task::spawn_blocking(move || {
+                        Pbkdf2.verify_password(pw.as_bytes(), &hash.password_hash())
+                    })
+                    .await?;
+
+                    // if the hash is invalid, don't error
+                    // we will continue with the regular connection flow
+                    if validate.is_ok() {
+                        hash_valid = true;
+                        if let Some(entry) = pool.write().get_conn_entry(conn_info.db_and_user()) {
+                            client = Some(entry.conn)
+                        }
+                    }
+                }
+                (Some(_), None) => {}
             }
         }
 
@@ -400,10 +430,10 @@ impl GlobalConnPool {
             );
         }
 
-        match &new_client {
+        match (&new_client, password) {
             // clear the hash. it's no longer valid
             // TODO: update tokio-postgres fork to allow access to this error kind directly
-            Err(err)
+            (Err(err), _)
                 if hash_valid && err.to_string().contains("password authentication failed") =>
             {
                 let pool = self.get_or_create_endpoint_pool(&conn_info.hostname);
@@ -413,8 +443,7 @@ impl GlobalConnPool {
                 }
             }
             // new password is valid and we should insert/update it
-            Ok(_) if !force_new && !hash_valid => {
-                let pw = conn_info.password.clone();
+            (Ok(_), Some(pw)) if !force_new && !hash_valid => {
                 let new_hash = tokio::task::spawn_blocking(move || {
                     let salt = SaltString::generate(rand::rngs::OsRng);
                     Pbkdf2
@@ -436,6 +465,22 @@ impl GlobalConnPool {
         Ok(Client::new(new_client, conn_info, endpoint_pool).await)
     }
 
+    /// Check the query rate limits of the endpoint, if the control plane has set any.
+    /// Done after [`Self::get`], so that they come with the auth info it fetched.
+    pub async fn check_query_rate(
+        &self,
+        conn_info: &ConnInfo,
+        peer_addr: IpAddr,
+        endpoint_rate_limiter: &EndpointRateLimiter,
+    ) -> anyhow::Result<()> {
+        let (backend, extra) = console_backend(self.proxy_config, conn_info, peer_addr)?;
+        let limits = backend.get_endpoint_limits(&extra).await?;
+        if let Some(info) = backend.get_user_info() {
+            endpoint_rate_limiter.check_http_query(info.endpoint.clone(), &limits)?;
+        }
+        Ok(())
+    }
+
     fn get_or_create_endpoint_pool(&self, endpoint: &SmolStr) -> Arc<RwLock<EndpointConnPool>> {
         // fast path
         if let Some(pool) = self.global_pool.get(endpoint) {
@@ -483,6 +528,8 @@ impl GlobalConnPool {
 struct TokioMechanism<'a> {
     pool: Weak<RwLock<EndpointConnPool>>,
     conn_info: &'a ConnInfo,
+    /// See [`compute_password`].
+    password: &'a [u8],
     session_id: uuid::Uuid,
     conn_id: uuid::Uuid,
     idle: Duration,
@@ -502,6 +549,7 @@ impl ConnectMechanism for TokioMechanism<'_> {
         connect_to_compute_once(
             node_info,
             self.conn_info,
+            self.password,
             timeout,
             self.conn_id,
             self.session_id,
@@ -527,6 +575,52 @@ async fn connect_to_compute(
     peer_addr: IpAddr,
     pool: Weak<RwLock<EndpointConnPool>>,
 ) -> anyhow::Result<ClientInner> {
+    let (backend, extra) = console_backend(config, conn_info, peer_addr)?;
+    if !config.disable_ip_check_for_http {
+        let allowed_ips = backend.get_allowed_ips(&extra).await?;
+        if !check_peer_addr_is_in_list(&peer_addr, &allowed_ips) {
+            return Err(auth::AuthError::ip_address_not_allowed().into());
+        }
+    }
+    // Private link info is not carried over HTTP connections yet, so endpoints
+    // restricted to private links reject SQL-over-HTTP requests.
+    let allowed_vpc_endpoint_ids = backend.get_allowed_vpc_endpoint_ids(&extra).await?;
+    if !check_vpc_endpoint_id_is_in_list(None, &allowed_vpc_endpoint_ids) {
+        return Err(auth::AuthError::vpc_endpoint_id_not_allowed().into());
+    }
+    let password = compute_password(&backend, &extra, conn_info).await?;
+    let node_info = backend
+        .wake_compute(&extra)
+        .await?
+        .context("missing cache entry from wake_compute")?;
+
+    crate::proxy::connect_compute::connect_to_compute(
+        &TokioMechanism {
+            conn_id,
+            conn_info,
+            password: password.as_bytes(),
+            session_id,
+            pool,
+            idle: config.http_config.pool_options.idle_timeout,
+        },
+        node_info,
+        &extra,
+        &backend,
+        latency_timer,
+    )
+    .await
+}
+
+/// Prepare the structures that the console requests for the endpoint of the
+/// connection string expect.
+fn console_backend<'a>(
+    config: &'a config::ProxyConfig,
+    conn_info: &ConnInfo,
+    peer_addr: IpAddr,
+) -> anyhow::Result<(
+    auth::BackendType<'a, ComputeUserInfo>,
+    console::ConsoleReqExtra,
+)> {
     let tls = config.tls_config.as_ref();
     let common_names = tls.and_then(|tls| tls.common_names.clone());
 
@@ -554,36 +648,45 @@ async fn connect_to_compute(
         application_name: APP_NAME.to_string(),
         options: console_options,
     };
-    if !config.disable_ip_check_for_http {
-        let allowed_ips = backend.get_allowed_ips(&extra).await?;
-        if !check_peer_addr_is_in_list(&peer_addr, &allowed_ips) {
-            return Err(auth::AuthError::ip_address_not_allowed().into());
-        }
-    }
-    let node_info = backend
-        .wake_compute(&extra)
-        .await?
-        .context("missing cache entry from wake_compute")?;
+    Ok((backend, extra))
+}
 
-    crate::proxy::connect_compute::connect_to_compute(
-        &TokioMechanism {
-            conn_id,
-            conn_info,
-            session_id,
-            pool,
-            idle: config.http_config.pool_options.idle_timeout,
-        },
-        node_info,
-        &extra,
-        &backend,
-        latency_timer,
-    )
-    .await
+/// Check the JWT against the identity providers of the endpoint.
+async fn check_jwt(
+    config: &config::ProxyConfig,
+    conn_info: &ConnInfo,
+    jwt: &str,
+    peer_addr: IpAddr,
+) -> anyhow::Result<()> {
+    let (backend, extra) = console_backend(config, conn_info, peer_addr)?;
+    let rules = backend.get_endpoint_jwks(&extra).await?;
+    config
+        .authentication_config
+        .jwks_cache
+        .check_jwt(jwt, &rules, &conn_info.username)
+        .await
+        .map_err(auth::AuthError::from)?;
+    Ok(())
+}
+
+/// The password to log in to the compute with. JWTs are only checked by the
+/// proxy, the compute gets a password issued by the console for the role.
+async fn compute_password(
+    backend: &auth::BackendType<'_, ComputeUserInfo>,
+    extra: &console::ConsoleReqExtra,
+    conn_info: &ConnInfo,
+) -> Result<SmolStr, console::errors::GetAuthInfoError> {
+    match &conn_info.auth {
+        AuthData::Password(password) => Ok(password.clone()),
+        AuthData::Jwt(_) => backend.get_role_password(extra).await,
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn connect_to_compute_once(
     node_info: &console::CachedNodeInfo,
     conn_info: &ConnInfo,
+    password: &[u8],
     timeout: time::Duration,
     conn_id: uuid::Uuid,
     mut session: uuid::Uuid,
@@ -594,7 +697,7 @@ async fn connect_to_compute_once(
 
     let (client, mut connection) = config
         .user(&conn_info.username)
-        .password(&*conn_info.password)
+        .password(password)
         .dbname(&conn_info.dbname)
         .connect_timeout(timeout)
         .connect(tokio_postgres::NoTls)
@@ -802,3 +905,114 @@ impl Drop for Client {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::backend::{AuthRule, TestBackend},
+        console::{
+            errors::{GetAuthInfoError, WakeComputeError},
+            CachedNodeInfo, NodeInfo,
+        },
+        stream::PqStream,
+    };
+    use pq_proto::{BeMessage as Be, FeStartupPacket};
+    use tokio::net::TcpListener;
+    use tokio_postgres::config::SslMode;
+
+    /// Issues the same password for every role.
+    struct RolePassword(&'static str);
+
+    impl TestBackend for RolePassword {
+        fn wake_compute(&self) -> Result<CachedNodeInfo, WakeComputeError> {
+            unimplemented!("not used in tests")
+        }
+
+        fn get_allowed_ips(&self) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+            unimplemented!("not used in tests")
+        }
+
+        fn get_allowed_vpc_endpoint_ids(&self) -> Result<Arc<Vec<String>>, GetAuthInfoError> {
+            unimplemented!("not used in tests")
+        }
+
+        fn get_endpoint_jwks(&self) -> Result<Arc<Vec<AuthRule>>, GetAuthInfoError> {
+            unimplemented!("not used in tests")
+        }
+
+        fn get_role_password(&self) -> Result<SmolStr, GetAuthInfoError> {
+            Ok(self.0.into())
+        }
+    }
+
+    /// Accept one connection which logs in with a cleartext password, and
+    /// return the password it sent.
+    async fn fake_compute(listener: TcpListener) -> anyhow::Result<bytes::Bytes> {
+        let (socket, _) = listener.accept().await?;
+        let mut stream = PqStream::new(socket);
+        match stream.read_startup_packet().await? {
+            FeStartupPacket::StartupMessage { .. } => {}
+            msg => anyhow::bail!("unexpected message {msg:?}"),
+        }
+        stream
+            .write_message(&Be::AuthenticationCleartextPassword)
+            .await?;
+        let password = stream.read_password_message().await?;
+        stream
+            .write_message_noflush(&Be::AuthenticationOk)?
+            .write_message(&Be::ReadyForQuery)
+            .await?;
+        Ok(password)
+    }
+
+    #[tokio::test]
+    async fn jwt_login_uses_role_password() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let compute = tokio::spawn(fake_compute(listener));
+
+        let mut config = compute::ConnCfg::new();
+        config
+            .host(&addr.ip().to_string())
+            .port(addr.port())
+            .ssl_mode(SslMode::Disable);
+        let node_info = CachedNodeInfo::new_uncached(NodeInfo {
+            config,
+            aux: Default::default(),
+            allow_self_signed_compute: false,
+        });
+
+        let conn_info = ConnInfo {
+            username: "alice".into(),
+            dbname: "db".into(),
+            hostname: "ep-foo.localhost".into(),
+            auth: AuthData::Jwt("eyJ.eyJ.sig".into()),
+            options: None,
+        };
+        let role_password = RolePassword("issued by the console");
+        let backend = auth::BackendType::Test(&role_password);
+        let extra = console::ConsoleReqExtra {
+            session_id: uuid::Uuid::new_v4(),
+            application_name: APP_NAME.to_string(),
+            options: vec![],
+        };
+
+        let password = compute_password(&backend, &extra, &conn_info).await?;
+        connect_to_compute_once(
+            &node_info,
+            &conn_info,
+            password.as_bytes(),
+            Duration::from_secs(5),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            Weak::new(),
+            Duration::from_secs(60),
+        )
+        .await?;
+
+        // The token itself never reaches the compute.
+        assert_eq!(&compute.await??[..], b"issued by the console\0");
+        Ok(())
+    }
+}
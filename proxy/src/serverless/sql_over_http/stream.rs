@@ -0,0 +1,526 @@
+//! Streaming responses for SQL over HTTP.
+//!
+//! Instead of collecting the whole result into a single JSON document, rows
+//! are encoded and written to the response body as they come from the compute
+//! node. The format is picked with the `Neon-Response-Format` header:
+//!
+//! * `ndjson`: newline-delimited JSON. The first line holds the `fields` of the
+//!   result, then every row is a line of its own, formatted like in the JSON
+//!   response, and the last line holds the `command` and the `rowCount`.
+//! * `arrow`: an Arrow IPC stream, with a record batch per chunk of rows.
+//!   Booleans, integers and floats are typed, everything else is passed as text.
+//!
+//! A failure in the middle of the stream aborts the response body, so that
+//! clients can't mistake a truncated result for a complete one. So does a
+//! stream that stops making progress: the request timeout doesn't apply to
+//! the body, which may take long to send, but no row arriving from the compute
+//! node or the client not reading the body for the idle timeout does.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use arrow_array::builder::{
+    BooleanBuilder, Float32Builder, Float64Builder, Int16Builder, Int32Builder, Int64Builder,
+    StringBuilder,
+};
+use arrow_array::{ArrayRef, RecordBatch, RecordBatchOptions};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use bytes::{Bytes, BytesMut};
+use futures::{pin_mut, StreamExt};
+use hyper::body::Sender;
+use hyper::header;
+use hyper::http::HeaderValue;
+use hyper::{Body, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio_postgres::types::Type;
+use tokio_postgres::{Column, GenericClient, ReadyForQueryStatus, Row, RowStream};
+use tracing::{error, Instrument};
+
+use super::{field_to_json, json_to_pg_text, parse_command_tag, pg_text_row_to_json, QueryData};
+use crate::serverless::conn_pool::Client;
+use crate::usage_metrics::MetricCounter;
+
+/// Send the body in chunks of about this size, rather than a chunk per row.
+const CHUNK_SIZE: usize = 64 * 1024; // 64 KiB
+/// Limits of a single Arrow record batch.
+const ARROW_BATCH_ROWS: usize = 1024;
+const ARROW_BATCH_SIZE: usize = 1024 * 1024; // 1 MiB
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// The whole result in a single JSON document.
+    Json,
+    Stream(StreamFormat),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    NdJson,
+    Arrow,
+}
+
+impl ResponseFormat {
+    pub fn from_header(value: Option<&HeaderValue>) -> anyhow::Result<Self> {
+        match value.map(HeaderValue::as_bytes) {
+            None | Some(b"json") => Ok(Self::Json),
+            Some(b"ndjson") => Ok(Self::Stream(StreamFormat::NdJson)),
+            Some(b"arrow") => Ok(Self::Stream(StreamFormat::Arrow)),
+            Some(_) => bail!("invalid response format"),
+        }
+    }
+}
+
+impl StreamFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::NdJson => "application/x-ndjson",
+            StreamFormat::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+}
+
+/// Run the query and respond with a body that the rows are streamed into.
+///
+/// Errors that happen before the first row, e.g. in the query itself, are
+/// returned as usual. If no row arrives or the client doesn't read any of the
+/// body for `idle_timeout`, the remaining rows are dropped along with the
+/// connection.
+pub async fn query_to_stream(
+    mut client: Client,
+    data: QueryData,
+    format: StreamFormat,
+    raw_output: bool,
+    array_mode: bool,
+    idle_timeout: Duration,
+) -> anyhow::Result<Response<Body>> {
+    let (row_stream, types) = match start_query(&*client, data).await {
+        Ok(res) => res,
+        Err(e) => {
+            client.discard();
+            return Err(e);
+        }
+    };
+    let encoder = RowEncoder::new(format, row_stream.columns(), types, raw_output, array_mode)?;
+
+    let (sender, body) = Body::channel();
+    tokio::spawn(send_rows(client, row_stream, encoder, sender, idle_timeout).in_current_span());
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(body)
+        // only fails if invalid status code or invalid header/values are given.
+        // these are not user configurable so it cannot fail dynamically
+        .expect("building response payload should not fail");
+    Ok(response)
+}
+
+async fn start_query<T: GenericClient>(
+    client: &T,
+    data: QueryData,
+) -> anyhow::Result<(RowStream, Vec<Type>)> {
+    let query_params = json_to_pg_text(data.params);
+    let row_stream = client.query_raw_txt(&data.query, query_params).await?;
+
+    let mut types = Vec::with_capacity(row_stream.columns().len());
+    for c in row_stream.columns() {
+        types.push(client.get_type(c.type_oid()).await?);
+    }
+    Ok((row_stream, types))
+}
+
+async fn send_rows(
+    mut client: Client,
+    row_stream: RowStream,
+    encoder: RowEncoder,
+    mut sender: Sender,
+    idle_timeout: Duration,
+) {
+    let metrics = client.metrics();
+    let e = match encode_rows(row_stream, encoder, &mut sender, &metrics, idle_timeout).await {
+        Ok(status) => {
+            client.check_idle(status);
+            return;
+        }
+        Err(e) => e,
+    };
+    // the rest of the result is still on its way, so the connection
+    // can't be reused.
+    client.discard();
+    error!("sql-over-http response stream finished with an error: {e:#}");
+    sender.abort();
+}
+
+async fn encode_rows(
+    row_stream: RowStream,
+    mut encoder: RowEncoder,
+    sender: &mut Sender,
+    metrics: &MetricCounter,
+    idle_timeout: Duration,
+) -> anyhow::Result<ReadyForQueryStatus> {
+    pin_mut!(row_stream);
+
+    let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+    encoder.begin(&mut buf)?;
+    while let Some(row) = with_idle_timeout(idle_timeout, row_stream.next()).await? {
+        encoder.push_row(&row?, &mut buf)?;
+        if buf.len() >= CHUNK_SIZE {
+            with_idle_timeout(idle_timeout, send_chunk(sender, &mut buf, metrics)).await??;
+        }
+    }
+
+    let command_tag = row_stream.command_tag().unwrap_or_default();
+    let (command_tag_name, command_tag_count) = parse_command_tag(&command_tag);
+    encoder.finish(command_tag_name, command_tag_count, &mut buf)?;
+    with_idle_timeout(idle_timeout, send_chunk(sender, &mut buf, metrics)).await??;
+
+    Ok(row_stream.ready_status())
+}
+
+/// Fails if `fut`, a step of the stream, doesn't complete within `idle_timeout`:
+/// e.g. a client that doesn't read the body, holding on to the connection.
+async fn with_idle_timeout<F: Future>(idle_timeout: Duration, fut: F) -> anyhow::Result<F::Output> {
+    tokio::time::timeout(idle_timeout, fut)
+        .await
+        .map_err(|_| anyhow::anyhow!("response stream made no progress for {idle_timeout:?}"))
+}
+
+async fn send_chunk(
+    sender: &mut Sender,
+    buf: &mut BytesMut,
+    metrics: &MetricCounter,
+) -> anyhow::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let chunk: Bytes = buf.split().freeze();
+    // count the egress bytes - we miss the TLS and header overhead but oh well...
+    metrics.record_egress(chunk.len() as u64);
+    sender.send_data(chunk).await?;
+    Ok(())
+}
+
+enum RowEncoder {
+    NdJson(NdJsonEncoder),
+    Arrow(ArrowEncoder),
+}
+
+impl RowEncoder {
+    fn new(
+        format: StreamFormat,
+        columns: &[Column],
+        types: Vec<Type>,
+        raw_output: bool,
+        array_mode: bool,
+    ) -> anyhow::Result<Self> {
+        match format {
+            StreamFormat::NdJson => Ok(Self::NdJson(NdJsonEncoder {
+                fields: columns.iter().map(field_to_json).collect(),
+                types,
+                raw_output,
+                array_mode,
+            })),
+            StreamFormat::Arrow => Ok(Self::Arrow(ArrowEncoder::new(columns, &types, raw_output)?)),
+        }
+    }
+
+    fn begin(&mut self, buf: &mut BytesMut) -> anyhow::Result<()> {
+        match self {
+            Self::NdJson(e) => e.begin(buf),
+            Self::Arrow(e) => e.begin(buf),
+        }
+    }
+
+    fn push_row(&mut self, row: &Row, buf: &mut BytesMut) -> anyhow::Result<()> {
+        match self {
+            Self::NdJson(e) => e.push_row(row, buf),
+            Self::Arrow(e) => e.push_row(row, buf),
+        }
+    }
+
+    fn finish(
+        &mut self,
+        command: &str,
+        row_count: Option<i64>,
+        buf: &mut BytesMut,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::NdJson(e) => e.finish(command, row_count, buf),
+            Self::Arrow(e) => e.finish(buf),
+        }
+    }
+}
+
+struct NdJsonEncoder {
+    fields: Vec<Value>,
+    types: Vec<Type>,
+    raw_output: bool,
+    array_mode: bool,
+}
+
+impl NdJsonEncoder {
+    fn begin(&mut self, buf: &mut BytesMut) -> anyhow::Result<()> {
+        let fields = std::mem::take(&mut self.fields);
+        write_line(
+            buf,
+            &json!({ "fields": fields, "rowAsArray": self.array_mode }),
+        )
+    }
+
+    fn push_row(&mut self, row: &Row, buf: &mut BytesMut) -> anyhow::Result<()> {
+        let row = pg_text_row_to_json(row, &self.types, self.raw_output, self.array_mode)?;
+        write_line(buf, &row)
+    }
+
+    fn finish(
+        &mut self,
+        command: &str,
+        row_count: Option<i64>,
+        buf: &mut BytesMut,
+    ) -> anyhow::Result<()> {
+        write_line(buf, &json!({ "command": command, "rowCount": row_count }))
+    }
+}
+
+fn write_line(buf: &mut BytesMut, value: &Value) -> anyhow::Result<()> {
+    buf.extend_from_slice(&serde_json::to_vec(value)?);
+    buf.extend_from_slice(b"\n");
+    Ok(())
+}
+
+struct ArrowEncoder {
+    schema: SchemaRef,
+    columns: Vec<ColumnBuilder>,
+    writer: StreamWriter<Vec<u8>>,
+    /// Rows and their size in the batch being built.
+    rows: usize,
+    size: usize,
+}
+
+impl ArrowEncoder {
+    fn new(columns: &[Column], types: &[Type], raw_output: bool) -> anyhow::Result<Self> {
+        let builders: Vec<_> = types
+            .iter()
+            .map(|typ| ColumnBuilder::new(if raw_output { &Type::TEXT } else { typ }))
+            .collect();
+        let fields: Vec<_> = columns
+            .iter()
+            .zip(&builders)
+            .map(|(c, b)| Field::new(c.name(), b.data_type(), true))
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        // writes out the schema message right away
+        let writer = StreamWriter::try_new(Vec::new(), &schema)?;
+
+        Ok(Self {
+            schema,
+            columns: builders,
+            writer,
+            rows: 0,
+            size: 0,
+        })
+    }
+
+    fn begin(&mut self, buf: &mut BytesMut) -> anyhow::Result<()> {
+        self.drain(buf);
+        Ok(())
+    }
+
+    fn push_row(&mut self, row: &Row, buf: &mut BytesMut) -> anyhow::Result<()> {
+        for (i, column) in self.columns.iter_mut().enumerate() {
+            column.append(row.as_text(i)?)?;
+        }
+        self.rows += 1;
+        self.size += row.body_len();
+        if self.rows >= ARROW_BATCH_ROWS || self.size >= ARROW_BATCH_SIZE {
+            self.write_batch(buf)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, buf: &mut BytesMut) -> anyhow::Result<()> {
+        self.write_batch(buf)?;
+        self.writer.finish()?;
+        self.drain(buf);
+        Ok(())
+    }
+
+    fn write_batch(&mut self, buf: &mut BytesMut) -> anyhow::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let arrays = self.columns.iter_mut().map(ColumnBuilder::finish).collect();
+        // the row count is needed for results without columns
+        let options = RecordBatchOptions::new().with_row_count(Some(self.rows));
+        let batch = RecordBatch::try_new_with_options(self.schema.clone(), arrays, &options)?;
+        self.writer.write(&batch)?;
+        self.rows = 0;
+        self.size = 0;
+        self.drain(buf);
+        Ok(())
+    }
+
+    fn drain(&mut self, buf: &mut BytesMut) {
+        let written = self.writer.get_mut();
+        buf.extend_from_slice(written);
+        written.clear();
+    }
+}
+
+/// Builds an Arrow array of a column from the text-encoded postgres values.
+enum ColumnBuilder {
+    Bool(BooleanBuilder),
+    Int16(Int16Builder),
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    Float32(Float32Builder),
+    Float64(Float64Builder),
+    Text(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(pg_type: &Type) -> Self {
+        match *pg_type {
+            Type::BOOL => Self::Bool(BooleanBuilder::new()),
+            Type::INT2 => Self::Int16(Int16Builder::new()),
+            Type::INT4 => Self::Int32(Int32Builder::new()),
+            Type::INT8 => Self::Int64(Int64Builder::new()),
+            Type::FLOAT4 => Self::Float32(Float32Builder::new()),
+            Type::FLOAT8 => Self::Float64(Float64Builder::new()),
+            _ => Self::Text(StringBuilder::new()),
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            Self::Bool(_) => DataType::Boolean,
+            Self::Int16(_) => DataType::Int16,
+            Self::Int32(_) => DataType::Int32,
+            Self::Int64(_) => DataType::Int64,
+            Self::Float32(_) => DataType::Float32,
+            Self::Float64(_) => DataType::Float64,
+            Self::Text(_) => DataType::Utf8,
+        }
+    }
+
+    fn append(&mut self, value: Option<&str>) -> anyhow::Result<()> {
+        // `NaN`, `Infinity` and `-Infinity` parse as floats just fine
+        match self {
+            Self::Bool(b) => b.append_option(value.map(|v| v == "t")),
+            Self::Int16(b) => b.append_option(value.map(str::parse).transpose()?),
+            Self::Int32(b) => b.append_option(value.map(str::parse).transpose()?),
+            Self::Int64(b) => b.append_option(value.map(str::parse).transpose()?),
+            Self::Float32(b) => b.append_option(value.map(str::parse).transpose()?),
+            Self::Float64(b) => b.append_option(value.map(str::parse).transpose()?),
+            Self::Text(b) => b.append_option(value),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Self::Bool(b) => Arc::new(b.finish()),
+            Self::Int16(b) => Arc::new(b.finish()),
+            Self::Int32(b) => Arc::new(b.finish()),
+            Self::Int64(b) => Arc::new(b.finish()),
+            Self::Float32(b) => Arc::new(b.finish()),
+            Self::Float64(b) => Arc::new(b.finish()),
+            Self::Text(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int32Type};
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+
+    use super::*;
+
+    #[test]
+    fn response_format_header() {
+        let parse =
+            |v: &'static str| ResponseFormat::from_header(Some(&HeaderValue::from_static(v)));
+        assert_eq!(
+            ResponseFormat::from_header(None).unwrap(),
+            ResponseFormat::Json
+        );
+        assert_eq!(parse("json").unwrap(), ResponseFormat::Json);
+        assert_eq!(
+            parse("ndjson").unwrap(),
+            ResponseFormat::Stream(StreamFormat::NdJson)
+        );
+        assert_eq!(
+            parse("arrow").unwrap(),
+            ResponseFormat::Stream(StreamFormat::Arrow)
+        );
+        assert!(parse("csv").is_err());
+    }
+
+    #[test]
+    fn arrow_columns_roundtrip() {
+        let mut columns = [
+            ColumnBuilder::new(&Type::INT4),
+            ColumnBuilder::new(&Type::FLOAT8),
+            ColumnBuilder::new(&Type::BOOL),
+            ColumnBuilder::new(&Type::JSONB),
+        ];
+        let rows = [
+            [Some("1"), Some("4.2"), Some("t"), Some(r#"{"a": 1}"#)],
+            [None, Some("NaN"), Some("f"), None],
+        ];
+        for row in rows {
+            for (column, value) in columns.iter_mut().zip(row) {
+                column.append(value).unwrap();
+            }
+        }
+
+        let fields: Vec<_> = ["i", "f", "b", "j"]
+            .into_iter()
+            .zip(&columns)
+            .map(|(name, c)| Field::new(name, c.data_type(), true))
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        let arrays = columns.iter_mut().map(ColumnBuilder::finish).collect();
+        let batch = RecordBatch::try_new(schema.clone(), arrays).unwrap();
+
+        let mut writer = StreamWriter::try_new(Vec::new(), &schema).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        let written = writer.into_inner().unwrap();
+
+        let mut reader = StreamReader::try_new(written.as_slice(), None).unwrap();
+        let read = reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+
+        assert_eq!(read.num_rows(), 2);
+        let ints = read.column(0).as_primitive::<Int32Type>();
+        assert_eq!(ints.value(0), 1);
+        assert!(ints.is_null(1));
+        let floats = read.column(1).as_primitive::<Float64Type>();
+        assert_eq!(floats.value(0), 4.2);
+        assert!(floats.value(1).is_nan());
+        let bools = read.column(2).as_boolean();
+        assert!(bools.value(0));
+        assert!(!bools.value(1));
+        let text = read.column(3).as_string::<i32>();
+        assert_eq!(text.value(0), r#"{"a": 1}"#);
+        assert!(text.is_null(1));
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        assert!(ColumnBuilder::new(&Type::INT2)
+            .append(Some("70000"))
+            .is_err());
+        assert!(ColumnBuilder::new(&Type::FLOAT4)
+            .append(Some("one"))
+            .is_err());
+        assert!(ColumnBuilder::new(&Type::TEXT).append(Some("one")).is_ok());
+    }
+}
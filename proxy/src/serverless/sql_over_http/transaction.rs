@@ -0,0 +1,381 @@
+//! Interactive transactions for SQL over HTTP.
+//!
+//! A request with `Neon-Transaction: begin` opens a transaction, and the
+//! response carries its id in the same header. Requests that pass the id back
+//! run their queries in that transaction, on the same compute connection,
+//! until one of them ends it with `Neon-Transaction-End: commit` or
+//! `rollback`. The id is only valid with the connection string it was opened
+//! with.
+//!
+//! Between requests the connection stays pinned to the transaction. If no
+//! request comes within the idle timeout, the connection is closed, which
+//! rolls the transaction back. A failed query rolls it back right away.
+
+use std::sync::Arc;
+
+use anyhow::bail;
+use dashmap::DashMap;
+use futures::{pin_mut, StreamExt};
+use hyper::header;
+use hyper::http::{HeaderName, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::time::{Duration, Instant};
+use tokio_postgres::{GenericClient, IsolationLevel, ReadyForQueryStatus};
+use tracing::{error, info};
+
+use super::{json_body, query_to_json, Payload};
+use crate::serverless::conn_pool::{Client, ConnInfo};
+
+pub static TXN: HeaderName = HeaderName::from_static("neon-transaction");
+pub static TXN_END: HeaderName = HeaderName::from_static("neon-transaction-end");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionTarget {
+    Begin,
+    Resume(uuid::Uuid),
+}
+
+impl TransactionTarget {
+    pub fn from_header(value: Option<&HeaderValue>) -> anyhow::Result<Option<Self>> {
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        match value.as_bytes() {
+            b"begin" => Ok(Some(Self::Begin)),
+            id => match std::str::from_utf8(id).ok().and_then(|id| id.parse().ok()) {
+                Some(id) => Ok(Some(Self::Resume(id))),
+                None => bail!("invalid transaction id"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionEnd {
+    Commit,
+    Rollback,
+}
+
+impl TransactionEnd {
+    pub fn from_header(value: Option<&HeaderValue>) -> anyhow::Result<Option<Self>> {
+        match value.map(HeaderValue::as_bytes) {
+            None => Ok(None),
+            Some(b"commit") => Ok(Some(Self::Commit)),
+            Some(b"rollback") => Ok(Some(Self::Rollback)),
+            Some(_) => bail!("invalid transaction end"),
+        }
+    }
+}
+
+/// The `BEGIN` statement for the options of the batch transactions.
+pub fn begin_statement(
+    isolation_level: Option<IsolationLevel>,
+    read_only: bool,
+    deferrable: bool,
+) -> anyhow::Result<String> {
+    let mut query = "BEGIN".to_string();
+    if let Some(level) = isolation_level {
+        let level = match level {
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+            _ => bail!("invalid isolation level"),
+        };
+        query.push_str(" ISOLATION LEVEL ");
+        query.push_str(level);
+    }
+    if read_only {
+        query.push_str(" READ ONLY");
+    }
+    if deferrable {
+        query.push_str(" DEFERRABLE");
+    }
+    Ok(query)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpTransactionError {
+    #[error("transaction not found, it might have ended or timed out")]
+    NotFound,
+    #[error("transaction is in use by another request")]
+    Busy,
+}
+
+/// The open interactive transactions of this proxy.
+pub struct HttpTransactions {
+    idle_timeout: Duration,
+    transactions: DashMap<uuid::Uuid, TransactionSlot>,
+}
+
+struct TransactionSlot {
+    conn_info: ConnInfo,
+    /// `None` while a request is using the transaction.
+    idle: Option<IdleTransaction>,
+}
+
+struct IdleTransaction {
+    client: Client,
+    expires_at: Instant,
+}
+
+impl HttpTransactions {
+    pub fn new(idle_timeout: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            idle_timeout,
+            transactions: DashMap::new(),
+        })
+    }
+
+    pub async fn gc_worker(&self) {
+        let mut interval = tokio::time::interval(self.idle_timeout);
+        loop {
+            interval.tick().await;
+            self.gc();
+        }
+    }
+
+    fn gc(&self) {
+        let now = Instant::now();
+        self.transactions.retain(|id, slot| match &mut slot.idle {
+            Some(idle) if idle.expires_at <= now => {
+                info!(%id, "sql-over-http: closing the connection of an idle transaction");
+                idle.client.discard();
+                false
+            }
+            _ => true,
+        });
+    }
+
+    /// Open a transaction on the client.
+    pub async fn begin(
+        self: &Arc<Self>,
+        conn_info: ConnInfo,
+        mut client: Client,
+        begin: &str,
+    ) -> anyhow::Result<ActiveTransaction> {
+        if let Err(e) = simple_query(&*client, begin).await {
+            client.discard();
+            return Err(e);
+        }
+
+        let id = uuid::Uuid::new_v4();
+        self.transactions.insert(
+            id,
+            TransactionSlot {
+                conn_info,
+                idle: None,
+            },
+        );
+        Ok(ActiveTransaction {
+            id,
+            client: Some(client),
+            ended: false,
+            transactions: Arc::clone(self),
+        })
+    }
+
+    /// Take up a transaction opened by an earlier request.
+    pub fn resume(
+        self: &Arc<Self>,
+        id: uuid::Uuid,
+        conn_info: &ConnInfo,
+    ) -> Result<ActiveTransaction, HttpTransactionError> {
+        let mut slot = self
+            .transactions
+            .get_mut(&id)
+            .ok_or(HttpTransactionError::NotFound)?;
+        // Don't tell the transactions of other users apart from missing ones.
+        if slot.conn_info != *conn_info {
+            return Err(HttpTransactionError::NotFound);
+        }
+        let idle = slot.idle.take().ok_or(HttpTransactionError::Busy)?;
+        Ok(ActiveTransaction {
+            id,
+            client: Some(idle.client),
+            ended: false,
+            transactions: Arc::clone(self),
+        })
+    }
+}
+
+/// A transaction in use by a request. Unless it's suspended for the next
+/// request, the transaction is over once this is dropped: if it wasn't ended
+/// properly, the connection is closed.
+pub struct ActiveTransaction {
+    id: uuid::Uuid,
+    client: Option<Client>,
+    ended: bool,
+    transactions: Arc<HttpTransactions>,
+}
+
+impl ActiveTransaction {
+    pub fn client(&mut self) -> &mut Client {
+        self.client
+            .as_mut()
+            .expect("client is only taken when suspended")
+    }
+
+    /// Keep the transaction open for the next request.
+    pub fn suspend(mut self) -> uuid::Uuid {
+        let client = self
+            .client
+            .take()
+            .expect("client is only taken when suspended");
+        if let Some(mut slot) = self.transactions.transactions.get_mut(&self.id) {
+            slot.idle = Some(IdleTransaction {
+                client,
+                expires_at: Instant::now() + self.transactions.idle_timeout,
+            });
+        }
+        self.id
+    }
+
+    pub async fn end(mut self, end: TransactionEnd) -> anyhow::Result<()> {
+        let query = match end {
+            TransactionEnd::Commit => "COMMIT",
+            TransactionEnd::Rollback => "ROLLBACK",
+        };
+        let status = simple_query(&**self.client(), query).await?;
+        self.client().check_idle(status);
+        self.ended = true;
+        Ok(())
+    }
+
+    /// The transaction was ended by the queries of the request, e.g. with
+    /// an explicit `COMMIT`.
+    pub fn ended_by_client(&mut self, status: ReadyForQueryStatus) -> bool {
+        if status == ReadyForQueryStatus::Idle {
+            self.ended = true;
+        }
+        self.ended
+    }
+}
+
+impl Drop for ActiveTransaction {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            if !self.ended {
+                client.discard();
+            }
+            self.transactions.transactions.remove(&self.id);
+        }
+    }
+}
+
+/// Run the queries of a request in the transaction, then keep it open for
+/// the next request or end it.
+pub async fn query(
+    mut txn: ActiveTransaction,
+    payload: Payload,
+    end: Option<TransactionEnd>,
+    raw_output: bool,
+    array_mode: bool,
+) -> anyhow::Result<Response<Body>> {
+    let (status, result) = match run_payload(txn.client(), payload, raw_output, array_mode).await {
+        Ok(res) => res,
+        Err(e) => {
+            // the failed query has aborted the transaction anyway
+            if let Err(e) = txn.end(TransactionEnd::Rollback).await {
+                error!("sql-over-http: failed to roll back the transaction: {e:#}");
+            }
+            return Err(e);
+        }
+    };
+
+    let metrics = txn.client().metrics();
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json");
+    if !status.is_some_and(|status| txn.ended_by_client(status)) {
+        match end {
+            Some(end) => txn.end(end).await?,
+            None => {
+                let id = txn.suspend();
+                response = response.header(TXN.clone(), HeaderValue::try_from(id.to_string())?);
+            }
+        }
+    }
+    Ok(json_body(response, &result, &metrics))
+}
+
+/// Returns the status after the last query, if there were any.
+async fn run_payload(
+    client: &Client,
+    payload: Payload,
+    raw_output: bool,
+    array_mode: bool,
+) -> anyhow::Result<(Option<ReadyForQueryStatus>, Value)> {
+    let mut size = 0;
+    match payload {
+        Payload::Single(stmt) => {
+            let (status, result) =
+                query_to_json(&**client, stmt, &mut size, raw_output, array_mode).await?;
+            Ok((Some(status), result))
+        }
+        Payload::Batch(batch) => {
+            let mut status = None;
+            let mut results = Vec::with_capacity(batch.queries.len());
+            for stmt in batch.queries {
+                let (s, result) =
+                    query_to_json(&**client, stmt, &mut size, raw_output, array_mode).await?;
+                status = Some(s);
+                results.push(result);
+            }
+            Ok((status, json!({ "results": results })))
+        }
+    }
+}
+
+async fn simple_query<T: GenericClient>(
+    client: &T,
+    query: &str,
+) -> anyhow::Result<ReadyForQueryStatus> {
+    let row_stream = client
+        .query_raw_txt(query, Vec::<Option<String>>::new())
+        .await?;
+    pin_mut!(row_stream);
+    while let Some(row) = row_stream.next().await {
+        row?;
+    }
+    Ok(row_stream.ready_status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_headers() {
+        let target =
+            |v: &'static str| TransactionTarget::from_header(Some(&HeaderValue::from_static(v)));
+        assert_eq!(TransactionTarget::from_header(None).unwrap(), None);
+        assert_eq!(target("begin").unwrap(), Some(TransactionTarget::Begin));
+        let id = uuid::Uuid::new_v4();
+        assert_eq!(
+            TransactionTarget::from_header(Some(&HeaderValue::try_from(id.to_string()).unwrap()))
+                .unwrap(),
+            Some(TransactionTarget::Resume(id))
+        );
+        assert!(target("not-a-uuid").is_err());
+
+        let end = |v: &'static str| TransactionEnd::from_header(Some(&HeaderValue::from_static(v)));
+        assert_eq!(end("commit").unwrap(), Some(TransactionEnd::Commit));
+        assert_eq!(end("rollback").unwrap(), Some(TransactionEnd::Rollback));
+        assert!(end("abort").is_err());
+    }
+
+    #[test]
+    fn begin_statements() {
+        assert_eq!(begin_statement(None, false, false).unwrap(), "BEGIN");
+        assert_eq!(
+            begin_statement(Some(IsolationLevel::Serializable), true, true).unwrap(),
+            "BEGIN ISOLATION LEVEL SERIALIZABLE READ ONLY DEFERRABLE"
+        );
+        assert_eq!(
+            begin_statement(Some(IsolationLevel::ReadCommitted), false, false).unwrap(),
+            "BEGIN ISOLATION LEVEL READ COMMITTED"
+        );
+    }
+}
@@ -146,7 +146,9 @@ pub async fn serve_websocket(
         WebSocketRw::new(websocket),
         ClientMode::Websockets { hostname },
         peer_addr,
+        None,
         endpoint_rate_limiter,
+        None,
     )
     .await?;
     Ok(())
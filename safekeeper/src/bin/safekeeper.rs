@@ -27,8 +27,8 @@ use utils::pid_file;
 
 use metrics::set_build_info_metric;
 use safekeeper::defaults::{
-    DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_HTTP_LISTEN_ADDR, DEFAULT_MAX_OFFLOADER_LAG_BYTES,
-    DEFAULT_PG_LISTEN_ADDR,
+    DEFAULT_EVICTION_MIN_IDLE, DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_HTTP_LISTEN_ADDR,
+    DEFAULT_MAX_OFFLOADER_LAG_BYTES, DEFAULT_PG_LISTEN_ADDR,
 };
 use safekeeper::wal_service;
 use safekeeper::GlobalTimelines;
@@ -141,6 +141,15 @@ struct Args {
     /// WAL backup horizon.
     #[arg(long)]
     disable_wal_backup: bool,
+    /// Delete the local WAL segments of timelines which are inactive and fully
+    /// offloaded to remote storage. The WAL is read from remote storage when
+    /// needed, and the last segment is downloaded back when the timeline
+    /// becomes active again.
+    #[arg(long, verbatim_doc_comment)]
+    enable_wal_eviction: bool,
+    /// How long a timeline must be inactive before its WAL is evicted.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_EVICTION_MIN_IDLE)]
+    eviction_min_idle: Duration,
     /// If given, enables auth on incoming connections to WAL service endpoint
     /// (--listen-pg). Value specifies path to a .pem public key used for
     /// validations of JWT tokens. Empty string is allowed and means disabling
@@ -290,6 +299,8 @@ async fn main() -> anyhow::Result<()> {
         remote_storage: args.remote_storage,
         max_offloader_lag_bytes: args.max_offloader_lag,
         wal_backup_enabled: !args.disable_wal_backup,
+        enable_wal_eviction: args.enable_wal_eviction,
+        eviction_min_idle: args.eviction_min_idle,
         backup_parallel_jobs: args.wal_backup_parallel_jobs,
         pg_auth,
         pg_tenant_only_auth,
@@ -2,7 +2,8 @@ use hyper::{Body, Request, Response, StatusCode, Uri};
 
 use once_cell::sync::Lazy;
 use postgres_ffi::WAL_SEGMENT_SIZE;
-use safekeeper_api::models::SkTimelineInfo;
+use safekeeper_api::membership::Configuration;
+use safekeeper_api::models::{SkTimelineInfo, TimelineMembershipSwitchRequest};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
@@ -108,6 +109,8 @@ pub struct TimelineStatus {
     pub peers: Vec<PeerInfo>,
     pub walsenders: Vec<WalSenderState>,
     pub walreceivers: Vec<WalReceiverState>,
+    #[serde(default)]
+    pub mconf: Configuration,
 }
 
 fn check_permission(request: &Request<Body>, tenant_id: Option<TenantId>) -> Result<(), ApiError> {
@@ -162,6 +165,7 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
         peers: tli.get_peers(conf).await,
         walsenders: tli.get_walsenders().get_all(),
         walreceivers: tli.get_walreceivers().get_all(),
+        mconf: state.mconf,
     };
     json_response(StatusCode::OK, status)
 }
@@ -204,6 +208,55 @@ async fn timeline_pull_handler(mut request: Request<Body>) -> Result<Response<Bo
     json_response(StatusCode::OK, resp)
 }
 
+/// Get membership configuration of the timeline.
+async fn timeline_membership_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::from)?;
+    let (_, state) = tli.get_state().await;
+    json_response(StatusCode::OK, state.mconf)
+}
+
+/// Switch the timeline to a new membership configuration. A safekeeper which
+/// joins the timeline first pulls it from the given peers.
+async fn timeline_membership_switch_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    // Like pull_timeline, as it may pull the timeline from the given hosts.
+    check_permission(&request, None)?;
+
+    let data: TimelineMembershipSwitchRequest = json_request(&mut request).await?;
+    let conf = get_conf(&request);
+
+    if GlobalTimelines::get(ttid).is_err()
+        && data.mconf.contains(conf.my_id)
+        && !data.pull_from.is_empty()
+    {
+        pull_timeline::handle_request(pull_timeline::Request {
+            tenant_id: ttid.tenant_id,
+            timeline_id: ttid.timeline_id,
+            http_hosts: data.pull_from,
+        })
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    }
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::from)?;
+    let resp = tli
+        .membership_switch(data.mconf)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, resp)
+}
+
 /// Download a file from the timeline directory.
 // TODO: figure out a better way to copy files between safekeepers
 async fn timeline_files_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
@@ -468,6 +521,14 @@ pub fn make_router(conf: SafeKeeperConf) -> RouterBuilder<hyper::Body, ApiError>
         .post("/v1/pull_timeline", |r| {
             request_span(r, timeline_pull_handler)
         })
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/membership",
+            |r| request_span(r, timeline_membership_handler),
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/membership",
+            |r| request_span(r, timeline_membership_switch_handler),
+        )
         .get(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/file/:filename",
             |r| request_span(r, timeline_files_handler),
@@ -28,6 +28,7 @@ pub mod remove_wal;
 pub mod safekeeper;
 pub mod send_wal;
 pub mod timeline;
+pub mod timeline_eviction;
 pub mod wal_backup;
 pub mod wal_service;
 pub mod wal_storage;
@@ -45,6 +46,7 @@ pub mod defaults {
 
     pub const DEFAULT_HEARTBEAT_TIMEOUT: &str = "5000ms";
     pub const DEFAULT_MAX_OFFLOADER_LAG_BYTES: u64 = 128 * (1 << 20);
+    pub const DEFAULT_EVICTION_MIN_IDLE: &str = "10m";
 }
 
 #[derive(Debug, Clone)]
@@ -71,6 +73,10 @@ pub struct SafeKeeperConf {
     pub max_offloader_lag_bytes: u64,
     pub backup_parallel_jobs: usize,
     pub wal_backup_enabled: bool,
+    /// Delete local WAL of timelines that have been inactive for
+    /// `eviction_min_idle` and are fully backed up, see [`timeline_eviction`].
+    pub enable_wal_eviction: bool,
+    pub eviction_min_idle: Duration,
     pub pg_auth: Option<Arc<JwtAuth>>,
     pub pg_tenant_only_auth: Option<Arc<JwtAuth>>,
     pub http_auth: Option<Arc<SwappableJwtAuth>>,
@@ -107,6 +113,8 @@ impl SafeKeeperConf {
             broker_keepalive_interval: Duration::from_secs(5),
             peer_recovery_enabled: true,
             wal_backup_enabled: true,
+            enable_wal_eviction: false,
+            eviction_min_idle: Duration::from_secs(600),
             backup_parallel_jobs: 1,
             pg_auth: None,
             pg_tenant_only_auth: None,
@@ -121,6 +121,20 @@ pub static BACKUP_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
     )
     .expect("Failed to register safekeeper_backup_errors_total counter")
 });
+pub static TIMELINE_EVICTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_timeline_evictions_total",
+        "Number of inactive timelines whose local WAL was evicted to remote storage"
+    )
+    .expect("Failed to register safekeeper_timeline_evictions_total counter")
+});
+pub static TIMELINE_RESTORES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_timeline_restores_total",
+        "Number of evicted timelines whose WAL was downloaded back to local disk"
+    )
+    .expect("Failed to register safekeeper_timeline_restores_total counter")
+});
 pub static BROKER_PUSH_ALL_UPDATES_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     register_histogram!(
         "safekeeper_broker_push_update_seconds",
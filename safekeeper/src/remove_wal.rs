@@ -1,4 +1,4 @@
-//! Thread removing old WAL.
+//! Thread removing old WAL, and evicting WAL of idle timelines.
 
 use std::time::Duration;
 
@@ -12,10 +12,19 @@ pub async fn task_main(conf: SafeKeeperConf) -> anyhow::Result<()> {
     loop {
         let tlis = GlobalTimelines::get_all();
         for tli in &tlis {
+            let ttid = tli.ttid;
             if !tli.is_active().await {
+                if conf.enable_wal_eviction {
+                    if let Err(e) = tli
+                        .maybe_evict(&conf)
+                        .instrument(info_span!("WAL eviction", ttid = %ttid))
+                        .await
+                    {
+                        warn!("failed to evict timeline {ttid}: {e:#}");
+                    }
+                }
                 continue;
             }
-            let ttid = tli.ttid;
             async {
                 if let Err(e) = tli.maybe_persist_control_file().await {
                     warn!("failed to persist control file: {e}");
@@ -19,7 +19,8 @@ use crate::control_file;
 use crate::send_wal::HotStandbyFeedback;
 
 use crate::wal_storage;
-use pq_proto::SystemId;
+use pq_proto::{read_cstr, SystemId};
+use safekeeper_api::membership::{Configuration, Generation, MemberSet, SafekeeperId};
 use utils::pageserver_feedback::PageserverFeedback;
 use utils::{
     bin_ser::LeSer,
@@ -28,8 +29,10 @@ use utils::{
 };
 
 pub const SK_MAGIC: u32 = 0xcafeceefu32;
-pub const SK_FORMAT_VERSION: u32 = 7;
-const SK_PROTOCOL_VERSION: u32 = 2;
+pub const SK_FORMAT_VERSION: u32 = 9;
+const SK_PROTOCOL_VERSION: u32 = 3;
+/// Protocol of walproposers which don't know about membership configurations.
+const SK_PROTOCOL_VERSION_NO_MCONF: u32 = 2;
 pub const UNKNOWN_SERVER_VERSION: u32 = 0;
 
 /// Consensus logical timestamp.
@@ -235,6 +238,22 @@ impl PersistedPeerInfo {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct PersistedPeers(pub Vec<(NodeId, PersistedPeerInfo)>);
 
+/// Whether the WAL of the timeline is on local disk. See
+/// [`crate::timeline_eviction`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EvictionState {
+    /// WAL segments are on local disk, as far as they haven't been removed by
+    /// `remove_wal`.
+    Present,
+    /// The timeline was evicted at `flush_lsn`: its WAL is in remote storage
+    /// only. The last, partial, segment was uploaded as `partial_segment_name`
+    /// in the remote timeline directory.
+    Offloaded {
+        flush_lsn: Lsn,
+        partial_segment_name: String,
+    },
+}
+
 /// Persistent information stored on safekeeper node
 /// On disk data is prefixed by magic and format version and followed by checksum.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -279,6 +298,11 @@ pub struct SafeKeeperState {
     // obviously can be stale. (Currently not saved at all, but let's provision
     // place to have less file version upgrades).
     pub peers: PersistedPeers,
+    /// Whether the WAL segments are on local disk or have been evicted.
+    pub eviction_state: EvictionState,
+    /// Membership configuration of the timeline, see
+    /// [`safekeeper_api::membership`].
+    pub mconf: Configuration,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -321,6 +345,8 @@ impl SafeKeeperState {
                     .map(|p| (*p, PersistedPeerInfo::new()))
                     .collect(),
             ),
+            eviction_state: EvictionState::Present,
+            mconf: Configuration::empty(),
         }
     }
 
@@ -363,6 +389,9 @@ pub struct ProposerGreeting {
 pub struct AcceptorGreeting {
     term: u64,
     node_id: NodeId,
+    /// Membership configuration, sent to proposers which speak
+    /// [`SK_PROTOCOL_VERSION`].
+    mconf: Option<Configuration>,
 }
 
 /// Vote request sent from proposer to safekeepers
@@ -451,6 +480,24 @@ impl AppendResponse {
     }
 }
 
+/// Proposer -> Acceptor message asking to switch to a new membership
+/// configuration. The proposer sends it to members of both the old and the
+/// new configuration.
+#[derive(Debug)]
+pub struct ConfigurationSwitchRequest {
+    /// Term of the proposer, the switch is accepted only in the current term.
+    pub term: Term,
+    pub configuration: Configuration,
+}
+
+/// Acceptor -> Proposer reply to ConfigurationSwitchRequest: the generation of
+/// the configuration the acceptor has after processing it.
+#[derive(Debug, Serialize)]
+pub struct ConfigurationSwitchResponse {
+    pub term: Term,
+    pub generation: Generation,
+}
+
 /// Proposer -> Acceptor messages
 #[derive(Debug)]
 pub enum ProposerAcceptorMessage {
@@ -460,6 +507,7 @@ pub enum ProposerAcceptorMessage {
     AppendRequest(AppendRequest),
     NoFlushAppendRequest(AppendRequest),
     FlushWAL,
+    ConfigurationSwitch(ConfigurationSwitchRequest),
 }
 
 impl ProposerAcceptorMessage {
@@ -520,17 +568,100 @@ impl ProposerAcceptorMessage {
 
                 Ok(ProposerAcceptorMessage::AppendRequest(msg))
             }
+            'c' => {
+                let mut msg_bytes = stream.into_inner();
+                if msg_bytes.remaining() < 8 {
+                    bail!("ConfigurationSwitch misses term");
+                }
+                let term = msg_bytes.get_u64_le();
+                let configuration = configuration_from_bytes(&mut msg_bytes)?;
+                let msg = ConfigurationSwitchRequest {
+                    term,
+                    configuration,
+                };
+                Ok(ProposerAcceptorMessage::ConfigurationSwitch(msg))
+            }
             _ => bail!("unknown proposer-acceptor message tag: {}", tag),
         }
     }
 }
 
+/// Parse a membership configuration: generation, member set and a flag
+/// telling whether the set of new members follows. A member set is a count of
+/// members, each of them node id, NUL-terminated host and pg port.
+fn configuration_from_bytes(bytes: &mut Bytes) -> Result<Configuration> {
+    if bytes.remaining() < 4 {
+        bail!("Configuration misses generation");
+    }
+    let generation = bytes.get_u32_le();
+    let members = member_set_from_bytes(bytes)?;
+    if bytes.remaining() < 1 {
+        bail!("Configuration misses new members flag");
+    }
+    let new_members = match bytes.get_u8() {
+        0 => None,
+        _ => Some(member_set_from_bytes(bytes)?),
+    };
+    Ok(Configuration {
+        generation,
+        members,
+        new_members,
+    })
+}
+
+/// Serialize a membership configuration the way [`configuration_from_bytes`]
+/// parses it.
+fn configuration_to_bytes(conf: &Configuration, buf: &mut BytesMut) {
+    buf.put_u32_le(conf.generation);
+    member_set_to_bytes(&conf.members, buf);
+    match &conf.new_members {
+        None => buf.put_u8(0),
+        Some(new_members) => {
+            buf.put_u8(1);
+            member_set_to_bytes(new_members, buf);
+        }
+    }
+}
+
+fn member_set_to_bytes(set: &MemberSet, buf: &mut BytesMut) {
+    buf.put_u32_le(set.members.len() as u32);
+    for m in &set.members {
+        buf.put_u64_le(m.id.0);
+        buf.put_slice(m.host.as_bytes());
+        buf.put_u8(0);
+        buf.put_u16_le(m.pg_port);
+    }
+}
+
+fn member_set_from_bytes(bytes: &mut Bytes) -> Result<MemberSet> {
+    if bytes.remaining() < 4 {
+        bail!("MemberSet misses len");
+    }
+    let n_members = bytes.get_u32_le();
+    let mut members = Vec::new();
+    for _ in 0..n_members {
+        if bytes.remaining() < 8 {
+            bail!("MemberSet is incomplete");
+        }
+        let id = NodeId(bytes.get_u64_le());
+        let host = read_cstr(bytes)?;
+        let host = String::from_utf8(host.to_vec()).context("host is not valid utf-8")?;
+        if bytes.remaining() < 2 {
+            bail!("MemberSet is incomplete");
+        }
+        let pg_port = bytes.get_u16_le();
+        members.push(SafekeeperId { id, host, pg_port });
+    }
+    MemberSet::new(members)
+}
+
 /// Acceptor -> Proposer messages
 #[derive(Debug)]
 pub enum AcceptorProposerMessage {
     Greeting(AcceptorGreeting),
     VoteResponse(VoteResponse),
     AppendResponse(AppendResponse),
+    ConfigurationSwitchResponse(ConfigurationSwitchResponse),
 }
 
 impl AcceptorProposerMessage {
@@ -541,6 +672,9 @@ impl AcceptorProposerMessage {
                 buf.put_u64_le('g' as u64);
                 buf.put_u64_le(msg.term);
                 buf.put_u64_le(msg.node_id.0);
+                if let Some(mconf) = &msg.mconf {
+                    configuration_to_bytes(mconf, buf);
+                }
             }
             AcceptorProposerMessage::VoteResponse(msg) => {
                 buf.put_u64_le('v' as u64);
@@ -566,6 +700,11 @@ impl AcceptorProposerMessage {
 
                 msg.pageserver_feedback.serialize(buf);
             }
+            AcceptorProposerMessage::ConfigurationSwitchResponse(msg) => {
+                buf.put_u64_le('c' as u64);
+                buf.put_u64_le(msg.term);
+                buf.put_u32_le(msg.generation);
+            }
         }
 
         Ok(())
@@ -652,6 +791,17 @@ where
         &mut self,
         msg: &ProposerAcceptorMessage,
     ) -> Result<Option<AcceptorProposerMessage>> {
+        if !matches!(msg, ProposerAcceptorMessage::ConfigurationSwitch(_))
+            && !self.state.mconf.allows(self.node_id)
+        {
+            // The timeline has been moved away from this safekeeper, the
+            // proposer must learn the new configuration.
+            bail!(
+                "safekeeper {} is not a member of timeline configuration {}",
+                self.node_id,
+                self.state.mconf
+            );
+        }
         match msg {
             ProposerAcceptorMessage::Greeting(msg) => self.handle_greeting(msg).await,
             ProposerAcceptorMessage::VoteRequest(msg) => self.handle_vote_request(msg).await,
@@ -663,6 +813,9 @@ where
                 self.handle_append_request(msg, false).await
             }
             ProposerAcceptorMessage::FlushWAL => self.handle_flush().await,
+            ProposerAcceptorMessage::ConfigurationSwitch(msg) => {
+                self.handle_configuration_switch(msg).await
+            }
         }
     }
 
@@ -673,7 +826,9 @@ where
         msg: &ProposerGreeting,
     ) -> Result<Option<AcceptorProposerMessage>> {
         // Check protocol compatibility
-        if msg.protocol_version != SK_PROTOCOL_VERSION {
+        if msg.protocol_version != SK_PROTOCOL_VERSION
+            && msg.protocol_version != SK_PROTOCOL_VERSION_NO_MCONF
+        {
             bail!(
                 "incompatible protocol version {}, expected {}",
                 msg.protocol_version,
@@ -739,9 +894,13 @@ where
             msg.proposer_id.map(|b| format!("{:X}", b)).join(""),
             self.state.acceptor_state.term
         );
+        // The proposer needs the configuration to tell whether the votes and
+        // acknowledgements it gets form a quorum in all of its member sets.
+        let mconf = (msg.protocol_version == SK_PROTOCOL_VERSION).then(|| self.state.mconf.clone());
         Ok(Some(AcceptorProposerMessage::Greeting(AcceptorGreeting {
             term: self.state.acceptor_state.term,
             node_id: self.node_id,
+            mconf,
         })))
     }
 
@@ -924,6 +1083,60 @@ where
         self.persist_control_file(state).await
     }
 
+    /// Handle request to switch membership configuration.
+    async fn handle_configuration_switch(
+        &mut self,
+        msg: &ConfigurationSwitchRequest,
+    ) -> Result<Option<AcceptorProposerMessage>> {
+        if self.state.acceptor_state.term < msg.term {
+            bail!("got ConfigurationSwitch before ProposerElected");
+        }
+
+        // Only the elected proposer of the current term may change the
+        // configuration, refuse the switch from a stale one.
+        if self.state.acceptor_state.term == msg.term {
+            self.switch_membership(&msg.configuration).await?;
+        }
+        Ok(Some(AcceptorProposerMessage::ConfigurationSwitchResponse(
+            ConfigurationSwitchResponse {
+                term: self.get_term(),
+                generation: self.state.mconf.generation,
+            },
+        )))
+    }
+
+    /// Switch to the given membership configuration, if its generation is
+    /// higher than the current one. Returns whether the switch happened.
+    pub async fn switch_membership(&mut self, to: &Configuration) -> Result<bool> {
+        if to.generation <= self.state.mconf.generation {
+            if to.generation == self.state.mconf.generation && *to != self.state.mconf {
+                bail!(
+                    "configuration {} conflicts with current configuration {}",
+                    to,
+                    self.state.mconf
+                );
+            }
+            return Ok(false);
+        }
+        info!("switching membership from {} to {}", self.state.mconf, to);
+        let mut state = self.state.clone();
+        state.mconf = to.clone();
+        self.persist_control_file(state).await?;
+        Ok(true)
+    }
+
+    /// Persist a new eviction state, along with the in-memory state.
+    pub async fn persist_eviction_state(
+        &mut self,
+        eviction_state: EvictionState,
+        inmem_remote_consistent_lsn: Lsn,
+    ) -> Result<()> {
+        let mut state = self.state.clone();
+        state.remote_consistent_lsn = inmem_remote_consistent_lsn;
+        state.eviction_state = eviction_state;
+        self.persist_control_file(state).await
+    }
+
     /// Persist in-memory state to the disk, taking other data from state.
     async fn persist_control_file(&mut self, mut state: SafeKeeperState) -> Result<()> {
         state.commit_lsn = self.inmem.commit_lsn;
@@ -1255,6 +1468,71 @@ mod tests {
         assert_eq!(sk.get_epoch(), 1);
     }
 
+    /// Serialize a ConfigurationSwitch message the way proposer does.
+    fn configuration_switch_msg(term: Term, generation: Generation, members: &[u64]) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u64_le('c' as u64);
+        buf.put_u64_le(term);
+        buf.put_u32_le(generation);
+        buf.put_u32_le(members.len() as u32);
+        for id in members {
+            buf.put_u64_le(*id);
+            buf.put_slice(b"localhost\0");
+            buf.put_u16_le(5454 + *id as u16);
+        }
+        // no new members
+        buf.put_u8(0);
+        buf.freeze()
+    }
+
+    /// Sends a ConfigurationSwitch and returns the generation from the response.
+    async fn switch_generation(
+        sk: &mut SafeKeeper<InMemoryState, DummyWalStore>,
+        term: Term,
+        generation: Generation,
+        members: &[u64],
+    ) -> Result<Generation> {
+        let switch =
+            ProposerAcceptorMessage::parse(configuration_switch_msg(term, generation, members))?;
+        match sk.process_msg(&switch).await? {
+            Some(AcceptorProposerMessage::ConfigurationSwitchResponse(resp)) => Ok(resp.generation),
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_membership_switch() {
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(1)).unwrap();
+
+        let generation = switch_generation(&mut sk, 0, 2, &[1, 2, 3]).await.unwrap();
+        assert_eq!(generation, 2);
+        assert_eq!(sk.state.persisted_state.mconf.members.ids().len(), 3);
+
+        // older generation is ignored
+        let generation = switch_generation(&mut sk, 0, 1, &[2, 3]).await.unwrap();
+        assert_eq!(generation, 2);
+
+        // as a member, safekeeper votes
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 1 });
+        assert!(sk.process_msg(&vote_request).await.is_ok());
+
+        // the proposer of a previous term can't switch the configuration
+        let generation = switch_generation(&mut sk, 0, 3, &[2, 3, 4]).await.unwrap();
+        assert_eq!(generation, 2);
+        // nor a proposer which hasn't been elected yet
+        assert!(switch_generation(&mut sk, 2, 3, &[2, 3, 4]).await.is_err());
+
+        // safekeeper doesn't vote once it is removed
+        let generation = switch_generation(&mut sk, 1, 3, &[2, 3, 4]).await.unwrap();
+        assert_eq!(generation, 3);
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 2 });
+        assert!(sk.process_msg(&vote_request).await.is_err());
+    }
+
     #[test]
     fn test_find_highest_common_point_none() {
         let prop_th = TermHistory(vec![(0, Lsn(1)).into()]);
@@ -1356,6 +1634,20 @@ mod tests {
                     commit_lsn: Lsn(1234567600),
                 },
             )]),
+            eviction_state: EvictionState::Offloaded {
+                flush_lsn: Lsn(0x1234567800),
+                partial_segment_name: "X".to_string(),
+            },
+            mconf: Configuration {
+                generation: 3,
+                members: MemberSet::new(vec![SafekeeperId {
+                    id: NodeId(2),
+                    host: "h".to_string(),
+                    pg_port: 0x1234,
+                }])
+                .unwrap(),
+                new_members: None,
+            },
         };
 
         let ser = state.ser().unwrap();
@@ -1401,6 +1693,26 @@ mod tests {
             0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             0x70, 0x02, 0x96, 0x49, 0x00, 0x00, 0x00, 0x00,
             0xb0, 0x01, 0x96, 0x49, 0x00, 0x00, 0x00, 0x00,
+            // eviction_state variant
+            0x01, 0x00, 0x00, 0x00,
+            // flush_lsn
+            0x00, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00,
+            // partial_segment_name as length prefixed string
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x58,
+            // generation
+            0x03, 0x00, 0x00, 0x00,
+            // length prefix for members
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // member id
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // host as length prefixed string
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x68,
+            // pg_port
+            0x34, 0x12,
+            // new_members
+            0x00,
         ];
 
         assert_eq!(Hex(&ser), Hex(&expected));
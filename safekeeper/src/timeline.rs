@@ -4,6 +4,8 @@
 use anyhow::{anyhow, bail, Result};
 use camino::Utf8PathBuf;
 use postgres_ffi::XLogSegNo;
+use safekeeper_api::membership::Configuration;
+use safekeeper_api::models::TimelineMembershipSwitchResponse;
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 
@@ -28,7 +30,7 @@ use storage_broker::proto::TenantTimelineId as ProtoTenantTimelineId;
 use crate::receive_wal::WalReceivers;
 use crate::recovery::{recovery_main, Donor, RecoveryNeededInfo};
 use crate::safekeeper::{
-    AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
+    AcceptorProposerMessage, EvictionState, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
     SafekeeperMemState, ServerInfo, Term, TermLsn, INVALID_TERM,
 };
 use crate::send_wal::WalSenders;
@@ -98,7 +100,7 @@ impl PeersInfo {
 /// Shared state associated with database instance
 pub struct SharedState {
     /// Safekeeper object
-    sk: SafeKeeper<control_file::FileStorage, wal_storage::PhysicalStorage>,
+    pub(crate) sk: SafeKeeper<control_file::FileStorage, wal_storage::PhysicalStorage>,
     /// In memory list containing state of peers sent in latest messages from them.
     peers_info: PeersInfo,
     /// True when WAL backup launcher oversees the timeline, making sure WAL is
@@ -112,7 +114,9 @@ pub struct SharedState {
     ///
     /// TODO: it might be better to remove tli completely from GlobalTimelines
     /// when tli is inactive instead of having this flag.
-    active: bool,
+    pub(crate) active: bool,
+    /// When the timeline was last seen active, see [`crate::timeline_eviction`].
+    pub(crate) last_active_at: Instant,
     last_removed_segno: XLogSegNo,
 }
 
@@ -151,6 +155,7 @@ impl SharedState {
             peers_info: PeersInfo(vec![]),
             wal_backup_active: false,
             active: false,
+            last_active_at: Instant::now(),
             last_removed_segno: 0,
         })
     }
@@ -170,6 +175,7 @@ impl SharedState {
             peers_info: PeersInfo(vec![]),
             wal_backup_active: false,
             active: false,
+            last_active_at: Instant::now(),
             last_removed_segno: 0,
         })
     }
@@ -203,6 +209,9 @@ impl SharedState {
             }
         }
         self.active = is_active;
+        if is_active {
+            self.last_active_at = Instant::now();
+        }
         self.is_wal_backup_action_pending(num_computes)
     }
 
@@ -344,6 +353,10 @@ pub struct Timeline {
     walsenders: Arc<WalSenders>,
     walreceivers: Arc<WalReceivers>,
 
+    /// Serializes eviction and restore of the local WAL, which do their remote
+    /// storage I/O without holding `mutex`.
+    pub(crate) eviction_lock: Mutex<()>,
+
     /// Cancellation channel. Delete/cancel will send `true` here as a cancellation signal.
     cancellation_tx: watch::Sender<bool>,
 
@@ -384,6 +397,7 @@ impl Timeline {
             mutex: Mutex::new(shared_state),
             walsenders: WalSenders::new(rcl),
             walreceivers: WalReceivers::new(),
+            eviction_lock: Mutex::new(()),
             cancellation_rx,
             cancellation_tx,
             timeline_dir: conf.timeline_dir(&ttid),
@@ -415,6 +429,7 @@ impl Timeline {
             mutex: Mutex::new(SharedState::create_new(conf, &ttid, state)?),
             walsenders: WalSenders::new(Lsn(0)),
             walreceivers: WalReceivers::new(),
+            eviction_lock: Mutex::new(()),
             cancellation_rx,
             cancellation_tx,
             timeline_dir: conf.timeline_dir(&ttid),
@@ -612,6 +627,13 @@ impl Timeline {
         let term_flush_lsn: TermLsn;
         {
             let mut shared_state = self.write_shared_state().await;
+            while shared_state.sk.state.eviction_state != EvictionState::Present {
+                // Download the WAL before it is appended to. The download
+                // happens without the lock, so check the state again after it.
+                drop(shared_state);
+                self.restore_evicted().await?;
+                shared_state = self.write_shared_state().await;
+            }
             rmsg = shared_state.sk.process_msg(msg).await?;
 
             // if this is AppendResponse, fill in proper pageserver and hot
@@ -651,6 +673,25 @@ impl Timeline {
         (state.sk.inmem.clone(), state.sk.state.clone())
     }
 
+    /// Switches the timeline to the given membership configuration, unless it
+    /// already has the same or a newer one.
+    pub async fn membership_switch(
+        &self,
+        to: Configuration,
+    ) -> Result<TimelineMembershipSwitchResponse> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let mut state = self.write_shared_state().await;
+        let previous_conf = state.sk.state.mconf.clone();
+        state.sk.switch_membership(&to).await?;
+        Ok(TimelineMembershipSwitchResponse {
+            previous_conf,
+            current_conf: state.sk.state.mconf.clone(),
+        })
+    }
+
     /// Returns latest backup_lsn.
     pub async fn get_wal_backup_lsn(&self) -> Lsn {
         self.write_shared_state().await.sk.inmem.backup_lsn
@@ -0,0 +1,233 @@
+//! Eviction of WAL of idle timelines from local disk.
+//!
+//! Once a timeline is inactive and all of its WAL has been offloaded by
+//! [`crate::wal_backup`], the local segments are no longer needed: full
+//! segments are already in remote storage, and the last, partial segment is
+//! uploaded under a name of its own before eviction. The eviction is recorded
+//! in the control file along with the end of WAL, as the segments can't tell
+//! it anymore.
+//!
+//! Readers of an evicted timeline read the WAL from remote storage, see
+//! [`crate::wal_storage::WalReader`]. The partial segment is downloaded back
+//! before the timeline accepts any more WAL, and its remote copy is deleted
+//! once the restore is recorded.
+//!
+//! Uploads and downloads don't hold the timeline lock, so that the timeline
+//! keeps serving requests meanwhile. The state is checked again after the
+//! transfer, and eviction gives up if the timeline has changed in between.
+//! Eviction and restore of a timeline never run concurrently, they are
+//! serialized by the eviction lock of the timeline.
+
+use anyhow::{bail, Result};
+use postgres_ffi::{XLogFileName, PG_TLI};
+use tracing::*;
+use utils::lsn::Lsn;
+
+use crate::metrics::{TIMELINE_EVICTIONS, TIMELINE_RESTORES};
+use crate::safekeeper::{EvictionState, Term};
+use crate::timeline::{SharedState, Timeline};
+use crate::wal_backup::{backup_object, delete_object, download_object, remote_path};
+use crate::wal_storage::Storage;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// State of the timeline which an eviction is based on. The eviction is
+/// committed only if it is still the same after the partial segment upload.
+#[derive(PartialEq, Eq)]
+struct EvictionCandidate {
+    flush_lsn: Lsn,
+    term: Term,
+    wal_seg_size: usize,
+}
+
+impl EvictionCandidate {
+    fn partial_segment_name(&self, conf: &SafeKeeperConf) -> String {
+        format!(
+            "{}_{}_{:016X}_sk{}.partial",
+            XLogFileName(PG_TLI, self.flush_segno(), self.wal_seg_size),
+            self.term,
+            self.flush_lsn.0,
+            conf.my_id.0,
+        )
+    }
+
+    fn flush_segno(&self) -> u64 {
+        self.flush_lsn.segment_number(self.wal_seg_size)
+    }
+}
+
+impl Timeline {
+    /// Evict the local WAL of the timeline, if it is idle for long enough and
+    /// all of its WAL is in remote storage.
+    pub async fn maybe_evict(&self, conf: &SafeKeeperConf) -> Result<()> {
+        if !conf.enable_wal_eviction || !conf.wal_backup_enabled || conf.remote_storage.is_none() {
+            return Ok(());
+        }
+        if self.is_cancelled() {
+            return Ok(());
+        }
+        // A restore in progress means the timeline is about to become active.
+        let Ok(_eviction_guard) = self.eviction_lock.try_lock() else {
+            return Ok(());
+        };
+
+        let candidate = {
+            let mut shared_state = self.write_shared_state().await;
+            let Some(candidate) = self.eviction_candidate(&shared_state, conf) else {
+                return Ok(());
+            };
+            shared_state.sk.wal_store.flush_wal().await?;
+            candidate
+        };
+
+        let timeline_dir = conf.timeline_dir(&self.ttid);
+        let partial_segment_name = candidate.partial_segment_name(conf);
+        let remote = remote_path(&conf.workdir, &timeline_dir.join(&partial_segment_name))?;
+        let uploaded = candidate.flush_lsn.segment_offset(candidate.wal_seg_size) != 0;
+        if uploaded {
+            let local_path = timeline_dir.join(format!(
+                "{}.partial",
+                XLogFileName(PG_TLI, candidate.flush_segno(), candidate.wal_seg_size)
+            ));
+            backup_object(&local_path, &remote, candidate.wal_seg_size).await?;
+        }
+
+        let mut shared_state = self.write_shared_state().await;
+        if self.eviction_candidate(&shared_state, conf).as_ref() != Some(&candidate) {
+            drop(shared_state);
+            info!("timeline changed while uploading the partial segment, not evicting");
+            if uploaded {
+                if let Err(e) = delete_object(&remote).await {
+                    warn!("failed to delete unused partial segment {remote}: {e:#}");
+                }
+            }
+            return Ok(());
+        }
+
+        shared_state.sk.wal_store.close();
+        let eviction_state = EvictionState::Offloaded {
+            flush_lsn: candidate.flush_lsn,
+            partial_segment_name,
+        };
+        let remote_consistent_lsn = self.get_walsenders().get_remote_consistent_lsn();
+        shared_state
+            .sk
+            .persist_eviction_state(eviction_state, remote_consistent_lsn)
+            .await?;
+        let remove_segments = shared_state
+            .sk
+            .wal_store
+            .remove_up_to(candidate.flush_segno());
+        drop(shared_state);
+
+        // The eviction is durable, the local segments are not needed anymore.
+        remove_segments.await?;
+
+        TIMELINE_EVICTIONS.inc();
+        info!("evicted local WAL up to {}", candidate.flush_lsn);
+        Ok(())
+    }
+
+    /// Check whether the timeline can be evicted in its current state.
+    fn eviction_candidate(
+        &self,
+        shared_state: &SharedState,
+        conf: &SafeKeeperConf,
+    ) -> Option<EvictionCandidate> {
+        if shared_state.sk.state.eviction_state != EvictionState::Present
+            || shared_state.active
+            || shared_state.last_active_at.elapsed() < conf.eviction_min_idle
+        {
+            return None;
+        }
+        // Connections may come and go without the timeline becoming active.
+        if self.get_walreceivers().get_num() > 0 || !self.get_walsenders().get_all().is_empty() {
+            return None;
+        }
+
+        let wal_seg_size = shared_state.sk.state.server.wal_seg_size as usize;
+        let flush_lsn = shared_state.sk.flush_lsn();
+        if flush_lsn == Lsn(0) {
+            return None;
+        }
+        let backup_lsn = shared_state.sk.inmem.backup_lsn;
+        if backup_lsn.segment_number(wal_seg_size) < flush_lsn.segment_number(wal_seg_size) {
+            // full segments are not offloaded yet
+            return None;
+        }
+
+        Some(EvictionCandidate {
+            flush_lsn,
+            term: shared_state.sk.get_term(),
+            wal_seg_size,
+        })
+    }
+
+    /// Download the partial segment of an evicted timeline and mark it present
+    /// again. Full segments are left in remote storage, readers get them there.
+    pub(crate) async fn restore_evicted(&self) -> Result<()> {
+        let _eviction_guard = self.eviction_lock.lock().await;
+
+        let (eviction_state, wal_seg_size) = {
+            let shared_state = self.write_shared_state().await;
+            (
+                shared_state.sk.state.eviction_state.clone(),
+                shared_state.sk.state.server.wal_seg_size as usize,
+            )
+        };
+        let (flush_lsn, partial_segment_name) = match &eviction_state {
+            EvictionState::Present => return Ok(()),
+            EvictionState::Offloaded {
+                flush_lsn,
+                partial_segment_name,
+            } => (*flush_lsn, partial_segment_name),
+        };
+        let conf = GlobalTimelines::get_global_config();
+        if conf.remote_storage.is_none() {
+            bail!(
+                "timeline {} is evicted, but remote storage is not configured",
+                self.ttid
+            );
+        }
+
+        let timeline_dir = conf.timeline_dir(&self.ttid);
+        let remote = remote_path(&conf.workdir, &timeline_dir.join(partial_segment_name))?;
+        let downloaded = flush_lsn.segment_offset(wal_seg_size) != 0;
+        if downloaded {
+            let flush_segno = flush_lsn.segment_number(wal_seg_size);
+            let local_path = timeline_dir.join(format!(
+                "{}.partial",
+                XLogFileName(PG_TLI, flush_segno, wal_seg_size)
+            ));
+            download_object(&remote, &local_path).await?;
+        }
+
+        {
+            let mut shared_state = self.write_shared_state().await;
+            // Only restores change the state of an evicted timeline, and they
+            // are serialized by the eviction lock.
+            if shared_state.sk.state.eviction_state != eviction_state {
+                bail!(
+                    "eviction state of timeline {} changed during restore",
+                    self.ttid
+                );
+            }
+            let remote_consistent_lsn = self.get_walsenders().get_remote_consistent_lsn();
+            shared_state
+                .sk
+                .persist_eviction_state(EvictionState::Present, remote_consistent_lsn)
+                .await?;
+        }
+
+        TIMELINE_RESTORES.inc();
+        info!("restored evicted WAL up to {flush_lsn}");
+
+        // The local segment is the source of truth again, the next eviction
+        // uploads a partial segment under a name of its own.
+        if downloaded {
+            if let Err(e) = delete_object(&remote).await {
+                warn!("failed to delete restored partial segment {remote}: {e:#}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -486,7 +486,7 @@ fn get_segments(start: Lsn, end: Lsn, seg_size: usize) -> Vec<Segment> {
 
 static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
 
-async fn backup_object(
+pub(crate) async fn backup_object(
     source_file: &Utf8Path,
     target_file: &RemotePath,
     size: usize,
@@ -506,6 +506,52 @@ async fn backup_object(
     storage.upload_storage_object(file, size, target_file).await
 }
 
+/// Path in remote storage of a file under the safekeeper workdir. The remote
+/// storage mirrors the structure of the file system.
+pub fn remote_path(workdir: &Utf8Path, local_path: &Utf8Path) -> Result<RemotePath> {
+    local_path
+        .strip_prefix(workdir)
+        .context("Failed to strip workdir prefix")
+        .and_then(RemotePath::new)
+        .with_context(|| {
+            format!("Failed to resolve remote part of path {local_path:?} for base {workdir:?}")
+        })
+}
+
+/// Download a whole object to a local file. The file is written under a
+/// temporary name and renamed into place once it's durable.
+pub(crate) async fn download_object(file_path: &RemotePath, local_path: &Utf8Path) -> Result<()> {
+    let mut reader = read_object(file_path, 0).await?;
+
+    let tmp_path = local_path.with_extension("download");
+    let mut file = File::create(&tmp_path)
+        .await
+        .with_context(|| format!("Failed to create {tmp_path:?}"))?;
+    tokio::io::copy(&mut reader, &mut file)
+        .await
+        .with_context(|| format!("Failed to download {file_path:?} to {tmp_path:?}"))?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp_path, local_path)
+        .await
+        .with_context(|| format!("Failed to rename {tmp_path:?} to {local_path:?}"))?;
+    Ok(())
+}
+
+pub(crate) async fn delete_object(file_path: &RemotePath) -> Result<()> {
+    let storage = REMOTE_STORAGE
+        .get()
+        .context("Failed to get remote storage")?
+        .as_ref()
+        .context("No remote storage configured")?;
+
+    storage
+        .delete(file_path)
+        .await
+        .with_context(|| format!("Failed to delete remote object {file_path:?}"))
+}
+
 pub async fn read_object(
     file_path: &RemotePath,
     offset: u64,
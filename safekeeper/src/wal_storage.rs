@@ -13,7 +13,6 @@ use camino::{Utf8Path, Utf8PathBuf};
 use futures::future::BoxFuture;
 use postgres_ffi::v14::xlog_utils::{IsPartialXLogFileName, IsXLogFileName, XLogFromFileName};
 use postgres_ffi::{dispatch_pgversion, XLogSegNo, PG_TLI};
-use remote_storage::RemotePath;
 use std::cmp::{max, min};
 use std::io::{self, SeekFrom};
 use std::pin::Pin;
@@ -23,8 +22,8 @@ use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tracing::*;
 
 use crate::metrics::{time_io_closure, WalStorageMetrics, REMOVED_WAL_SEGMENTS};
-use crate::safekeeper::SafeKeeperState;
-use crate::wal_backup::read_object;
+use crate::safekeeper::{EvictionState, SafeKeeperState};
+use crate::wal_backup::{read_object, remote_path};
 use crate::SafeKeeperConf;
 use postgres_ffi::waldecoder::WalStreamDecoder;
 use postgres_ffi::XLogFileName;
@@ -137,6 +136,10 @@ impl PhysicalStorage {
         // older version of the code, we could lose data forever.
         let write_lsn = if state.commit_lsn == Lsn(0) {
             Lsn(0)
+        } else if let EvictionState::Offloaded { flush_lsn, .. } = state.eviction_state {
+            // The segments are in remote storage only, the end of WAL was
+            // recorded when the timeline was evicted.
+            flush_lsn
         } else {
             let version = state.server.pg_version / 10000;
 
@@ -515,6 +518,11 @@ pub struct WalReader {
     // We will respond with zero-ed bytes before this Lsn as long as
     // pos is in the same segment as timeline_start_lsn.
     timeline_start_lsn: Lsn,
+    // If the timeline was evicted, the end of WAL at that point, and the
+    // remote object holding the WAL of its last segment.
+    offloaded_partial: Option<(Lsn, String)>,
+    // If `wal_segment` reads that remote object, the end of its WAL.
+    wal_segment_end: Option<Lsn>,
     // integer version number of PostgreSQL, e.g. 14; 15; 16
     pg_version: u32,
     system_id: SystemId,
@@ -559,6 +567,14 @@ impl WalReader {
             enable_remote_read,
             local_start_lsn: state.local_start_lsn,
             timeline_start_lsn: state.timeline_start_lsn,
+            offloaded_partial: match &state.eviction_state {
+                EvictionState::Present => None,
+                EvictionState::Offloaded {
+                    flush_lsn,
+                    partial_segment_name,
+                } => Some((*flush_lsn, partial_segment_name.clone())),
+            },
+            wal_segment_end: None,
             pg_version: state.server.pg_version / 10000,
             system_id: state.server.system_id,
             timeline_start_segment: None,
@@ -622,13 +638,29 @@ impl WalReader {
 
         let mut wal_segment = match self.wal_segment.take() {
             Some(reader) => reader,
-            None => self.open_segment().await?,
+            None => {
+                let (reader, end) = self.open_segment().await?;
+                self.wal_segment_end = end;
+                reader
+            }
         };
 
         // How much to read and send in message? We cannot cross the WAL file
         // boundary, and we don't want send more than provided buffer.
         let xlogoff = self.pos.segment_offset(self.wal_seg_size);
-        let send_size = min(buf.len(), self.wal_seg_size - xlogoff);
+        let mut send_size = min(buf.len(), self.wal_seg_size - xlogoff);
+        if let Some(end) = self.wal_segment_end {
+            // The remote copy of an evicted partial segment has nothing
+            // beyond the end of WAL at eviction.
+            if self.pos >= end {
+                bail!(
+                    "WAL at {} is not available, timeline was evicted at {}",
+                    self.pos,
+                    end
+                );
+            }
+            send_size = min(send_size, (end - self.pos) as usize);
+        }
 
         // Read some data from the file.
         let buf = &mut buf[0..send_size];
@@ -636,16 +668,21 @@ impl WalReader {
         self.pos += send_size as u64;
 
         // Decide whether to reuse this file. If we don't set wal_segment here
-        // a new reader will be opened next time.
-        if self.pos.segment_offset(self.wal_seg_size) != 0 {
+        // a new reader will be opened next time. That's also the case at the
+        // end of an evicted partial segment: by the time more WAL is read,
+        // the segment is local again.
+        let at_end = self.wal_segment_end.is_some_and(|end| self.pos >= end);
+        if self.pos.segment_offset(self.wal_seg_size) != 0 && !at_end {
             self.wal_segment = Some(wal_segment);
         }
 
         Ok(send_size)
     }
 
-    /// Open WAL segment at the current position of the reader.
-    async fn open_segment(&self) -> Result<Pin<Box<dyn AsyncRead + Send + Sync>>> {
+    /// Open WAL segment at the current position of the reader. If it is read
+    /// from the remote copy of an evicted partial segment, also returns the end
+    /// of WAL in it.
+    async fn open_segment(&self) -> Result<(Pin<Box<dyn AsyncRead + Send + Sync>>, Option<Lsn>)> {
         let xlogoff = self.pos.segment_offset(self.wal_seg_size);
         let segno = self.pos.segment_number(self.wal_seg_size);
         let wal_file_name = XLogFileName(PG_TLI, segno, self.wal_seg_size);
@@ -657,7 +694,7 @@ impl WalReader {
             match res {
                 Ok(mut file) => {
                     file.seek(SeekFrom::Start(xlogoff as u64)).await?;
-                    return Ok(Box::pin(file));
+                    return Ok((Box::pin(file), None));
                 }
                 Err(e) => {
                     let is_not_found = e.chain().any(|e| {
@@ -677,17 +714,17 @@ impl WalReader {
 
         // Try to open remote file, if remote reads are enabled
         if self.enable_remote_read {
-            let remote_wal_file_path = wal_file_path
-                .strip_prefix(&self.workdir)
-                .context("Failed to strip workdir prefix")
-                .and_then(RemotePath::new)
-                .with_context(|| {
-                    format!(
-                        "Failed to resolve remote part of path {:?} for base {:?}",
-                        wal_file_path, self.workdir,
-                    )
-                })?;
-            return read_object(&remote_wal_file_path, xlogoff as u64).await;
+            if let Some((flush_lsn, partial_segment_name)) = &self.offloaded_partial {
+                if segno == flush_lsn.segment_number(self.wal_seg_size) {
+                    let remote_partial_path =
+                        remote_path(&self.workdir, &self.timeline_dir.join(partial_segment_name))?;
+                    let reader = read_object(&remote_partial_path, xlogoff as u64).await?;
+                    return Ok((reader, Some(*flush_lsn)));
+                }
+            }
+            let remote_wal_file_path = remote_path(&self.workdir, &wal_file_path)?;
+            let reader = read_object(&remote_wal_file_path, xlogoff as u64).await?;
+            return Ok((reader, None));
         }
 
         bail!("WAL segment is not found")
@@ -326,6 +326,17 @@ class PageserverHttpClient(requests.Session):
         res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/heatmap_upload")
         self.verbose_error(res)
 
+    def tenant_secondary_download(self, tenant_id: TenantId):
+        res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/secondary/download")
+        self.verbose_error(res)
+
+    def tenant_secondary_status(self, tenant_id: TenantId) -> dict[str, Any]:
+        res = self.get(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/secondary/status")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def set_tenant_config(self, tenant_id: TenantId, config: dict[str, Any]):
         assert "tenant_id" not in config.keys()
         res = self.put(
@@ -557,6 +568,19 @@ class PageserverHttpClient(requests.Session):
         res_json = res.json()
         assert res_json is None
 
+    def timeline_archival_config(self, tenant_id: TenantId, timeline_id: TimelineId, state: str):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/archival_config",
+            json={"state": state},
+        )
+        self.verbose_error(res)
+
+    def detach_ancestor(self, tenant_id: TenantId, timeline_id: TimelineId):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/detach_ancestor"
+        )
+        self.verbose_error(res)
+
     def timeline_spawn_download_remote_layers(
         self,
         tenant_id: TenantId,
@@ -155,6 +155,7 @@ def test_fully_custom_config(positive_env: NeonEnv):
     env = positive_env
 
     fully_custom_config = {
+        "blob_compression": "zstd",
         "compaction_period": "1h",
         "compaction_threshold": 13,
         "compaction_target_size": 1048576,
@@ -0,0 +1,78 @@
+import io
+import os
+import subprocess
+import tarfile
+
+import zstandard
+from fixtures.neon_fixtures import NeonEnvBuilder, PgBin, wait_for_last_flush_lsn
+
+
+def test_basebackup_cache(neon_env_builder: NeonEnvBuilder):
+    """
+    Start endpoints repeatedly with the basebackup cache enabled, with and without changes to
+    the non-relation files in between, and check that they see the right data.
+    """
+    neon_env_builder.pageserver_config_override = "basebackup_cache=true"
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenant_id = env.initial_tenant
+    timeline_id = env.initial_timeline
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        ep.safe_psql("CREATE TABLE foo (i int)")
+        ep.safe_psql("INSERT INTO foo SELECT generate_series(1, 1000)")
+        wait_for_last_flush_lsn(env, ep, tenant_id, timeline_id)
+
+    # Nothing changed since the shutdown checkpoint of the previous endpoint.
+    for _ in range(2):
+        with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+            assert ep.safe_psql("SELECT count(*) FROM foo")[0][0] == 1000
+    hits = client.get_metric_value("pageserver_basebackup_cache_hits_total")
+    assert hits is not None and hits > 0
+
+    # A new unlogged table changes the cached files: the next endpoint must see its init fork.
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        ep.safe_psql("CREATE UNLOGGED TABLE bar (i int)")
+        ep.safe_psql("INSERT INTO foo SELECT generate_series(1, 1000)")
+        wait_for_last_flush_lsn(env, ep, tenant_id, timeline_id)
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        assert ep.safe_psql("SELECT count(*) FROM foo")[0][0] == 2000
+        assert ep.safe_psql("SELECT count(*) FROM bar")[0][0] == 0
+
+    misses = client.get_metric_value("pageserver_basebackup_cache_misses_total")
+    assert misses is not None and misses > 1
+
+
+def test_basebackup_zstd(neon_env_builder: NeonEnvBuilder, pg_bin: PgBin):
+    """
+    Request a zstd-compressed basebackup, and check that it is a complete tarball.
+    """
+    env = neon_env_builder.init_start()
+    tenant_id = env.initial_tenant
+    timeline_id = env.initial_timeline
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        ep.safe_psql("CREATE TABLE foo (i int)")
+        lsn = wait_for_last_flush_lsn(env, ep, tenant_id, timeline_id)
+
+    output = env.repo_dir / "basebackup.tar.zst"
+    psql_path = os.path.join(pg_bin.pg_bin_path, "psql")
+    cmd = rf"""
+        {psql_path}                                    \
+            --no-psqlrc                                \
+            postgres://localhost:{env.pageserver.service_port.pg}  \
+            -c 'basebackup {tenant_id} {timeline_id} {lsn} --zstd'  \
+         > {output}
+    """
+    psql_env = {"LD_LIBRARY_PATH": pg_bin.pg_lib_dir}
+    result = subprocess.run(cmd, env=psql_env, capture_output=True, text=True, shell=True)
+    assert result.returncode == 0, result.stderr
+
+    with open(output, "rb") as f:
+        tar_bytes = zstandard.ZstdDecompressor().stream_reader(f).read()
+    with tarfile.open(fileobj=io.BytesIO(tar_bytes)) as tar:
+        names = tar.getnames()
+    assert "global/pg_control" in names
+    assert "zenith.signal" in names
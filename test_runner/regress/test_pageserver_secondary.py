@@ -258,7 +258,9 @@ def test_live_migration(neon_env_builder: NeonEnvBuilder):
     # Writes and reads still work in AttachedStale.
     workload.validate(pageserver_a.id)
 
-    # TODO: call into secondary mode API hooks to do an upload/download sync
+    # Sync the destination's local layers with what the origin has
+    pageserver_a.http_client().tenant_heatmap_upload(tenant_id)
+    pageserver_b.http_client().tenant_secondary_download(tenant_id)
 
     # Generate some more dirty writes: we expect the origin to ingest WAL in
     # in AttachedStale
@@ -369,3 +371,107 @@ def test_heatmap_uploads(neon_env_builder: NeonEnvBuilder):
     log.info(f"Read back heatmap: {heatmap_second}")
     assert heatmap_second != heatmap_first
     validate_heatmap(heatmap_second)
+
+
+def list_layers(
+    pageserver: NeonPageserver, tenant_id: TenantId, timeline_id: TimelineId
+) -> set[str]:
+    """
+    The names of the layer files in a pageserver's local timeline directory
+    """
+    timeline_path = pageserver.timeline_dir(tenant_id, timeline_id)
+    return set(
+        path.name
+        for path in timeline_path.glob("*")
+        if path.name != "metadata"
+        and "ephemeral" not in path.name
+        and "temp_download" not in path.name
+    )
+
+
+def test_secondary_downloads(neon_env_builder: NeonEnvBuilder):
+    """
+    Test the data flow of secondary mode: heatmap uploads from the attached location,
+    downloads of the layers it lists to the secondary location, deletion of layers evicted
+    by the attached location, and attaching the secondary location with its warm cache.
+    """
+    neon_env_builder.num_pageservers = 2
+    neon_env_builder.enable_pageserver_remote_storage(
+        remote_storage_kind=RemoteStorageKind.LOCAL_FS,
+    )
+    env = neon_env_builder.init_start(initial_tenant_conf=TENANT_CONF)
+    assert isinstance(env.pageserver_remote_storage, LocalFsStorage)
+    assert env.attachment_service is not None
+
+    tenant_id = env.initial_tenant
+    timeline_id = env.initial_timeline
+
+    ps_attached = env.pageservers[0]
+    ps_secondary = env.pageservers[1]
+
+    workload = Workload(env, tenant_id, timeline_id)
+    workload.init(ps_attached.id)
+    workload.write_rows(256, ps_attached.id)
+    workload.churn_rows(64, ps_attached.id)
+
+    ps_secondary.tenant_location_configure(
+        tenant_id,
+        {
+            "mode": "Secondary",
+            "secondary_conf": {"warm": True},
+            "tenant_conf": {},
+        },
+    )
+
+    def heatmap_layers() -> set[str]:
+        heatmap = env.pageserver_remote_storage.heatmap_content(tenant_id)
+        assert len(heatmap["timelines"]) == 1
+        return set(layer["name"] for layer in heatmap["timelines"][0]["layers"])
+
+    # The secondary location downloads all the layers in the heatmap
+    ps_attached.http_client().tenant_heatmap_upload(tenant_id)
+    ps_secondary.http_client().tenant_secondary_download(tenant_id)
+    first_layers = heatmap_layers()
+    assert len(first_layers) > 0
+    assert list_layers(ps_secondary, tenant_id, timeline_id) == first_layers
+
+    status = ps_secondary.http_client().tenant_secondary_status(tenant_id)
+    log.info(f"Secondary status: {status}")
+    assert status["layers_total"] == len(first_layers)
+    assert status["layers_downloaded"] == status["layers_total"]
+    assert status["bytes_downloaded"] == status["bytes_total"]
+
+    # Layers evicted on the attached location leave the heatmap, and the secondary deletes them
+    evict_random_layers(random.Random(1), ps_attached, tenant_id, timeline_id)
+    ps_attached.http_client().tenant_heatmap_upload(tenant_id)
+    ps_secondary.http_client().tenant_secondary_download(tenant_id)
+    second_layers = heatmap_layers()
+    assert second_layers != first_layers
+    assert list_layers(ps_secondary, tenant_id, timeline_id) == second_layers
+    assert ps_secondary.http_client().get_metric_value("pageserver_secondary_evict_layer") == len(
+        first_layers - second_layers
+    )
+
+    # Attaching the secondary location uses the layers it already has
+    ps_attached.tenant_location_configure(
+        tenant_id,
+        {
+            "mode": "AttachedStale",
+            "secondary_conf": None,
+            "tenant_conf": {},
+            "generation": 1,
+        },
+        flush_ms=5000,
+    )
+    generation = env.attachment_service.attach_hook_issue(tenant_id, ps_secondary.id)
+    ps_secondary.tenant_location_configure(
+        tenant_id,
+        {
+            "mode": "AttachedSingle",
+            "secondary_conf": None,
+            "tenant_conf": {},
+            "generation": generation,
+        },
+    )
+    assert second_layers <= list_layers(ps_secondary, tenant_id, timeline_id)
+    workload.validate(ps_secondary.id)
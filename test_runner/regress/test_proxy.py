@@ -295,6 +295,101 @@ def test_sql_over_http_output_options(static_proxy: NeonProxy):
     assert rows == [["1", "a", "{1,2,3}"]]
 
 
+def test_sql_over_http_ndjson(static_proxy: NeonProxy):
+    static_proxy.safe_psql("create role http3 with login password 'http3' superuser")
+
+    def q(sql: str, response_format: str, expected_code: int = 200) -> requests.Response:
+        connstr = (
+            f"postgresql://http3:http3@{static_proxy.domain}:{static_proxy.proxy_port}/postgres"
+        )
+        response = requests.post(
+            f"https://{static_proxy.domain}:{static_proxy.external_http_port}/sql",
+            data=json.dumps({"query": sql, "params": []}),
+            headers={
+                "Content-Type": "application/sql",
+                "Neon-Connection-String": connstr,
+                "Neon-Response-Format": response_format,
+            },
+            verify=str(static_proxy.test_output_dir / "proxy.crt"),
+        )
+        assert response.status_code == expected_code
+        return response
+
+    # bigger than the size limit of the buffered responses
+    response = q("select g as n, repeat('x', 1024) as s from generate_series(1, 20000) g", "ndjson")
+    assert response.headers["Content-Type"] == "application/x-ndjson"
+    lines = [json.loads(line) for line in response.text.splitlines()]
+    assert [f["name"] for f in lines[0]["fields"]] == ["n", "s"]
+    assert lines[1] == {"n": 1, "s": "x" * 1024}
+    assert len(lines) == 20000 + 2
+    assert lines[-1] == {"command": "SELECT", "rowCount": 20000}
+
+    response = q("select 1 as n", "arrow")
+    assert response.headers["Content-Type"] == "application/vnd.apache.arrow.stream"
+
+    # errors in the query are still reported before the stream starts
+    response = q("select * from no_such_table", "ndjson", expected_code=400)
+    assert "no_such_table" in response.json()["message"]
+
+    q("select 1", "csv", expected_code=400)
+
+
+def test_sql_over_http_interactive_transaction(static_proxy: NeonProxy):
+    static_proxy.safe_psql("create role http4 with login password 'http4' superuser")
+    static_proxy.safe_psql("create table counters (id int primary key, n int)")
+    static_proxy.safe_psql("insert into counters values (1, 0)")
+
+    def q(sql: str, expected_code: int = 200, **headers: str) -> requests.Response:
+        connstr = (
+            f"postgresql://http4:http4@{static_proxy.domain}:{static_proxy.proxy_port}/postgres"
+        )
+        response = requests.post(
+            f"https://{static_proxy.domain}:{static_proxy.external_http_port}/sql",
+            data=json.dumps({"query": sql, "params": []}),
+            headers={
+                "Content-Type": "application/sql",
+                "Neon-Connection-String": connstr,
+                **headers,
+            },
+            verify=str(static_proxy.test_output_dir / "proxy.crt"),
+        )
+        assert response.status_code == expected_code, f"response: {response.json()}"
+        return response
+
+    # read-modify-write over three requests
+    response = q("select n from counters where id = 1 for update", **{"Neon-Transaction": "begin"})
+    txn = response.headers["Neon-Transaction"]
+    n = response.json()["rows"][0]["n"]
+    response = q(f"update counters set n = {n + 1} where id = 1", **{"Neon-Transaction": txn})
+    assert response.headers["Neon-Transaction"] == txn
+
+    # not visible outside of the transaction yet
+    assert q("select n from counters where id = 1").json()["rows"] == [{"n": 0}]
+
+    response = q(
+        "select n from counters where id = 1",
+        **{"Neon-Transaction": txn, "Neon-Transaction-End": "commit"},
+    )
+    assert "Neon-Transaction" not in response.headers
+    assert q("select n from counters where id = 1").json()["rows"] == [{"n": 1}]
+
+    # the transaction is gone after the commit
+    q("select 1", 400, **{"Neon-Transaction": txn})
+
+    # a failed query rolls the transaction back
+    response = q("update counters set n = 10 where id = 1", **{"Neon-Transaction": "begin"})
+    txn = response.headers["Neon-Transaction"]
+    q("select * from no_such_table", 400, **{"Neon-Transaction": txn})
+    q("select 1", 400, **{"Neon-Transaction": txn})
+    assert q("select n from counters where id = 1").json()["rows"] == [{"n": 1}]
+
+    # explicit rollback
+    response = q("update counters set n = 20 where id = 1", **{"Neon-Transaction": "begin"})
+    txn = response.headers["Neon-Transaction"]
+    q("select 1", **{"Neon-Transaction": txn, "Neon-Transaction-End": "rollback"})
+    assert q("select n from counters where id = 1").json()["rows"] == [{"n": 1}]
+
+
 def test_sql_over_http_batch(static_proxy: NeonProxy):
     static_proxy.safe_psql("create role http with login password 'http' superuser")
 
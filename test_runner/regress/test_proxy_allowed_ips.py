@@ -72,3 +72,25 @@ async def test_proxy_http_allowed_ips(static_proxy: NeonProxy, vanilla_pg: Vanil
         f"UPDATE {TABLE_NAME} SET allowed_ips = '8.8.8.8,127.0.0.1' WHERE endpoint_id = 'proxy'"
     )
     query(200, "select 1;")  # should work now
+
+
+@pytest.mark.asyncio
+async def test_proxy_psql_allowed_vpc_endpoint_ids(
+    static_proxy: NeonProxy, vanilla_pg: VanillaPostgres
+):
+    # Only reachable through a private link, which a direct connection doesn't come through
+    vanilla_pg.safe_psql(
+        f"INSERT INTO {TABLE_NAME} (endpoint_id, allowed_ips, allowed_vpc_endpoint_ids) VALUES ('vpc-project', '::1,127.0.0.1', 'vpce-0123456789abcdef0')"
+    )
+    # No private link restrictions
+    vanilla_pg.safe_psql(
+        f"INSERT INTO {TABLE_NAME} (endpoint_id, allowed_ips) VALUES ('public-project', '::1,127.0.0.1')"
+    )
+
+    with pytest.raises(psycopg2.Error) as exprinfo:
+        static_proxy.safe_psql(query="select 1", host="vpc-project.localtest.me")
+    text = str(exprinfo.value).strip()
+    assert "only accepts connections through its allowed private links" in text
+
+    out = static_proxy.safe_psql(query="select 1", host="public-project.localtest.me")
+    assert out[0][0] == 1
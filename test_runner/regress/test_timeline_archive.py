@@ -0,0 +1,59 @@
+import pytest
+from fixtures.neon_fixtures import NeonEnvBuilder, wait_for_last_flush_lsn
+from fixtures.pageserver.http import PageserverApiException
+from fixtures.remote_storage import RemoteStorageKind
+
+
+def test_timeline_archive(neon_env_builder: NeonEnvBuilder):
+    """
+    Archive a branch, check that it is offloaded from the pageserver, also across restarts,
+    and that it serves its data again once unarchived.
+    """
+    neon_env_builder.enable_pageserver_remote_storage(RemoteStorageKind.LOCAL_FS)
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenant_id = env.initial_tenant
+    main_timeline_id = env.initial_timeline
+
+    branch_timeline_id = env.neon_cli.create_branch("branch", "main", tenant_id=tenant_id)
+    leaf_timeline_id = env.neon_cli.create_branch("leaf", "branch", tenant_id=tenant_id)
+
+    with env.endpoints.create_start("leaf", tenant_id=tenant_id) as ep:
+        ep.safe_psql("CREATE TABLE foo AS SELECT i FROM generate_series(1, 1000) i")
+        wait_for_last_flush_lsn(env, ep, tenant_id, leaf_timeline_id)
+
+    # a timeline with unarchived children cannot be archived
+    with pytest.raises(PageserverApiException, match="unarchived child timelines"):
+        client.timeline_archival_config(tenant_id, branch_timeline_id, "Archived")
+
+    client.timeline_archival_config(tenant_id, leaf_timeline_id, "Archived")
+    client.timeline_archival_config(tenant_id, branch_timeline_id, "Archived")
+    # archiving is idempotent
+    client.timeline_archival_config(tenant_id, branch_timeline_id, "Archived")
+
+    def loaded_timelines():
+        return {t["timeline_id"] for t in client.timeline_list(tenant_id)}
+
+    assert loaded_timelines() == {str(main_timeline_id)}
+    assert not env.pageserver.timeline_dir(tenant_id, leaf_timeline_id).exists()
+
+    # archived timelines are not loaded on restart either
+    env.pageserver.stop()
+    env.pageserver.start()
+    assert loaded_timelines() == {str(main_timeline_id)}
+
+    # the ancestor must be unarchived first
+    with pytest.raises(PageserverApiException, match="is archived"):
+        client.timeline_archival_config(tenant_id, leaf_timeline_id, "Unarchived")
+
+    client.timeline_archival_config(tenant_id, branch_timeline_id, "Unarchived")
+    client.timeline_archival_config(tenant_id, leaf_timeline_id, "Unarchived")
+    assert loaded_timelines() == {
+        str(main_timeline_id),
+        str(branch_timeline_id),
+        str(leaf_timeline_id),
+    }
+
+    with env.endpoints.create_start("leaf", tenant_id=tenant_id) as ep:
+        assert ep.safe_psql("SELECT count(*) FROM foo")[0][0] == 1000
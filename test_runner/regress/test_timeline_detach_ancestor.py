@@ -0,0 +1,54 @@
+import pytest
+from fixtures.neon_fixtures import NeonEnvBuilder, wait_for_last_flush_lsn
+from fixtures.pageserver.http import PageserverApiException
+from fixtures.remote_storage import RemoteStorageKind
+
+
+def test_detach_ancestor(neon_env_builder: NeonEnvBuilder):
+    """
+    Detach a branch from its ancestor, and check that it keeps serving the data it inherited
+    from the ancestor, also after a pageserver restart.
+    """
+    neon_env_builder.enable_pageserver_remote_storage(RemoteStorageKind.LOCAL_FS)
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenant_id = env.initial_tenant
+    main_timeline_id = env.initial_timeline
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        ep.safe_psql("CREATE TABLE foo (i int)")
+        ep.safe_psql("INSERT INTO foo SELECT i FROM generate_series(1, 1000) i")
+        wait_for_last_flush_lsn(env, ep, tenant_id, main_timeline_id)
+        client.timeline_checkpoint(tenant_id, main_timeline_id)
+        ep.safe_psql("INSERT INTO foo SELECT i FROM generate_series(1001, 2000) i")
+        wait_for_last_flush_lsn(env, ep, tenant_id, main_timeline_id)
+
+    branch_timeline_id = env.neon_cli.create_branch("branch", "main", tenant_id=tenant_id)
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        # writes on the ancestor after the branch point must not show up on the branch
+        ep.safe_psql("INSERT INTO foo SELECT i FROM generate_series(2001, 3000) i")
+        wait_for_last_flush_lsn(env, ep, tenant_id, main_timeline_id)
+
+    client.detach_ancestor(tenant_id, branch_timeline_id)
+
+    detail = client.timeline_detail(tenant_id, branch_timeline_id)
+    assert detail["ancestor_timeline_id"] is None
+
+    # detaching again is rejected, as is detaching a root timeline
+    for timeline_id in [branch_timeline_id, main_timeline_id]:
+        with pytest.raises(PageserverApiException, match="timeline has no ancestor"):
+            client.detach_ancestor(tenant_id, timeline_id)
+
+    with env.endpoints.create_start("branch", tenant_id=tenant_id) as ep:
+        assert ep.safe_psql("SELECT count(*) FROM foo")[0][0] == 2000
+
+    env.pageserver.stop()
+    env.pageserver.start()
+
+    detail = client.timeline_detail(tenant_id, branch_timeline_id)
+    assert detail["ancestor_timeline_id"] is None
+
+    with env.endpoints.create_start("branch", tenant_id=tenant_id) as ep:
+        assert ep.safe_psql("SELECT count(*) FROM foo")[0][0] == 2000
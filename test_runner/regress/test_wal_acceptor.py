@@ -500,6 +500,58 @@ def test_wal_backup(neon_env_builder: NeonEnvBuilder):
     )
 
 
+def safekeeper_counter(sk: Safekeeper, name: str) -> float:
+    metrics = parse_metrics(sk.http_client().get_metrics_str(), f"safekeeper_{sk.id}")
+    return metrics.query_one(name).value
+
+
+def test_wal_eviction(neon_env_builder: NeonEnvBuilder):
+    """
+    Check that the WAL of an idle, fully offloaded timeline is evicted from
+    safekeepers, and that the timeline works again once compute is back.
+    """
+    neon_env_builder.num_safekeepers = 3
+    neon_env_builder.enable_safekeeper_remote_storage(default_remote_storage())
+
+    env = neon_env_builder.init_start()
+    for sk in env.safekeepers:
+        sk.stop().start(extra_opts=["--enable-wal-eviction", "--eviction-min-idle=1s"])
+
+    tenant_id = env.initial_tenant
+    timeline_id = env.neon_cli.create_branch("test_wal_eviction")
+    endpoint = env.endpoints.create_start("test_wal_eviction")
+    endpoint.safe_psql("create table t(key int, value text)")
+    # a bit more than one segment, so that both full and partial segments are evicted
+    endpoint.safe_psql("insert into t select generate_series(1,300000), 'payload'")
+    endpoint.stop()
+
+    # Let the pageserver upload everything, then stop it, so that nothing
+    # keeps the timeline active.
+    sk_http = env.safekeepers[0].http_client()
+    commit_lsn = sk_http.timeline_status(tenant_id, timeline_id).commit_lsn
+    ps_http = env.pageserver.http_client()
+    wait_for_last_record_lsn(ps_http, tenant_id, timeline_id, commit_lsn)
+    ps_http.timeline_checkpoint(tenant_id, timeline_id)
+    wait_for_upload(ps_http, tenant_id, timeline_id, commit_lsn)
+    env.pageserver.stop()
+
+    for sk in env.safekeepers:
+        wait(
+            lambda sk=sk: len(sk.list_segments(tenant_id, timeline_id)) == 0,  # type: ignore
+            f"sk_id={sk.id} to evict WAL",
+            timeout=60,
+        )
+        assert safekeeper_counter(sk, "safekeeper_timeline_evictions_total") > 0
+
+    env.pageserver.start()
+    endpoint = env.endpoints.create_start("test_wal_eviction")
+    endpoint.safe_psql("insert into t values (0, 'payload')")
+    assert endpoint.safe_psql("select count(*) from t")[0][0] == 300001
+
+    for sk in env.safekeepers:
+        assert safekeeper_counter(sk, "safekeeper_timeline_restores_total") > 0
+
+
 def test_s3_wal_replay(neon_env_builder: NeonEnvBuilder):
     neon_env_builder.num_safekeepers = 3
 
@@ -1682,6 +1734,82 @@ def test_delete_force(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
             cur.execute("INSERT INTO t (key) VALUES (123)")
 
 
+def test_membership_switch(neon_env_builder: NeonEnvBuilder):
+    """
+    Move a timeline from safekeepers 1-3 to safekeepers 2-4 through a joint
+    configuration, and check that the removed safekeeper refuses to serve it.
+    """
+    neon_env_builder.num_safekeepers = 4
+    env = neon_env_builder.init_start()
+    tenant_id = env.initial_tenant
+    timeline_id = env.neon_cli.create_branch("test_membership_switch")
+
+    def member_set(ids: List[int]) -> Dict[str, Any]:
+        return {
+            "members": [
+                {"id": sk.id, "host": "localhost", "pg_port": sk.port.pg}
+                for sk in env.safekeepers
+                if sk.id in ids
+            ]
+        }
+
+    endpoint = env.endpoints.create("test_membership_switch")
+    endpoint.active_safekeepers = [1, 2, 3]
+    endpoint.start()
+    endpoint.safe_psql("create table t(key int, value text)")
+    endpoint.safe_psql("insert into t select generate_series(1,1000), 'payload'")
+    endpoint.stop()
+
+    # Safekeeper 4 joins with the joint configuration, pulling the timeline.
+    joint = {
+        "generation": 1,
+        "members": member_set([1, 2, 3]),
+        "new_members": member_set([2, 3, 4]),
+    }
+    pull_from = [f"http://localhost:{sk.port.http}" for sk in env.safekeepers[:3]]
+    for sk in env.safekeepers:
+        res = sk.http_client().membership_switch(
+            tenant_id, timeline_id, {"mconf": joint, "pull_from": pull_from}
+        )
+        assert res["current_conf"] == joint
+
+    new = {"generation": 2, "members": member_set([2, 3, 4]), "new_members": None}
+    for sk in env.safekeepers:
+        sk.http_client().membership_switch(tenant_id, timeline_id, {"mconf": new})
+        assert sk.http_client().membership_get(tenant_id, timeline_id) == new
+
+    # an older configuration doesn't take effect
+    res = env.safekeepers[0].http_client().membership_switch(
+        tenant_id, timeline_id, {"mconf": joint}
+    )
+    assert res["current_conf"] == new
+
+    endpoint = env.endpoints.create("test_membership_switch")
+    endpoint.active_safekeepers = [2, 3, 4]
+    endpoint.start()
+    assert endpoint.safe_psql("select count(*) from t")[0][0] == 1000
+    endpoint.safe_psql("insert into t values (0, 'payload')")
+
+    sk1 = env.safekeepers[0]
+    flush_lsn = sk1.http_client().timeline_status(tenant_id, timeline_id).flush_lsn
+    with pytest.raises(psycopg2.Error, match="not a member"):
+        sk1.append_logical_message(
+            tenant_id,
+            timeline_id,
+            {
+                "lm_prefix": "prefix",
+                "lm_message": "message",
+                "set_commit_lsn": True,
+                "send_proposer_elected": True,
+                "term": 100,
+                "begin_lsn": int(flush_lsn),
+                "epoch_start_lsn": int(flush_lsn),
+                "truncate_lsn": int(flush_lsn),
+                "pg_version": int(env.pg_version) * 10000,
+            },
+        )
+
+
 def test_pull_timeline(neon_env_builder: NeonEnvBuilder):
     def safekeepers_guc(env: NeonEnv, sk_names: List[int]) -> str:
         return ",".join([f"localhost:{sk.port.pg}" for sk in env.safekeepers if sk.id in sk_names])
@@ -0,0 +1,45 @@
+import io
+
+from fixtures.neon_fixtures import NeonEnvBuilder, wait_for_last_flush_lsn
+from fixtures.pg_version import PgVersion, skip_on_postgres
+
+
+@skip_on_postgres(PgVersion.V14, reason="heap records are replayed in-process on v16 only")
+@skip_on_postgres(PgVersion.V15, reason="heap records are replayed in-process on v16 only")
+def test_wal_redo_native_heap_differential(neon_env_builder: NeonEnvBuilder):
+    """
+    Replay a heap workload with both the in-process heap redo and the wal-redo process, and
+    check that they produce identical pages.
+    """
+    neon_env_builder.pageserver_config_override = "wal_redo_native_heap='differential'"
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenant_id = env.initial_tenant
+    timeline_id = env.initial_timeline
+    query = "SELECT count(*), sum(id), string_agg(val, ',' ORDER BY id) FROM foo"
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        ep.safe_psql("CREATE TABLE foo (id int primary key, val text)")
+        # multi-insert
+        with ep.cursor() as cur:
+            rows = "".join(f"{i}\tcopied\n" for i in range(1, 1001))
+            cur.copy_expert("COPY foo FROM STDIN", io.StringIO(rows))
+        # insert
+        ep.safe_psql("INSERT INTO foo SELECT i, 'inserted' FROM generate_series(1001, 2000) i")
+        # HOT and non-HOT updates, with prefix and suffix compression
+        ep.safe_psql("UPDATE foo SET val = 'updated' WHERE id % 3 = 0")
+        ep.safe_psql("UPDATE foo SET id = id + 10000 WHERE id % 7 = 0")
+        # lock and delete
+        ep.safe_psql("SELECT * FROM foo WHERE id % 5 = 0 FOR UPDATE")
+        ep.safe_psql("DELETE FROM foo WHERE id % 11 = 0")
+        expected = ep.safe_psql(query)
+        wait_for_last_flush_lsn(env, ep, tenant_id, timeline_id)
+
+    # A new endpoint starts with an empty cache, so every page is reconstructed from WAL.
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+        assert ep.safe_psql(query) == expected
+
+    applied = client.get_metric_value("pageserver_wal_redo_native_heap_records_total")
+    assert applied is not None and applied > 0
+    assert client.get_metric_value("pageserver_wal_redo_native_heap_mismatches_total") == 0
@@ -0,0 +1,40 @@
+from fixtures.neon_fixtures import NeonEnvBuilder, wait_for_last_flush_lsn
+
+
+def test_wal_redo_process_pool(neon_env_builder: NeonEnvBuilder):
+    """
+    Do WAL redo for two tenants with a pool of a single WAL redo process, so that the process
+    has to be handed over between the tenants.
+    """
+    neon_env_builder.pageserver_config_override = (
+        "wal_redo_process_pool={ max_processes = 1, acquire_timeout = '30s' }"
+    )
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenants = [(env.initial_tenant, env.initial_timeline), env.neon_cli.create_tenant()]
+
+    # The tenants get the same relfilenodes, but different data.
+    for i, (tenant_id, timeline_id) in enumerate(tenants):
+        with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+            ep.safe_psql("CREATE TABLE foo (i int, tenant int)")
+            ep.safe_psql(f"INSERT INTO foo SELECT g, {i} FROM generate_series(1, 10000) g")
+            ep.safe_psql("UPDATE foo SET i = -i WHERE i % 2 = 0")
+            wait_for_last_flush_lsn(env, ep, tenant_id, timeline_id)
+
+    # odd numbers count positive, even numbers negative
+    expected_sum = sum(range(1, 10001, 2)) - sum(range(2, 10001, 2))
+
+    # Fresh endpoints need every page to be reconstructed; alternate between the tenants.
+    for _ in range(2):
+        for i, (tenant_id, _) in enumerate(tenants):
+            with env.endpoints.create_start("main", tenant_id=tenant_id) as ep:
+                row = ep.safe_psql("SELECT count(*), sum(i), min(tenant), max(tenant) FROM foo")[0]
+                assert row == (10000, expected_sum, i, i)
+
+    processes = client.get_metric_value("pageserver_wal_redo_pool_processes")
+    assert processes is not None and processes <= 1
+    misses = client.get_metric_value("pageserver_wal_redo_pool_misses_total")
+    assert misses is not None and misses > 1
+    reassignments = client.get_metric_value("pageserver_wal_redo_pool_reassignments_total")
+    assert reassignments is not None and reassignments > 0